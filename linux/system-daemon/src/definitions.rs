@@ -49,7 +49,9 @@ impl slog::Value for CreateDeviceRequest {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeviceDescription {
-    pub id: String
+    pub id: String,
+    /// User the device was created for, from `SO_PEERCRED` on their connection
+    pub uid: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
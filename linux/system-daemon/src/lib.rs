@@ -1,12 +1,19 @@
 extern crate bincode;
 extern crate bytes;
+extern crate libc;
+extern crate nanoid;
 #[macro_use]
 extern crate serde_derive;
 extern crate slog;
+extern crate tokio_linux_uhid;
 extern crate u2fhid_protocol;
 
 pub use definitions::*;
+pub use soft_u2f_device::{SoftU2FDevice, SoftU2FDeviceBuilder};
+pub use termination::TerminationSignals;
 
 mod definitions;
+mod soft_u2f_device;
+mod termination;
 
 pub const DEFAULT_SOCKET_PATH: &str = "/run/softu2f/softu2f.sock";
@@ -11,31 +11,31 @@ extern crate slog_journald;
 extern crate slog_term;
 extern crate softu2f_system_daemon;
 extern crate systemd;
-extern crate take_mut;
 extern crate tokio;
-extern crate tokio_codec;
-extern crate tokio_core;
-extern crate tokio_io;
 extern crate tokio_linux_uhid;
-extern crate tokio_serde_bincode;
-extern crate tokio_uds;
+extern crate tokio_util;
 extern crate u2fhid_protocol;
 extern crate users;
 
 use std::io;
 use std::os::unix::io::FromRawFd;
+use std::thread;
 
 use clap::{App, Arg};
-use futures::future;
-use futures::prelude::*;
+use futures::channel::oneshot;
+use futures::future::{self, Shared};
+use futures::FutureExt;
 use slog::{Drain, Logger};
 use systemd::daemon::{is_socket_unix, Listening, SocketType};
-use tokio::reactor::Handle;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+use tokio_linux_uhid::{DeviceRegistry, MiscDriver, UHIDDevice, UHIDError};
 
 use device::Device;
-use softu2f_system_daemon::DEFAULT_SOCKET_PATH;
+use softu2f_system_daemon::{TerminationSignals, DEFAULT_SOCKET_PATH};
 
-mod bidirectional_pipe;
 mod device;
 
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
@@ -65,6 +65,9 @@ quick_error! {
     }
 }
 
+/// Completes with `Ok` once SIGTERM or SIGINT is received
+type Termination = Shared<oneshot::Receiver<()>>;
+
 fn main() {
     let args = App::new("SoftU2F System Daemon")
         .version(VERSION)
@@ -85,34 +88,100 @@ fn main() {
 
     info!(log, "starting SoftU2F system daemon"; "version" => VERSION);
 
-    tokio::run(
-        listener(socket_path, &log)
-            .map_err(|err| error!(log, "failed to start"; "error" => %err))
-            .unwrap(),
-    );
+    // Blocked before the runtime starts its worker threads, which inherit the mask
+    let termination = match TerminationSignals::block() {
+        Ok(signals) => wait_for_termination(signals, &log),
+        Err(err) => {
+            error!(log, "failed to block termination signals"; "error" => %err);
+            return;
+        }
+    };
+    let runtime = match Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            error!(log, "failed to start runtime"; "error" => %err);
+            return;
+        }
+    };
+
+    if let Err(err) = runtime.block_on(listen(socket_path, termination, &log)) {
+        error!(log, "daemon failed"; "error" => %err);
+    }
+}
+
+fn wait_for_termination(signals: TerminationSignals, log: &Logger) -> Termination {
+    let (sender, receiver) = oneshot::channel();
+    let log = log.clone();
+    thread::spawn(move || match signals.wait() {
+        Ok(signal) => {
+            info!(log, "received termination signal"; "signal" => signal);
+            let _ = sender.send(());
+        }
+        // Dropping the sender cancels the termination, the daemon keeps running
+        Err(err) => error!(log, "failed to wait for termination signals"; "error" => %err),
+    });
+    receiver.shared()
+}
+
+/// Resolves once the daemon is terminated, never if waiting for signals failed
+async fn terminated(termination: Termination) {
+    if termination.await.is_err() {
+        future::pending::<()>().await
+    }
 }
 
-fn listener(
+/// Accept connections until terminated, then destroy the devices of those still open
+async fn listen(
     socket_path: Option<&str>,
+    termination: Termination,
     log: &Logger,
-) -> Result<impl Future<Item = (), Error = ()>, Error> {
-    let accept_log = log.clone();
-    let incoming_log = log.clone();
-    let accept = move |stream| accept(stream, &accept_log);
+) -> Result<(), Error> {
     let listener = socket_listener(socket_path)?;
-    let incoming = listener.incoming().map_err(
-        move |err| error!(incoming_log, "failed to poll for incoming connections"; "error" => %err),
-    );
-    Ok(incoming.for_each(accept))
+    let mut connections = Vec::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = terminated(termination.clone()) => break,
+        };
+        connections.retain(|connection: &JoinHandle<_>| !connection.is_finished());
+        match accepted {
+            Ok((stream, _addr)) => connections.push(accept(stream, &termination, log)),
+            Err(err) => error!(log, "failed to poll for incoming connections"; "error" => %err),
+        }
+    }
+
+    info!(log, "shutting down"; "connections" => connections.len());
+    destroy_devices(connections)
+        .await
+        .map_err(device::Error::from)?;
+    Ok(())
+}
+
+/// Destroy the devices handed back by connections that were shut down
+///
+/// Connections that ended on their own have already dropped their device.
+async fn destroy_devices<T>(
+    connections: Vec<JoinHandle<Option<UHIDDevice<T>>>>,
+) -> Result<(), UHIDError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut registry = DeviceRegistry::new();
+    for connection in connections {
+        if let Ok(Some(device)) = connection.await {
+            registry.add(device);
+        }
+    }
+    registry.destroy_all()
 }
 
-fn socket_listener(socket_path: Option<&str>) -> Result<tokio_uds::UnixListener, Error> {
-    let handle = Handle::default();
+fn socket_listener(socket_path: Option<&str>) -> Result<UnixListener, Error> {
     let listener = socket_path
         .map(std::os::unix::net::UnixListener::bind)
         .map(|res| res.map_err(Error::Io))
         .unwrap_or_else(|| systemd_socket_listener())?;
-    tokio_uds::UnixListener::from_std(listener, &handle).map_err(Error::Io)
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener).map_err(Error::Io)
 }
 
 fn systemd_socket_listener() -> Result<std::os::unix::net::UnixListener, Error> {
@@ -137,20 +206,69 @@ fn systemd_socket_listener() -> Result<std::os::unix::net::UnixListener, Error>
     Ok(unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) })
 }
 
-fn accept(stream: tokio_uds::UnixStream, log: &Logger) -> impl Future<Item = (), Error = ()> {
+fn accept(
+    stream: UnixStream,
+    termination: &Termination,
+    log: &Logger,
+) -> JoinHandle<Option<UHIDDevice<MiscDriver>>> {
     debug!(log, "accepting connection";
         "local_addr" => ?stream.local_addr(),
         "peer_addr" => ?stream.peer_addr(),
         "peer_cred" => ?stream.peer_cred());
-    tokio::spawn(handle_connection(stream, log)).into_future()
+    let log = log.clone();
+    let termination = termination.clone();
+    tokio::spawn(async move {
+        match handle_connection(stream, termination, &log).await {
+            Ok(device) => device,
+            Err(err) => {
+                error!(log, "device failure"; "error" => %err);
+                None
+            }
+        }
+    })
 }
 
-fn handle_connection(
-    stream: tokio_uds::UnixStream,
+async fn handle_connection(
+    stream: UnixStream,
+    termination: Termination,
     log: &Logger,
-) -> impl Future<Item = (), Error = ()> {
-    let log = log.clone();
-    let device_created = future::result(Device::new(stream, &log).map_err(Error::Io));
-    let device_completed = device_created.and_then(|device| device.from_err());
-    device_completed.map_err(move |err| error!(log, "device failure"; "error" => %err))
+) -> Result<Option<UHIDDevice<MiscDriver>>, Error> {
+    let device = Device::new(stream, log)?;
+    Ok(device.run(terminated(termination)).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use softu2f_system_daemon::SoftU2FDevice;
+    use tokio_linux_uhid::{uhid_loopback, DeviceEvent};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn shutdown_destroys_devices_handed_back() {
+        let mut hosts = Vec::new();
+        let mut connections = Vec::new();
+        for _ in 0..2 {
+            let (transport, host) = uhid_loopback();
+            let params = SoftU2FDevice::builder()
+                .name("test")
+                .create_params()
+                .unwrap();
+            let device = UHIDDevice::create_with(transport, params).unwrap();
+            hosts.push(host);
+            connections.push(tokio::spawn(async move { Some(device) }));
+        }
+        // A connection that ended on its own has no device left
+        connections.push(tokio::spawn(async { None }));
+
+        destroy_devices(connections).await.unwrap();
+
+        for mut host in hosts {
+            assert!(matches!(
+                host.next_event().unwrap(),
+                DeviceEvent::Create { .. }
+            ));
+            assert_eq!(host.next_event().unwrap(), DeviceEvent::Destroy);
+        }
+    }
 }
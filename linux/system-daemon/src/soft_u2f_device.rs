@@ -0,0 +1,144 @@
+use std::io;
+
+use tokio_linux_uhid::report_descriptor;
+use tokio_linux_uhid::{Bus, CreateParams, CreateParamsError, MiscDriver, UHIDDevice};
+
+const DEFAULT_NAME: &str = "SoftU2F Linux";
+const DEFAULT_VENDOR: u32 = 0xffff;
+const DEFAULT_PRODUCT: u32 = 0xffff;
+const DEFAULT_VERSION: u32 = 0;
+
+/// Emulated FIDO U2F token presented to the kernel as a USB HID device
+pub struct SoftU2FDevice;
+
+impl SoftU2FDevice {
+    pub fn builder() -> SoftU2FDeviceBuilder {
+        SoftU2FDeviceBuilder::default()
+    }
+}
+
+/// Chooses the identifiers the token presents, the report descriptor is always the FIDO
+/// U2F one
+///
+/// Unless set explicitly the serial (`uniq`) is randomly generated, so tokens created by
+/// the same process can still be told apart.
+pub struct SoftU2FDeviceBuilder {
+    name: String,
+    vendor: u32,
+    product: u32,
+    version: u32,
+    uniq: Option<String>,
+}
+
+impl Default for SoftU2FDeviceBuilder {
+    fn default() -> SoftU2FDeviceBuilder {
+        SoftU2FDeviceBuilder {
+            name: String::from(DEFAULT_NAME),
+            vendor: DEFAULT_VENDOR,
+            product: DEFAULT_PRODUCT,
+            version: DEFAULT_VERSION,
+            uniq: None,
+        }
+    }
+}
+
+impl SoftU2FDeviceBuilder {
+    /// Product string shown in device listings
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn vendor(mut self, vendor: u32) -> Self {
+        self.vendor = vendor;
+        self
+    }
+
+    pub fn product(mut self, product: u32) -> Self {
+        self.product = product;
+        self
+    }
+
+    /// Device release number, reported as the firmware version
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn uniq<S: Into<String>>(mut self, uniq: S) -> Self {
+        self.uniq = Some(uniq.into());
+        self
+    }
+
+    pub fn create_params(self) -> Result<CreateParams, CreateParamsError> {
+        CreateParams::builder()
+            .name(self.name)
+            .uniq(self.uniq.unwrap_or_else(nanoid::simple))
+            .bus(Bus::USB)
+            .vendor(self.vendor)
+            .product(self.product)
+            .version(self.version)
+            .report_descriptor(report_descriptor::fido_u2f_hid())
+            .build()
+    }
+
+    /// Create the device using the UHID character device found by `uhid_path`
+    pub fn create(self) -> io::Result<UHIDDevice<MiscDriver>> {
+        let params = self
+            .create_params()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        UHIDDevice::create(params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_params_uses_builder_fields() {
+        let params = SoftU2FDevice::builder()
+            .name("Test Token")
+            .vendor(0x1050)
+            .product(0x0120)
+            .version(0x0512)
+            .create_params()
+            .unwrap();
+
+        assert_eq!(params.name, "Test Token");
+        assert_eq!(params.vendor, 0x1050);
+        assert_eq!(params.product, 0x0120);
+        assert_eq!(params.version, 0x0512);
+        assert_eq!(params.bus as u16, Bus::USB as u16);
+        assert_eq!(params.data, report_descriptor::fido_u2f_hid());
+    }
+
+    #[test]
+    fn create_params_defaults() {
+        let params = SoftU2FDevice::builder().create_params().unwrap();
+
+        assert_eq!(params.name, DEFAULT_NAME);
+        assert_eq!(params.vendor, DEFAULT_VENDOR);
+        assert_eq!(params.product, DEFAULT_PRODUCT);
+        assert_eq!(params.version, DEFAULT_VERSION);
+    }
+
+    #[test]
+    fn generated_uniq_differs_between_devices() {
+        let first = SoftU2FDevice::builder().create_params().unwrap();
+        let second = SoftU2FDevice::builder().create_params().unwrap();
+
+        assert!(!first.uniq.is_empty());
+        assert_ne!(first.uniq, second.uniq);
+    }
+
+    #[test]
+    fn explicit_uniq_is_kept() {
+        let params = SoftU2FDevice::builder()
+            .uniq("serial-1")
+            .create_params()
+            .unwrap();
+
+        assert_eq!(params.uniq, "serial-1");
+    }
+}
@@ -0,0 +1,57 @@
+use std::io;
+use std::mem;
+use std::ptr;
+
+/// SIGTERM and SIGINT, blocked so a thread can wait for them instead of the
+/// process being killed
+///
+/// Shared by both daemons, the wait happens on a thread of its own and is passed on
+/// to the event loop over a channel.
+#[derive(Clone, Copy)]
+pub struct TerminationSignals {
+    set: libc::sigset_t,
+}
+
+impl TerminationSignals {
+    /// Block the signals in the calling thread and every thread it starts from now on
+    ///
+    /// Must be called before any other thread is started, a thread that does not block
+    /// the signals is killed by them as usual.
+    pub fn block() -> io::Result<TerminationSignals> {
+        let set = unsafe {
+            let mut set = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGTERM);
+            libc::sigaddset(&mut set, libc::SIGINT);
+            set
+        };
+        match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) } {
+            0 => Ok(TerminationSignals { set }),
+            err => Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+
+    /// Wait until one of the signals is sent, returning its number
+    pub fn wait(&self) -> io::Result<libc::c_int> {
+        let mut signal = 0;
+        match unsafe { libc::sigwait(&self.set, &mut signal) } {
+            0 => Ok(signal),
+            err => Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocked_signal_is_waited_for() {
+        let signals = TerminationSignals::block().unwrap();
+
+        // Pending on this thread only, as the signal is blocked here
+        assert_eq!(unsafe { libc::raise(libc::SIGTERM) }, 0);
+
+        assert_eq!(signals.wait().unwrap(), libc::SIGTERM);
+    }
+}
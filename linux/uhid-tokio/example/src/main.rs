@@ -1,4 +1,3 @@
-extern crate futures;
 extern crate tokio;
 extern crate tokio_linux_uhid;
 
@@ -56,6 +55,9 @@ const RDESC: [u8; 85] = [
 ];
 
 fn main() {
+    let runtime = Runtime::new().unwrap();
+    let _guard = runtime.enter();
+
     let create_params = CreateParams {
         name: String::from("test-uhid-device"),
         phys: String::from(""),
@@ -68,7 +70,7 @@ fn main() {
         data: RDESC.to_vec(),
     };
 
-    let mut uhid_device = UHIDDevice::create(create_params, None).unwrap();
+    let mut uhid_device = UHIDDevice::create(create_params).unwrap();
 
     let button_flags = 0;
     let mouse_abs_hor = 20;
@@ -0,0 +1,362 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use bytes::BytesMut;
+use nix::libc;
+
+use codec::*;
+use create_params::CreateParams;
+use error::UHIDError;
+use transport::{check_write, Decoder, Encoder};
+use uhid_device::{generate_uniq, into_io_error, uhid_path};
+
+/// UHID device driven by blocking reads and writes, for callers without a tokio runtime
+///
+/// Each call does exactly one read or write of the character device, so
+/// `recv_output_event` blocks until the kernel has an event for this device.
+pub struct BlockingUHIDDevice<F: Write> {
+    inner: F,
+    codec: Codec,
+    destroyed: bool,
+    stopped: bool,
+    name: String,
+    uniq: String,
+}
+
+impl BlockingUHIDDevice<File> {
+    /// Create a UHID device using the character device found by `uhid_path`
+    pub fn create(params: CreateParams) -> io::Result<BlockingUHIDDevice<File>> {
+        Self::create_with_path(&uhid_path(), params)
+    }
+
+    /// Create a UHID device using the specified character misc-device file path
+    pub fn create_with_path(
+        path: &Path,
+        params: CreateParams,
+    ) -> io::Result<BlockingUHIDDevice<File>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Self::create_with(file, params)
+    }
+}
+
+impl<F: Read + Write> BlockingUHIDDevice<F> {
+    /// A blank `uniq` is replaced with one unique to this device, as for `UHIDDevice`
+    pub(crate) fn create_with(
+        inner: F,
+        mut params: CreateParams,
+    ) -> io::Result<BlockingUHIDDevice<F>> {
+        if params.uniq.is_empty() {
+            params.uniq = generate_uniq();
+        }
+        let mut device = BlockingUHIDDevice {
+            inner,
+            codec: Codec::default(),
+            destroyed: false,
+            stopped: false,
+            name: params.name.clone(),
+            uniq: params.uniq.clone(),
+        };
+        debug!(name = %params.name, uniq = %params.uniq, "Sending create device event");
+        // A device that was never created must not send destroy when dropped
+        device.destroyed = true;
+        match device.send_event(params.create_event()) {
+            Err(ref err) if is_create2_unsupported(err) => {
+                debug!("Kernel does not support UHID_CREATE2, falling back to UHID_CREATE");
+                device.codec.use_legacy_create();
+                device
+                    .send_event(params.create_event())
+                    .map_err(into_io_error)?;
+            }
+            result => result.map_err(into_io_error)?,
+        }
+        device.destroyed = false;
+        Ok(device)
+    }
+
+    /// Send a HID packet to the UHID device
+    pub fn send_input(&mut self, data: &[u8]) -> io::Result<()> {
+        debug!(len = data.len(), "send input");
+        self.send_event(InputEvent::Input {
+            data: data.to_vec(),
+        })
+        .map_err(into_io_error)
+    }
+
+    /// Wait for the next event from the kernel
+    ///
+    /// After `Stop`, or once the kernel removed the device, fails with an `InvalidData`
+    /// error wrapping `UHIDError::DeviceStopped`.
+    pub fn recv_output_event(&mut self) -> io::Result<OutputEvent> {
+        self.read_event().map_err(into_io_error)
+    }
+
+    /// Whether the kernel stopped or removed the device, it cannot be used any more
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Unique identifier of the device, generated if none was given when creating it
+    pub fn uniq(&self) -> &str {
+        &self.uniq
+    }
+
+    /// Send a 'destroy' to the UHID device and close it
+    pub fn destroy(mut self) -> io::Result<()> {
+        debug!("destroy");
+        self.destroyed = true;
+        if self.stopped {
+            return Ok(());
+        }
+        self.send_event(InputEvent::Destroy).map_err(into_io_error)
+    }
+
+    fn read_event(&mut self) -> Result<OutputEvent, UHIDError> {
+        self.check_not_stopped()?;
+        let mut buf = BytesMut::zeroed(self.codec.read_len());
+        let len = match self.inner.read(&mut buf) {
+            Ok(len) => len,
+            Err(err) => return Err(self.check_removed(err.into())),
+        };
+        buf.truncate(len);
+        let event = self.codec.decode(&mut buf)?;
+        if let OutputEvent::Stop = event {
+            debug!("Device stopped by the kernel");
+            self.stopped = true;
+        }
+        Ok(event)
+    }
+}
+
+impl<F: Write> BlockingUHIDDevice<F> {
+    fn send_event(&mut self, event: InputEvent) -> Result<(), UHIDError> {
+        self.check_not_stopped()?;
+        let mut buf = BytesMut::new();
+        self.codec.encode(event, &mut buf)?;
+        trace!(?buf, "BlockingUHIDDevice::send_event");
+        let result = check_write(self.inner.write(&buf), buf.len());
+        result.map_err(|err| self.check_removed(err.into()))
+    }
+
+    fn check_not_stopped(&self) -> Result<(), UHIDError> {
+        if self.stopped {
+            Err(UHIDError::DeviceStopped)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// `ENODEV` means the kernel removed the device, mark it as stopped
+    fn check_removed(&mut self, err: UHIDError) -> UHIDError {
+        match err {
+            UHIDError::Io(ref io_err) if io_err.raw_os_error() == Some(libc::ENODEV) => {
+                debug!("Device removed by the kernel");
+                self.stopped = true;
+                UHIDError::DeviceStopped
+            }
+            err => err,
+        }
+    }
+}
+
+/// Dropping a device that was not explicitly destroyed makes a best-effort
+/// attempt to remove it from the kernel, failures are only logged.
+impl<F: Write> Drop for BlockingUHIDDevice<F> {
+    fn drop(&mut self) {
+        if self.destroyed || self.stopped {
+            return;
+        }
+        self.destroyed = true;
+        debug!("Destroying device on drop");
+        if let Err(err) = self.send_event(InputEvent::Destroy) {
+            warn!(error = %err, "Failed to destroy device on drop");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::mem;
+    use std::sync::{Arc, Mutex};
+
+    use uhid_sys as sys;
+
+    use super::*;
+
+    const UHID_CREATE: u8 = 0x00;
+    const UHID_DESTROY: u8 = 0x01;
+    const UHID_START: u8 = 0x02;
+    const UHID_STOP: u8 = 0x03;
+    const UHID_CREATE2: u8 = 0x0b;
+    const UHID_INPUT2: u8 = 0x0c;
+
+    /// Device answering reads from a queue of kernel events and recording writes
+    #[derive(Clone, Default)]
+    struct BlockingRecordingDevice {
+        readable: Arc<Mutex<VecDeque<io::Result<u8>>>>,
+        written: Arc<Mutex<Vec<Vec<u8>>>>,
+        write_errors: Arc<Mutex<VecDeque<i32>>>,
+    }
+
+    impl BlockingRecordingDevice {
+        fn push_event(&self, event_type: u8) {
+            self.readable.lock().unwrap().push_back(Ok(event_type));
+        }
+
+        fn push_read_error(&self, errno: i32) {
+            self.readable
+                .lock()
+                .unwrap()
+                .push_back(Err(io::Error::from_raw_os_error(errno)));
+        }
+
+        /// Fail the next write with the given errno
+        fn push_write_error(&self, errno: i32) {
+            self.write_errors.lock().unwrap().push_back(errno);
+        }
+
+        fn written(&self) -> Vec<Vec<u8>> {
+            self.written.lock().unwrap().clone()
+        }
+    }
+
+    impl Read for BlockingRecordingDevice {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.readable.lock().unwrap().pop_front() {
+                Some(Ok(event_type)) => {
+                    let len = mem::size_of::<sys::uhid_event>();
+                    for byte in buf[..len].iter_mut() {
+                        *byte = 0;
+                    }
+                    buf[0] = event_type;
+                    Ok(len)
+                }
+                Some(Err(err)) => Err(err),
+                None => panic!("read would block forever"),
+            }
+        }
+    }
+
+    impl Write for BlockingRecordingDevice {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            assert_eq!(buf.len(), mem::size_of::<sys::uhid_event>());
+            if let Some(errno) = self.write_errors.lock().unwrap().pop_front() {
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+            self.written.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn is_device_stopped(err: &io::Error) -> bool {
+        matches!(
+            err.get_ref()
+                .and_then(|err| err.downcast_ref::<UHIDError>()),
+            Some(UHIDError::DeviceStopped)
+        )
+    }
+
+    fn params() -> CreateParams {
+        CreateParams {
+            name: String::from("test-uhid-device"),
+            phys: String::from(""),
+            uniq: String::from(""),
+            bus: Bus::USB,
+            vendor: 0x15d9,
+            product: 0x0a37,
+            version: 0,
+            country: 0,
+            data: vec![0x05, 0x01],
+        }
+    }
+
+    #[test]
+    fn send_input_and_receive_event() {
+        let device = BlockingRecordingDevice::default();
+        device.push_event(UHID_START);
+        let mut uhid_device = BlockingUHIDDevice::create_with(device.clone(), params()).unwrap();
+
+        uhid_device.send_input(&[1, 2, 3]).unwrap();
+        let event = uhid_device.recv_output_event().unwrap();
+
+        assert!(matches!(event, OutputEvent::Start { .. }));
+        let written = device.written();
+        assert_eq!(written.len(), 2);
+        assert_eq!(written[0][0], UHID_CREATE2);
+        assert_eq!(written[1][0], UHID_INPUT2);
+        // Input payload follows the event type and the 16-bit size
+        assert_eq!(&written[1][4..9], &[3, 0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn drop_destroys_device() {
+        let device = BlockingRecordingDevice::default();
+        let uhid_device = BlockingUHIDDevice::create_with(device.clone(), params()).unwrap();
+
+        drop(uhid_device);
+
+        let event_types: Vec<u8> = device.written().iter().map(|event| event[0]).collect();
+        assert_eq!(event_types, vec![UHID_CREATE2, UHID_DESTROY]);
+    }
+
+    #[test]
+    fn create_falls_back_to_legacy_event() {
+        let device = BlockingRecordingDevice::default();
+        device.push_write_error(libc::EINVAL);
+
+        let _uhid_device = BlockingUHIDDevice::create_with(device.clone(), params()).unwrap();
+
+        let written = device.written();
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0][0], UHID_CREATE);
+        assert_eq!(&written[0][4..20], b"test-uhid-device");
+    }
+
+    #[test]
+    fn create_fails_on_other_errors() {
+        let device = BlockingRecordingDevice::default();
+        device.push_write_error(libc::EACCES);
+
+        let err = BlockingUHIDDevice::create_with(device.clone(), params()).err().unwrap();
+
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        assert!(device.written().is_empty());
+    }
+
+    #[test]
+    fn stop_event_stops_device() {
+        let device = BlockingRecordingDevice::default();
+        device.push_event(UHID_STOP);
+        let mut uhid_device = BlockingUHIDDevice::create_with(device.clone(), params()).unwrap();
+
+        let event = uhid_device.recv_output_event().unwrap();
+
+        assert!(matches!(event, OutputEvent::Stop));
+        assert!(uhid_device.is_stopped());
+        assert!(is_device_stopped(
+            &uhid_device.send_input(&[1]).unwrap_err()
+        ));
+    }
+
+    #[test]
+    fn read_enodev_stops_device() {
+        let device = BlockingRecordingDevice::default();
+        device.push_read_error(libc::ENODEV);
+        let mut uhid_device = BlockingUHIDDevice::create_with(device.clone(), params()).unwrap();
+
+        let result = uhid_device.recv_output_event();
+
+        assert!(is_device_stopped(&result.err().unwrap()));
+        drop(uhid_device);
+        assert_eq!(device.written().len(), 1);
+    }
+}
@@ -1,15 +1,12 @@
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
 
-use mio;
-
 #[derive(Debug)]
 pub struct CharacterDevice<F>(F);
 
 impl<F: AsRawFd> CharacterDevice<F> {
-    /// Wraps a character device-like object so it can be used with
-    /// `tokio_core::reactor::Evented`
-    /// ```
+    /// Wraps a character device-like object so it can be registered with
+    /// `tokio::io::unix::AsyncFd`
     pub fn new(file: F) -> Self {
         CharacterDevice(file)
     }
@@ -21,32 +18,6 @@ impl<F: AsRawFd> AsRawFd for CharacterDevice<F> {
     }
 }
 
-impl<F: AsRawFd> mio::Evented for CharacterDevice<F> {
-    fn register(
-        &self,
-        poll: &mio::Poll,
-        token: mio::Token,
-        interest: mio::Ready,
-        opts: mio::PollOpt,
-    ) -> io::Result<()> {
-        mio::unix::EventedFd(&self.as_raw_fd()).register(poll, token, interest, opts)
-    }
-
-    fn reregister(
-        &self,
-        poll: &mio::Poll,
-        token: mio::Token,
-        interest: mio::Ready,
-        opts: mio::PollOpt,
-    ) -> io::Result<()> {
-        mio::unix::EventedFd(&self.as_raw_fd()).reregister(poll, token, interest, opts)
-    }
-
-    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
-        mio::unix::EventedFd(&self.as_raw_fd()).deregister(poll)
-    }
-}
-
 impl<F: io::Read> io::Read for CharacterDevice<F> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.0.read(buf)
@@ -1,35 +1,26 @@
+use std::cmp;
 use std::ffi;
+use std::fmt;
 use std::io;
-use std::iter::repeat;
+use std::iter::repeat_n;
 use std::mem;
 use std::slice;
 
 use bytes::BytesMut;
-use slog;
+use nix::libc;
 
+use error::UHIDError;
 use transport::{Decoder, Encoder};
 use uhid_sys as sys;
 
-quick_error! {
-    #[derive(Debug)]
-    pub enum StreamError {
-        Io(err: io::Error) {
-            from()
-        }
-        UnknownEventType(event_type_value: u32) {
-            description("Unknown/Unsupported event type")
-            display(r#"Unknown/Unsupported event type: "{}""#, event_type_value)
-        }
-        BufferOverflow(data_size: usize, max_size: usize) {
-            description("Size exceeds available space.")
-            display(r#"Size "{}" exceeds available space "{}""#, data_size, max_size)
-        }
-        Nul(err: ffi::NulError) {
-            from()
-        }
-        Unknown
-    }
-}
+/// Largest input or report reply payload a `uhid_event` can carry
+pub const UHID_DATA_MAX: usize = 4096;
+
+// Sizes of the fixed length fields of `struct uhid_create2_req` in `linux/uhid.h`
+const UHID_NAME_LEN: usize = 128;
+const UHID_PHYS_LEN: usize = 64;
+const UHID_UNIQ_LEN: usize = 64;
+const HID_MAX_DESCRIPTOR_SIZE: usize = 4096;
 
 bitflags! {
     pub struct DevFlags: u64 {
@@ -39,15 +30,39 @@ bitflags! {
     }
 }
 
+/// Kind of report a request refers to, values are the kernel's `UHID_*_REPORT` constants
 #[allow(non_camel_case_types)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ReportType {
     Feature = 0,
     Output = 1,
     Input = 2,
 }
 
+impl ReportType {
+    fn from_raw(value: u8) -> Result<ReportType, UHIDError> {
+        match value {
+            0 => Ok(ReportType::Feature),
+            1 => Ok(ReportType::Output),
+            2 => Ok(ReportType::Input),
+            value => Err(UHIDError::UnknownReportType(value)),
+        }
+    }
+
+    /// Flag telling whether reports of this type start with their report number
+    fn numbered_flag(&self) -> DevFlags {
+        match *self {
+            ReportType::Feature => DevFlags::NUMBERED_FEATURE_REPORTS,
+            ReportType::Output => DevFlags::NUMBERED_OUTPUT_REPORTS,
+            ReportType::Input => DevFlags::NUMBERED_INPUT_REPORTS,
+        }
+    }
+}
+
+/// Bus the device claims to be attached to, values are the kernel's `BUS_*`
+/// constants from `linux/input.h`
 #[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Bus {
     PCI = 1,
     ISAPNP = 2,
@@ -100,14 +115,33 @@ pub enum InputEvent {
     },
 }
 
+/// Events sent by the kernel to the UHID device
+///
+/// Lifecycle events arrive in a fixed order. `Start` is sent once the kernel HID
+/// driver has been attached after the create event, and `Stop` once it is detached,
+/// e.g. when the device is destroyed. Between the two, `Open` is sent when some
+/// process opens the device (the kernel counts users, only the first open and last
+/// close generate events) and `Close` when the last user goes away. Input sent before
+/// `Start` may be dropped and input sent while the device is closed is not read by
+/// anyone, though it is not an error to send it.
 pub enum OutputEvent {
+    /// The HID driver was attached, `dev_flags` describes how reports are numbered
     Start {
         dev_flags: DevFlags,
     },
+    /// The HID driver was detached, `UHIDDevice` ends its stream after this event
     Stop,
+    /// The device was opened for the first time, it is now worth sending input
     Open,
+    /// The last user of the device closed it
     Close,
+    /// Report written by the host, `data` is the report as sent by the kernel
+    ///
+    /// When the `Start` event announced numbered reports of this type, the first byte
+    /// of `data` is the report number and is also given as `report_number`.
     Output {
+        report_type: ReportType,
+        report_number: Option<u8>,
         data: Vec<u8>,
     },
     GetReport {
@@ -123,58 +157,93 @@ pub enum OutputEvent {
     },
 }
 
-impl slog::Value for OutputEvent {
-    fn serialize(
-        &self,
-        record: &slog::Record,
-        key: slog::Key,
-        serializer: &mut dyn slog::Serializer,
-    ) -> slog::Result {
-        match self {
-            &OutputEvent::Start { .. } => "Start",
-            &OutputEvent::Stop => "Stop",
-            &OutputEvent::Open => "Open",
-            &OutputEvent::Close => "Close",
-            &OutputEvent::Output { .. } => "Output",
-            &OutputEvent::GetReport { .. } => "GetReport",
-            &OutputEvent::SetReport { .. } => "SetReport",
-        }.serialize(record, key, serializer)
+/// Encodes and decodes `uhid_event`s
+///
+/// Decoding a `Start` event remembers its `dev_flags`, later `Output` events use them
+/// to tell whether the report is prefixed with its number.
+///
+/// Create events are encoded as `UHID_CREATE2` unless `use_legacy_create` was called.
+#[derive(Debug)]
+pub struct Codec {
+    dev_flags: DevFlags,
+    legacy_create: bool,
+    /// Report descriptor the last legacy create event points to, kept until the
+    /// next one is encoded so the pointer stays valid while the event is written
+    legacy_rd_data: Vec<u8>,
+    raw_tap: Option<RawTap>,
+}
+
+/// Callback given the bytes of every event before it is decoded
+pub(crate) type RawTapFn = Box<dyn FnMut(&[u8]) + Send>;
+
+pub(crate) struct RawTap(RawTapFn);
+
+impl fmt::Debug for RawTap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RawTap")
     }
 }
 
-#[derive(Debug, Default)]
-pub struct Codec;
+impl Default for Codec {
+    fn default() -> Codec {
+        Codec {
+            dev_flags: DevFlags::empty(),
+            legacy_create: false,
+            legacy_rd_data: Vec::new(),
+            raw_tap: None,
+        }
+    }
+}
+
+impl Codec {
+    /// Encode create events as the legacy `UHID_CREATE`, for kernels before 3.15
+    /// that do not know `UHID_CREATE2`
+    pub(crate) fn use_legacy_create(&mut self) {
+        self.legacy_create = true;
+    }
+
+    /// Call `tap` with the bytes of every event decoded from now on
+    pub(crate) fn set_raw_tap(&mut self, tap: RawTapFn) {
+        self.raw_tap = Some(RawTap(tap));
+    }
+}
+
+/// Whether writing a `UHID_CREATE2` event failed because the kernel predates it
+///
+/// Older kernels reject unknown event types with `EOPNOTSUPP`, some vendor kernels
+/// with `EINVAL`.
+pub(crate) fn is_create2_unsupported(err: &UHIDError) -> bool {
+    match *err {
+        UHIDError::Io(ref err) => matches!(
+            err.raw_os_error(),
+            Some(libc::EINVAL) | Some(libc::EOPNOTSUPP)
+        ),
+        _ => false,
+    }
+}
 
 impl InputEvent {
-    fn into_uhid_event(self) -> Result<sys::uhid_event, StreamError> {
+    /// Reject payloads the kernel's fixed size buffer cannot hold
+    fn check_payload_len(&self) -> Result<(), UHIDError> {
+        let len = match *self {
+            InputEvent::Input { ref data } => data.len(),
+            InputEvent::GetReportReply { ref data, .. } => data.len(),
+            _ => return Ok(()),
+        };
+        if len > UHID_DATA_MAX {
+            return Err(UHIDError::PayloadTooLarge {
+                len,
+                max: UHID_DATA_MAX,
+            });
+        }
+        Ok(())
+    }
+
+    fn into_uhid_event(self) -> Result<sys::uhid_event, UHIDError> {
         let mut event: sys::uhid_event = unsafe { mem::zeroed() };
 
         match self {
-            InputEvent::Create {
-                name,
-                phys,
-                uniq,
-                bus,
-                vendor,
-                product,
-                version,
-                country,
-                data,
-            } => {
-                event.type_ = sys::uhid_event_type_UHID_CREATE2 as u32;
-                unsafe {
-                    let payload = &mut event.u.create2;
-                    copy_as_cstr(name, &mut payload.name)?;
-                    copy_as_cstr(phys, &mut payload.phys)?;
-                    copy_as_cstr(uniq, &mut payload.uniq)?;
-                    payload.rd_size = copy_bytes_sized(data, &mut payload.rd_data)? as u16;
-                    payload.bus = bus as u16;
-                    payload.vendor = vendor;
-                    payload.product = product;
-                    payload.version = version;
-                    payload.country = country;
-                }
-            }
+            InputEvent::Create { .. } => unreachable!("create events are encoded separately"),
             InputEvent::Destroy => {
                 event.type_ = sys::uhid_event_type_UHID_DESTROY as u32;
             }
@@ -185,18 +254,20 @@ impl InputEvent {
                     payload.size = copy_bytes_sized(data, &mut payload.data)? as u16;
                 }
             }
-            InputEvent::GetReportReply { err, data, .. } => {
+            InputEvent::GetReportReply { id, err, data } => {
                 event.type_ = sys::uhid_event_type_UHID_GET_REPORT_REPLY as u32;
                 unsafe {
                     let payload = &mut event.u.get_report_reply;
+                    payload.id = id;
                     payload.err = err;
                     payload.size = copy_bytes_sized(data, &mut payload.data)? as u16;
                 }
             }
-            InputEvent::SetReportReply { err, .. } => {
+            InputEvent::SetReportReply { id, err } => {
                 event.type_ = sys::uhid_event_type_UHID_SET_REPORT_REPLY as u32;
                 unsafe {
                     let payload = &mut event.u.set_report_reply;
+                    payload.id = id;
                     payload.err = err;
                 }
             }
@@ -206,12 +277,116 @@ impl InputEvent {
     }
 }
 
-fn copy_bytes_sized(src: Vec<u8>, dst: &mut [u8]) -> Result<usize, StreamError> {
+/// Encode a `UHID_CREATE2` event field by field
+///
+/// The kernel reads `struct uhid_event` as packed little-endian `__u16`/`__u32`
+/// fields. Writing them explicitly keeps the layout right whatever the native
+/// byte order and alignment of the target are.
+fn encode_create2(event: InputEvent) -> Result<Vec<u8>, UHIDError> {
+    let (name, phys, uniq, bus, vendor, product, version, country, data) = match event {
+        InputEvent::Create {
+            name,
+            phys,
+            uniq,
+            bus,
+            vendor,
+            product,
+            version,
+            country,
+            data,
+        } => (name, phys, uniq, bus, vendor, product, version, country, data),
+        _ => unreachable!("only create events are encoded by encode_create2"),
+    };
+
+    let mut name_field = [0u8; UHID_NAME_LEN];
+    let mut phys_field = [0u8; UHID_PHYS_LEN];
+    let mut uniq_field = [0u8; UHID_UNIQ_LEN];
+    let mut rd_data = [0u8; HID_MAX_DESCRIPTOR_SIZE];
+    copy_as_cstr(name, &mut name_field)?;
+    copy_as_cstr(phys, &mut phys_field)?;
+    copy_as_cstr(uniq, &mut uniq_field)?;
+    let rd_size = copy_bytes_sized(data, &mut rd_data)? as u16;
+
+    let mut bytes = Vec::with_capacity(mem::size_of::<sys::uhid_event>());
+    bytes.extend_from_slice(&(sys::uhid_event_type_UHID_CREATE2 as u32).to_le_bytes());
+    bytes.extend_from_slice(&name_field);
+    bytes.extend_from_slice(&phys_field);
+    bytes.extend_from_slice(&uniq_field);
+    bytes.extend_from_slice(&rd_size.to_le_bytes());
+    bytes.extend_from_slice(&(bus as u16).to_le_bytes());
+    bytes.extend_from_slice(&vendor.to_le_bytes());
+    bytes.extend_from_slice(&product.to_le_bytes());
+    bytes.extend_from_slice(&version.to_le_bytes());
+    bytes.extend_from_slice(&country.to_le_bytes());
+    bytes.extend_from_slice(&rd_data);
+    // The create request is the largest member of the event union, so it fills
+    // the event exactly
+    assert_eq!(bytes.len(), mem::size_of::<sys::uhid_event>());
+    Ok(bytes)
+}
+
+/// Encode a legacy `UHID_CREATE` event, which points to the report descriptor
+/// instead of carrying it
+///
+/// The descriptor is moved into `rd_data`, which must outlive the write of the
+/// returned event. The pointer is in native byte order, the other fields are
+/// written as for `encode_create2`.
+fn encode_create_legacy(event: InputEvent, rd_data: &mut Vec<u8>) -> Result<Vec<u8>, UHIDError> {
+    let (name, phys, uniq, bus, vendor, product, version, country, data) = match event {
+        InputEvent::Create {
+            name,
+            phys,
+            uniq,
+            bus,
+            vendor,
+            product,
+            version,
+            country,
+            data,
+        } => (name, phys, uniq, bus, vendor, product, version, country, data),
+        _ => unreachable!("only create events are encoded by encode_create_legacy"),
+    };
+    if data.len() > HID_MAX_DESCRIPTOR_SIZE {
+        return Err(UHIDError::PayloadTooLarge {
+            len: data.len(),
+            max: HID_MAX_DESCRIPTOR_SIZE,
+        });
+    }
+
+    let mut name_field = [0u8; UHID_NAME_LEN];
+    let mut phys_field = [0u8; UHID_PHYS_LEN];
+    let mut uniq_field = [0u8; UHID_UNIQ_LEN];
+    copy_as_cstr(name, &mut name_field)?;
+    copy_as_cstr(phys, &mut phys_field)?;
+    copy_as_cstr(uniq, &mut uniq_field)?;
+    *rd_data = data;
+
+    let mut bytes = Vec::with_capacity(mem::size_of::<sys::uhid_event>());
+    bytes.extend_from_slice(&(sys::uhid_legacy_event_type_UHID_CREATE as u32).to_le_bytes());
+    bytes.extend_from_slice(&name_field);
+    bytes.extend_from_slice(&phys_field);
+    bytes.extend_from_slice(&uniq_field);
+    bytes.extend_from_slice(&(rd_data.as_ptr() as usize).to_ne_bytes());
+    bytes.extend_from_slice(&(rd_data.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(&(bus as u16).to_le_bytes());
+    bytes.extend_from_slice(&vendor.to_le_bytes());
+    bytes.extend_from_slice(&product.to_le_bytes());
+    bytes.extend_from_slice(&version.to_le_bytes());
+    bytes.extend_from_slice(&country.to_le_bytes());
+    // Writes are always a whole `uhid_event`, older kernels ignore the excess
+    bytes.resize(mem::size_of::<sys::uhid_event>(), 0);
+    Ok(bytes)
+}
+
+fn copy_bytes_sized(src: Vec<u8>, dst: &mut [u8]) -> Result<usize, UHIDError> {
     let src_size = src.len();
     let dst_size = dst.len();
 
     if src_size > dst_size {
-        return Err(StreamError::BufferOverflow(src_size, dst_size));
+        return Err(UHIDError::PayloadTooLarge {
+            len: src_size,
+            max: dst_size,
+        });
     }
 
     dst.get_mut(0..src_size)
@@ -220,69 +395,73 @@ fn copy_bytes_sized(src: Vec<u8>, dst: &mut [u8]) -> Result<usize, StreamError>
     Ok(src_size)
 }
 
-fn copy_as_cstr(string: String, dst: &mut [u8]) -> Result<(), StreamError> {
+fn copy_as_cstr(string: String, dst: &mut [u8]) -> Result<(), UHIDError> {
     let mut src: Vec<u8> = ffi::CString::new(string)?.into_bytes_with_nul();
     let src_size = src.len();
     let dst_size = dst.len();
 
-    if src_size >= dst_size {
-        return Err(StreamError::BufferOverflow(src_size, dst_size));
+    if src_size > dst_size {
+        return Err(UHIDError::PayloadTooLarge {
+            len: src_size,
+            max: dst_size,
+        });
     }
 
-    src.extend(repeat(0).take(dst_size - src_size));
+    src.extend(repeat_n(0, dst_size - src_size));
     dst.copy_from_slice(src.as_slice());
     Ok(())
 }
 
-fn decode_event(event: sys::uhid_event) -> Result<OutputEvent, StreamError> {
+fn decode_event(event: sys::uhid_event, dev_flags: DevFlags) -> Result<OutputEvent, UHIDError> {
     if let Some(event_type) = to_uhid_event_type(event.type_) {
         match event_type {
-            sys::uhid_event_type_UHID_START => Ok(unsafe {
-                let payload = &event.u.start;
-                OutputEvent::Start {
-                    dev_flags: mem::transmute(payload.dev_flags),
-                }
-            }),
+            sys::uhid_event_type_UHID_START => {
+                let payload = unsafe { &event.u.start };
+                Ok(OutputEvent::Start {
+                    dev_flags: DevFlags::from_bits_truncate(payload.dev_flags),
+                })
+            }
             sys::uhid_event_type_UHID_STOP => Ok(OutputEvent::Stop),
             sys::uhid_event_type_UHID_OPEN => Ok(OutputEvent::Open),
             sys::uhid_event_type_UHID_CLOSE => Ok(OutputEvent::Close),
-            sys::uhid_event_type_UHID_OUTPUT => Ok(unsafe {
-                let payload = &event.u.output;
-                assert_eq!(
-                    payload.rtype,
-                    sys::uhid_report_type_UHID_OUTPUT_REPORT as u8
-                );
-                OutputEvent::Output {
-                    data: slice::from_raw_parts(
-                        &payload.data[0] as *const u8,
-                        payload.size as usize,
-                    ).to_vec(),
-                }
-            }),
+            sys::uhid_event_type_UHID_OUTPUT => {
+                let payload = unsafe { &event.u.output };
+                let report_type = ReportType::from_raw(payload.rtype)?;
+                let len = (payload.size as usize).min(payload.data.len());
+                let data = payload.data[..len].to_vec();
+                let report_number = if dev_flags.contains(report_type.numbered_flag()) {
+                    data.first().cloned()
+                } else {
+                    None
+                };
+                Ok(OutputEvent::Output {
+                    report_type,
+                    report_number,
+                    data,
+                })
+            }
             sys::uhid_event_type_UHID_GET_REPORT => Ok(unsafe {
                 let payload = &event.u.get_report;
                 OutputEvent::GetReport {
                     id: payload.id,
                     report_number: payload.rnum,
-                    report_type: mem::transmute(payload.rtype),
+                    report_type: ReportType::from_raw(payload.rtype)?,
                 }
             }),
             sys::uhid_event_type_UHID_SET_REPORT => Ok(unsafe {
                 let payload = &event.u.set_report;
+                let len = (payload.size as usize).min(payload.data.len());
                 OutputEvent::SetReport {
                     id: payload.id,
                     report_number: payload.rnum,
-                    report_type: mem::transmute(payload.rtype),
-                    data: slice::from_raw_parts(
-                        &payload.data[0] as *const u8,
-                        payload.size as usize,
-                    ).to_vec(),
+                    report_type: ReportType::from_raw(payload.rtype)?,
+                    data: payload.data[..len].to_vec(),
                 }
             }),
-            _ => Err(StreamError::UnknownEventType(event.type_)),
+            _ => Err(UHIDError::UnknownEventType(event.type_)),
         }
     } else {
-        Err(StreamError::UnknownEventType(event.type_))
+        Err(UHIDError::UnknownEventType(event.type_))
     }
 }
 
@@ -316,13 +495,22 @@ unsafe fn as_u8_slice<T: Sized>(p: &T) -> &[u8] {
 
 impl Decoder for Codec {
     type Item = OutputEvent;
-    type Error = StreamError;
+    type Error = UHIDError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Self::Item, Self::Error> {
+        if let Some(RawTap(ref mut tap)) = self.raw_tap {
+            // Only the next event, the buffer may already hold the ones after it
+            let len = cmp::min(src.len(), mem::size_of::<sys::uhid_event>());
+            tap(&src[..len]);
+        }
         if let Some(event) = read_event(src) {
-            Ok(decode_event(event)?)
+            let event = decode_event(event, self.dev_flags)?;
+            if let OutputEvent::Start { dev_flags } = event {
+                self.dev_flags = dev_flags;
+            }
+            Ok(event)
         } else {
-            Err(StreamError::Unknown)
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete uhid event").into())
         }
     }
 
@@ -333,17 +521,28 @@ impl Decoder for Codec {
 
 impl Encoder for Codec {
     type Item = InputEvent;
-    type Error = StreamError;
+    type Error = UHIDError;
 
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let event = item.into_uhid_event()?;
-        dst.extend_from_slice(encode_event(&event));
+        item.check_payload_len()?;
+        if let InputEvent::Create { .. } = item {
+            if self.legacy_create {
+                dst.extend_from_slice(&encode_create_legacy(item, &mut self.legacy_rd_data)?);
+            } else {
+                dst.extend_from_slice(&encode_create2(item)?);
+            }
+        } else {
+            let event = item.into_uhid_event()?;
+            dst.extend_from_slice(encode_event(&event));
+        }
         Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{Arc, Mutex};
+
     use super::*;
 
     const RDESC: [u8; 85] = [
@@ -493,7 +692,7 @@ mod tests {
         expected[364] = 0xc0;
         let mut result = BytesMut::new();
 
-        Codec
+        Codec::default()
             .encode(
                 InputEvent::Create {
                     name: String::from("test-uhid-device"),
@@ -513,14 +712,390 @@ mod tests {
         assert_bytes_eq(&result[..], &expected);
     }
 
+    #[test]
+    fn encode_create_request_fields_are_little_endian() {
+        let mut expected = vec![0; mem::size_of::<sys::uhid_event>()];
+        expected[0..4].copy_from_slice(&[0x0b, 0x00, 0x00, 0x00]);
+        expected[4..6].copy_from_slice(b"n\0");
+        expected[132..134].copy_from_slice(b"p\0");
+        expected[196..198].copy_from_slice(b"u\0");
+        // rd_size, bus, vendor, product, version, country
+        expected[260..280].copy_from_slice(&[
+            0x03, 0x00, 0x06, 0x00, 0x78, 0x56, 0x34, 0x12, 0x21, 0x43, 0x65, 0x87, 0x04,
+            0x03, 0x02, 0x01, 0x0d, 0x0c, 0x0b, 0x0a,
+        ]);
+        expected[280..283].copy_from_slice(&[0xc0, 0xc1, 0xc2]);
+        let mut result = BytesMut::new();
+
+        Codec::default()
+            .encode(
+                InputEvent::Create {
+                    name: String::from("n"),
+                    phys: String::from("p"),
+                    uniq: String::from("u"),
+                    bus: Bus::VIRTUAL,
+                    vendor: 0x1234_5678,
+                    product: 0x8765_4321,
+                    version: 0x0102_0304,
+                    country: 0x0a0b_0c0d,
+                    data: vec![0xc0, 0xc1, 0xc2],
+                },
+                &mut result,
+            )
+            .unwrap();
+
+        assert_bytes_eq(&result[..], &expected);
+    }
+
+    #[test]
+    fn encode_legacy_create_request() {
+        let ptr_len = mem::size_of::<usize>();
+        let mut codec = Codec::default();
+        codec.use_legacy_create();
+        let mut result = BytesMut::new();
+
+        codec
+            .encode(
+                InputEvent::Create {
+                    name: String::from("n"),
+                    phys: String::from("p"),
+                    uniq: String::from("u"),
+                    bus: Bus::VIRTUAL,
+                    vendor: 0x1234_5678,
+                    product: 0x8765_4321,
+                    version: 0x0102_0304,
+                    country: 0x0a0b_0c0d,
+                    data: RDESC.to_vec(),
+                },
+                &mut result,
+            )
+            .unwrap();
+
+        let mut expected = vec![0; mem::size_of::<sys::uhid_event>()];
+        expected[4..6].copy_from_slice(b"n\0");
+        expected[132..134].copy_from_slice(b"p\0");
+        expected[196..198].copy_from_slice(b"u\0");
+        let rd_data_ptr = codec.legacy_rd_data.as_ptr() as usize;
+        expected[260..260 + ptr_len].copy_from_slice(&rd_data_ptr.to_ne_bytes());
+        // rd_size, bus, vendor, product, version, country
+        expected[260 + ptr_len..280 + ptr_len].copy_from_slice(&[
+            0x55, 0x00, 0x06, 0x00, 0x78, 0x56, 0x34, 0x12, 0x21, 0x43, 0x65, 0x87, 0x04,
+            0x03, 0x02, 0x01, 0x0d, 0x0c, 0x0b, 0x0a,
+        ]);
+        assert_bytes_eq(&result[..], &expected);
+        assert_eq!(codec.legacy_rd_data, RDESC.to_vec());
+    }
+
+    #[test]
+    fn create2_unsupported_errors() {
+        let err = |errno| UHIDError::Io(io::Error::from_raw_os_error(errno));
+
+        assert!(is_create2_unsupported(&err(libc::EINVAL)));
+        assert!(is_create2_unsupported(&err(libc::EOPNOTSUPP)));
+        assert!(!is_create2_unsupported(&err(libc::ENODEV)));
+    }
+
+    #[test]
+    fn encode_create_request_bus() {
+        let buses = vec![
+            (Bus::USB, 0x03),
+            (Bus::BLUETOOTH, 0x05),
+            (Bus::VIRTUAL, 0x06),
+            (Bus::I2C, 0x18),
+        ];
+
+        for (bus, expected) in buses {
+            let mut result = BytesMut::new();
+            Codec::default()
+                .encode(
+                    InputEvent::Create {
+                        name: String::from("test-uhid-device"),
+                        phys: String::from(""),
+                        uniq: String::from(""),
+                        bus,
+                        vendor: 0,
+                        product: 0,
+                        version: 0,
+                        country: 0,
+                        data: RDESC.to_vec(),
+                    },
+                    &mut result,
+                )
+                .unwrap();
+
+            // __u16 bus follows the name, phys, uniq and rd_size fields
+            assert_eq!(&result[262..264], &[expected, 0x00]);
+        }
+    }
+
     #[test]
     fn encode_destroy_request() {
         let mut expected = vec![0; mem::size_of::<sys::uhid_event>()];
         expected[0] = 0x01;
         let mut result = BytesMut::new();
 
-        Codec.encode(InputEvent::Destroy, &mut result).unwrap();
+        Codec::default().encode(InputEvent::Destroy, &mut result).unwrap();
+
+        assert_bytes_eq(&result[..], &expected);
+    }
+
+    #[test]
+    fn decode_get_report_request() {
+        let mut bytes = vec![0; mem::size_of::<sys::uhid_event>()];
+        bytes[0] = 0x09;
+        bytes[4] = 0x2a;
+        bytes[8] = 0x03;
+        bytes[9] = 0x00;
+
+        match Codec::default().decode(&mut BytesMut::from(&bytes[..])).unwrap() {
+            OutputEvent::GetReport {
+                id,
+                report_number,
+                report_type,
+            } => {
+                assert_eq!(id, 42);
+                assert_eq!(report_number, 3);
+                assert_eq!(report_type, ReportType::Feature);
+            }
+            _ => panic!("Expected GetReport event"),
+        }
+    }
+
+    #[test]
+    fn decode_set_report_request() {
+        let mut bytes = vec![0; mem::size_of::<sys::uhid_event>()];
+        bytes[0] = 0x0d;
+        bytes[4] = 0x07;
+        bytes[8] = 0x01;
+        bytes[9] = 0x00;
+        bytes[10] = 0x02;
+        bytes[12] = 0xde;
+        bytes[13] = 0xad;
+
+        match Codec::default().decode(&mut BytesMut::from(&bytes[..])).unwrap() {
+            OutputEvent::SetReport {
+                id,
+                report_number,
+                report_type,
+                data,
+            } => {
+                assert_eq!(id, 7);
+                assert_eq!(report_number, 1);
+                assert_eq!(report_type, ReportType::Feature);
+                assert_eq!(data, vec![0xde, 0xad]);
+            }
+            _ => panic!("Expected SetReport event"),
+        }
+    }
+
+    #[test]
+    fn decode_set_report_clamps_oversized_length() {
+        let mut bytes = vec![0; mem::size_of::<sys::uhid_event>()];
+        bytes[0] = 0x0d;
+        bytes[10] = 0xff;
+        bytes[11] = 0xff;
+
+        match Codec::default().decode(&mut BytesMut::from(&bytes[..])).unwrap() {
+            OutputEvent::SetReport { data, .. } => assert_eq!(data.len(), UHID_DATA_MAX),
+            _ => panic!("Expected SetReport event"),
+        }
+    }
+
+    fn output_event(rtype: u8, data: &[u8]) -> BytesMut {
+        let mut bytes = vec![0; mem::size_of::<sys::uhid_event>()];
+        bytes[0] = 0x06;
+        bytes[4..4 + data.len()].copy_from_slice(data);
+        // __u16 size and __u8 rtype follow the UHID_DATA_MAX bytes of data
+        bytes[4100] = data.len() as u8;
+        bytes[4102] = rtype;
+        BytesMut::from(&bytes[..])
+    }
+
+    #[test]
+    fn decode_output_report_type() {
+        let rtypes = vec![
+            (0x00, ReportType::Feature),
+            (0x01, ReportType::Output),
+            (0x02, ReportType::Input),
+        ];
+
+        for (rtype, expected) in rtypes {
+            match Codec::default().decode(&mut output_event(rtype, &[0x02, 0xab])).unwrap() {
+                OutputEvent::Output {
+                    report_type,
+                    report_number,
+                    data,
+                } => {
+                    assert_eq!(report_type, expected);
+                    assert_eq!(report_number, None);
+                    assert_eq!(data, vec![0x02, 0xab]);
+                }
+                _ => panic!("Expected Output event"),
+            }
+        }
+    }
+
+    #[test]
+    fn decode_numbered_output_report() {
+        let mut codec = Codec::default();
+        let mut start = raw_event(0x02);
+        start[4] = DevFlags::NUMBERED_OUTPUT_REPORTS.bits() as u8;
+        codec.decode(&mut start).unwrap();
+
+        match codec.decode(&mut output_event(0x01, &[0x02, 0xab])).unwrap() {
+            OutputEvent::Output {
+                report_type,
+                report_number,
+                data,
+            } => {
+                assert_eq!(report_type, ReportType::Output);
+                assert_eq!(report_number, Some(0x02));
+                assert_eq!(data, vec![0x02, 0xab]);
+            }
+            _ => panic!("Expected Output event"),
+        }
+        // Only output reports were announced as numbered
+        match codec.decode(&mut output_event(0x00, &[0x02, 0xab])).unwrap() {
+            OutputEvent::Output { report_number, .. } => assert_eq!(report_number, None),
+            _ => panic!("Expected Output event"),
+        }
+    }
+
+    #[test]
+    fn decode_output_unknown_report_type() {
+        match Codec::default().decode(&mut output_event(0x07, &[])) {
+            Err(UHIDError::UnknownReportType(0x07)) => {}
+            _ => panic!("Expected UnknownReportType error"),
+        }
+    }
+
+    #[test]
+    fn encode_get_report_reply() {
+        let mut expected = vec![0; mem::size_of::<sys::uhid_event>()];
+        expected[0] = 0x0a;
+        expected[4] = 0x2a;
+        expected[8] = 0x05;
+        expected[10] = 0x03;
+        expected[12] = 0x01;
+        expected[13] = 0x02;
+        expected[14] = 0x03;
+        let mut result = BytesMut::new();
+
+        Codec::default()
+            .encode(
+                InputEvent::GetReportReply {
+                    id: 42,
+                    err: 5,
+                    data: vec![0x01, 0x02, 0x03],
+                },
+                &mut result,
+            )
+            .unwrap();
 
         assert_bytes_eq(&result[..], &expected);
     }
+
+    #[test]
+    fn encode_input_larger_than_uhid_data_max() {
+        let mut result = BytesMut::new();
+
+        let err = Codec::default()
+            .encode(InputEvent::Input { data: vec![0; 5000] }, &mut result)
+            .unwrap_err();
+
+        match err {
+            UHIDError::PayloadTooLarge { len: 5000, max: UHID_DATA_MAX } => {}
+            err => panic!("Expected PayloadTooLarge error, got {:?}", err),
+        }
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn encode_input_of_uhid_data_max() {
+        let mut result = BytesMut::new();
+
+        Codec::default()
+            .encode(InputEvent::Input { data: vec![0xff; UHID_DATA_MAX] }, &mut result)
+            .unwrap();
+
+        assert_eq!(result.len(), mem::size_of::<sys::uhid_event>());
+    }
+
+    #[test]
+    fn encode_set_report_reply() {
+        let mut expected = vec![0; mem::size_of::<sys::uhid_event>()];
+        expected[0] = 0x0e;
+        expected[4] = 0x07;
+        expected[5] = 0x01;
+        let mut result = BytesMut::new();
+
+        Codec::default()
+            .encode(InputEvent::SetReportReply { id: 0x0107, err: 0 }, &mut result)
+            .unwrap();
+
+        assert_bytes_eq(&result[..], &expected);
+    }
+
+    #[test]
+    fn decode_unknown_event_type() {
+        let mut bytes = vec![0; mem::size_of::<sys::uhid_event>()];
+        bytes[0] = 0x63;
+
+        match Codec::default().decode(&mut BytesMut::from(&bytes[..])) {
+            Err(UHIDError::UnknownEventType(0x63)) => {}
+            _ => panic!("Expected UnknownEventType error"),
+        }
+    }
+
+    fn raw_event(event_type: u8) -> BytesMut {
+        let mut bytes = vec![0; mem::size_of::<sys::uhid_event>()];
+        bytes[0] = event_type;
+        BytesMut::from(&bytes[..])
+    }
+
+    #[test]
+    fn decode_lifecycle_events() {
+        let mut start = raw_event(0x02);
+        start[4] = 0x05;
+
+        match Codec::default().decode(&mut start).unwrap() {
+            OutputEvent::Start { dev_flags } => assert_eq!(
+                dev_flags,
+                DevFlags::NUMBERED_FEATURE_REPORTS | DevFlags::NUMBERED_INPUT_REPORTS
+            ),
+            _ => panic!("Expected Start event"),
+        }
+        match Codec::default().decode(&mut raw_event(0x04)).unwrap() {
+            OutputEvent::Open => {}
+            _ => panic!("Expected Open event"),
+        }
+        match Codec::default().decode(&mut raw_event(0x05)).unwrap() {
+            OutputEvent::Close => {}
+            _ => panic!("Expected Close event"),
+        }
+        match Codec::default().decode(&mut raw_event(0x03)).unwrap() {
+            OutputEvent::Stop => {}
+            _ => panic!("Expected Stop event"),
+        }
+    }
+
+    #[test]
+    fn raw_tap_sees_one_event_at_a_time() {
+        let tapped = Arc::new(Mutex::new(Vec::new()));
+        let tapped_by_codec = tapped.clone();
+        let mut codec = Codec::default();
+        codec.set_raw_tap(Box::new(move |bytes| {
+            tapped_by_codec.lock().unwrap().push(bytes.to_vec())
+        }));
+        let mut src = raw_event(0x04);
+        src.extend_from_slice(&raw_event(0x05));
+
+        codec.decode(&mut src).unwrap();
+        codec.decode(&mut src).unwrap();
+
+        assert_eq!(
+            *tapped.lock().unwrap(),
+            vec![raw_event(0x04).to_vec(), raw_event(0x05).to_vec()]
+        );
+    }
 }
@@ -0,0 +1,393 @@
+use codec::{Bus, InputEvent};
+use rand::RngCore;
+use report_descriptor::{fido_report_lens, FIDO_REPORT_LEN};
+use uhid_sys as sys;
+
+const NAME_MAX_LEN: usize = 128;
+const PHYS_MAX_LEN: usize = 64;
+const UNIQ_MAX_LEN: usize = 64;
+const RANDOM_UNIQ_LEN: usize = 8;
+
+quick_error! {
+    #[derive(Debug, PartialEq)]
+    pub enum CreateParamsError {
+        FieldTooLong(field: &'static str, len: usize, max: usize) {
+            description("Field is too long")
+            display(r#"Field "{}" is {} bytes, at most {} are allowed"#, field, len, max)
+        }
+        NulInField(field: &'static str) {
+            description("Field contains a nul byte")
+            display(r#"Field "{}" contains a nul byte"#, field)
+        }
+        EmptyDescriptor {
+            description("Report descriptor is empty")
+        }
+        DescriptorTooLarge(len: usize, max: usize) {
+            description("Report descriptor is too large")
+            display("Report descriptor is {} bytes, at most {} are allowed", len, max)
+        }
+        FidoReportLength(input_len: usize, output_len: usize) {
+            description("FIDO reports are not 64 bytes")
+            display("FIDO input and output reports must be 64 bytes, the descriptor has {} and {} byte reports", input_len, output_len)
+        }
+    }
+}
+
+/// Parameters used to create UHID devices
+#[derive(Clone, Debug)]
+pub struct CreateParams {
+    pub name: String,
+    pub phys: String,
+    pub uniq: String,
+    pub bus: Bus,
+    pub vendor: u32,
+    pub product: u32,
+    pub version: u32,
+    pub country: u32,
+    pub data: Vec<u8>,
+}
+
+impl CreateParams {
+    pub fn builder() -> CreateParamsBuilder {
+        CreateParamsBuilder::default()
+    }
+
+    /// The event creating a device with these parameters
+    pub(crate) fn create_event(&self) -> InputEvent {
+        InputEvent::Create {
+            name: self.name.clone(),
+            phys: self.phys.clone(),
+            uniq: self.uniq.clone(),
+            bus: self.bus,
+            vendor: self.vendor,
+            product: self.product,
+            version: self.version,
+            country: self.country,
+            data: self.data.clone(),
+        }
+    }
+}
+
+/// What `CreateParamsBuilder::build` does with a FIDO report descriptor whose input
+/// or output reports are not 64 bytes
+///
+/// Browsers ignore such a device without telling why, though the kernel accepts it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DescriptorCheck {
+    Ignore,
+    Warn,
+    Reject,
+}
+
+/// Builds `CreateParams`, validating them against the limits of the kernel create event
+///
+/// Defaults to the USB bus and country code 0. Report descriptors on the FIDO usage
+/// page are checked for 64 byte reports, with a warning unless `descriptor_check`
+/// says otherwise.
+pub struct CreateParamsBuilder {
+    name: String,
+    phys: String,
+    uniq: String,
+    bus: Bus,
+    vendor: u32,
+    product: u32,
+    version: u32,
+    country: u32,
+    data: Vec<u8>,
+    descriptor_check: DescriptorCheck,
+}
+
+impl Default for CreateParamsBuilder {
+    fn default() -> CreateParamsBuilder {
+        CreateParamsBuilder {
+            name: String::new(),
+            phys: String::new(),
+            uniq: String::new(),
+            bus: Bus::USB,
+            vendor: 0,
+            product: 0,
+            version: 0,
+            country: 0,
+            data: Vec::new(),
+            descriptor_check: DescriptorCheck::Warn,
+        }
+    }
+}
+
+impl CreateParamsBuilder {
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn phys<S: Into<String>>(mut self, phys: S) -> Self {
+        self.phys = phys.into();
+        self
+    }
+
+    pub fn uniq<S: Into<String>>(mut self, uniq: S) -> Self {
+        self.uniq = uniq.into();
+        self
+    }
+
+    /// Serial made of random bytes drawn from `rng`, hex encoded
+    ///
+    /// Without this a blank `uniq` is filled in when the device is created from
+    /// the process id and a counter. Pass `OsRng` or another CSPRNG in production,
+    /// tests can pass a seeded generator to get a stable serial.
+    pub fn random_uniq(mut self, rng: &mut dyn RngCore) -> Self {
+        let mut bytes = [0u8; RANDOM_UNIQ_LEN];
+        rng.fill_bytes(&mut bytes);
+        self.uniq = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        self
+    }
+
+    pub fn bus(mut self, bus: Bus) -> Self {
+        self.bus = bus;
+        self
+    }
+
+    pub fn vendor(mut self, vendor: u32) -> Self {
+        self.vendor = vendor;
+        self
+    }
+
+    pub fn product(mut self, product: u32) -> Self {
+        self.product = product;
+        self
+    }
+
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn country(mut self, country: u32) -> Self {
+        self.country = country;
+        self
+    }
+
+    /// HID report descriptor describing the reports the device sends and receives
+    pub fn report_descriptor(mut self, data: Vec<u8>) -> Self {
+        self.data = data;
+        self
+    }
+
+    pub fn descriptor_check(mut self, descriptor_check: DescriptorCheck) -> Self {
+        self.descriptor_check = descriptor_check;
+        self
+    }
+
+    pub fn build(self) -> Result<CreateParams, CreateParamsError> {
+        validate_cstr("name", &self.name, NAME_MAX_LEN)?;
+        validate_cstr("phys", &self.phys, PHYS_MAX_LEN)?;
+        validate_cstr("uniq", &self.uniq, UNIQ_MAX_LEN)?;
+
+        let max_descriptor_size = sys::HID_MAX_DESCRIPTOR_SIZE as usize;
+        if self.data.is_empty() {
+            return Err(CreateParamsError::EmptyDescriptor);
+        }
+        if self.data.len() > max_descriptor_size {
+            return Err(CreateParamsError::DescriptorTooLarge(
+                self.data.len(),
+                max_descriptor_size,
+            ));
+        }
+        self.check_fido_reports()?;
+
+        Ok(CreateParams {
+            name: self.name,
+            phys: self.phys,
+            uniq: self.uniq,
+            bus: self.bus,
+            vendor: self.vendor,
+            product: self.product,
+            version: self.version,
+            country: self.country,
+            data: self.data,
+        })
+    }
+}
+
+impl CreateParamsBuilder {
+    fn check_fido_reports(&self) -> Result<(), CreateParamsError> {
+        if self.descriptor_check == DescriptorCheck::Ignore {
+            return Ok(());
+        }
+        let expected_len = FIDO_REPORT_LEN as usize;
+        match fido_report_lens(&self.data) {
+            Some((input_len, output_len))
+                if input_len != expected_len || output_len != expected_len =>
+            {
+                let err = CreateParamsError::FidoReportLength(input_len, output_len);
+                if self.descriptor_check == DescriptorCheck::Reject {
+                    return Err(err);
+                }
+                warn!(name = %self.name, "{}", err);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Check a string fits in a fixed-size, nul terminated kernel buffer
+fn validate_cstr(
+    field: &'static str,
+    value: &str,
+    buffer_size: usize,
+) -> Result<(), CreateParamsError> {
+    if value.bytes().any(|b| b == 0) {
+        return Err(CreateParamsError::NulInField(field));
+    }
+    let max = buffer_size - 1;
+    if value.len() > max {
+        return Err(CreateParamsError::FieldTooLong(field, value.len(), max));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    use super::*;
+    use report_descriptor::{fido_u2f_hid, ReportDescriptorBuilder, FIDO_USAGE_PAGE};
+
+    /// FIDO descriptor with 32 byte input reports
+    fn short_fido_descriptor() -> Vec<u8> {
+        ReportDescriptorBuilder::new()
+            .usage_page(FIDO_USAGE_PAGE)
+            .usage(0x01)
+            .collection(0x01)
+            .usage(0x20)
+            .report_size(8)
+            .report_count(32)
+            .input(0x02)
+            .usage(0x21)
+            .report_size(8)
+            .report_count(64)
+            .output(0x02)
+            .end_collection()
+            .build()
+    }
+
+    #[test]
+    fn build_uses_defaults() {
+        let params = CreateParams::builder()
+            .name("test-uhid-device")
+            .report_descriptor(vec![0x05, 0x01])
+            .build()
+            .unwrap();
+
+        assert_eq!(params.name, "test-uhid-device");
+        assert_eq!(params.bus as u16, Bus::USB as u16);
+        assert_eq!(params.country, 0);
+    }
+
+    #[test]
+    fn build_accepts_maximum_name() {
+        let name: String = (0..NAME_MAX_LEN - 1).map(|_| 'a').collect();
+
+        let result = CreateParams::builder()
+            .name(name)
+            .report_descriptor(vec![0x05, 0x01])
+            .build();
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn build_rejects_long_uniq() {
+        let uniq: String = (0..UNIQ_MAX_LEN).map(|_| 'a').collect();
+
+        let result = CreateParams::builder()
+            .uniq(uniq)
+            .report_descriptor(vec![0x05, 0x01])
+            .build();
+
+        assert_eq!(
+            result.err(),
+            Some(CreateParamsError::FieldTooLong("uniq", 64, 63))
+        );
+    }
+
+    #[test]
+    fn random_uniq_is_stable_for_seed() {
+        let uniq = |seed| {
+            CreateParams::builder()
+                .random_uniq(&mut ChaCha20Rng::seed_from_u64(seed))
+                .report_descriptor(vec![0x05, 0x01])
+                .build()
+                .unwrap()
+                .uniq
+        };
+
+        assert_eq!(uniq(7), uniq(7));
+        assert_ne!(uniq(7), uniq(8));
+        assert_eq!(uniq(7).len(), RANDOM_UNIQ_LEN * 2);
+    }
+
+    #[test]
+    fn build_rejects_short_fido_reports_if_asked() {
+        let result = CreateParams::builder()
+            .report_descriptor(short_fido_descriptor())
+            .descriptor_check(DescriptorCheck::Reject)
+            .build();
+
+        assert_eq!(
+            result.err(),
+            Some(CreateParamsError::FidoReportLength(32, 64))
+        );
+    }
+
+    #[test]
+    fn build_warns_about_short_fido_reports_by_default() {
+        let result = CreateParams::builder()
+            .report_descriptor(short_fido_descriptor())
+            .build();
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn build_accepts_fido_u2f_hid_descriptor() {
+        let result = CreateParams::builder()
+            .report_descriptor(fido_u2f_hid())
+            .descriptor_check(DescriptorCheck::Reject)
+            .build();
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn build_rejects_empty_descriptor() {
+        let result = CreateParams::builder().name("test-uhid-device").build();
+
+        assert_eq!(result.err(), Some(CreateParamsError::EmptyDescriptor));
+    }
+
+    #[test]
+    fn clone_keeps_every_field() {
+        let params = CreateParams::builder()
+            .name("test-uhid-device")
+            .uniq("token-1")
+            .report_descriptor(vec![0x05, 0x01])
+            .build()
+            .unwrap();
+
+        let cloned = params.clone();
+
+        assert_eq!(cloned.name, params.name);
+        assert_eq!(cloned.phys, params.phys);
+        assert_eq!(cloned.uniq, params.uniq);
+        assert_eq!(cloned.bus, params.bus);
+        assert_eq!(cloned.vendor, params.vendor);
+        assert_eq!(cloned.product, params.product);
+        assert_eq!(cloned.version, params.version);
+        assert_eq!(cloned.country, params.country);
+        assert_eq!(cloned.data, params.data);
+        assert!(format!("{:?}", cloned).contains("test-uhid-device"));
+    }
+}
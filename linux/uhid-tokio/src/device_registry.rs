@@ -0,0 +1,298 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use codec::OutputEvent;
+use create_params::CreateParams;
+use error::UHIDError;
+use misc_driver::MiscDriver;
+use uhid_device::UHIDDevice;
+
+/// Owns several devices and merges their output events into one stream
+///
+/// Each device is identified by the index it was added at, indices stay valid
+/// for the lifetime of the registry. Devices are polled round-robin so a busy
+/// device cannot starve the others. The stream ends once every device's
+/// stream has ended.
+pub struct DeviceRegistry<T: AsyncWrite + Unpin> {
+    devices: Vec<Entry<T>>,
+    next_poll: usize,
+}
+
+struct Entry<T: AsyncWrite + Unpin> {
+    device: UHIDDevice<T>,
+    finished: bool,
+}
+
+impl DeviceRegistry<MiscDriver> {
+    /// Create a device using `uhid_path` and add it, returning its index
+    ///
+    /// Must be called from within a tokio runtime, the device is registered with it.
+    pub fn create(&mut self, params: CreateParams) -> io::Result<usize> {
+        Ok(self.add(UHIDDevice::create(params)?))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> DeviceRegistry<T> {
+    pub fn new() -> DeviceRegistry<T> {
+        DeviceRegistry {
+            devices: Vec::new(),
+            next_poll: 0,
+        }
+    }
+
+    /// Add a device, returning the index its events will be tagged with
+    pub fn add(&mut self, device: UHIDDevice<T>) -> usize {
+        self.devices.push(Entry {
+            device,
+            finished: false,
+        });
+        self.devices.len() - 1
+    }
+
+    pub fn get(&self, index: usize) -> Option<&UHIDDevice<T>> {
+        self.devices.get(index).map(|entry| &entry.device)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut UHIDDevice<T>> {
+        self.devices.get_mut(index).map(|entry| &mut entry.device)
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Take back ownership of the devices, in index order
+    pub fn into_devices(self) -> Vec<UHIDDevice<T>> {
+        self.devices.into_iter().map(|entry| entry.device).collect()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> DeviceRegistry<T> {
+    /// Destroy every device, e.g. when shutting down
+    ///
+    /// Every device is destroyed even if destroying an earlier one fails, the
+    /// first error is returned.
+    pub fn destroy_all(self) -> Result<(), UHIDError> {
+        let mut result = Ok(());
+        for entry in self.devices {
+            let destroyed = entry.device.destroy();
+            if result.is_ok() {
+                result = destroyed;
+            }
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Default for DeviceRegistry<T> {
+    fn default() -> DeviceRegistry<T> {
+        DeviceRegistry::new()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Stream for DeviceRegistry<T> {
+    type Item = (usize, Result<OutputEvent, UHIDError>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let len = self.devices.len();
+        for offset in 0..len {
+            let index = (self.next_poll + offset) % len;
+            let entry = &mut self.devices[index];
+            if entry.finished {
+                continue;
+            }
+            match Pin::new(&mut entry.device).poll_next(cx) {
+                Poll::Ready(Some(event)) => {
+                    self.next_poll = (index + 1) % len;
+                    return Poll::Ready(Some((index, event)));
+                }
+                Poll::Ready(None) => entry.finished = true,
+                Poll::Pending => {}
+            }
+        }
+        if self.devices.iter().all(|entry| entry.finished) {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::mem;
+    use std::rc::Rc;
+
+    use futures::executor::block_on;
+    use futures::StreamExt;
+    use tokio::io::ReadBuf;
+    use uhid_sys as sys;
+
+    use super::*;
+    use codec::Bus;
+
+    const UHID_OPEN: u8 = 0x04;
+    const UHID_CLOSE: u8 = 0x05;
+    const UHID_DESTROY: u8 = 0x01;
+
+    /// Device that replays a fixed list of kernel events and then reports EOF
+    ///
+    /// The type of each event written to it is recorded in `written`.
+    struct ScriptedDevice {
+        events: VecDeque<u8>,
+        written: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl ScriptedDevice {
+        fn new(events: &[u8]) -> ScriptedDevice {
+            ScriptedDevice {
+                events: events.iter().cloned().collect(),
+                written: Rc::new(RefCell::new(Vec::new())),
+            }
+        }
+    }
+
+    impl AsyncRead for ScriptedDevice {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &mut ReadBuf,
+        ) -> Poll<io::Result<()>> {
+            if let Some(event_type) = self.events.pop_front() {
+                let mut event = vec![0u8; mem::size_of::<sys::uhid_event>()];
+                event[0] = event_type;
+                buf.put_slice(&event);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for ScriptedDevice {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.written.borrow_mut().push(buf[0]);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn device(events: &[u8]) -> UHIDDevice<ScriptedDevice> {
+        device_with(ScriptedDevice::new(events))
+    }
+
+    fn device_with(scripted: ScriptedDevice) -> UHIDDevice<ScriptedDevice> {
+        let params = CreateParams {
+            name: String::from("test-uhid-device"),
+            phys: String::from(""),
+            uniq: String::from(""),
+            bus: Bus::USB,
+            vendor: 0x15d9,
+            product: 0x0a37,
+            version: 0,
+            country: 0,
+            data: vec![0x05, 0x01],
+        };
+        UHIDDevice::create_with(scripted, params).unwrap()
+    }
+
+    fn tag(item: (usize, Result<OutputEvent, UHIDError>)) -> (usize, &'static str) {
+        let event = match item.1.unwrap() {
+            OutputEvent::Open => "Open",
+            OutputEvent::Close => "Close",
+            _ => "Other",
+        };
+        (item.0, event)
+    }
+
+    #[test]
+    fn events_are_tagged_with_device_index() {
+        let mut registry = DeviceRegistry::new();
+        let first = registry.add(device(&[UHID_OPEN]));
+        let second = registry.add(device(&[UHID_CLOSE]));
+
+        let events: Vec<_> = block_on(registry.map(tag).collect());
+
+        assert_eq!((first, second), (0, 1));
+        assert_eq!(events, vec![(0, "Open"), (1, "Close")]);
+    }
+
+    #[test]
+    fn devices_are_polled_round_robin() {
+        let mut registry = DeviceRegistry::new();
+        registry.add(device(&[UHID_OPEN, UHID_CLOSE, UHID_OPEN]));
+        registry.add(device(&[UHID_CLOSE]));
+        registry.add(device(&[UHID_OPEN, UHID_CLOSE]));
+
+        let events: Vec<_> = block_on(registry.map(tag).collect());
+
+        assert_eq!(
+            events,
+            vec![
+                (0, "Open"),
+                (1, "Close"),
+                (2, "Open"),
+                (0, "Close"),
+                (2, "Close"),
+                (0, "Open"),
+            ]
+        );
+    }
+
+    #[test]
+    fn devices_have_distinct_uniq() {
+        let mut registry = DeviceRegistry::new();
+        for _ in 0..3 {
+            registry.add(device(&[]));
+        }
+
+        let devices = registry.into_devices();
+
+        assert_ne!(devices[0].uniq(), devices[1].uniq());
+        assert_ne!(devices[1].uniq(), devices[2].uniq());
+        assert_ne!(devices[0].uniq(), devices[2].uniq());
+    }
+
+    #[test]
+    fn empty_registry_ends_immediately() {
+        let registry: DeviceRegistry<ScriptedDevice> = DeviceRegistry::new();
+
+        assert_eq!(block_on(registry.collect::<Vec<_>>()).len(), 0);
+    }
+
+    #[test]
+    fn destroy_all_destroys_every_device() {
+        let mut registry = DeviceRegistry::new();
+        let mut written = Vec::new();
+        for _ in 0..2 {
+            let scripted = ScriptedDevice::new(&[]);
+            written.push(scripted.written.clone());
+            registry.add(device_with(scripted));
+        }
+
+        registry.destroy_all().unwrap();
+
+        for written in written {
+            assert_eq!(written.borrow().last(), Some(&UHID_DESTROY));
+        }
+    }
+}
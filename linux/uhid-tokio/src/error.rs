@@ -0,0 +1,35 @@
+use std::ffi;
+use std::io;
+
+quick_error! {
+    /// Errors produced while encoding, decoding or exchanging UHID events
+    #[derive(Debug)]
+    pub enum UHIDError {
+        Io(err: io::Error) {
+            from()
+            display("I/O error: {}", err)
+            cause(err)
+        }
+        UnknownEventType(event_type_value: u32) {
+            description("Unknown/Unsupported event type")
+            display(r#"Unknown/Unsupported event type: "{}""#, event_type_value)
+        }
+        UnknownReportType(report_type_value: u8) {
+            description("Unknown report type")
+            display(r#"Unknown report type: "{}""#, report_type_value)
+        }
+        PayloadTooLarge { len: usize, max: usize } {
+            description("Payload exceeds available space")
+            display(r#"Payload size "{}" exceeds available space "{}""#, len, max)
+        }
+        DeviceStopped {
+            description("Device has been stopped")
+            display("Device has been stopped")
+        }
+        Nul(err: ffi::NulError) {
+            from()
+            display("String contains nul byte: {}", err)
+            cause(err)
+        }
+    }
+}
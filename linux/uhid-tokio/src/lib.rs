@@ -4,110 +4,178 @@
 //!
 //! See https://www.kernel.org/doc/Documentation/hid/uhid.txt
 //!
+//! `UHIDDevice` is a `futures::Stream` of `OutputEvent`s from the kernel and a
+//! `futures::Sink` of `InputEvent`s, so it can be used from `async` code running on
+//! a tokio 1.x runtime.
+//!
+//! ## Multiple devices
+//!
+//! Each `UHIDDevice` opens its own file descriptor, so any number of devices can
+//! exist at once in one process. Devices created with a blank `uniq` get a unique
+//! one generated, keeping devices with the same name distinguishable to the kernel
+//! and to `resolve_hidraw_path`. `DeviceRegistry` owns a set of devices and merges
+//! their output events into a single stream tagged with each device's index.
+//!
+//! ## Suspend and resume
+//!
+//! The kernel may stop or remove a virtual device when the machine suspends.
+//! `ResilientDevice` creates a new device in its place and reports that it did,
+//! so long running daemons keep working without a restart.
+//!
+//! ## Testing
+//!
+//! With the `test-util` feature, `uhid_loopback` gives an in-memory transport to
+//! create a device on, and a `LoopbackHost` that plays the kernel at its other end.
+//! Code driving a device can be tested that way without root or `/dev/uhid`.
+//!
+//! ## Logging
+//!
+//! Diagnostics are emitted with `tracing`. Every operation on a device happens
+//! within a span named `uhid_device` (see `DEVICE_SPAN_NAME`) that carries the
+//! device's `name` and `uniq` fields, so the events of one device can be told apart
+//! from those of others, e.g. to show them in an activity pane. A layer built on
+//! `tracing-subscriber` can forward them to a channel:
+//!
+//! ```rust,ignore
+//! struct ActivityLayer(std::sync::mpsc::Sender<String>);
+//!
+//! impl<S> Layer<S> for ActivityLayer
+//! where
+//!     S: Subscriber + for<'a> LookupSpan<'a>,
+//! {
+//!     fn on_event(&self, event: &Event, ctx: Context<S>) {
+//!         let in_device = ctx
+//!             .event_scope(event)
+//!             .map_or(false, |mut scope| scope.any(|span| span.name() == DEVICE_SPAN_NAME));
+//!         if in_device {
+//!             let _ = self.0.send(format!("{:?}", event));
+//!         }
+//!     }
+//! }
+//! ```
+//!
 //! ## Example
 //! ```rust,no_run
 //!#  extern crate futures;
 //!#  extern crate tokio;
 //!#  extern crate tokio_linux_uhid;
-//! 
+//!
 //! use tokio_linux_uhid::{Bus, CreateParams, UHIDDevice};
-//! 
+//!
 //! // Formulate a 'HID Report Descriptor' to describe the function of your device.
 //! // This tells the kernel how to interpret the HID packets you send to the device.
 //! const RDESC: [u8; 85] = [
-//!     0x05, 0x01,	/* USAGE_PAGE (Generic Desktop) */
-//!     0x09, 0x02,	/* USAGE (Mouse) */
-//!     0xa1, 0x01,	/* COLLECTION (Application) */
-//!     0x09, 0x01,		/* USAGE (Pointer) */
-//!     0xa1, 0x00,		/* COLLECTION (Physical) */
-//!     0x85, 0x01,			/* REPORT_ID (1) */
-//!     0x05, 0x09,			/* USAGE_PAGE (Button) */
-//!     0x19, 0x01,			/* USAGE_MINIMUM (Button 1) */
-//!     0x29, 0x03,			/* USAGE_MAXIMUM (Button 3) */
-//!     0x15, 0x00,			/* LOGICAL_MINIMUM (0) */
-//!     0x25, 0x01,			/* LOGICAL_MAXIMUM (1) */
-//!     0x95, 0x03,			/* REPORT_COUNT (3) */
-//!     0x75, 0x01,			/* REPORT_SIZE (1) */
-//!     0x81, 0x02,			/* INPUT (Data,Var,Abs) */
-//!     0x95, 0x01,			/* REPORT_COUNT (1) */
-//!     0x75, 0x05,			/* REPORT_SIZE (5) */
-//!     0x81, 0x01,			/* INPUT (Cnst,Var,Abs) */
-//!     0x05, 0x01,			/* USAGE_PAGE (Generic Desktop) */
-//!     0x09, 0x30,			/* USAGE (X) */
-//!     0x09, 0x31,			/* USAGE (Y) */
-//!     0x09, 0x38,			/* USAGE (WHEEL) */
-//!     0x15, 0x81,			/* LOGICAL_MINIMUM (-127) */
-//!     0x25, 0x7f,			/* LOGICAL_MAXIMUM (127) */
-//!     0x75, 0x08,			/* REPORT_SIZE (8) */
-//!     0x95, 0x03,			/* REPORT_COUNT (3) */
-//!     0x81, 0x06,			/* INPUT (Data,Var,Rel) */
-//!     0xc0,			/* END_COLLECTION */
-//!     0xc0,		/* END_COLLECTION */
-//!     0x05, 0x01,	/* USAGE_PAGE (Generic Desktop) */
-//!     0x09, 0x06,	/* USAGE (Keyboard) */
-//!     0xa1, 0x01,	/* COLLECTION (Application) */
-//!     0x85, 0x02,		/* REPORT_ID (2) */
-//!     0x05, 0x08,		/* USAGE_PAGE (Led) */
-//!     0x19, 0x01,		/* USAGE_MINIMUM (1) */
-//!     0x29, 0x03,		/* USAGE_MAXIMUM (3) */
-//!     0x15, 0x00,		/* LOGICAL_MINIMUM (0) */
-//!     0x25, 0x01,		/* LOGICAL_MAXIMUM (1) */
-//!     0x95, 0x03,		/* REPORT_COUNT (3) */
-//!     0x75, 0x01,		/* REPORT_SIZE (1) */
-//!     0x91, 0x02,		/* Output (Data,Var,Abs) */
-//!     0x95, 0x01,		/* REPORT_COUNT (1) */
-//!     0x75, 0x05,		/* REPORT_SIZE (5) */
-//!     0x91, 0x01,		/* Output (Cnst,Var,Abs) */
-//!     0xc0,		/* END_COLLECTION */
+//!     0x05, 0x01, /* USAGE_PAGE (Generic Desktop) */
+//!     0x09, 0x02, /* USAGE (Mouse) */
+//!     0xa1, 0x01, /* COLLECTION (Application) */
+//!     0x09, 0x01, /* USAGE (Pointer) */
+//!     0xa1, 0x00, /* COLLECTION (Physical) */
+//!     0x85, 0x01, /* REPORT_ID (1) */
+//!     0x05, 0x09, /* USAGE_PAGE (Button) */
+//!     0x19, 0x01, /* USAGE_MINIMUM (Button 1) */
+//!     0x29, 0x03, /* USAGE_MAXIMUM (Button 3) */
+//!     0x15, 0x00, /* LOGICAL_MINIMUM (0) */
+//!     0x25, 0x01, /* LOGICAL_MAXIMUM (1) */
+//!     0x95, 0x03, /* REPORT_COUNT (3) */
+//!     0x75, 0x01, /* REPORT_SIZE (1) */
+//!     0x81, 0x02, /* INPUT (Data,Var,Abs) */
+//!     0x95, 0x01, /* REPORT_COUNT (1) */
+//!     0x75, 0x05, /* REPORT_SIZE (5) */
+//!     0x81, 0x01, /* INPUT (Cnst,Var,Abs) */
+//!     0x05, 0x01, /* USAGE_PAGE (Generic Desktop) */
+//!     0x09, 0x30, /* USAGE (X) */
+//!     0x09, 0x31, /* USAGE (Y) */
+//!     0x09, 0x38, /* USAGE (WHEEL) */
+//!     0x15, 0x81, /* LOGICAL_MINIMUM (-127) */
+//!     0x25, 0x7f, /* LOGICAL_MAXIMUM (127) */
+//!     0x75, 0x08, /* REPORT_SIZE (8) */
+//!     0x95, 0x03, /* REPORT_COUNT (3) */
+//!     0x81, 0x06, /* INPUT (Data,Var,Rel) */
+//!     0xc0, /* END_COLLECTION */
+//!     0xc0, /* END_COLLECTION */
+//!     0x05, 0x01, /* USAGE_PAGE (Generic Desktop) */
+//!     0x09, 0x06, /* USAGE (Keyboard) */
+//!     0xa1, 0x01, /* COLLECTION (Application) */
+//!     0x85, 0x02, /* REPORT_ID (2) */
+//!     0x05, 0x08, /* USAGE_PAGE (Led) */
+//!     0x19, 0x01, /* USAGE_MINIMUM (1) */
+//!     0x29, 0x03, /* USAGE_MAXIMUM (3) */
+//!     0x15, 0x00, /* LOGICAL_MINIMUM (0) */
+//!     0x25, 0x01, /* LOGICAL_MAXIMUM (1) */
+//!     0x95, 0x03, /* REPORT_COUNT (3) */
+//!     0x75, 0x01, /* REPORT_SIZE (1) */
+//!     0x91, 0x02, /* Output (Data,Var,Abs) */
+//!     0x95, 0x01, /* REPORT_COUNT (1) */
+//!     0x75, 0x05, /* REPORT_SIZE (5) */
+//!     0x91, 0x01, /* Output (Cnst,Var,Abs) */
+//!     0xc0, /* END_COLLECTION */
 //! ];
-//! 
-//! fn main() {
-//!     let mut uhid_device = UHIDDevice::create(CreateParams {
-//!         name: String::from("test-uhid-device"),
-//!         phys: String::from(""),
-//!         uniq: String::from(""),
-//!         bus: Bus::USB,
-//!         vendor: 0x15d9,
-//!         product: 0x0a37,
-//!         version: 0,
-//!         country: 0,
-//!         // Most important field - HID Report Descriptor
-//!         data: RDESC.to_vec(),
-//!     }, None).unwrap();
-//! 
-//!     // Formulate a HID Packet
-//!     let button_flags = 0;
-//!     let mouse_abs_hor = 20;
-//!     let mouse_abs_ver = 0;
-//!     let wheel = 0;
-//!     let data: [u8; 5] = [1, button_flags, mouse_abs_hor, mouse_abs_ver, wheel];
-//!     
-//!     // Send the HID packet to the device. Cursor should move 20 points to the right. 
-//!     uhid_device.send_input(&data).unwrap();
-//! }
+//!
+//! // The device is registered with the ambient tokio runtime
+//! let runtime = tokio::runtime::Runtime::new().unwrap();
+//! let _guard = runtime.enter();
+//!
+//! let mut uhid_device = UHIDDevice::create(CreateParams {
+//!     name: String::from("test-uhid-device"),
+//!     phys: String::from(""),
+//!     uniq: String::from(""),
+//!     bus: Bus::USB,
+//!     vendor: 0x15d9,
+//!     product: 0x0a37,
+//!     version: 0,
+//!     country: 0,
+//!     // Most important field - HID Report Descriptor
+//!     data: RDESC.to_vec(),
+//! }).unwrap();
+//!
+//! // Formulate a HID Packet
+//! let button_flags = 0;
+//! let mouse_abs_hor = 20;
+//! let mouse_abs_ver = 0;
+//! let wheel = 0;
+//! let data: [u8; 5] = [1, button_flags, mouse_abs_hor, mouse_abs_ver, wheel];
+//!
+//! // Send the HID packet to the device. Cursor should move 20 points to the right. 
+//! uhid_device.send_input(&data).unwrap();
 //! ```
 #[macro_use]
 extern crate bitflags;
 extern crate bytes;
 extern crate futures;
-extern crate mio;
 extern crate nix;
 #[macro_use]
 extern crate quick_error;
-#[macro_use]
-extern crate slog;
-extern crate slog_stdlog;
+extern crate rand;
+#[cfg(test)]
+extern crate rand_chacha;
 extern crate tokio;
-extern crate tokio_io;
+#[macro_use]
+extern crate tracing;
 extern crate uhid_sys;
 
-pub use codec::{Bus, InputEvent, OutputEvent, StreamError};
-pub use uhid_device::CreateParams;
-pub use uhid_device::UHIDDevice;
-pub use misc_driver::MiscDriver;
+pub use blocking_device::BlockingUHIDDevice;
+pub use codec::{Bus, InputEvent, OutputEvent, ReportType, UHID_DATA_MAX};
+pub use error::UHIDError;
+pub use create_params::{CreateParams, CreateParamsBuilder, CreateParamsError, DescriptorCheck};
+pub use device_registry::DeviceRegistry;
+pub use resilient_device::{OpenUHID, ResilientDevice, ResilientEvent};
+pub use uhid_device::{
+    uhid_path, Heartbeat, NextEventTimeout, DEVICE_SPAN_NAME, SendInputs, Started, UHIDDevice,
+};
+pub use misc_driver::{DeviceOpenOptions, MiscDriver};
+#[cfg(any(test, feature = "test-util"))]
+pub use loopback::{uhid_loopback, DeviceEvent, LoopbackHost};
 
+mod blocking_device;
 mod character_device;
 mod codec;
+mod create_params;
+mod device_registry;
+mod error;
+#[cfg(any(test, feature = "test-util"))]
+mod loopback;
 mod misc_driver;
+pub mod report_descriptor;
+mod resilient_device;
 mod transport;
 mod uhid_device;
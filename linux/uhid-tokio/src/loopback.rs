@@ -0,0 +1,232 @@
+//! In-memory stand-in for `/dev/uhid`, to test devices without root
+//!
+//! `uhid_loopback` returns a transport to create a `UHIDDevice` on and the
+//! `LoopbackHost` at its other end, which plays the kernel: it sees the events the
+//! device writes and sends it lifecycle events and output reports, e.g. those a
+//! browser would write to the hidraw node.
+//!
+//! The host's methods block until the pipe lets them through, they need no
+//! runtime. Only built for this crate's tests or with the `test-util` feature.
+use std::io;
+use std::mem;
+
+use futures::executor::block_on;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use codec::{ReportType, UHID_DATA_MAX};
+use uhid_sys as sys;
+
+/// Events either end can write before the other reads them
+const LOOPBACK_EVENTS: usize = 16;
+
+/// Event written by the device, as the kernel reads it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Create {
+        name: String,
+        uniq: String,
+        vendor: u32,
+        product: u32,
+        data: Vec<u8>,
+    },
+    Destroy,
+    Input {
+        data: Vec<u8>,
+    },
+    GetReportReply {
+        id: u32,
+        err: u16,
+        data: Vec<u8>,
+    },
+    SetReportReply {
+        id: u32,
+        err: u16,
+    },
+}
+
+/// Kernel end of a loopback, see `uhid_loopback`
+pub struct LoopbackHost {
+    stream: DuplexStream,
+}
+
+/// Connected transport and host, each event written to one is read whole by the other
+pub fn uhid_loopback() -> (DuplexStream, LoopbackHost) {
+    let (device, host) = duplex(LOOPBACK_EVENTS * event_len());
+    (device, LoopbackHost { stream: host })
+}
+
+impl LoopbackHost {
+    /// Next event written by the device, `UnexpectedEof` once it was dropped
+    pub fn next_event(&mut self) -> io::Result<DeviceEvent> {
+        let mut event = vec![0u8; event_len()];
+        block_on(self.stream.read_exact(&mut event))?;
+        decode_device_event(&event)
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        self.send(sys::uhid_event_type_UHID_START, &[])
+    }
+
+    pub fn stop(&mut self) -> io::Result<()> {
+        self.send(sys::uhid_event_type_UHID_STOP, &[])
+    }
+
+    pub fn open(&mut self) -> io::Result<()> {
+        self.send(sys::uhid_event_type_UHID_OPEN, &[])
+    }
+
+    pub fn close(&mut self) -> io::Result<()> {
+        self.send(sys::uhid_event_type_UHID_CLOSE, &[])
+    }
+
+    /// Send an output report, as written by a host process to the hidraw node
+    pub fn output(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.len() > UHID_DATA_MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "output report larger than UHID_DATA_MAX",
+            ));
+        }
+        // struct uhid_output_req: data[UHID_DATA_MAX], __u16 size, __u8 rtype
+        let mut payload = vec![0u8; UHID_DATA_MAX + 3];
+        payload[..data.len()].copy_from_slice(data);
+        let size = (data.len() as u16).to_le_bytes();
+        payload[UHID_DATA_MAX..UHID_DATA_MAX + 2].copy_from_slice(&size);
+        payload[UHID_DATA_MAX + 2] = ReportType::Output as u8;
+        self.send(sys::uhid_event_type_UHID_OUTPUT, &payload)
+    }
+
+    fn send(&mut self, event_type: u32, payload: &[u8]) -> io::Result<()> {
+        let mut event = vec![0u8; event_len()];
+        event[..4].copy_from_slice(&event_type.to_le_bytes());
+        event[4..4 + payload.len()].copy_from_slice(payload);
+        block_on(self.stream.write_all(&event))
+    }
+}
+
+fn event_len() -> usize {
+    mem::size_of::<sys::uhid_event>()
+}
+
+/// Decode an event field by field, the inverse of what `Codec` encodes
+fn decode_device_event(event: &[u8]) -> io::Result<DeviceEvent> {
+    let u16_at = |offset: usize| u16::from_le_bytes([event[offset], event[offset + 1]]);
+    let u32_at = |offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&event[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    };
+    let cstr_at = |offset: usize, len: usize| {
+        let field = &event[offset..offset + len];
+        let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+        String::from_utf8_lossy(&field[..end]).into_owned()
+    };
+    let data_at = |offset: usize, len: usize| event[offset..offset + len].to_vec();
+
+    let event_type = u32_at(0);
+    match event_type {
+        sys::uhid_event_type_UHID_CREATE2 => {
+            // struct uhid_create2_req: name[128], phys[64], uniq[64], __u16 rd_size,
+            // __u16 bus, __u32 vendor, product, version, country, rd_data[]
+            let rd_size = u16_at(260) as usize;
+            Ok(DeviceEvent::Create {
+                name: cstr_at(4, 128),
+                uniq: cstr_at(196, 64),
+                vendor: u32_at(264),
+                product: u32_at(268),
+                data: data_at(280, rd_size),
+            })
+        }
+        sys::uhid_event_type_UHID_DESTROY => Ok(DeviceEvent::Destroy),
+        sys::uhid_event_type_UHID_INPUT2 => Ok(DeviceEvent::Input {
+            data: data_at(6, u16_at(4) as usize),
+        }),
+        sys::uhid_event_type_UHID_GET_REPORT_REPLY => Ok(DeviceEvent::GetReportReply {
+            id: u32_at(4),
+            err: u16_at(8),
+            data: data_at(12, u16_at(10) as usize),
+        }),
+        sys::uhid_event_type_UHID_SET_REPORT_REPLY => Ok(DeviceEvent::SetReportReply {
+            id: u32_at(4),
+            err: u16_at(8),
+        }),
+        t => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected uhid event type {} from device", t),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use codec::{Bus, OutputEvent};
+    use create_params::CreateParams;
+    use uhid_device::UHIDDevice;
+
+    fn params() -> CreateParams {
+        CreateParams {
+            name: String::from("test-uhid-device"),
+            phys: String::from(""),
+            uniq: String::from("loopback"),
+            bus: Bus::USB,
+            vendor: 0x15d9,
+            product: 0x0a37,
+            version: 0,
+            country: 0,
+            data: vec![0x06, 0xd0, 0xf1],
+        }
+    }
+
+    #[test]
+    fn device_and_host_exchange_reports() {
+        let (transport, mut host) = uhid_loopback();
+        let mut device = UHIDDevice::create_with(transport, params()).unwrap();
+
+        assert_eq!(
+            host.next_event().unwrap(),
+            DeviceEvent::Create {
+                name: String::from("test-uhid-device"),
+                uniq: String::from("loopback"),
+                vendor: 0x15d9,
+                product: 0x0a37,
+                data: vec![0x06, 0xd0, 0xf1],
+            }
+        );
+
+        host.start().unwrap();
+        host.open().unwrap();
+        host.output(&[0x00, 0x86, 0x00, 0x08]).unwrap();
+        match block_on(device.next()) {
+            Some(Ok(OutputEvent::Start { .. })) => {}
+            _ => panic!("Expected Start event"),
+        }
+        match block_on(device.next()) {
+            Some(Ok(OutputEvent::Open)) => {}
+            _ => panic!("Expected Open event"),
+        }
+        match block_on(device.next()) {
+            Some(Ok(OutputEvent::Output { data, .. })) => {
+                assert_eq!(data, vec![0x00, 0x86, 0x00, 0x08])
+            }
+            _ => panic!("Expected Output event"),
+        }
+
+        device.send_input(&[0x86, 0x00, 0x11]).unwrap();
+        assert_eq!(
+            host.next_event().unwrap(),
+            DeviceEvent::Input {
+                data: vec![0x86, 0x00, 0x11]
+            }
+        );
+
+        drop(device);
+        assert_eq!(host.next_event().unwrap(), DeviceEvent::Destroy);
+        assert_eq!(
+            host.next_event().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
@@ -1,49 +1,174 @@
 use std::fs::File;
-use std::io;
+use std::io::{self, Read, Write};
 use std::os::unix::io::FromRawFd;
 use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
+use futures::ready;
 use nix::{fcntl, libc, sys};
-use tokio::prelude::Read;
-use tokio::reactor::PollEvented2;
-use tokio_io::AsyncRead;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use character_device::CharacterDevice;
 
-pub struct MiscDriver(PollEvented2<CharacterDevice<File>>);
+/// Non-blocking handle to a misc character device registered with the ambient tokio runtime
+///
+/// Reads and writes are attempted directly before waiting on readiness, so a
+/// write can complete even if the runtime has not yet polled the device.
+pub struct MiscDriver(AsyncFd<CharacterDevice<File>>);
+
+/// How the device file is opened by `MiscDriver::open_with`
+///
+/// The defaults are those of `MiscDriver::open`: non-blocking, and mode `0o660` in
+/// case the path does not exist yet. `O_CLOEXEC` is always set so the descriptor
+/// never leaks into child processes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceOpenOptions {
+    nonblocking: bool,
+    mode: libc::mode_t,
+}
+
+impl DeviceOpenOptions {
+    pub fn new() -> DeviceOpenOptions {
+        DeviceOpenOptions {
+            nonblocking: true,
+            mode: libc::S_IRUSR | libc::S_IWUSR | libc::S_IRGRP | libc::S_IWGRP,
+        }
+    }
+
+    /// Open with `O_NONBLOCK`, on by default
+    ///
+    /// Without it a read with no pending kernel event blocks the runtime thread
+    /// polling the device, only turn it off when that thread is dedicated to it.
+    pub fn nonblocking(mut self, nonblocking: bool) -> DeviceOpenOptions {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    /// Permission bits used if the file is created, bits other than permissions are ignored
+    pub fn mode(mut self, mode: libc::mode_t) -> DeviceOpenOptions {
+        self.mode = mode;
+        self
+    }
+
+    fn flags(&self) -> fcntl::OFlag {
+        let mut flags = fcntl::OFlag::O_RDWR | fcntl::OFlag::O_CLOEXEC;
+        if self.nonblocking {
+            flags |= fcntl::OFlag::O_NONBLOCK;
+        }
+        flags
+    }
+
+    fn file_mode(&self) -> sys::stat::Mode {
+        sys::stat::Mode::from_bits_truncate(self.mode)
+    }
+}
+
+impl Default for DeviceOpenOptions {
+    fn default() -> DeviceOpenOptions {
+        DeviceOpenOptions::new()
+    }
+}
 
 impl MiscDriver {
+    /// Open the device file, must be called from within a tokio runtime
+    ///
+    /// Failures to open keep their errno, e.g. `EACCES` is `PermissionDenied` and
+    /// `EBUSY` is `ResourceBusy`, so callers can tell transient failures apart.
     pub fn open(path: &Path) -> io::Result<MiscDriver> {
-        let fd = fcntl::open(
-            path,
-            fcntl::OFlag::from_bits(libc::O_RDWR | libc::O_CLOEXEC | libc::O_NONBLOCK).unwrap(),
-            sys::stat::Mode::from_bits(libc::S_IRUSR | libc::S_IWUSR | libc::S_IRGRP | libc::S_IWGRP).unwrap(),
-        ).map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::Other,
+        Self::open_with(path, &DeviceOpenOptions::new())
+    }
+
+    /// Open the device file as described by `options`, otherwise like `open`
+    pub fn open_with(path: &Path, options: &DeviceOpenOptions) -> io::Result<MiscDriver> {
+        let flags = options.flags();
+        let mode = options.file_mode();
+        let fd = fcntl::open(path, flags, mode).map_err(|err| match err {
+            nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+            err => io::Error::new(
+                io::ErrorKind::InvalidInput,
                 format!("Cannot open uhid-cdev {:?}: {}", path, err),
-            )
+            ),
         })?;
         let file = unsafe { File::from_raw_fd(fd) };
         let character_device = CharacterDevice::new(file);
-        Ok(MiscDriver(PollEvented2::new(character_device)))
+        Ok(MiscDriver(AsyncFd::new(character_device)?))
     }
 }
 
-impl Read for MiscDriver {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
+impl AsyncRead for MiscDriver {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            match self.0.get_mut().read(buf.initialize_unfilled()) {
+                Ok(len) => {
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+            ready!(self.0.poll_read_ready_mut(cx))?.clear_ready();
+        }
+    }
+}
+
+impl AsyncWrite for MiscDriver {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            match self.0.get_mut().write(buf) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => return Poll::Ready(result),
+            }
+            ready!(self.0.poll_write_ready_mut(cx))?.clear_ready();
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.0.get_mut().flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
     }
 }
 
-impl AsyncRead for MiscDriver {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_options_match_previous_flags() {
+        let options = DeviceOpenOptions::new();
+
+        assert_eq!(
+            options.flags().bits(),
+            libc::O_RDWR | libc::O_CLOEXEC | libc::O_NONBLOCK
+        );
+        assert_eq!(options.file_mode().bits(), 0o660);
+    }
+
+    #[test]
+    fn requested_options_are_passed_to_open() {
+        let options = DeviceOpenOptions::new().nonblocking(false).mode(0o600);
 
-impl io::Write for MiscDriver {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.get_mut().write(buf)
+        assert_eq!(options.flags().bits(), libc::O_RDWR | libc::O_CLOEXEC);
+        assert_eq!(options.file_mode().bits(), 0o600);
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.0.get_mut().flush()
+    #[test]
+    fn cloexec_is_always_set() {
+        for &nonblocking in &[true, false] {
+            let options = DeviceOpenOptions::new().nonblocking(nonblocking);
+            assert!(options.flags().contains(fcntl::OFlag::O_CLOEXEC));
+        }
     }
 }
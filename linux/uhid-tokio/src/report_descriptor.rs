@@ -0,0 +1,266 @@
+//! Helpers for writing HID report descriptors
+//!
+//! See the "Device Class Definition for HID" specification, section 6.2.2 for the
+//! encoding of short items used here.
+
+/// Length of the input and output reports of a FIDO U2F HID device
+pub const FIDO_REPORT_LEN: u8 = 64;
+
+/// FIDO Alliance usage page, see http://www.usb.org/developers/hidpage/HUTRR48.pdf
+pub const FIDO_USAGE_PAGE: u16 = 0xf1d0;
+
+const FIDO_USAGE_U2FHID: u8 = 0x01;
+const FIDO_USAGE_DATA_IN: u8 = 0x20;
+const FIDO_USAGE_DATA_OUT: u8 = 0x21;
+
+const COLLECTION_APPLICATION: u8 = 0x01;
+const DATA_VARIABLE_ABSOLUTE: u8 = 0x02;
+
+// Short item prefixes with the size bits cleared
+const MAIN_INPUT: u8 = 0x80;
+const MAIN_OUTPUT: u8 = 0x90;
+const MAIN_COLLECTION: u8 = 0xa0;
+const MAIN_END_COLLECTION: u8 = 0xc0;
+const GLOBAL_USAGE_PAGE: u8 = 0x04;
+const GLOBAL_LOGICAL_MINIMUM: u8 = 0x14;
+const GLOBAL_LOGICAL_MAXIMUM: u8 = 0x24;
+const GLOBAL_REPORT_SIZE: u8 = 0x74;
+const GLOBAL_REPORT_COUNT: u8 = 0x94;
+const LOCAL_USAGE: u8 = 0x08;
+
+const PREFIX_LONG_ITEM: u8 = 0xfe;
+const PREFIX_SIZE_MASK: u8 = 0b11;
+
+/// Report descriptor of a FIDO U2F HID device with 64 byte input and output reports
+pub fn fido_u2f_hid() -> Vec<u8> {
+    ReportDescriptorBuilder::new()
+        .usage_page(FIDO_USAGE_PAGE)
+        .usage(FIDO_USAGE_U2FHID)
+        .collection(COLLECTION_APPLICATION)
+        .usage(FIDO_USAGE_DATA_IN)
+        .logical_minimum(0)
+        .logical_maximum(0xff)
+        .report_size(8)
+        .report_count(FIDO_REPORT_LEN as u32)
+        .input(DATA_VARIABLE_ABSOLUTE)
+        .usage(FIDO_USAGE_DATA_OUT)
+        .logical_minimum(0)
+        .logical_maximum(0xff)
+        .report_size(8)
+        .report_count(FIDO_REPORT_LEN as u32)
+        .output(DATA_VARIABLE_ABSOLUTE)
+        .end_collection()
+        .build()
+}
+
+/// Lengths in bytes of the input and output reports on the FIDO usage page
+///
+/// `None` if the descriptor never selects the FIDO usage page, so it is not meant
+/// for a FIDO device. Only what is needed to find the report lengths is parsed:
+/// the usage page, report size and report count globals and the input and output
+/// main items. Report IDs and the global item stack are ignored, FIDO descriptors
+/// use neither. A truncated last item is ignored.
+pub(crate) fn fido_report_lens(descriptor: &[u8]) -> Option<(usize, usize)> {
+    let mut is_fido = false;
+    let mut usage_page = 0;
+    let mut report_size = 0;
+    let mut report_count = 0;
+    let mut input_bits = 0;
+    let mut output_bits = 0;
+    let mut rest = descriptor;
+    while let Some((&prefix, data)) = rest.split_first() {
+        if prefix == PREFIX_LONG_ITEM {
+            // bDataSize, bLongItemTag, then the data
+            let len = 2 + data.first().cloned().unwrap_or(0) as usize;
+            rest = &data[len.min(data.len())..];
+            continue;
+        }
+        let len = match prefix & PREFIX_SIZE_MASK {
+            0b11 => 4,
+            size => size as usize,
+        };
+        if data.len() < len {
+            break;
+        }
+        let value = data[..len]
+            .iter()
+            .rev()
+            .fold(0u32, |value, &byte| (value << 8) | u32::from(byte));
+        rest = &data[len..];
+        match prefix & !PREFIX_SIZE_MASK {
+            GLOBAL_USAGE_PAGE => {
+                usage_page = value;
+                is_fido |= value == u32::from(FIDO_USAGE_PAGE);
+            }
+            GLOBAL_REPORT_SIZE => report_size = value as usize,
+            GLOBAL_REPORT_COUNT => report_count = value as usize,
+            MAIN_INPUT if usage_page == u32::from(FIDO_USAGE_PAGE) => {
+                input_bits += report_size * report_count
+            }
+            MAIN_OUTPUT if usage_page == u32::from(FIDO_USAGE_PAGE) => {
+                output_bits += report_size * report_count
+            }
+            _ => {}
+        }
+    }
+    if is_fido {
+        Some((input_bits / 8, output_bits / 8))
+    } else {
+        None
+    }
+}
+
+/// Writes report descriptor items, picking the smallest encoding for each value
+#[derive(Debug, Default)]
+pub struct ReportDescriptorBuilder {
+    bytes: Vec<u8>,
+}
+
+impl ReportDescriptorBuilder {
+    pub fn new() -> ReportDescriptorBuilder {
+        ReportDescriptorBuilder::default()
+    }
+
+    pub fn usage_page(self, usage_page: u16) -> Self {
+        self.unsigned_item(GLOBAL_USAGE_PAGE, usage_page as u32)
+    }
+
+    pub fn usage(self, usage: u8) -> Self {
+        self.unsigned_item(LOCAL_USAGE, usage as u32)
+    }
+
+    pub fn collection(self, collection_type: u8) -> Self {
+        self.unsigned_item(MAIN_COLLECTION, collection_type as u32)
+    }
+
+    pub fn end_collection(mut self) -> Self {
+        self.bytes.push(MAIN_END_COLLECTION);
+        self
+    }
+
+    pub fn logical_minimum(self, minimum: i32) -> Self {
+        self.signed_item(GLOBAL_LOGICAL_MINIMUM, minimum)
+    }
+
+    pub fn logical_maximum(self, maximum: i32) -> Self {
+        self.signed_item(GLOBAL_LOGICAL_MAXIMUM, maximum)
+    }
+
+    /// Size of each report field in bits
+    pub fn report_size(self, bits: u32) -> Self {
+        self.unsigned_item(GLOBAL_REPORT_SIZE, bits)
+    }
+
+    /// Number of fields in the report
+    pub fn report_count(self, count: u32) -> Self {
+        self.unsigned_item(GLOBAL_REPORT_COUNT, count)
+    }
+
+    pub fn input(self, flags: u8) -> Self {
+        self.unsigned_item(MAIN_INPUT, flags as u32)
+    }
+
+    pub fn output(self, flags: u8) -> Self {
+        self.unsigned_item(MAIN_OUTPUT, flags as u32)
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn unsigned_item(self, prefix: u8, value: u32) -> Self {
+        let size = if value <= 0xff {
+            1
+        } else if value <= 0xffff {
+            2
+        } else {
+            4
+        };
+        self.item(prefix, value, size)
+    }
+
+    fn signed_item(self, prefix: u8, value: i32) -> Self {
+        let size = if value >= i32::from(i8::MIN) && value <= i32::from(i8::MAX) {
+            1
+        } else if value >= i32::from(i16::MIN) && value <= i32::from(i16::MAX) {
+            2
+        } else {
+            4
+        };
+        self.item(prefix, value as u32, size)
+    }
+
+    fn item(mut self, prefix: u8, value: u32, size: usize) -> Self {
+        let size_bits = match size {
+            1 => 0b01,
+            2 => 0b10,
+            _ => 0b11,
+        };
+        self.bytes.push(prefix | size_bits);
+        for i in 0..size {
+            self.bytes.push((value >> (8 * i)) as u8);
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fido_u2f_hid_bytes() {
+        assert_eq!(
+            fido_u2f_hid(),
+            vec![
+                0x06, 0xd0, 0xf1, // USAGE_PAGE (FIDO Alliance)
+                0x09, 0x01, // USAGE (U2F HID Authenticator Device)
+                0xa1, 0x01, // COLLECTION (Application)
+                0x09, 0x20, //   USAGE (Input Report Data)
+                0x15, 0x00, //   LOGICAL_MINIMUM (0)
+                0x26, 0xff, 0x00, //   LOGICAL_MAXIMUM (255)
+                0x75, 0x08, //   REPORT_SIZE (8)
+                0x95, 0x40, //   REPORT_COUNT (64)
+                0x81, 0x02, //   INPUT (Data,Var,Abs)
+                0x09, 0x21, //   USAGE (Output Report Data)
+                0x15, 0x00, //   LOGICAL_MINIMUM (0)
+                0x26, 0xff, 0x00, //   LOGICAL_MAXIMUM (255)
+                0x75, 0x08, //   REPORT_SIZE (8)
+                0x95, 0x40, //   REPORT_COUNT (64)
+                0x91, 0x02, //   OUTPUT (Data,Var,Abs)
+                0xc0, // END_COLLECTION
+            ]
+        );
+    }
+
+    #[test]
+    fn fido_u2f_hid_report_lens() {
+        assert_eq!(
+            fido_report_lens(&fido_u2f_hid()),
+            Some((FIDO_REPORT_LEN as usize, FIDO_REPORT_LEN as usize))
+        );
+    }
+
+    #[test]
+    fn fido_report_lens_of_other_usage_page_is_none() {
+        let mouse = ReportDescriptorBuilder::new()
+            .usage_page(0x01)
+            .report_size(8)
+            .report_count(3)
+            .input(DATA_VARIABLE_ABSOLUTE)
+            .build();
+
+        assert_eq!(fido_report_lens(&mouse), None);
+    }
+
+    #[test]
+    fn negative_logical_minimum() {
+        let bytes = ReportDescriptorBuilder::new()
+            .logical_minimum(-127)
+            .logical_maximum(127)
+            .logical_minimum(-32768)
+            .build();
+
+        assert_eq!(bytes, vec![0x15, 0x81, 0x25, 0x7f, 0x16, 0x00, 0x80]);
+    }
+}
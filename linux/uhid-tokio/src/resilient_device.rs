@@ -0,0 +1,338 @@
+use std::cmp;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::Stream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{self as tokio_time, Sleep};
+
+use codec::OutputEvent;
+use create_params::CreateParams;
+use error::UHIDError;
+use misc_driver::MiscDriver;
+use uhid_device::{uhid_path, UHIDDevice};
+
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Event from a `ResilientDevice`
+pub enum ResilientEvent {
+    /// Output event from the current device
+    Output(OutputEvent),
+    /// The device was stopped and a new one created in its place
+    ///
+    /// Any state kept for the old device, such as CTAPHID channels, must be reset.
+    Recreated,
+}
+
+/// Device that is created again whenever the kernel stops or removes it
+///
+/// On suspend and resume the kernel may remove a virtual device. Once a `Stop` event
+/// or `ENODEV` is seen the device is dropped, a new transport is opened and the same
+/// `CreateParams` are used to create its replacement, then `Recreated` is yielded.
+/// A generated `uniq` is kept, so the replacement looks like the same device.
+///
+/// Failing to open a transport or to create a device on it, or a device that stops
+/// before it was started, delays the next attempt by a backoff that doubles up to a
+/// maximum. It is reset once a device starts. The stream never ends, it must be
+/// polled within a tokio runtime for backoff to work.
+pub struct ResilientDevice<T, F>
+where
+    T: AsyncWrite + Unpin,
+{
+    params: CreateParams,
+    open: F,
+    device: Option<UHIDDevice<T>>,
+    started: bool,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    backoff: Duration,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+/// Opens the character device found by `uhid_path`, see `ResilientDevice::create`
+pub type OpenUHID = fn() -> io::Result<MiscDriver>;
+
+impl ResilientDevice<MiscDriver, OpenUHID> {
+    /// Create a device using the character device found by `uhid_path`
+    ///
+    /// Must be called from within a tokio runtime, each device is registered with it.
+    pub fn create(params: CreateParams) -> io::Result<ResilientDevice<MiscDriver, OpenUHID>> {
+        Self::create_with(open_uhid, params)
+    }
+}
+
+impl<T, F> ResilientDevice<T, F>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut() -> io::Result<T> + Unpin,
+{
+    /// Create a device over transports returned by `open`, called again for every
+    /// replacement device
+    pub fn create_with(mut open: F, mut params: CreateParams) -> io::Result<ResilientDevice<T, F>> {
+        let device = UHIDDevice::create_with(open()?, params.clone())?;
+        params.uniq = device.uniq().to_string();
+        Ok(ResilientDevice {
+            params,
+            open,
+            device: Some(device),
+            started: false,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            backoff: DEFAULT_INITIAL_BACKOFF,
+            sleep: None,
+        })
+    }
+
+    /// Wait `initial` after the first failed attempt, doubling up to `max` for later
+    /// ones, instead of 100ms and 10s
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> ResilientDevice<T, F> {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self.backoff = initial;
+        self
+    }
+
+    /// Current device, `None` while it is being recreated
+    pub fn device(&self) -> Option<&UHIDDevice<T>> {
+        self.device.as_ref()
+    }
+
+    pub fn device_mut(&mut self) -> Option<&mut UHIDDevice<T>> {
+        self.device.as_mut()
+    }
+
+    /// Send a HID packet to the current device, fails with `DeviceStopped` while
+    /// it is being recreated
+    pub fn send_input(&mut self, data: &[u8]) -> Result<(), UHIDError> {
+        match self.device {
+            Some(ref mut device) => device.send_input(data),
+            None => Err(UHIDError::DeviceStopped),
+        }
+    }
+
+    fn recreate(&mut self) -> io::Result<()> {
+        let inner = (self.open)()?;
+        self.device = Some(UHIDDevice::create_with(inner, self.params.clone())?);
+        self.started = false;
+        Ok(())
+    }
+
+    fn wait_before_retry(&mut self) {
+        debug!(backoff = ?self.backoff, "Waiting before recreating device");
+        self.sleep = Some(Box::pin(tokio_time::sleep(self.backoff)));
+        self.backoff = cmp::min(self.backoff * 2, self.max_backoff);
+    }
+}
+
+impl<T, F> Stream for ResilientDevice<T, F>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut() -> io::Result<T> + Unpin,
+{
+    type Item = Result<ResilientEvent, UHIDError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                match sleep.as_mut().poll(cx) {
+                    Poll::Ready(()) => self.sleep = None,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            let device = match self.device.as_mut() {
+                Some(device) => device,
+                None => match self.recreate() {
+                    Ok(()) => {
+                        info!("Recreated device");
+                        return Poll::Ready(Some(Ok(ResilientEvent::Recreated)));
+                    }
+                    Err(err) => {
+                        warn!(error = %err, "Recreating device failed");
+                        self.wait_before_retry();
+                        continue;
+                    }
+                },
+            };
+            match Pin::new(device).poll_next(cx) {
+                Poll::Ready(Some(Ok(OutputEvent::Stop))) | Poll::Ready(None) => {
+                    info!("Device stopped, recreating it");
+                    self.device = None;
+                    if !self.started {
+                        self.wait_before_retry();
+                    }
+                }
+                Poll::Ready(Some(Ok(event @ OutputEvent::Start { .. }))) => {
+                    self.started = true;
+                    self.backoff = self.initial_backoff;
+                    return Poll::Ready(Some(Ok(ResilientEvent::Output(event))));
+                }
+                Poll::Ready(Some(Ok(event))) => {
+                    return Poll::Ready(Some(Ok(ResilientEvent::Output(event))));
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+fn open_uhid() -> io::Result<MiscDriver> {
+    MiscDriver::open(&uhid_path())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::mem;
+    use std::rc::Rc;
+
+    use futures::executor::block_on;
+    use futures::StreamExt;
+    use nix::libc;
+    use tokio::io::ReadBuf;
+    use uhid_sys as sys;
+
+    use super::*;
+    use codec::Bus;
+
+    const UHID_DESTROY: u8 = 0x01;
+    const UHID_START: u8 = 0x02;
+    const UHID_STOP: u8 = 0x03;
+    const UHID_CREATE2: u8 = 0x0b;
+
+    /// Device that replays a fixed list of kernel events, then has nothing to read
+    ///
+    /// The type of each event written to it is recorded in `written`, which is
+    /// shared by every device of a test.
+    struct ScriptedDevice {
+        events: VecDeque<u8>,
+        written: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl AsyncRead for ScriptedDevice {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &mut ReadBuf,
+        ) -> Poll<io::Result<()>> {
+            match self.events.pop_front() {
+                Some(event_type) => {
+                    let mut event = vec![0u8; mem::size_of::<sys::uhid_event>()];
+                    event[0] = event_type;
+                    buf.put_slice(&event);
+                    Poll::Ready(Ok(()))
+                }
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    impl AsyncWrite for ScriptedDevice {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.written.borrow_mut().push(buf[0]);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Opener handing out one scripted device per entry of `devices`, `None` fails to open
+    fn opener(
+        devices: Vec<Option<&'static [u8]>>,
+        written: Rc<RefCell<Vec<u8>>>,
+    ) -> impl FnMut() -> io::Result<ScriptedDevice> {
+        let mut devices: VecDeque<_> = devices.into_iter().collect();
+        move || match devices.pop_front().expect("No more devices to open") {
+            Some(events) => Ok(ScriptedDevice {
+                events: events.iter().cloned().collect(),
+                written: written.clone(),
+            }),
+            None => Err(io::Error::from_raw_os_error(libc::EBUSY)),
+        }
+    }
+
+    fn params() -> CreateParams {
+        CreateParams {
+            name: String::from("test-uhid-device"),
+            phys: String::from(""),
+            uniq: String::from(""),
+            bus: Bus::USB,
+            vendor: 0x15d9,
+            product: 0x0a37,
+            version: 0,
+            country: 0,
+            data: vec![0x05, 0x01],
+        }
+    }
+
+    #[test]
+    fn stop_creates_new_device() {
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let open = opener(
+            vec![Some(&[UHID_START, UHID_STOP][..]), Some(&[][..])],
+            written.clone(),
+        );
+        let mut device = ResilientDevice::create_with(open, params()).unwrap();
+        let uniq = device.device().unwrap().uniq().to_string();
+
+        match block_on(device.next()) {
+            Some(Ok(ResilientEvent::Output(OutputEvent::Start { .. }))) => {}
+            _ => panic!("Expected Start event"),
+        }
+        match block_on(device.next()) {
+            Some(Ok(ResilientEvent::Recreated)) => {}
+            _ => panic!("Expected Recreated event"),
+        }
+
+        assert_eq!(*written.borrow(), vec![UHID_CREATE2, UHID_CREATE2]);
+        assert_eq!(device.device().unwrap().uniq(), uniq);
+    }
+
+    #[test]
+    fn failed_recreation_is_retried_after_backoff() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let open = opener(
+            vec![Some(&[UHID_STOP][..]), None, None, Some(&[][..])],
+            written.clone(),
+        );
+        let mut device = ResilientDevice::create_with(open, params())
+            .unwrap()
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(4));
+
+        match runtime.block_on(device.next()) {
+            Some(Ok(ResilientEvent::Recreated)) => {}
+            _ => panic!("Expected Recreated event"),
+        }
+
+        assert_eq!(*written.borrow(), vec![UHID_CREATE2, UHID_CREATE2]);
+        assert_eq!(device.backoff, Duration::from_millis(4));
+    }
+
+    #[test]
+    fn send_input_goes_to_current_device() {
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let open = opener(vec![Some(&[][..])], written.clone());
+        let mut device = ResilientDevice::create_with(open, params()).unwrap();
+
+        device.send_input(&[1, 2, 3]).unwrap();
+        drop(device);
+
+        assert_eq!(*written.borrow(), vec![UHID_CREATE2, 0x0c, UHID_DESTROY]);
+    }
+}
@@ -1,12 +1,12 @@
 use std::fmt;
 use std::io;
-use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
-use bytes::buf::FromBuf;
 use bytes::BytesMut;
-use futures::{Async, Poll, Stream};
-use slog;
-use tokio_io::AsyncRead;
+use futures::task::noop_waker_ref;
+use futures::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 /// Decoding of items in buffers.
 ///
@@ -76,102 +76,181 @@ pub trait SyncSink {
     }
 }
 
+/// Adapts a character device to a `Stream` of decoded items and a `Sink` of
+/// items to encode.
+///
+/// Each item is read and written with a single call to the underlying device,
+/// as character devices such as `/dev/uhid` process exactly one event per call.
+///
+/// At most one encoded item is held while the device would block, until it is
+/// written `poll_ready` stays pending and further items remain with the caller.
 pub struct Transport<T, E, D> {
     inner: T,
     encoder: E,
     decoder: D,
-    logger: slog::Logger,
+    pending_write: Option<BytesMut>,
 }
 
 impl<T, E, D> Transport<T, E, D>
     where
-        T: AsyncRead + Write,
+        T: AsyncRead + AsyncWrite + Unpin,
         E: Encoder,
         D: Decoder,
 {
-    pub fn new(inner: T, encoder: E, decoder: D, logger: slog::Logger) -> Transport<T, E, D> {
+    pub fn new(inner: T, encoder: E, decoder: D) -> Transport<T, E, D> {
         Transport {
             decoder,
             encoder,
             inner,
-            logger,
+            pending_write: None,
         }
     }
+
+    pub(crate) fn encoder_mut(&mut self) -> &mut E {
+        &mut self.encoder
+    }
+
+    pub(crate) fn decoder_mut(&mut self) -> &mut D {
+        &mut self.decoder
+    }
 }
 
-impl<T: Write, E, D> Write for Transport<T, E, D> {
-    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
-        self.inner.write(src)
+impl<T, E, D> Transport<T, E, D>
+    where
+        T: AsyncWrite + Unpin,
+{
+    /// Write the held item, registering `cx` to be woken once the device is writable
+    fn poll_write_pending(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        if let Some(bytes) = self.pending_write.take() {
+            trace!(?bytes, "CharacterDevice::Sink::poll_write_pending");
+            match Pin::new(&mut self.inner).poll_write(cx, &bytes) {
+                Poll::Ready(result) => return Poll::Ready(check_write(result, bytes.len())),
+                Poll::Pending => {
+                    self.pending_write = Some(bytes);
+                    return Poll::Pending;
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.inner.flush()
+pub(crate) fn check_write(result: io::Result<usize>, len: usize) -> io::Result<()> {
+    match result {
+        Ok(0) => Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "failed to write item to transport",
+        )),
+        Ok(n) if n == len => Ok(()),
+        Ok(_) => Err(io::Error::other("failed to write entire item to transport")),
+        Err(e) => Err(e),
     }
 }
 
 impl<T, E, D> Stream for Transport<T, E, D>
     where
-        T: AsyncRead,
-        D: Decoder,
+        T: AsyncRead + Unpin,
+        E: Unpin,
+        D: Decoder + Unpin,
 {
-    type Item = D::Item;
-    type Error = D::Error;
+    type Item = Result<D::Item, D::Error>;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let read_len = self.decoder.read_len();
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let read_len = this.decoder.read_len();
         let mut buffer = vec![0u8; read_len];
-        match self.inner.read(&mut buffer[..]) {
-            Ok(0) => {
-                trace!(self.logger, "CharacterDevice::Stream::poll => Ok");
-                Ok(Async::Ready(None))
-            }
-            Ok(n) => {
+        let mut read_buf = ReadBuf::new(&mut buffer);
+        match Pin::new(&mut this.inner).poll_read(cx, &mut read_buf) {
+            Poll::Ready(Ok(())) => {
+                let n = read_buf.filled().len();
+                if n == 0 {
+                    trace!("CharacterDevice::Stream::poll_next => Ok");
+                    return Poll::Ready(None);
+                }
                 if n != read_len {
-                    return Err(io::Error::new(io::ErrorKind::InvalidData, "short read").into());
+                    let err = io::Error::new(io::ErrorKind::InvalidData, "short read");
+                    return Poll::Ready(Some(Err(err.into())));
                 }
-                let bytes = &mut BytesMut::from_buf(buffer);
-                trace!(self.logger, "CharacterDevice::Stream::poll => Ok"; "bytes" => ?&bytes);
-                let frame = self.decoder.decode(bytes)?;
-                Ok(Async::Ready(Some(frame)))
+                let bytes = &mut BytesMut::from(&buffer[..]);
+                trace!(?bytes, "CharacterDevice::Stream::poll_next => Ok");
+                Poll::Ready(Some(this.decoder.decode(bytes)))
             }
-            Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => {
-                trace!(self.logger, "CharacterDevice::Stream::poll => WouldBlock");
-                Ok(Async::NotReady)
+            Poll::Ready(Err(e)) => {
+                trace!("CharacterDevice::Stream::poll_next => Err");
+                Poll::Ready(Some(Err(e.into())))
             }
-            Err(e) => {
-                trace!(self.logger, "CharacterDevice::Stream::poll => Err");
-                Err(e.into())
+            Poll::Pending => {
+                trace!("CharacterDevice::Stream::poll_next => Pending");
+                Poll::Pending
             }
         }
     }
 }
 
+impl<T, E, D> Sink<E::Item> for Transport<T, E, D>
+    where
+        T: AsyncWrite + Unpin,
+        E: Encoder + Unpin,
+        D: Unpin,
+{
+    type Error = E::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_write_pending(cx).map_err(Into::into)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: E::Item) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        if this.pending_write.is_some() {
+            // Called without waiting for `poll_ready`, refuse rather than
+            // overwrite the write that is still waiting on the device
+            return Err(io::Error::from(io::ErrorKind::WouldBlock).into());
+        }
+        let mut buffer = BytesMut::new();
+        this.encoder.encode(item, &mut buffer)?;
+        this.pending_write = Some(buffer);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx).map_err(Into::into)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx).map_err(Into::into)
+    }
+}
+
+/// Writes items immediately, failing with `WouldBlock` if the device is not
+/// ready to accept the write. Nothing is buffered on failure and items are never
+/// written ahead of one still held by the `Sink` implementation.
 impl<T, E, D> SyncSink for Transport<T, E, D>
     where
-        T: Write,
+        T: AsyncWrite + Unpin,
         E: Encoder,
 {
     type SinkItem = E::Item;
     type SinkError = E::Error;
 
     fn send(&mut self, item: Self::SinkItem) -> Result<(), Self::SinkError> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        if self.poll_write_pending(&mut cx)?.is_pending() {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock).into());
+        }
+
         let mut buffer = BytesMut::new();
         self.encoder.encode(item, &mut buffer)?;
-        let bytes = buffer.take();
+        let bytes = buffer.split();
 
-        trace!(self.logger, "CharacterDevice::SyncSink::send"; "bytes" => ?&bytes);
+        trace!(?bytes, "CharacterDevice::SyncSink::send");
 
-        match self.inner.write(&bytes) {
-            Ok(0) => Err(io::Error::new(
-                io::ErrorKind::WriteZero,
-                "failed to write item to transport",
-            ).into()),
-            Ok(n) if n == bytes.len() => Ok(()),
-            Ok(_) => Err(io::Error::new(
-                io::ErrorKind::Other,
-                "failed to write entire item to transport",
-            ).into()),
-            Err(e) => Err(e.into()),
+        match Pin::new(&mut self.inner).poll_write(&mut cx, &bytes) {
+            Poll::Ready(result) => check_write(result, bytes.len()).map_err(Into::into),
+            Poll::Pending => Err(io::Error::from(io::ErrorKind::WouldBlock).into()),
         }
     }
 }
@@ -190,3 +269,184 @@ impl<T, E, D> fmt::Debug for Transport<T, E, D>
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::task::Waker;
+
+    use futures::task::{waker, ArcWake};
+
+    use super::*;
+
+    /// Device that accepts writes only while unblocked, like `/dev/uhid`
+    /// returning `WouldBlock` when the kernel queue is full
+    #[derive(Clone, Default)]
+    struct BlockingDevice {
+        state: Arc<Mutex<BlockingDeviceState>>,
+    }
+
+    #[derive(Default)]
+    struct BlockingDeviceState {
+        blocked: bool,
+        waker: Option<Waker>,
+        written: Vec<Vec<u8>>,
+    }
+
+    impl BlockingDevice {
+        fn set_blocked(&self, blocked: bool) {
+            let mut state = self.state.lock().unwrap();
+            state.blocked = blocked;
+            if !blocked {
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+
+        fn written(&self) -> Vec<Vec<u8>> {
+            self.state.lock().unwrap().written.clone()
+        }
+    }
+
+    impl AsyncRead for BlockingDevice {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context,
+            _buf: &mut ReadBuf,
+        ) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for BlockingDevice {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut state = self.state.lock().unwrap();
+            if state.blocked {
+                state.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            state.written.push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    struct ByteCodec;
+
+    impl Encoder for ByteCodec {
+        type Item = u8;
+        type Error = io::Error;
+
+        fn encode(&mut self, item: u8, buf: &mut BytesMut) -> io::Result<()> {
+            buf.extend_from_slice(&[item]);
+            Ok(())
+        }
+    }
+
+    impl Decoder for ByteCodec {
+        type Item = u8;
+        type Error = io::Error;
+
+        fn decode(&mut self, src: &mut BytesMut) -> io::Result<u8> {
+            Ok(src[0])
+        }
+
+        fn read_len(&self) -> usize {
+            1
+        }
+    }
+
+    #[derive(Default)]
+    struct WakeCounter(Mutex<usize>);
+
+    impl ArcWake for WakeCounter {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            *arc_self.0.lock().unwrap() += 1;
+        }
+    }
+
+    fn transport(device: &BlockingDevice) -> Transport<BlockingDevice, ByteCodec, ByteCodec> {
+        Transport::new(device.clone(), ByteCodec, ByteCodec)
+    }
+
+    #[test]
+    fn poll_ready_is_pending_while_device_would_block() {
+        let device = BlockingDevice::default();
+        let mut transport = transport(&device);
+        let wakes = Arc::new(WakeCounter::default());
+        let waker = waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+        device.set_blocked(true);
+
+        Pin::new(&mut transport).start_send(1).unwrap();
+        let flush = Pin::new(&mut transport).poll_flush(&mut cx);
+        let ready = Pin::new(&mut transport).poll_ready(&mut cx);
+
+        assert!(flush.is_pending());
+        assert!(ready.is_pending());
+        assert!(device.written().is_empty());
+
+        device.set_blocked(false);
+
+        assert_eq!(*wakes.0.lock().unwrap(), 1);
+        assert!(Pin::new(&mut transport).poll_ready(&mut cx).is_ready());
+        assert_eq!(device.written(), vec![vec![1]]);
+    }
+
+    #[test]
+    fn start_send_while_blocked_hands_item_back() {
+        let device = BlockingDevice::default();
+        let mut transport = transport(&device);
+        device.set_blocked(true);
+
+        Pin::new(&mut transport).start_send(1).unwrap();
+        let err = Pin::new(&mut transport).start_send(2).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        device.set_blocked(false);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(Pin::new(&mut transport).poll_flush(&mut cx).is_ready());
+        assert_eq!(device.written(), vec![vec![1]]);
+    }
+
+    #[test]
+    fn sync_send_would_block_without_buffering() {
+        let device = BlockingDevice::default();
+        let mut transport = transport(&device);
+        device.set_blocked(true);
+
+        let err = transport.send(1).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        device.set_blocked(false);
+        transport.send(2).unwrap();
+        assert_eq!(device.written(), vec![vec![2]]);
+    }
+
+    #[test]
+    fn sync_send_does_not_overtake_held_item() {
+        let device = BlockingDevice::default();
+        let mut transport = transport(&device);
+        device.set_blocked(true);
+        Pin::new(&mut transport).start_send(1).unwrap();
+
+        let err = transport.send(2).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        device.set_blocked(false);
+        transport.send(3).unwrap();
+        assert_eq!(device.written(), vec![vec![1], vec![3]]);
+    }
+}
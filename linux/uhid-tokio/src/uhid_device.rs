@@ -1,135 +1,1449 @@
-use std::io::{self, Write};
-use std::path::Path;
+use std::cmp;
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use std::task::{Context, Poll};
 
-use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
-use slog;
-use slog::Drain;
-use slog_stdlog;
-use tokio_io::AsyncRead;
+use futures::future::{self, Either};
+use futures::{Future, FutureExt, Sink, Stream};
+use nix::libc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{self as tokio_time, Interval, Sleep};
+use tracing::Span;
 
 use codec::*;
-use misc_driver::MiscDriver;
-use transport::{Decoder, Encoder, SyncSink, Transport};
+use create_params::CreateParams;
+use error::UHIDError;
+use misc_driver::{DeviceOpenOptions, MiscDriver};
+use transport::{SyncSink, Transport};
 
-pub struct UHIDDevice<T> {
-    inner: Transport<T, Codec, Codec>,
-    logger: slog::Logger,
-}
+const UHID_DEVICE_PATH_VAR: &str = "UHID_DEVICE_PATH";
+const UHID_DEVICE_PATHS: [&str; 2] = ["/dev/uhid", "/dev/misc/uhid"];
+const UHID_SYSFS_PATH: &str = "/sys/devices/virtual/misc/uhid";
+const HIDRAW_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Name of the span every operation on a device happens in, it has the device's
+/// `name` and `uniq` as fields
+pub const DEVICE_SPAN_NAME: &str = "uhid_device";
+
+static NEXT_DEVICE_NUMBER: AtomicUsize = AtomicUsize::new(0);
+
+/// Lifecycle of a device as last seen from its events, shared with `Heartbeat`
+const LIFECYCLE_CREATED: u8 = 0;
+const LIFECYCLE_STARTED: u8 = 1;
+const LIFECYCLE_STOPPED: u8 = 2;
+const LIFECYCLE_DESTROYED: u8 = 3;
 
-/// Parameters used to create UHID devices
-pub struct CreateParams {
-    pub name: String,
-    pub phys: String,
-    pub uniq: String,
-    pub bus: Bus,
-    pub vendor: u32,
-    pub product: u32,
-    pub version: u32,
-    pub country: u32,
-    pub data: Vec<u8>,
+pub struct UHIDDevice<T: AsyncWrite + Unpin> {
+    inner: Transport<T, Codec, Codec>,
+    span: Span,
+    destroyed: bool,
+    stopped: bool,
+    lifecycle: Arc<AtomicU8>,
+    name: String,
+    uniq: String,
 }
 
 impl UHIDDevice<MiscDriver> {
-    /// Create a UHID device using '/dev/uhid'
-    pub fn create<L: Into<Option<slog::Logger>>>(
+    /// Create a UHID device using the character device found by `uhid_path`
+    ///
+    /// Must be called from within a tokio runtime, the device is registered with it.
+    pub fn create(params: CreateParams) -> io::Result<UHIDDevice<MiscDriver>> {
+        Self::create_with_path(&uhid_path(), params)
+    }
+
+    /// Create a UHID device using the specified character misc-device file path
+    pub fn create_with_path(
+        path: &Path,
         params: CreateParams,
-        logger: L,
     ) -> io::Result<UHIDDevice<MiscDriver>> {
-        Self::create_with_path(Path::new("/dev/uhid"), params, logger)
+        Self::create_with(MiscDriver::open(path)?, params)
     }
 
-    /// Create a UHID device using the specified character misc-device file path
-    pub fn create_with_path<L: Into<Option<slog::Logger>>>(
+    /// Create a UHID device using the specified path, opened as described by `options`
+    ///
+    /// Must be called from within a tokio runtime, the device is registered with it.
+    pub fn create_with_options(
         path: &Path,
         params: CreateParams,
-        logger: L,
+        options: &DeviceOpenOptions,
     ) -> io::Result<UHIDDevice<MiscDriver>> {
-        Ok(Self::create_with(MiscDriver::open(path)?, params, logger))
+        Self::create_with(MiscDriver::open_with(path, options)?, params)
+    }
+
+    /// Create a UHID device using the specified path, retrying while opening it fails
+    /// with a transient error
+    ///
+    /// Right after boot or after a previous owner exits, opening the device can fail
+    /// with `ResourceBusy` or `PermissionDenied` until udev has applied its rules. Such
+    /// failures are retried up to `retries` times, waiting `backoff` before each retry.
+    /// Other errors, and the last transient one, are returned as is.
+    ///
+    /// Must be called from within a tokio runtime, the device is registered with it.
+    pub fn create_with_retry(
+        path: &Path,
+        params: CreateParams,
+        retries: usize,
+        backoff: Duration,
+    ) -> impl Future<Output = io::Result<UHIDDevice<MiscDriver>>> {
+        open_with_retry(path.to_path_buf(), retries, backoff)
+            .map(move |driver| Self::create_with(driver?, params))
+    }
+
+    /// Create a UHID device using `uhid_path` and wait until the kernel has started it
+    ///
+    /// Unlike `create`, input can be sent as soon as the returned future resolves.
+    /// Fails with `TimedOut` if the `Start` event is not seen within `timeout`.
+    pub fn create_and_ready(
+        params: CreateParams,
+        timeout: Duration,
+    ) -> impl Future<Output = io::Result<UHIDDevice<MiscDriver>>> {
+        match Self::create(params) {
+            Ok(device) => Either::Left(device.started(timeout)),
+            Err(err) => Either::Right(future::ready(Err(err))),
+        }
     }
 }
 
 impl<T> UHIDDevice<T>
 where
-    T: AsyncRead + Write,
+    T: AsyncRead + AsyncWrite + Unpin,
 {
-    fn create_with<L: Into<Option<slog::Logger>>>(
-        inner: T,
-        params: CreateParams,
-        logger: L,
-    ) -> UHIDDevice<T> {
-        let logger = logger
-            .into()
-            .unwrap_or(slog::Logger::root(slog_stdlog::StdLog.fuse(), o!()));
-        let logger = logger.new(o!("uhid_device" => params.name.to_string()));
+    /// Create a UHID device over any transport, e.g. an in-memory pipe in tests
+    ///
+    /// `inner` must behave like `/dev/uhid`: each write and read carries exactly
+    /// one `uhid_event`. The create event is written immediately, an error is returned
+    /// if `inner` cannot accept it without blocking or the write fails. Kernels that
+    /// reject `UHID_CREATE2` are sent the legacy `UHID_CREATE` instead.
+    ///
+    /// A blank `uniq` is replaced with one unique to this device, so that several
+    /// devices with the same name created by one or more processes can be told apart.
+    ///
+    /// The span created here is kept by the device and entered by every later
+    /// operation, so events from concurrent devices carry their `name` and `uniq`.
+    /// It is named `DEVICE_SPAN_NAME`.
+    pub fn create_with(inner: T, mut params: CreateParams) -> io::Result<UHIDDevice<T>> {
+        if params.uniq.is_empty() {
+            params.uniq = generate_uniq();
+        }
+        let span = debug_span!(
+            DEVICE_SPAN_NAME,
+            name = params.name.as_str(),
+            uniq = params.uniq.as_str()
+        );
+        let _enter = span.enter();
         let mut device = UHIDDevice {
-            inner: Transport::new(inner, Codec, Codec, logger.clone()),
-            logger: logger.clone(),
+            inner: Transport::new(inner, Codec::default(), Codec::default()),
+            span: span.clone(),
+            destroyed: false,
+            stopped: false,
+            lifecycle: Arc::new(AtomicU8::new(LIFECYCLE_CREATED)),
+            name: params.name.clone(),
+            uniq: params.uniq.clone(),
         };
-        debug!(logger, "Sending create device event");
-        device
-            .inner
-            .send(InputEvent::Create {
-                name: params.name,
-                phys: params.phys,
-                uniq: params.uniq,
-                bus: params.bus,
-                vendor: params.vendor,
-                product: params.product,
-                version: params.version,
-                country: params.country,
-                data: params.data,
-            })
-            .unwrap();
-        debug!(logger, "Sent create device event");
-        device
+        debug!("Sending create device event");
+        // A device that was never created must not send destroy when dropped
+        device.destroyed = true;
+        match device.inner.send(params.create_event()) {
+            Err(ref err) if is_create2_unsupported(err) => {
+                debug!("Kernel does not support UHID_CREATE2, falling back to UHID_CREATE");
+                device.inner.encoder_mut().use_legacy_create();
+                device
+                    .inner
+                    .send(params.create_event())
+                    .map_err(into_io_error)?;
+            }
+            result => result.map_err(into_io_error)?,
+        }
+        device.destroyed = false;
+        debug!("Sent create device event");
+        Ok(device)
+    }
+
+    /// Call `tap` with the bytes of every event read from the kernel, before decoding
+    ///
+    /// Meant for debugging interoperability with kernels and browsers, e.g. logging
+    /// exactly what the kernel sent. Events that fail to decode are passed too.
+    /// Without a tap events are decoded as usual, with no extra copy.
+    pub fn with_raw_tap<F>(mut self, tap: F) -> UHIDDevice<T>
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        self.inner.decoder_mut().set_raw_tap(Box::new(tap));
+        self
     }
 
     /// Send a HID packet to the UHID device
-    pub fn send_input(&mut self, data: &[u8]) -> Result<(), <Codec as Encoder>::Error> {
-        debug!(self.logger, "send input");
-        self.inner.send(InputEvent::Input {
+    pub fn send_input(&mut self, data: &[u8]) -> Result<(), UHIDError> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        debug!(len = data.len(), "send input");
+        self.send_now(InputEvent::Input {
             data: data.to_vec(),
         })
     }
 
+    /// Send several HID packets in order, e.g. every frame of one CTAPHID message
+    ///
+    /// `/dev/uhid` takes exactly one event per write, so this still makes one write per
+    /// frame, as many as calling `send_input` for each. What it saves is the rest of
+    /// the per-frame overhead: unlike `send_input` it waits for the device to accept
+    /// each write instead of failing with `WouldBlock`, and flushes once for the batch.
+    /// Resolves once every frame has been written.
+    pub fn send_inputs<'a>(&'a mut self, frames: &'a [[u8; 64]]) -> SendInputs<'a, T> {
+        SendInputs {
+            device: self,
+            frames,
+            next: 0,
+        }
+    }
+
+    /// Answer a `GetReport` output event, `id` must match the request being answered
+    pub fn send_get_report_reply(
+        &mut self,
+        id: u32,
+        err: u16,
+        data: Vec<u8>,
+    ) -> Result<(), UHIDError> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        debug!(id, err, "send get report reply");
+        self.send_now(InputEvent::GetReportReply { id, err, data })
+    }
+
+    /// Answer a `SetReport` output event, `id` must match the request being answered
+    pub fn send_set_report_reply(
+        &mut self,
+        id: u32,
+        err: u16,
+    ) -> Result<(), UHIDError> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        debug!(id, err, "send set report reply");
+        self.send_now(InputEvent::SetReportReply { id, err })
+    }
+
+    /// Resolve with this device once the kernel sends `Start`, or fail with `TimedOut`
+    ///
+    /// Other events seen before `Start` are discarded.
+    pub fn started(self, timeout: Duration) -> Started<T> {
+        Started {
+            device: Some(self),
+            timeout,
+            sleep: None,
+        }
+    }
+
+    /// Resolve with the next output event, or `None` if none arrives within `timeout`
+    ///
+    /// Fails with `UnexpectedEof` if the device's stream ends while waiting.
+    pub fn next_event_timeout<'a>(&'a mut self, timeout: Duration) -> NextEventTimeout<'a, T> {
+        NextEventTimeout {
+            device: self,
+            timeout,
+            sleep: None,
+        }
+    }
+
+    /// Whether the kernel stopped or removed the device, it cannot be used any more
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Whether the kernel has started the device and not stopped it since
+    ///
+    /// Only events read from the stream are seen, so this is as current as the last poll.
+    pub fn is_alive(&self) -> bool {
+        self.lifecycle.load(Ordering::Acquire) == LIFECYCLE_STARTED
+    }
+
+    /// Watchdog that checks every `interval` whether the device is still alive
+    ///
+    /// Fails with `DeviceStopped` once the kernel stops or removes the device, e.g.
+    /// after suspend and resume, so a daemon can recreate it. Resolves with `Ok` once
+    /// the device is destroyed by its owner. The device's stream must keep being
+    /// polled for a stop to be seen, the heartbeat can be spawned on its own task.
+    pub fn heartbeat(&self, interval: Duration) -> Heartbeat {
+        Heartbeat {
+            lifecycle: self.lifecycle.clone(),
+            interval,
+            ticks: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Unique identifier of the device, generated if none was given when creating it
+    pub fn uniq(&self) -> &str {
+        &self.uniq
+    }
+
+    /// Find the hidraw node (e.g. `/dev/hidraw3`) the kernel created for this device
+    ///
+    /// The node is created asynchronously after the create event is processed, until
+    /// it appears an error of kind `WouldBlock` is returned and the caller may retry.
+    pub fn resolve_hidraw_path(&self) -> io::Result<PathBuf> {
+        find_hidraw_path(Path::new(UHID_SYSFS_PATH), Path::new("/dev"), &self.name, &self.uniq)
+    }
+
+    /// Resolve with the hidraw node once this process can open it for reading and writing
+    ///
+    /// The node appearing is not enough for clients such as browsers, udev may still be
+    /// applying the rules that grant them access. Both the lookup and the open are
+    /// retried until `timeout` has passed, then this fails with `TimedOut`.
+    ///
+    /// Access is checked with the credentials of the calling process, so this is only
+    /// meaningful when these match the clients', i.e. not when running as root.
+    /// The timeout starts on first poll, which must happen within a tokio runtime.
+    pub fn wait_until_usable(
+        &self,
+        timeout: Duration,
+    ) -> impl Future<Output = io::Result<PathBuf>> {
+        let name = self.name.clone();
+        let uniq = self.uniq.clone();
+        future::lazy(move |_| {
+            wait_for_usable_hidraw(
+                PathBuf::from(UHID_SYSFS_PATH),
+                PathBuf::from("/dev"),
+                name,
+                uniq,
+                tokio_time::Instant::now() + timeout,
+            )
+        })
+        .flatten()
+    }
+
     /// Send a 'destroy' to the UHID device and close it
-    pub fn destroy(mut self) -> Result<(), <Codec as Encoder>::Error> {
-        debug!(self.logger, "destroy");
+    ///
+    /// A device already removed by the kernel has nothing left to destroy.
+    pub fn destroy(mut self) -> Result<(), UHIDError> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        debug!("destroy");
+        self.destroyed = true;
+        if self.stopped {
+            return Ok(());
+        }
+        self.lifecycle.store(LIFECYCLE_DESTROYED, Ordering::Release);
         self.inner.send(InputEvent::Destroy)?;
         self.inner.close()?;
         Ok(())
     }
+
+    fn send_now(&mut self, event: InputEvent) -> Result<(), UHIDError> {
+        self.check_not_stopped()?;
+        let result = self.inner.send(event);
+        result.map_err(|err| self.check_removed(err))
+    }
 }
 
-impl<T: AsyncRead> Stream for UHIDDevice<T> {
-    type Item = <Codec as Decoder>::Item;
-    type Error = <Codec as Decoder>::Error;
+impl<T: AsyncWrite + Unpin> UHIDDevice<T> {
+    fn check_not_stopped(&self) -> Result<(), UHIDError> {
+        if self.stopped {
+            Err(UHIDError::DeviceStopped)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// `ENODEV` means the kernel removed the device, mark it as stopped
+    fn check_removed(&mut self, err: UHIDError) -> UHIDError {
+        match err {
+            UHIDError::Io(ref io_err) if io_err.raw_os_error() == Some(libc::ENODEV) => {
+                debug!("Device removed by the kernel");
+                self.mark_stopped();
+                UHIDError::DeviceStopped
+            }
+            err => err,
+        }
+    }
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        debug!(self.logger, "Stream::poll");
-        self.inner.poll()
+    fn mark_stopped(&mut self) {
+        self.stopped = true;
+        self.lifecycle.store(LIFECYCLE_STOPPED, Ordering::Release);
     }
 }
 
-impl<T: Write> Sink for UHIDDevice<T> {
-    type SinkItem = <Codec as Encoder>::Item;
-    type SinkError = <Codec as Encoder>::Error;
+/// Dropping a device that was not explicitly destroyed makes a best-effort
+/// attempt to remove it from the kernel, failures are only logged.
+impl<T: AsyncWrite + Unpin> Drop for UHIDDevice<T> {
+    fn drop(&mut self) {
+        if self.destroyed || self.stopped {
+            return;
+        }
+        self.destroyed = true;
+        self.lifecycle.store(LIFECYCLE_DESTROYED, Ordering::Release);
+        let _enter = self.span.enter();
+        debug!("Destroying device on drop");
+        if let Err(err) = self.inner.send(InputEvent::Destroy) {
+            warn!(error = %err, "Failed to destroy device on drop");
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Stream for UHIDDevice<T> {
+    type Item = Result<OutputEvent, UHIDError>;
 
-    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        debug!(self.logger, "Sink::start_send");
-        self.inner.send(item)?;
-        Ok(AsyncSink::Ready)
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        trace!("Stream::poll_next");
+        if self.stopped {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(event @ OutputEvent::Start { .. }))) => {
+                self.lifecycle.store(LIFECYCLE_STARTED, Ordering::Release);
+                Poll::Ready(Some(Ok(event)))
+            }
+            Poll::Ready(Some(Ok(OutputEvent::Stop))) => {
+                debug!("Device stopped by the kernel");
+                self.mark_stopped();
+                Poll::Ready(Some(Ok(OutputEvent::Stop)))
+            }
+            Poll::Ready(Some(Err(err))) => match self.check_removed(err) {
+                UHIDError::DeviceStopped => Poll::Ready(None),
+                err => Poll::Ready(Some(Err(err))),
+            },
+            poll => poll,
+        }
     }
+}
 
-    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        debug!(self.logger, "Sink::poll_complete");
-        self.inner.flush()?;
-        Ok(Async::Ready(()))
+impl<T: AsyncWrite + Unpin> Sink<InputEvent> for UHIDDevice<T> {
+    type Error = UHIDError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.check_not_stopped()?;
+        Pin::new(&mut self.inner).poll_ready(cx)
     }
 
-    fn close(&mut self) -> Result<Async<()>, Self::SinkError> {
-        debug!(self.logger, "Sink::close");
-        self.inner.close()?;
-        Ok(Async::Ready(()))
+    fn start_send(mut self: Pin<&mut Self>, item: InputEvent) -> Result<(), Self::Error> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        debug!("Sink::start_send");
+        self.check_not_stopped()?;
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        match Pin::new(&mut self.inner).poll_flush(cx) {
+            Poll::Ready(Err(err)) => Poll::Ready(Err(self.check_removed(err))),
+            poll => poll,
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        debug!("Sink::poll_close");
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Future returned by `UHIDDevice::send_inputs`
+pub struct SendInputs<'a, T: AsyncWrite + Unpin> {
+    device: &'a mut UHIDDevice<T>,
+    frames: &'a [[u8; 64]],
+    next: usize,
+}
+
+impl<'a, T: AsyncWrite + Unpin> Future for SendInputs<'a, T> {
+    type Output = Result<(), UHIDError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let span = this.device.span.clone();
+        let _enter = span.enter();
+        while let Some(frame) = this.frames.get(this.next) {
+            match Pin::new(&mut *this.device).poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(this.device.check_removed(err))),
+                Poll::Pending => return Poll::Pending,
+            }
+            trace!(frame = this.next, "send inputs");
+            Pin::new(&mut *this.device).start_send(InputEvent::Input {
+                data: frame.to_vec(),
+            })?;
+            this.next += 1;
+        }
+        Pin::new(&mut *this.device).poll_flush(cx)
+    }
+}
+
+/// Future returned by `UHIDDevice::heartbeat`
+///
+/// The interval starts on first poll, which must happen within a tokio runtime.
+pub struct Heartbeat {
+    lifecycle: Arc<AtomicU8>,
+    interval: Duration,
+    ticks: Option<Interval>,
+}
+
+impl Future for Heartbeat {
+    type Output = Result<(), UHIDError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let interval = this.interval;
+        let ticks = this
+            .ticks
+            .get_or_insert_with(|| tokio_time::interval(interval));
+        while ticks.poll_tick(cx).is_ready() {
+            match this.lifecycle.load(Ordering::Acquire) {
+                LIFECYCLE_STOPPED => {
+                    debug!("Heartbeat found the device stopped");
+                    return Poll::Ready(Err(UHIDError::DeviceStopped));
+                }
+                LIFECYCLE_DESTROYED => return Poll::Ready(Ok(())),
+                _ => trace!("Heartbeat"),
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Future returned by `UHIDDevice::started`
+///
+/// The timeout starts on first poll, which must happen within a tokio runtime.
+pub struct Started<T: AsyncWrite + Unpin> {
+    device: Option<UHIDDevice<T>>,
+    timeout: Duration,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Future for Started<T> {
+    type Output = io::Result<UHIDDevice<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        loop {
+            let device = self
+                .device
+                .as_mut()
+                .expect("Started polled after completion");
+            match Pin::new(device).poll_next(cx) {
+                Poll::Ready(Some(Ok(OutputEvent::Start { .. }))) => {
+                    return Poll::Ready(Ok(self.device.take().unwrap()));
+                }
+                Poll::Ready(Some(Ok(_))) => continue,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(into_io_error(err))),
+                Poll::Ready(None) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "device closed before it was started",
+                    )));
+                }
+                Poll::Pending => break,
+            }
+        }
+        let timeout = self.timeout;
+        let sleep = self
+            .sleep
+            .get_or_insert_with(|| Box::pin(tokio_time::sleep(timeout)));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out waiting for the device to start",
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Future returned by `UHIDDevice::next_event_timeout`
+///
+/// The timeout starts on first poll, which must happen within a tokio runtime.
+pub struct NextEventTimeout<'a, T: AsyncWrite + Unpin> {
+    device: &'a mut UHIDDevice<T>,
+    timeout: Duration,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<'a, T: AsyncRead + AsyncWrite + Unpin> Future for NextEventTimeout<'a, T> {
+    type Output = io::Result<Option<OutputEvent>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match Pin::new(&mut *self.device).poll_next(cx) {
+            Poll::Ready(Some(Ok(event))) => return Poll::Ready(Ok(Some(event))),
+            Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(into_io_error(err))),
+            Poll::Ready(None) => {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "device closed while waiting for an event",
+                )));
+            }
+            Poll::Pending => {}
+        }
+        let timeout = self.timeout;
+        let sleep = self
+            .sleep
+            .get_or_insert_with(|| Box::pin(tokio_time::sleep(timeout)));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Ok(None)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+fn open_with_retry(
+    path: PathBuf,
+    retries: usize,
+    backoff: Duration,
+) -> Pin<Box<dyn Future<Output = io::Result<MiscDriver>>>> {
+    match MiscDriver::open(&path) {
+        Err(ref err) if retries > 0 && is_transient_open_error(err) => {
+            warn!(error = %err, ?path, retries, "Opening device failed, retrying");
+            Box::pin(
+                tokio_time::sleep(backoff)
+                    .then(move |()| open_with_retry(path, retries - 1, backoff)),
+            )
+        }
+        result => Box::pin(future::ready(result)),
+    }
+}
+
+fn wait_for_usable_hidraw(
+    uhid_sysfs_path: PathBuf,
+    dev_path: PathBuf,
+    name: String,
+    uniq: String,
+    deadline: tokio_time::Instant,
+) -> Pin<Box<dyn Future<Output = io::Result<PathBuf>>>> {
+    let result = find_hidraw_path(&uhid_sysfs_path, &dev_path, &name, &uniq).and_then(|path| {
+        OpenOptions::new().read(true).write(true).open(&path)?;
+        Ok(path)
+    });
+    match result {
+        Err(ref err) if is_pending_hidraw_error(err) => {
+            let now = tokio_time::Instant::now();
+            if now >= deadline {
+                return Box::pin(future::ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out waiting for the hidraw device node to be usable",
+                ))));
+            }
+            trace!(error = %err, "hidraw device node not usable yet");
+            let sleep = cmp::min(HIDRAW_POLL_INTERVAL, deadline - now);
+            Box::pin(tokio_time::sleep(sleep).then(move |()| {
+                wait_for_usable_hidraw(uhid_sysfs_path, dev_path, name, uniq, deadline)
+            }))
+        }
+        result => Box::pin(future::ready(result)),
+    }
+}
+
+/// Errors expected while the kernel and udev are still setting up the node
+fn is_pending_hidraw_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied
+    )
+}
+
+fn is_transient_open_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ResourceBusy | io::ErrorKind::PermissionDenied
+    )
+}
+
+/// Path of the UHID character device used by `create`
+///
+/// The first existing one of `$UHID_DEVICE_PATH`, '/dev/uhid' and '/dev/misc/uhid'. If
+/// none exists '/dev/uhid' is returned, so opening it fails with `NotFound`.
+pub fn uhid_path() -> PathBuf {
+    uhid_path_with_override(env::var_os(UHID_DEVICE_PATH_VAR))
+}
+
+/// `uhid_path` with the value of `$UHID_DEVICE_PATH` passed in
+fn uhid_path_with_override(from_env: Option<OsString>) -> PathBuf {
+    from_env
+        .map(PathBuf::from)
+        .into_iter()
+        .chain(UHID_DEVICE_PATHS.iter().map(PathBuf::from))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| PathBuf::from(UHID_DEVICE_PATHS[0]))
+}
+
+pub(crate) fn into_io_error(err: UHIDError) -> io::Error {
+    match err {
+        UHIDError::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+pub(crate) fn generate_uniq() -> String {
+    let device_number = NEXT_DEVICE_NUMBER.fetch_add(1, Ordering::Relaxed);
+    format!("{}-{}", process::id(), device_number)
+}
+
+fn find_hidraw_path(
+    uhid_sysfs_path: &Path,
+    dev_path: &Path,
+    name: &str,
+    uniq: &str,
+) -> io::Result<PathBuf> {
+    for entry in fs::read_dir(uhid_sysfs_path)? {
+        let device_path = entry?.path();
+        let uevent = match fs::read_to_string(device_path.join("uevent")) {
+            Ok(uevent) => uevent,
+            Err(_) => continue,
+        };
+        if !uevent_matches(&uevent, name, uniq) {
+            continue;
+        }
+        if let Ok(mut nodes) = fs::read_dir(device_path.join("hidraw")) {
+            if let Some(node) = nodes.next() {
+                return Ok(dev_path.join(node?.file_name()));
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::WouldBlock,
+        "hidraw device node is not available yet",
+    ))
+}
+
+fn uevent_matches(uevent: &str, name: &str, uniq: &str) -> bool {
+    let mut name_matches = false;
+    let mut uniq_matches = uniq.is_empty();
+    for line in uevent.lines() {
+        if let Some(value) = line.strip_prefix("HID_NAME=") {
+            name_matches = value == name;
+        } else if let Some(value) = line.strip_prefix("HID_UNIQ=") {
+            uniq_matches = value == uniq;
+        }
+    }
+    name_matches && uniq_matches
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, VecDeque};
+    use std::fmt;
+    use std::mem;
+    use std::process;
+    use std::sync::Mutex;
+
+    use futures::executor::block_on;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::ReadBuf;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+    use uhid_sys as sys;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct RecordingDevice {
+        readable: Arc<Mutex<VecDeque<io::Result<u8>>>>,
+        written: Arc<Mutex<Vec<Vec<u8>>>>,
+        write_errors: Arc<Mutex<VecDeque<i32>>>,
+    }
+
+    impl RecordingDevice {
+        /// Queue a kernel event of the given type to be read
+        fn push_event(&self, event_type: u8) {
+            self.readable.lock().unwrap().push_back(Ok(event_type));
+        }
+
+        /// Queue a failed read with the given errno
+        fn push_read_error(&self, errno: i32) {
+            self.readable
+                .lock()
+                .unwrap()
+                .push_back(Err(io::Error::from_raw_os_error(errno)));
+        }
+
+        /// Fail the next write with the given errno
+        fn push_write_error(&self, errno: i32) {
+            self.write_errors.lock().unwrap().push_back(errno);
+        }
+
+        fn event_types(&self) -> Vec<u8> {
+            self.written.lock().unwrap().iter().map(|event| event[0]).collect()
+        }
+    }
+
+    impl AsyncRead for RecordingDevice {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &mut ReadBuf,
+        ) -> Poll<io::Result<()>> {
+            match self.readable.lock().unwrap().pop_front() {
+                Some(Err(err)) => Poll::Ready(Err(err)),
+                Some(Ok(event_type)) => {
+                    let mut event = vec![0u8; mem::size_of::<sys::uhid_event>()];
+                    event[0] = event_type;
+                    buf.put_slice(&event);
+                    Poll::Ready(Ok(()))
+                }
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    impl AsyncWrite for RecordingDevice {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            assert_eq!(buf.len(), mem::size_of::<sys::uhid_event>());
+            if let Some(errno) = self.write_errors.lock().unwrap().pop_front() {
+                return Poll::Ready(Err(io::Error::from_raw_os_error(errno)));
+            }
+            self.written.lock().unwrap().push(buf.to_vec());
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn params() -> CreateParams {
+        CreateParams {
+            name: String::from("test-uhid-device"),
+            phys: String::from(""),
+            uniq: String::from(""),
+            bus: Bus::USB,
+            vendor: 0x15d9,
+            product: 0x0a37,
+            version: 0,
+            country: 0,
+            data: vec![0x05, 0x01],
+        }
+    }
+
+    #[test]
+    fn drop_sends_destroy() {
+        let recorder = RecordingDevice::default();
+
+        drop(UHIDDevice::create_with(recorder.clone(), params()).unwrap());
+
+        assert_eq!(recorder.event_types(), vec![0x0b, 0x01]);
+    }
+
+    #[test]
+    fn create_fails_on_write_error() {
+        let recorder = RecordingDevice::default();
+        recorder.push_write_error(libc::EACCES);
+
+        let err = UHIDDevice::create_with(recorder.clone(), params())
+            .err()
+            .unwrap();
+
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        assert!(recorder.event_types().is_empty());
+    }
+
+    #[test]
+    fn destroy_then_drop_sends_single_destroy() {
+        let recorder = RecordingDevice::default();
+
+        UHIDDevice::create_with(recorder.clone(), params())
+            .unwrap()
+            .destroy()
+            .unwrap();
+
+        assert_eq!(recorder.event_types(), vec![0x0b, 0x01]);
+    }
+
+    #[test]
+    fn sink_writes_input_event() {
+        let recorder = RecordingDevice::default();
+        let mut device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+
+        block_on(device.send(InputEvent::Input { data: vec![1, 2, 3] })).unwrap();
+
+        assert_eq!(recorder.event_types(), vec![0x0b, 0x0c]);
+    }
+
+    #[test]
+    fn send_input_rejects_oversized_payload() {
+        let recorder = RecordingDevice::default();
+        let mut device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+
+        match device.send_input(&[0u8; 5000]) {
+            Err(UHIDError::PayloadTooLarge { len: 5000, .. }) => {}
+            result => panic!("Expected PayloadTooLarge error, got {:?}", result),
+        }
+        assert_eq!(recorder.event_types(), vec![0x0b]);
+    }
+
+    #[test]
+    fn blank_uniq_is_generated_per_device() {
+        let recorders: Vec<RecordingDevice> = (0..3).map(|_| RecordingDevice::default()).collect();
+
+        let devices: Vec<_> = recorders
+            .iter()
+            .map(|recorder| UHIDDevice::create_with(recorder.clone(), params()).unwrap())
+            .collect();
+
+        let mut uniqs: Vec<&str> = devices.iter().map(|device| device.uniq()).collect();
+        assert!(uniqs.iter().all(|uniq| !uniq.is_empty()));
+        uniqs.sort();
+        uniqs.dedup();
+        assert_eq!(uniqs.len(), 3);
+        for (device, recorder) in devices.iter().zip(&recorders) {
+            let create_event = recorder.written.lock().unwrap()[0].clone();
+            let uniq_offset = 4 + 128 + 64;
+            let uniq = &create_event[uniq_offset..uniq_offset + device.uniq().len()];
+            assert_eq!(uniq, device.uniq().as_bytes());
+        }
+    }
+
+    #[test]
+    fn given_uniq_is_kept() {
+        let mut params = params();
+        params.uniq = String::from("token-1");
+
+        let device = UHIDDevice::create_with(RecordingDevice::default(), params).unwrap();
+
+        assert_eq!(device.uniq(), "token-1");
+    }
+
+    #[test]
+    fn started_waits_for_start_event() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let recorder = RecordingDevice::default();
+        recorder.push_event(0x04);
+        recorder.push_event(0x02);
+        let device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+
+        let device = runtime
+            .block_on(device.started(Duration::from_secs(5)))
+            .unwrap();
+
+        assert!(recorder.readable.lock().unwrap().is_empty());
+        assert_eq!(device.name(), "test-uhid-device");
+    }
+
+    #[test]
+    fn started_times_out_without_start_event() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let recorder = RecordingDevice::default();
+        let device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+
+        let err = runtime
+            .block_on(device.started(Duration::from_millis(10)))
+            .map(|_| ())
+            .unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn next_event_timeout_returns_event_in_time() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let recorder = RecordingDevice::default();
+        recorder.push_event(0x04);
+        let mut device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+
+        match runtime.block_on(device.next_event_timeout(Duration::from_secs(5))) {
+            Ok(Some(OutputEvent::Open)) => {}
+            _ => panic!("Expected Open event"),
+        }
+    }
+
+    #[test]
+    fn next_event_timeout_returns_none_without_event() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let recorder = RecordingDevice::default();
+        let mut device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+
+        let event = runtime
+            .block_on(device.next_event_timeout(Duration::from_millis(10)))
+            .unwrap();
+
+        assert!(event.is_none());
+        assert!(!device.is_stopped());
+    }
+
+    #[test]
+    fn read_enodev_ends_stream_and_stops_device() {
+        let recorder = RecordingDevice::default();
+        recorder.push_read_error(libc::ENODEV);
+        let mut device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+
+        assert!(block_on(device.next()).is_none());
+        assert!(device.is_stopped());
+        match device.send_input(&[1, 2, 3]) {
+            Err(UHIDError::DeviceStopped) => {}
+            result => panic!("Expected DeviceStopped error, got {:?}", result),
+        }
+        drop(device);
+        assert_eq!(recorder.event_types(), vec![0x0b]);
+    }
+
+    #[test]
+    fn other_read_errors_are_passed_through() {
+        let recorder = RecordingDevice::default();
+        recorder.push_read_error(libc::EIO);
+        let mut device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+
+        match block_on(device.next()) {
+            Some(Err(UHIDError::Io(ref err))) if err.raw_os_error() == Some(libc::EIO) => {}
+            _ => panic!("Expected EIO error"),
+        }
+        assert!(!device.is_stopped());
+    }
+
+    #[test]
+    fn stop_event_ends_stream() {
+        let recorder = RecordingDevice::default();
+        recorder.push_event(0x03);
+        recorder.push_event(0x04);
+        let mut device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+
+        match block_on(device.next()) {
+            Some(Ok(OutputEvent::Stop)) => {}
+            _ => panic!("Expected Stop event"),
+        }
+        assert!(block_on(device.next()).is_none());
+        match block_on(device.send(InputEvent::Input { data: vec![1] })) {
+            Err(UHIDError::DeviceStopped) => {}
+            result => panic!("Expected DeviceStopped error, got {:?}", result),
+        }
+        device.destroy().unwrap();
+        assert_eq!(recorder.event_types(), vec![0x0b]);
+    }
+
+    #[test]
+    fn send_inputs_writes_frames_in_order() {
+        let recorder = RecordingDevice::default();
+        let mut device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+        let mut frames = [[0u8; 64]; 20];
+        for (index, frame) in frames.iter_mut().enumerate() {
+            frame[0] = index as u8;
+        }
+
+        block_on(device.send_inputs(&frames)).unwrap();
+
+        let written = recorder.written.lock().unwrap();
+        assert_eq!(written.len(), 21);
+        for (index, event) in written[1..].iter().enumerate() {
+            // __u16 size precedes the data of an input2 event
+            assert_eq!(&event[0..7], &[0x0c, 0, 0, 0, 64, 0, index as u8]);
+        }
+    }
+
+    #[test]
+    fn send_inputs_after_stop_is_device_stopped() {
+        let recorder = RecordingDevice::default();
+        recorder.push_event(0x03);
+        let mut device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+        block_on(device.next());
+
+        match block_on(device.send_inputs(&[[0u8; 64]; 2])) {
+            Err(UHIDError::DeviceStopped) => {}
+            result => panic!("Expected DeviceStopped error, got {:?}", result),
+        }
+        assert_eq!(recorder.event_types(), vec![0x0b]);
+    }
+
+    #[test]
+    fn stop_event_ends_is_alive() {
+        let recorder = RecordingDevice::default();
+        let mut device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+        assert!(!device.is_alive());
+
+        recorder.push_event(0x02);
+        block_on(device.next());
+        assert!(device.is_alive());
+
+        recorder.push_event(0x03);
+        block_on(device.next());
+        assert!(!device.is_alive());
+    }
+
+    #[test]
+    fn heartbeat_fails_once_device_stops() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let recorder = RecordingDevice::default();
+        recorder.push_event(0x02);
+        recorder.push_event(0x03);
+        let mut device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+        let heartbeat = device.heartbeat(Duration::from_millis(10));
+
+        block_on(device.next());
+        block_on(device.next());
+
+        match runtime.block_on(heartbeat) {
+            Err(UHIDError::DeviceStopped) => {}
+            result => panic!("Expected DeviceStopped error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn heartbeat_ends_once_device_destroyed() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let recorder = RecordingDevice::default();
+        let device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+        let heartbeat = device.heartbeat(Duration::from_millis(10));
+
+        device.destroy().unwrap();
+
+        assert!(runtime.block_on(heartbeat).is_ok());
+    }
+
+    /// `uniq` of the device span an event was emitted in, if any, and its message
+    type CapturedEvent = (Option<String>, String);
+
+    /// Subscriber recording the message of each event along with the `uniq` of
+    /// the device span it was emitted in, if any
+    #[derive(Default)]
+    struct CapturingSubscriber {
+        next_id: AtomicUsize,
+        spans: Mutex<HashMap<u64, CapturedSpan>>,
+        entered: Mutex<Vec<u64>>,
+        events: Arc<Mutex<Vec<CapturedEvent>>>,
+    }
+
+    struct CapturedSpan {
+        parent: Option<u64>,
+        uniq: Option<String>,
+    }
+
+    /// Collects the `uniq` and `message` fields
+    #[derive(Default)]
+    struct FieldVisitor {
+        uniq: Option<String>,
+        message: Option<String>,
+    }
+
+    impl Visit for FieldVisitor {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "uniq" {
+                self.uniq = Some(value.to_string());
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == "message" {
+                self.message = Some(format!("{:?}", value));
+            }
+        }
+    }
+
+    impl CapturingSubscriber {
+        fn device_uniq(&self, mut span: Option<u64>) -> Option<String> {
+            let spans = self.spans.lock().unwrap();
+            while let Some(captured) = span.and_then(|id| spans.get(&id)) {
+                if captured.uniq.is_some() {
+                    return captured.uniq.clone();
+                }
+                span = captured.parent;
+            }
+            None
+        }
+    }
+
+    impl Subscriber for CapturingSubscriber {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, attrs: &Attributes) -> Id {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed) as u64 + 1;
+            let parent = match attrs.parent() {
+                Some(parent) => Some(parent.into_u64()),
+                None if attrs.is_contextual() => self.entered.lock().unwrap().last().cloned(),
+                None => None,
+            };
+            let mut visitor = FieldVisitor::default();
+            attrs.record(&mut visitor);
+            let span = CapturedSpan {
+                parent,
+                uniq: visitor.uniq,
+            };
+            self.spans.lock().unwrap().insert(id, span);
+            Id::from_u64(id)
+        }
+
+        fn record(&self, span: &Id, values: &Record) {
+            let mut visitor = FieldVisitor::default();
+            values.record(&mut visitor);
+            if let Some(uniq) = visitor.uniq {
+                if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+                    span.uniq = Some(uniq);
+                }
+            }
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut visitor = FieldVisitor::default();
+            event.record(&mut visitor);
+            let span = self.entered.lock().unwrap().last().cloned();
+            let uniq = self.device_uniq(span);
+            if let Some(message) = visitor.message {
+                self.events.lock().unwrap().push((uniq, message));
+            }
+        }
+
+        fn enter(&self, span: &Id) {
+            self.entered.lock().unwrap().push(span.into_u64());
+        }
+
+        fn exit(&self, _span: &Id) {
+            self.entered.lock().unwrap().pop();
+        }
+    }
+
+    #[test]
+    fn device_events_are_recorded_in_its_span() {
+        let subscriber = CapturingSubscriber::default();
+        let events = subscriber.events.clone();
+        let recorder = RecordingDevice::default();
+
+        let uniq = tracing::subscriber::with_default(subscriber, || {
+            let mut device = UHIDDevice::create_with(recorder.clone(), params()).unwrap();
+            device.send_input(&[1, 2, 3]).unwrap();
+            device.uniq().to_string()
+        });
+
+        let events = events.lock().unwrap();
+        let sent_create = (Some(uniq.clone()), String::from("Sent create device event"));
+        let sent_input = (Some(uniq), String::from("send input"));
+        assert!(events.contains(&sent_create));
+        assert!(events.contains(&sent_input));
+    }
+
+    /// Read one event written to the other end of a duplex pipe
+    fn read_event(kernel: &mut tokio::io::DuplexStream) -> Vec<u8> {
+        use tokio::io::AsyncReadExt;
+        let mut event = vec![0u8; mem::size_of::<sys::uhid_event>()];
+        block_on(kernel.read_exact(&mut event)).unwrap();
+        event
+    }
+
+    #[test]
+    fn duplex_receives_create_event() {
+        let (device_end, mut kernel) = tokio::io::duplex(4 * mem::size_of::<sys::uhid_event>());
+
+        let _device = UHIDDevice::create_with(device_end, params()).unwrap();
+
+        let event = read_event(&mut kernel);
+        assert_eq!(&event[0..4], &[0x0b, 0, 0, 0]);
+        assert_eq!(&event[4..20], b"test-uhid-device");
+        // __u16 rd_size and bus, then __u32 vendor and product
+        assert_eq!(&event[260..264], &[2, 0, 3, 0]);
+        assert_eq!(&event[264..272], &[0xd9, 0x15, 0, 0, 0x37, 0x0a, 0, 0]);
+    }
+
+    #[test]
+    fn duplex_round_trips_data_events() {
+        use tokio::io::AsyncWriteExt;
+        let (device_end, mut kernel) = tokio::io::duplex(4 * mem::size_of::<sys::uhid_event>());
+        let mut device = UHIDDevice::create_with(device_end, params()).unwrap();
+        read_event(&mut kernel);
+
+        device.send_input(&[1, 2, 3]).unwrap();
+
+        let input = read_event(&mut kernel);
+        assert_eq!(&input[0..9], &[0x0c, 0, 0, 0, 3, 0, 1, 2, 3]);
+
+        let mut output = vec![0u8; mem::size_of::<sys::uhid_event>()];
+        output[0] = 0x06;
+        output[4..6].copy_from_slice(&[0xab, 0xcd]);
+        // __u16 size and __u8 rtype follow the UHID_DATA_MAX bytes of data
+        output[4100] = 2;
+        output[4102] = 0x01;
+        block_on(kernel.write_all(&output)).unwrap();
+
+        match block_on(device.next()) {
+            Some(Ok(OutputEvent::Output {
+                report_type: ReportType::Output,
+                data,
+                ..
+            })) => assert_eq!(data, vec![0xab, 0xcd]),
+            _ => panic!("Expected Output event"),
+        }
+    }
+
+    #[test]
+    fn raw_tap_sees_undecoded_event() {
+        use tokio::io::AsyncWriteExt;
+        let (device_end, mut kernel) = tokio::io::duplex(4 * mem::size_of::<sys::uhid_event>());
+        let tapped = Arc::new(Mutex::new(Vec::new()));
+        let tapped_by_device = tapped.clone();
+        let mut device = UHIDDevice::create_with(device_end, params())
+            .unwrap()
+            .with_raw_tap(move |bytes| tapped_by_device.lock().unwrap().push(bytes.to_vec()));
+        read_event(&mut kernel);
+
+        let mut output = vec![0u8; mem::size_of::<sys::uhid_event>()];
+        output[0] = 0x06;
+        output[4..7].copy_from_slice(&[0x12, 0x34, 0x56]);
+        output[4100] = 3;
+        output[4102] = 0x01;
+        block_on(kernel.write_all(&output)).unwrap();
+
+        match block_on(device.next()) {
+            Some(Ok(OutputEvent::Output { data, .. })) => assert_eq!(data, vec![0x12, 0x34, 0x56]),
+            _ => panic!("Expected Output event"),
+        }
+        assert_eq!(*tapped.lock().unwrap(), vec![output]);
+    }
+
+    fn fake_sysfs(test_name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("uhid-sysfs-{}-{}", test_name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn add_fake_device(sysfs: &Path, dir: &str, name: &str, uniq: &str, hidraw: Option<&str>) {
+        let device = sysfs.join(dir);
+        fs::create_dir_all(&device).unwrap();
+        fs::write(
+            device.join("uevent"),
+            format!(
+                "DRIVER=hid-generic\nHID_ID=0003:000015D9:00000A37\nHID_NAME={}\nHID_PHYS=\nHID_UNIQ={}\n",
+                name, uniq
+            ),
+        )
+        .unwrap();
+        if let Some(hidraw) = hidraw {
+            fs::create_dir_all(device.join("hidraw").join(hidraw)).unwrap();
+        }
+    }
+
+    #[test]
+    fn find_hidraw_path_matches_name_and_uniq() {
+        let sysfs = fake_sysfs("match");
+        add_fake_device(&sysfs, "0003:15D9:0A37.0001", "test-uhid-device", "a", Some("hidraw1"));
+        add_fake_device(&sysfs, "0003:15D9:0A37.0002", "test-uhid-device", "b", Some("hidraw2"));
+
+        let path = find_hidraw_path(&sysfs, Path::new("/dev"), "test-uhid-device", "b").unwrap();
+
+        assert_eq!(path, Path::new("/dev/hidraw2"));
+        fs::remove_dir_all(&sysfs).unwrap();
+    }
+
+    #[test]
+    fn find_hidraw_path_would_block_until_node_exists() {
+        let sysfs = fake_sysfs("pending");
+        add_fake_device(&sysfs, "0003:15D9:0A37.0001", "test-uhid-device", "", None);
+
+        let err = find_hidraw_path(&sysfs, Path::new("/dev"), "test-uhid-device", "").unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        fs::remove_dir_all(&sysfs).unwrap();
+    }
+
+    #[test]
+    fn wait_for_usable_hidraw_resolves_once_node_can_be_opened() {
+        let sysfs = fake_sysfs("usable");
+        let dev = sysfs.join("dev");
+        fs::create_dir_all(&dev).unwrap();
+        add_fake_device(&sysfs, "0003:15D9:0A37.0001", "test-uhid-device", "a", Some("hidraw7"));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let _guard = runtime.enter();
+
+        let node = dev.join("hidraw7");
+        let create_node = tokio_time::sleep(Duration::from_millis(20)).map(|()| {
+            fs::write(&node, b"").unwrap();
+        });
+        let wait = wait_for_usable_hidraw(
+            sysfs.clone(),
+            dev.clone(),
+            String::from("test-uhid-device"),
+            String::from("a"),
+            tokio_time::Instant::now() + Duration::from_secs(5),
+        );
+        let ((), path) = runtime.block_on(future::join(create_node, wait));
+
+        assert_eq!(path.unwrap(), node);
+        fs::remove_dir_all(&sysfs).unwrap();
+    }
+
+    #[test]
+    fn wait_for_usable_hidraw_times_out_without_node() {
+        let sysfs = fake_sysfs("unusable");
+        add_fake_device(&sysfs, "0003:15D9:0A37.0001", "test-uhid-device", "a", Some("hidraw7"));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _guard = runtime.enter();
+
+        let err = runtime
+            .block_on(wait_for_usable_hidraw(
+                sysfs.clone(),
+                sysfs.join("dev"),
+                String::from("test-uhid-device"),
+                String::from("a"),
+                tokio_time::Instant::now() + Duration::from_millis(10),
+            ))
+            .unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        fs::remove_dir_all(&sysfs).unwrap();
+    }
+
+    #[test]
+    fn uhid_path_prefers_environment_override() {
+        let path = env::temp_dir().join(format!("uhid-override-{}", process::id()));
+        fs::write(&path, b"").unwrap();
+
+        let found = uhid_path_with_override(Some(path.clone().into_os_string()));
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(found, path);
+    }
+
+    #[test]
+    fn create_with_path_keeps_not_found() {
+        let path = env::temp_dir().join(format!("uhid-missing-{}", process::id()));
+
+        match UHIDDevice::create_with_path(&path, params()) {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+            Ok(_) => panic!("Expected NotFound error"),
+        }
+    }
+
+    #[test]
+    fn create_with_retry_does_not_retry_not_found() {
+        let path = env::temp_dir().join(format!("uhid-missing-{}", process::id()));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let result = runtime.block_on(UHIDDevice::create_with_retry(
+            &path,
+            params(),
+            3,
+            Duration::from_secs(60),
+        ));
+
+        match result {
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+            Ok(_) => panic!("Expected NotFound error"),
+        }
+    }
+
+    #[test]
+    fn busy_and_permission_denied_are_transient() {
+        let transient = |errno| is_transient_open_error(&io::Error::from_raw_os_error(errno));
+
+        assert!(transient(libc::EBUSY));
+        assert!(transient(libc::EACCES));
+        assert!(!transient(libc::ENOENT));
+        assert!(!transient(libc::ENODEV));
     }
 }
@@ -5,6 +5,10 @@ use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
 
+/// Replace the contents of `path` with whatever `writer_fn` writes
+///
+/// Once this returns `Ok` the new contents are on disk, both the file and its directory
+/// entry have been synced.
 pub(crate) fn overwrite<W>(path: &Path, writer_fn: W) -> io::Result<()>
 where
     W: FnOnce(Box<&mut dyn Write>) -> io::Result<()>,
@@ -10,11 +10,22 @@ use atomic_file;
 #[derive(Serialize, Deserialize, Clone)]
 pub(crate) struct Config {
     pub(crate) secret_store_type: SecretStoreType,
+    /// Use this attestation instead of the shared self-signed one
+    #[serde(default)]
+    pub(crate) attestation: Option<AttestationConfig>,
+}
+
+/// Paths to PEM files holding the attestation certificate chain (leaf first) and its key
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct AttestationConfig {
+    pub(crate) certificate_chain: PathBuf,
+    pub(crate) private_key: PathBuf,
 }
 
 #[derive(Serialize, Deserialize, Copy, Clone)]
 pub(crate) enum SecretStoreType {
     File,
+    EncryptedFile,
     SecretService,
 }
 
@@ -83,4 +94,15 @@ mod tests {
 
         assert!(ConfigFile::load(file_path).unwrap().is_none());
     }
+
+    #[test]
+    fn load_without_attestation_defaults_to_none() {
+        let temp_dir = TempDir::new("config_tests").unwrap();
+        let file_path = ConfigFilePath::from_dir(temp_dir.path());
+        std::fs::write(file_path.get(), r#"{"secret_store_type":"File"}"#).unwrap();
+
+        let config_file = ConfigFile::load(file_path).unwrap().unwrap();
+
+        assert!(config_file.config().attestation.is_none());
+    }
 }
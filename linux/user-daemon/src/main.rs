@@ -1,4 +1,6 @@
+extern crate aes_gcm;
 extern crate alloc;
+extern crate argon2;
 extern crate bincode;
 extern crate clap;
 extern crate core;
@@ -10,6 +12,7 @@ extern crate futures;
 extern crate futures_cpupool;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 extern crate notify_rust;
 #[macro_use]
 extern crate quick_error;
@@ -28,24 +31,28 @@ extern crate tokio_serde_bincode;
 extern crate tokio_uds;
 extern crate u2f_core;
 extern crate u2fhid_protocol;
+extern crate zeroize;
 
 use std::io;
+use std::thread;
 
 use clap::{App, Arg};
 use directories::{ProjectDirs, UserDirs};
 use failure::{Compat, Error};
-use futures::future;
+use futures::future::{self, Either, Shared};
 use futures::prelude::*;
+use futures::sync::oneshot;
 use slog::{Drain, Logger};
 use tokio_core::reactor::{Core, Handle};
 use tokio_io::codec::length_delimited;
 use tokio_serde_bincode::{ReadBincode, WriteBincode};
 use tokio_uds::{UCred, UnixStream};
-use u2f_core::{SecretStore, SecureCryptoOperations, U2F};
+use u2f_core::{SecureCryptoOperations, U2F};
 use u2fhid_protocol::{Packet, U2FHID};
 
 use softu2f_system_daemon::{
     CreateDeviceError, CreateDeviceRequest, DeviceDescription, SocketInput, SocketOutput,
+    TerminationSignals,
 };
 use storage::AppDirs;
 use user_presence::NotificationUserPresence;
@@ -109,6 +116,9 @@ type Transport = Box<
 
 trait Pipe: Stream + Sink {}
 
+/// Completes once SIGTERM or SIGINT is received
+type Termination = Shared<oneshot::Receiver<()>>;
+
 impl<'a, T> Pipe for T where T: Stream + Sink + 'a {}
 
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
@@ -136,15 +146,44 @@ fn main() -> Result<(), TransportError> {
 
     info!(logger, "Starting software Universal 2nd Factor device user daemon"; "version" => VERSION);
 
+    // Blocked before any other thread is started, they inherit the mask
+    let termination = wait_for_termination(TerminationSignals::block()?, &logger);
     let socket_path = socket_path.unwrap_or(softu2f_system_daemon::DEFAULT_SOCKET_PATH);
     let mut core = Core::new()?;
     let handle = core.handle();
-    core.run(connect(socket_path, handle, &logger))
+    core.run(connect(socket_path, handle, termination, &logger))
+}
+
+fn wait_for_termination(signals: TerminationSignals, logger: &Logger) -> Termination {
+    let (sender, receiver) = oneshot::channel();
+    let logger = logger.clone();
+    thread::spawn(move || match signals.wait() {
+        Ok(signal) => {
+            info!(logger, "Received termination signal"; "signal" => signal);
+            let _ = sender.send(());
+        }
+        // Dropping the sender cancels the termination, the daemon keeps running
+        Err(err) => error!(logger, "Failed to wait for termination signals"; "error" => %err),
+    });
+    receiver.shared()
+}
+
+/// Resolves once the daemon is terminated, never if waiting for signals failed
+fn terminated(termination: &Termination) -> Box<dyn Future<Item = (), Error = TransportError>> {
+    Box::new(termination.clone().then(
+        |result| -> Box<dyn Future<Item = (), Error = TransportError>> {
+            match result {
+                Ok(_) => Box::new(future::ok(())),
+                Err(_) => Box::new(future::empty()),
+            }
+        },
+    ))
 }
 
 fn connect(
     socket_path: &str,
     handle: Handle,
+    termination: Termination,
     logger: &Logger,
 ) -> Box<dyn Future<Item = (), Error = TransportError>> {
     let logger = logger.clone();
@@ -152,13 +191,14 @@ fn connect(
     Box::new(
         UnixStream::connect(socket_path)
             .map_err(TransportError::Io)
-            .and_then(|stream| connected(stream, handle, logger)),
+            .and_then(|stream| connected(stream, handle, termination, logger)),
     )
 }
 
 fn connected(
     stream: UnixStream,
     handle: Handle,
+    termination: Termination,
     logger: Logger,
 ) -> Box<dyn Future<Item = (), Error = TransportError>> {
     match stream
@@ -173,9 +213,18 @@ fn connected(
     let transport = bind_transport(stream);
     let created_device = create_device(transport, logger.clone());
 
-    Box::new(created_device.and_then(move |(device, transport)| {
-        bind_service(device, transport, handle, &logger.clone())
-    }))
+    Box::new(
+        created_device
+            .select2(terminated(&termination))
+            .then(move |result| match result {
+                Ok(Either::A(((device, transport), _))) => {
+                    bind_service(device, transport, handle, termination, &logger)
+                }
+                // Nothing to shut down before the device exists
+                Ok(Either::B(((), _))) => Box::new(future::ok(())),
+                Err(Either::A((err, _))) | Err(Either::B((err, _))) => Box::new(future::err(err)),
+            }),
+    )
 }
 
 fn bind_transport(stream: UnixStream) -> Transport {
@@ -235,10 +284,15 @@ where
     Box::new(created)
 }
 
+/// Run a U2F device over `transport` until either side closes it
+///
+/// When terminated the service is shut down, flushing the secret store, and the
+/// transport closed so the system daemon destroys the device.
 fn bind_service<T>(
     device: DeviceDescription,
     transport: T,
     handle: Handle,
+    termination: Termination,
     log: &Logger,
 ) -> Box<dyn Future<Item = (), Error = TransportError>>
 where
@@ -251,25 +305,40 @@ where
         .filter_map(move |output| socket_output_to_packet(&packet_logger, output))
         .with(|packet| future::ok(packet_to_socket_input(packet)));
 
-    let attestation = u2f_core::self_signed_attestation();
+    let dirs = match app_dirs() {
+        Ok(dirs) => dirs,
+        Err(err) => return Box::new(future::err(TransportError::Failure(err.compat()))),
+    };
+    let attestation = match storage::build_attestation(&dirs, log) {
+        Ok(attestation) => attestation,
+        Err(err) => return Box::new(future::err(TransportError::Failure(err.compat()))),
+    };
     let user_presence = Box::new(NotificationUserPresence::new(&handle, log.new(o!())));
     let operations = Box::new(SecureCryptoOperations::new(attestation));
-    let storage = match build_storage(log) {
+    // Keys are kept per user, the device was created for the one the system daemon saw connect
+    let stores = storage::principal_stores(dirs, log.new(o!()));
+    let storage = match stores.for_principal(device.uid) {
         Ok(store) => store,
-        Err(err) => return Box::new(future::err(TransportError::Failure(err.compat()))),
+        Err(err) => return Box::new(future::err(TransportError::Io(err))),
     };
     let service = match U2F::new(user_presence, operations, storage, log.new(o!())) {
         Ok(service) => service,
         Err(err) => return Box::new(future::err(TransportError::Io(err))),
     };
 
-    info!(log, "Virtual U2F device created"; "device_id" => device.id);
+    info!(log, "Virtual U2F device created"; "device_id" => device.id, "uid" => device.uid);
 
-    Box::new(U2FHID::bind_service(
-        handle,
-        transport,
-        service,
-        log.new(o!()),
+    let u2fhid = U2FHID::bind_service(handle, transport, service, log.new(o!()));
+    Box::new(u2fhid.select2(terminated(&termination)).then(
+        |result| -> Box<dyn Future<Item = (), Error = TransportError>> {
+            match result {
+                Ok(Either::A(((), _))) => Box::new(future::ok(())),
+                Ok(Either::B(((), u2fhid))) => {
+                    Box::new(u2fhid.shutdown().map_err(TransportError::Io))
+                }
+                Err(Either::A((err, _))) | Err(Either::B((err, _))) => Box::new(future::err(err)),
+            }
+        },
     ))
 }
 
@@ -293,19 +362,16 @@ fn packet_to_socket_input(packet: Packet) -> SocketInput {
     ))
 }
 
-fn build_storage(log: &Logger) -> Result<Box<dyn SecretStore>, Error> {
+fn app_dirs() -> Result<AppDirs, Error> {
     let user_dirs = UserDirs::new().ok_or(HomeDirectoryNotFound)?;
     let project_dirs =
         ProjectDirs::from("com.github", "danstiner", "Rust U2F").ok_or(HomeDirectoryNotFound)?;
 
-    storage::build(
-        &AppDirs {
-            user_home_dir: user_dirs.home_dir().to_owned(),
-            config_dir: project_dirs.config_dir().to_owned(),
-            data_local_dir: project_dirs.data_local_dir().to_owned(),
-        },
-        log,
-    )
+    Ok(AppDirs {
+        user_home_dir: user_dirs.home_dir().to_owned(),
+        config_dir: project_dirs.config_dir().to_owned(),
+        data_local_dir: project_dirs.data_local_dir().to_owned(),
+    })
 }
 
 fn require_root(cred: UCred) -> Result<(), TransportError> {
@@ -1,16 +1,22 @@
-use std::borrow::Borrow;
+use std::env;
+use std::fs;
 use std::io;
 use std::path::PathBuf;
 
+use libc;
 use slog::Logger;
-use u2f_core::SecretStore;
+use u2f_core::{self_signed_attestation, Attestation, PrincipalStores, SecretStore, Uid};
 
 use config::{Config, ConfigFile, ConfigFilePath, SecretStoreType};
-use stores::file_store::FileStore;
+use stores::encrypted_file_store::{self, EncryptedFileStore, KeySource};
 use stores::file_store_v2::FileStoreV2;
+use stores::migrate;
 use stores::secret_service_store::SecretServiceStore;
 use stores::UserSecretStore;
 
+/// Passphrase for the encrypted file store, a generated key file is used when unset
+const PASSPHRASE_ENV_VAR: &str = "SOFTU2F_STORE_PASSPHRASE";
+
 pub struct AppDirs {
     pub user_home_dir: PathBuf,
     pub config_dir: PathBuf,
@@ -19,9 +25,56 @@ pub struct AppDirs {
 
 pub(crate) fn build(dirs: &AppDirs, log: &Logger) -> Result<Box<dyn SecretStore>, failure::Error> {
     let config = determine_config(dirs, log)?;
-    let secret_store = build_secret_store(dirs, &config, log)?;
-    migrate_legacy_file_store(dirs, secret_store.borrow(), log)?;
-    Ok(secret_store.into_u2f_store())
+    let mut secret_store = build_secret_store(dirs, &config, log)?.into_u2f_store();
+    migrate_legacy_file_store(dirs, &mut *secret_store, log)?;
+    Ok(secret_store)
+}
+
+/// Stores by user, only the user running the daemon has one, opened with `build`
+pub(crate) fn principal_stores(dirs: AppDirs, log: Logger) -> PrincipalStores {
+    own_user_stores(move || build(&dirs, &log).map_err(|err| io::Error::other(err.compat())))
+}
+
+/// Stores by user, opening the store of the user running the daemon with `open`
+///
+/// The daemon only has access to its own user's secrets, any other user is refused
+/// rather than handed the same store.
+fn own_user_stores<F>(open: F) -> PrincipalStores
+where
+    F: Fn() -> io::Result<Box<dyn SecretStore>> + 'static,
+{
+    let own_uid = current_uid();
+    PrincipalStores::new(move |uid| {
+        if uid != own_uid {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "No secret store for uid {}, running as uid {}",
+                    uid, own_uid
+                ),
+            ));
+        }
+        open()
+    })
+}
+
+fn current_uid() -> Uid {
+    unsafe { libc::getuid() }
+}
+
+pub(crate) fn build_attestation(
+    dirs: &AppDirs,
+    log: &Logger,
+) -> Result<Attestation, failure::Error> {
+    match determine_config(dirs, log)?.attestation {
+        Some(config) => {
+            info!(log, "Using configured attestation certificate"; "path" => config.certificate_chain.display());
+            let chain = fs::read(&config.certificate_chain)?;
+            let key = fs::read(&config.private_key)?;
+            Ok(Attestation::from_pem(&chain, &key)?)
+        }
+        None => Ok(self_signed_attestation()),
+    }
 }
 
 fn determine_config(dirs: &AppDirs, log: &Logger) -> io::Result<Config> {
@@ -38,7 +91,10 @@ fn determine_config(dirs: &AppDirs, log: &Logger) -> io::Result<Config> {
             } else {
                 secret_store_type = SecretStoreType::File;
             }
-            let config = Config { secret_store_type };
+            let config = Config {
+                secret_store_type,
+                attestation: None,
+            };
             info!(log, "Creating configuration file"; "path" => config_file_path.get().display());
             ConfigFile::create(config_file_path, config)?
         }
@@ -57,33 +113,92 @@ fn build_secret_store(
                 log,
                 "Storing secrets in your keychain using the D-Bus Secret Service API"
             );
-            Ok(Box::new(SecretServiceStore::new()?))
+            let store = SecretServiceStore::new()?;
+            if store.is_locked().unwrap_or(false) {
+                warn!(
+                    log,
+                    "Keyring is locked, you will be prompted to unlock it on first use"
+                );
+            }
+            Ok(Box::new(store))
         }
         SecretStoreType::File => {
             let store_dir = dirs.data_local_dir.as_path();
             warn!(log, "Storing secrets in an unencrypted file"; "dir" => store_dir.display());
             Ok(Box::new(FileStoreV2::new(store_dir)?))
         }
+        SecretStoreType::EncryptedFile => {
+            let key_source = match env::var(PASSPHRASE_ENV_VAR) {
+                Ok(passphrase) => KeySource::Passphrase(passphrase),
+                Err(_) => {
+                    let key_path = dirs.config_dir.join("secrets.key");
+                    if !key_path.exists() {
+                        info!(log, "Generating secret store key file"; "path" => key_path.display());
+                        encrypted_file_store::generate_key_file(&key_path)?;
+                    }
+                    KeySource::KeyFile(key_path)
+                }
+            };
+            let store_path = dirs.data_local_dir.join("secrets.enc");
+            info!(log, "Storing secrets in an encrypted file"; "path" => store_path.display());
+            Ok(Box::new(EncryptedFileStore::open(store_path, &key_source)?))
+        }
     }
 }
 
 fn migrate_legacy_file_store(
     dirs: &AppDirs,
-    secret_store: &dyn UserSecretStore,
+    secret_store: &mut dyn SecretStore,
     log: &Logger,
 ) -> io::Result<()> {
-    let legacy_file_store = FileStore::new(dirs.user_home_dir.join(".softu2f-secrets.json"))?;
-    if legacy_file_store.exists() {
+    let legacy_path = dirs.user_home_dir.join(".softu2f-secrets.json");
+    if legacy_path.exists() {
         info!(
             log,
             "copying secrets from legacy secret store to newer format"
         );
-        for secret in legacy_file_store.iter()? {
-            secret_store.add_secret(secret)?;
+        match migrate::import_legacy(&legacy_path, secret_store) {
+            Ok(imported) => info!(log, "finished copying secrets"; "count" => imported),
+            Err(err) => {
+                error!(log, "failed copying secrets, legacy secret store kept"; "count" => err.imported(), "err" => %err);
+                return Err(err.into());
+            }
         }
-        info!(log, "finished copying secrets");
-        legacy_file_store.delete()?;
+        fs::remove_file(&legacy_path)?;
         info!(log, "deleted legacy secret store");
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use futures::Future;
+    use u2f_core::InMemoryStore;
+
+    use super::*;
+
+    #[test]
+    fn store_is_only_opened_for_own_user() {
+        let opened = Rc::new(Cell::new(0));
+        let opened_count = opened.clone();
+        let stores = own_user_stores(move || {
+            opened_count.set(opened_count.get() + 1);
+            Ok(Box::new(InMemoryStore::new()))
+        });
+        let own_uid = current_uid();
+        let other_uid = own_uid.wrapping_add(1);
+
+        let own_store = stores.for_principal(own_uid).unwrap();
+        let other_store = stores.for_principal(other_uid);
+
+        assert_eq!(own_store.list_application_keys().wait().unwrap(), vec![]);
+        assert_eq!(
+            other_store.err().map(|err| err.kind()),
+            Some(io::ErrorKind::PermissionDenied)
+        );
+        assert_eq!(opened.get(), 1);
+    }
+}
@@ -1,9 +1,17 @@
 use std::io;
 
-use u2f_core::{ApplicationKey, Counter, SecretStore};
+use argon2;
+use futures::{future, stream, Future};
+use secret_service::SsError;
+use serde_json;
+use u2f_core::{
+    increment_counter, AppId, ApplicationKey, Counter, KeyHandle, SecretStore, StoreFuture,
+    StoreStream,
+};
 
-pub(crate) mod file_store;
+pub(crate) mod encrypted_file_store;
 pub(crate) mod file_store_v2;
+pub(crate) mod migrate;
 pub(crate) mod secret_service_store;
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -12,7 +20,262 @@ pub struct Secret {
     counter: Counter,
 }
 
+/// Version of the JSON layout secrets are stored in, written at its top level
+///
+/// Bump it when the layout changes in a way older releases cannot read, and keep
+/// loading every earlier version.
+pub(crate) const SECRETS_VERSION: u16 = 1;
+
+/// Files written before the version was recorded are version 1
+pub(crate) fn unversioned() -> u16 {
+    1
+}
+
+/// Version of stored secrets, read on its own before the secrets themselves
+///
+/// A file written by a newer release fails with `UnsupportedVersion` instead of
+/// whatever error parsing its unknown layout would give.
+#[derive(Deserialize)]
+pub(crate) struct StoredVersion {
+    #[serde(default = "unversioned")]
+    version: u16,
+}
+
+impl StoredVersion {
+    pub(crate) fn check(&self) -> Result<(), StoreError> {
+        match self.version {
+            SECRETS_VERSION => Ok(()),
+            version => Err(StoreError::UnsupportedVersion(version)),
+        }
+    }
+}
+
 pub trait UserSecretStore: SecretStore {
     fn add_secret(&self, secret: Secret) -> io::Result<()>;
     fn into_u2f_store(self: Box<Self>) -> Box<dyn SecretStore>;
 }
+
+/// All secrets of a `SecretsFileStore`, read and written as a whole
+#[derive(Serialize, Deserialize)]
+pub struct Data {
+    #[serde(default = "unversioned")]
+    version: u16,
+    secrets: Vec<Secret>,
+}
+
+impl Data {
+    fn new() -> Data {
+        Data {
+            version: SECRETS_VERSION,
+            secrets: Vec::new(),
+        }
+    }
+    fn find_secret(&self, application: &AppId, handle: &KeyHandle) -> Option<&Secret> {
+        self.secrets
+            .iter()
+            .find(|s| s.application_key.matches(application, handle))
+    }
+    fn find_secret_mut(&mut self, application: &AppId, handle: &KeyHandle) -> Option<&mut Secret> {
+        self.secrets
+            .iter_mut()
+            .find(|s| s.application_key.matches(application, handle))
+    }
+    fn push(&mut self, secret: Secret) {
+        self.secrets.push(secret)
+    }
+    fn remove(&mut self, application: &AppId, handle: &KeyHandle) -> bool {
+        let len = self.secrets.len();
+        self.secrets
+            .retain(|s| !s.application_key.matches(application, handle));
+        self.secrets.len() != len
+    }
+    fn keys(&self) -> Vec<(AppId, KeyHandle)> {
+        self.secrets
+            .iter()
+            .map(|s| {
+                (
+                    s.application_key.application,
+                    s.application_key.handle.clone(),
+                )
+            })
+            .collect()
+    }
+}
+
+/// A file holding `Data`, a missing file reads as no secrets
+pub trait SecretsFile {
+    fn read(&self) -> io::Result<Data>;
+
+    /// Replace the file's contents, they must be on disk when this returns
+    fn write(&self, data: &Data) -> io::Result<()>;
+
+    /// The application and handle of each secret, in the order they were added
+    fn read_keys(&self) -> io::Result<Vec<(AppId, KeyHandle)>> {
+        Ok(self.read()?.keys())
+    }
+}
+
+/// Secret store keeping all secrets in one file, which is rewritten on every change
+pub struct SecretsFileStore<F>(F);
+
+impl<F: SecretsFile> SecretsFileStore<F> {
+    fn add(&self, secret: Secret) -> io::Result<()> {
+        let mut data = self.0.read()?;
+        data.push(secret);
+        self.0.write(&data)
+    }
+
+    fn increment_counter(&self, application: &AppId, handle: &KeyHandle) -> io::Result<Counter> {
+        let mut data = self.0.read()?;
+        let new_counter = {
+            let secret = data.find_secret_mut(application, handle).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "application key not found")
+            })?;
+            secret.counter = increment_counter(secret.counter)?;
+            secret.counter
+        };
+        // Never hand out a counter value that isn't on disk yet
+        self.0.write(&data)?;
+        Ok(new_counter)
+    }
+
+    fn retrieve(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> io::Result<Option<ApplicationKey>> {
+        Ok(self
+            .0
+            .read()?
+            .find_secret(application, handle)
+            .map(|secret| secret.application_key.clone()))
+    }
+
+    fn remove(&self, application: &AppId, handle: &KeyHandle) -> io::Result<bool> {
+        let mut data = self.0.read()?;
+        let removed = data.remove(application, handle);
+        if removed {
+            self.0.write(&data)?;
+        }
+        Ok(removed)
+    }
+}
+
+impl<F: SecretsFile + 'static> UserSecretStore for SecretsFileStore<F> {
+    fn add_secret(&self, secret: Secret) -> io::Result<()> {
+        self.add(secret)
+    }
+
+    fn into_u2f_store(self: Box<Self>) -> Box<dyn SecretStore> {
+        self
+    }
+}
+
+impl<F: SecretsFile> SecretStore for SecretsFileStore<F> {
+    fn add_application_key(&self, key: &ApplicationKey) -> StoreFuture<()> {
+        self.import_application_key(key, 0)
+    }
+
+    fn import_application_key(&self, key: &ApplicationKey, counter: Counter) -> StoreFuture<()> {
+        Box::new(future::result(self.add(Secret {
+            application_key: key.clone(),
+            counter,
+        })))
+    }
+
+    fn get_and_increment_counter(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> StoreFuture<Counter> {
+        Box::new(future::result(self.increment_counter(application, handle)))
+    }
+
+    fn retrieve_application_key(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> StoreFuture<Option<ApplicationKey>> {
+        Box::new(future::result(self.retrieve(application, handle)))
+    }
+
+    fn list_application_keys(&self) -> StoreFuture<Vec<(AppId, KeyHandle)>> {
+        Box::new(future::result(self.0.read().map(|data| data.keys())))
+    }
+
+    fn iter_application_keys(&self) -> StoreStream<(AppId, KeyHandle)> {
+        Box::new(future::result(self.0.read_keys().map(stream::iter_ok)).flatten_stream())
+    }
+
+    fn remove_application_key(&self, application: &AppId, handle: &KeyHandle) -> StoreFuture<bool> {
+        Box::new(future::result(self.remove(application, handle)))
+    }
+
+    fn clear_all(&self) -> StoreFuture<()> {
+        Box::new(future::result(self.0.write(&Data::new())))
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum StoreError {
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            display("I/O error: {}", err)
+        }
+        Json(err: serde_json::Error) {
+            from()
+            cause(err)
+            display("JSON error: {}", err)
+        }
+        /// The passphrase or key file does not match the one the store was written with
+        BadPassphrase {
+            display("Secret store could not be unlocked, wrong passphrase or key file")
+        }
+        /// The store was unlocked but its contents failed authentication or could not be parsed
+        Corrupt(reason: &'static str) {
+            display("Secret store is corrupt: {}", reason)
+        }
+        /// The store was written by a newer release in a format this one cannot read
+        UnsupportedVersion(version: u16) {
+            display("Secret store format version {} is not supported, please upgrade", version)
+        }
+        KeyDerivation(err: argon2::Error) {
+            display("Key derivation failed: {}", err)
+        }
+        /// The keyring is locked and unlocking it failed or the unlock prompt was dismissed
+        Locked {
+            display("Keyring is locked, please unlock your keyring")
+        }
+        SecretService(message: String) {
+            display("Secret Service error: {}", message)
+        }
+    }
+}
+
+impl From<SsError> for StoreError {
+    fn from(err: SsError) -> StoreError {
+        match err {
+            SsError::Locked | SsError::Prompt => StoreError::Locked,
+            SsError::Crypto(err) => StoreError::SecretService(format!("crypto error {}", err)),
+            SsError::Dbus(err) => StoreError::SecretService(format!(
+                "D-Bus error {} {}",
+                err.name().unwrap_or(""),
+                err.message().unwrap_or("")
+            )),
+            SsError::NoResult => StoreError::SecretService("no result found".into()),
+            SsError::Parse => StoreError::SecretService("failed to parse D-Bus output".into()),
+        }
+    }
+}
+
+impl From<StoreError> for io::Error {
+    fn from(err: StoreError) -> io::Error {
+        match err {
+            StoreError::Io(err) => err,
+            StoreError::Locked => io::Error::new(io::ErrorKind::PermissionDenied, err.to_string()),
+            err => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+        }
+    }
+}
@@ -1,139 +1,82 @@
 use std::collections::HashMap;
 use std::io;
-use std::io::ErrorKind;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use failure::Error;
-use secret_service::{Collection, EncryptionType, Item, SecretService, SsError};
+use futures::future;
+use futures::Future;
+use secret_service::{Collection, EncryptionType, Item, SecretService};
 use serde_json;
-use u2f_core::{try_reverse_app_id, AppId, ApplicationKey, Counter, KeyHandle, SecretStore};
-
-use stores::{Secret, UserSecretStore};
-
-#[derive(Debug, Fail)]
-pub enum SecretServiceError {
-    #[fail(display = "crypto error {}", _0)]
-    Crypto(String),
-    #[fail(display = "D-Bus error {} {}", _0, _1)]
-    DBus(String, String),
-    #[fail(display = "object locked")]
-    Locked,
-    #[fail(display = "no result found")]
-    NoResult,
-    #[fail(display = "failed to parse D-Bus output")]
-    Parse,
-    #[fail(display = "prompt dismissed")]
-    Prompt,
-}
+use u2f_core::{
+    increment_counter, try_reverse_app_id, AppId, ApplicationKey, Counter, KeyHandle, SecretStore,
+    StoreFuture,
+};
 
-impl From<secret_service::SsError> for SecretServiceError {
-    fn from(err: SsError) -> Self {
-        match err {
-            SsError::Crypto(err) => SecretServiceError::Crypto(err),
-            SsError::Dbus(err) => SecretServiceError::DBus(
-                err.name().unwrap_or("").into(),
-                err.message().unwrap_or("").into(),
-            ),
-            SsError::Locked => SecretServiceError::Locked,
-            SsError::NoResult => SecretServiceError::NoResult,
-            SsError::Parse => SecretServiceError::Parse,
-            SsError::Prompt => SecretServiceError::Prompt,
-        }
-    }
-}
+use stores::{Secret, StoreError, UserSecretStore};
 
+const APPLICATION_ATTRIBUTE: &str = "com.github.danstiner.rust-u2f";
+
+/// Stores secrets as items in the default collection of the D-Bus Secret Service
+///
+/// The collection is unlocked on first use rather than when the store is created, so a
+/// keyring that is locked when the daemon starts results in `StoreError::Locked` from
+/// the operation that needed it instead of a startup failure.
 pub struct SecretServiceStore {
     service: SecretService,
 }
 
 impl SecretServiceStore {
-    pub fn new() -> Result<SecretServiceStore, Error> {
-        let service =
-            SecretService::new(EncryptionType::Dh).map_err(|err| SecretServiceError::from(err))?;
+    pub fn new() -> Result<SecretServiceStore, StoreError> {
+        let service = SecretService::new(EncryptionType::Dh)?;
         Ok(SecretServiceStore { service })
     }
 
     pub fn is_supported() -> bool {
         SecretServiceStore::new().is_ok()
     }
-}
 
-impl UserSecretStore for SecretServiceStore {
-    fn add_secret(&self, secret: Secret) -> io::Result<()> {
-        let collection = self
-            .service
-            .get_default_collection()
-            .map_err(|_error| io::Error::new(ErrorKind::Other, "get_default_collection"))?;
+    pub fn is_locked(&self) -> Result<bool, StoreError> {
+        Ok(self.service.get_default_collection()?.is_locked()?)
+    }
+
+    fn unlocked_collection(&self) -> Result<Collection<'_>, StoreError> {
+        let collection = self.service.get_default_collection()?;
         unlock_if_locked(&collection)?;
+        Ok(collection)
+    }
+
+    fn add(&self, secret: &Secret) -> Result<(), StoreError> {
+        let collection = self.unlocked_collection()?;
         let attributes = registration_attributes(
             &secret.application_key.application,
             &secret.application_key.handle,
         );
         let attributes = attributes.iter().map(|(k, v)| (*k, v.as_str())).collect();
-        let label = match try_reverse_app_id(&secret.application_key.application) {
-            Some(app_id) => format!("Universal 2nd Factor token for {}", app_id),
-            None => format!(
-                "Universal 2nd Factor token for {}",
-                secret.application_key.application.to_base64()
-            ),
-        };
-        let secret = serde_json::to_string(&Secret {
-            application_key: secret.application_key.clone(),
-            counter: secret.counter,
-        })
-        .map_err(|error| io::Error::new(ErrorKind::Other, error))?;
+        let label = item_label(&secret.application_key.application);
+        let secret = serde_json::to_string(secret)?;
         let content_type = "application/json";
-        let _item = collection
-            .create_item(&label, attributes, secret.as_bytes(), false, content_type)
-            .map_err(|_error| io::Error::new(ErrorKind::Other, "create_item"))?;
+        let _item =
+            collection.create_item(&label, attributes, secret.as_bytes(), false, content_type)?;
         Ok(())
     }
 
-    fn into_u2f_store(self: Box<Self>) -> Box<dyn SecretStore> {
-        self
-    }
-}
-
-impl SecretStore for SecretServiceStore {
-    fn add_application_key(&self, key: &ApplicationKey) -> io::Result<()> {
-        self.add_secret(Secret {
-            application_key: key.clone(),
-            counter: 0,
-        })
-    }
-
-    fn get_and_increment_counter(
+    fn increment_counter(
         &self,
         application: &AppId,
         handle: &KeyHandle,
-    ) -> io::Result<Counter> {
-        let collection = self
-            .service
-            .get_default_collection()
-            .map_err(|_error| io::Error::new(ErrorKind::Other, "get_default_collection"))?;
-        let option = find_item(&collection, application, handle)
-            .map_err(|_error| io::Error::new(ErrorKind::Other, "find_item"))?;
-        if option.is_none() {
-            return Err(io::Error::new(ErrorKind::Other, "not found"));
-        }
-        let item = option.unwrap();
-        let secret_bytes = item
-            .get_secret()
-            .map_err(|_error| io::Error::new(ErrorKind::Other, "get_secret"))?;
-        let mut secret: Secret = serde_json::from_slice(&secret_bytes)
-            .map_err(|_error| io::Error::new(ErrorKind::Other, "from_slice"))?;
-
-        secret.counter += 1;
-
-        let secret_string = serde_json::to_string(&secret)
-            .map_err(|error| io::Error::new(ErrorKind::Other, error))?;
-        item.set_secret(secret_string.as_bytes(), "application/json")
-            .map_err(|_error| io::Error::new(ErrorKind::Other, "get_attributes"))?;
-
-        let attributes = item
-            .get_attributes()
-            .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?;
-        let mut attributes: HashMap<_, _> = attributes.into_iter().collect();
+    ) -> Result<Option<Counter>, StoreError> {
+        let collection = self.unlocked_collection()?;
+        let item = match find_item(&collection, application, handle)? {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        let mut secret: Secret = serde_json::from_slice(&item.get_secret()?)?;
+
+        secret.counter = increment_counter(secret.counter)?;
+
+        let secret_string = serde_json::to_string(&secret)?;
+        item.set_secret(secret_string.as_bytes(), "application/json")?;
+
+        let mut attributes: HashMap<_, _> = item.get_attributes()?.into_iter().collect();
         attributes
             .entry("times_used".to_string())
             .and_modify(|value| {
@@ -146,46 +89,133 @@ impl SecretStore for SecretServiceStore {
             .map(|(key, value)| (key.as_str(), value.as_str()))
             .collect();
         attributes.sort_by_cached_key(|(key, _)| key.to_owned());
-        item.set_attributes(attributes)
-            .map_err(|_error| io::Error::new(ErrorKind::Other, "get_attributes"))?;
+        item.set_attributes(attributes)?;
 
-        let label = match try_reverse_app_id(application) {
-            Some(app_id) => format!("Universal 2nd Factor token for {}", app_id),
-            None => format!("Universal 2nd Factor token for {}", application.to_base64()),
-        };
-        item.set_label(&label)
-            .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?;
+        item.set_label(&item_label(application))?;
 
-        Ok(secret.counter)
+        Ok(Some(secret.counter))
     }
 
-    fn retrieve_application_key(
+    fn retrieve(
         &self,
         application: &AppId,
         handle: &KeyHandle,
-    ) -> io::Result<Option<ApplicationKey>> {
-        let collection = self
-            .service
-            .get_default_collection()
-            .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?;
-        let option = find_item(&collection, application, handle)
-            .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?;
-        if option.is_none() {
-            return Ok(None);
+    ) -> Result<Option<ApplicationKey>, StoreError> {
+        let collection = self.unlocked_collection()?;
+        match find_item(&collection, application, handle)? {
+            Some(item) => {
+                let secret: Secret = serde_json::from_slice(&item.get_secret()?)?;
+                Ok(Some(secret.application_key))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<(AppId, KeyHandle)>, StoreError> {
+        let collection = self.unlocked_collection()?;
+        let mut keys = Vec::new();
+        for item in all_items(&collection)? {
+            let registered = date_registered(&item.get_attributes()?);
+            let secret: Secret = serde_json::from_slice(&item.get_secret()?)?;
+            keys.push((
+                registered,
+                secret.application_key.application,
+                secret.application_key.handle,
+            ));
+        }
+        Ok(registration_order(keys))
+    }
+
+    fn remove(&self, application: &AppId, handle: &KeyHandle) -> Result<bool, StoreError> {
+        let collection = self.unlocked_collection()?;
+        match find_item(&collection, application, handle)? {
+            Some(item) => {
+                item.delete()?;
+                Ok(true)
+            }
+            None => Ok(false),
         }
-        let item = option.unwrap();
-        let secret_bytes = item
-            .get_secret()
-            .map_err(|error| io::Error::new(ErrorKind::Other, error.to_string()))?;
-        let secret: Secret = serde_json::from_slice(&secret_bytes)
-            .map_err(|error| io::Error::new(ErrorKind::Other, error))?;
-        Ok(Some(secret.application_key))
+    }
+
+    fn clear(&self) -> Result<(), StoreError> {
+        let collection = self.unlocked_collection()?;
+        for item in all_items(&collection)? {
+            item.delete()?;
+        }
+        Ok(())
+    }
+}
+
+impl UserSecretStore for SecretServiceStore {
+    fn add_secret(&self, secret: Secret) -> io::Result<()> {
+        Ok(self.add(&secret)?)
+    }
+
+    fn into_u2f_store(self: Box<Self>) -> Box<dyn SecretStore> {
+        self
+    }
+}
+
+impl SecretStore for SecretServiceStore {
+    fn add_application_key(&self, key: &ApplicationKey) -> StoreFuture<()> {
+        Box::new(future::result(self.add_secret(Secret {
+            application_key: key.clone(),
+            counter: 0,
+        })))
+    }
+
+    fn import_application_key(&self, key: &ApplicationKey, counter: Counter) -> StoreFuture<()> {
+        Box::new(future::result(self.add_secret(Secret {
+            application_key: key.clone(),
+            counter,
+        })))
+    }
+
+    fn get_and_increment_counter(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> StoreFuture<Counter> {
+        let counter = match self.increment_counter(application, handle) {
+            Ok(counter) => counter.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "application key not found")
+            }),
+            Err(err) => Err(err.into()),
+        };
+        Box::new(future::result(counter))
+    }
+
+    fn retrieve_application_key(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> StoreFuture<Option<ApplicationKey>> {
+        Box::new(future::result(self.retrieve(application, handle)).from_err())
+    }
+
+    fn list_application_keys(&self) -> StoreFuture<Vec<(AppId, KeyHandle)>> {
+        Box::new(future::result(self.list()).from_err())
+    }
+
+    fn remove_application_key(&self, application: &AppId, handle: &KeyHandle) -> StoreFuture<bool> {
+        Box::new(future::result(self.remove(application, handle)).from_err())
+    }
+
+    fn clear_all(&self) -> StoreFuture<()> {
+        Box::new(future::result(self.clear()).from_err())
+    }
+}
+
+fn item_label(app_id: &AppId) -> String {
+    match try_reverse_app_id(app_id) {
+        Some(app_id) => format!("Universal 2nd Factor token for {}", app_id),
+        None => format!("Universal 2nd Factor token for {}", app_id.to_base64()),
     }
 }
 
 fn search_attributes(app_id: &AppId, handle: &KeyHandle) -> Vec<(&'static str, String)> {
     vec![
-        ("application", "com.github.danstiner.rust-u2f".to_string()),
+        ("application", APPLICATION_ATTRIBUTE.to_string()),
         ("u2f_app_id_hash", app_id.to_base64()),
         ("u2f_key_handle", handle.to_base64()),
         ("xdg:schema", "com.github.danstiner.rust-u2f".to_string()),
@@ -194,6 +224,7 @@ fn search_attributes(app_id: &AppId, handle: &KeyHandle) -> Vec<(&'static str, S
 
 fn registration_attributes(app_id: &AppId, handle: &KeyHandle) -> Vec<(&'static str, String)> {
     let mut attributes = search_attributes(app_id, handle);
+    attributes.push(("u2f_app_id_hex", app_id.to_hex()));
     attributes.push(("times_used", 0.to_string()));
 
     let start = SystemTime::now();
@@ -210,34 +241,108 @@ fn registration_attributes(app_id: &AppId, handle: &KeyHandle) -> Vec<(&'static
     attributes
 }
 
+/// Seconds since the epoch an item was registered at, 0 if it was not recorded
+fn date_registered(attributes: &[(String, String)]) -> u64 {
+    attributes
+        .iter()
+        .find(|(key, _)| key == "date_registered")
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Order keys by when they were registered, the service returns items in no stable order
+///
+/// Registration times only have second resolution, keys registered within the same
+/// second are ordered by application and handle.
+fn registration_order(mut keys: Vec<(u64, AppId, KeyHandle)>) -> Vec<(AppId, KeyHandle)> {
+    keys.sort_by(|a, b| (a.0, a.1.as_ref(), a.2.as_ref()).cmp(&(b.0, b.1.as_ref(), b.2.as_ref())));
+    keys.into_iter()
+        .map(|(_, application, handle)| (application, handle))
+        .collect()
+}
+
 fn find_item<'a>(
     collection: &'a Collection<'a>,
     app_id: &AppId,
     handle: &KeyHandle,
-) -> io::Result<Option<Item<'a>>> {
-    unlock_if_locked(collection)?;
+) -> Result<Option<Item<'a>>, StoreError> {
     let attributes = search_attributes(app_id, handle);
     let attributes = attributes.iter().map(|(k, v)| (*k, v.as_str())).collect();
-    let mut result = collection
-        .search_items(attributes)
-        .map_err(|_error| io::Error::new(ErrorKind::Other, "search_items"))?;
+    let mut result = collection.search_items(attributes)?;
     Ok(result.pop())
 }
 
-fn unlock_if_locked(collection: &Collection) -> io::Result<()> {
-    if collection
-        .is_locked()
-        .map_err(|_error| io::Error::new(ErrorKind::Other, "is_locked"))?
-    {
-        collection
-            .unlock()
-            .map_err(|_error| io::Error::new(ErrorKind::Other, "unlock"))?;
+fn all_items<'a>(collection: &'a Collection<'a>) -> Result<Vec<Item<'a>>, StoreError> {
+    Ok(collection.search_items(vec![("application", APPLICATION_ATTRIBUTE)])?)
+}
+
+fn unlock_if_locked(collection: &Collection) -> Result<(), StoreError> {
+    if collection.is_locked()? {
+        collection.unlock()?;
+        // Some implementations report success even when the unlock prompt is dismissed
+        if collection.is_locked()? {
+            return Err(StoreError::Locked);
+        }
     }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use secret_service::SsError;
+
+    use super::*;
+
     #[test]
-    fn todo() {}
+    fn locked_error_maps_to_locked() {
+        assert!(matches!(
+            StoreError::from(SsError::Locked),
+            StoreError::Locked
+        ));
+    }
+
+    #[test]
+    fn dismissed_prompt_maps_to_locked() {
+        assert!(matches!(
+            StoreError::from(SsError::Prompt),
+            StoreError::Locked
+        ));
+    }
+
+    #[test]
+    fn keys_are_listed_in_registration_order() {
+        let first = (AppId::from_bytes(&[2u8; 32]), KeyHandle::from(&[1u8; 4]));
+        let second = (AppId::from_bytes(&[1u8; 32]), KeyHandle::from(&[2u8; 4]));
+        let third = (AppId::from_bytes(&[1u8; 32]), KeyHandle::from(&[3u8; 4]));
+        let expected = vec![first.clone(), second.clone(), third.clone()];
+
+        let shuffled = vec![
+            (200, third.0, third.1.clone()),
+            (100, first.0, first.1.clone()),
+            (200, second.0, second.1.clone()),
+        ];
+        assert_eq!(registration_order(shuffled), expected);
+
+        let reversed = vec![
+            (200, second.0, second.1),
+            (200, third.0, third.1),
+            (100, first.0, first.1),
+        ];
+        assert_eq!(registration_order(reversed), expected);
+    }
+
+    #[test]
+    fn missing_registration_date_is_zero() {
+        let attributes = vec![("times_used".to_string(), "3".to_string())];
+
+        assert_eq!(date_registered(&attributes), 0);
+    }
+
+    #[test]
+    fn locked_error_is_permission_denied() {
+        let err = io::Error::from(StoreError::Locked);
+
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(err.to_string().contains("please unlock your keyring"));
+    }
 }
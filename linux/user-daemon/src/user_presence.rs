@@ -7,7 +7,7 @@ use notify_rust::{self, Notification, NotificationHint, NotificationUrgency};
 use slog::Logger;
 use time::Duration;
 use tokio_core::reactor::Handle;
-use u2f_core::{try_reverse_app_id, AppId, UserPresence};
+use u2f_core::{ApprovalRequest, Operation, UserPresence};
 
 const APPNAME: &str = "SoftU2F";
 const HINT_CATEGORY: &str = "device";
@@ -86,21 +86,15 @@ impl NotificationUserPresence {
 }
 
 impl UserPresence for NotificationUserPresence {
-    fn approve_registration(
+    fn approve(
         &self,
-        application: &AppId,
+        request: &ApprovalRequest,
     ) -> Box<dyn Future<Item = bool, Error = io::Error>> {
-        let site_name = try_reverse_app_id(application).unwrap_or(String::from("site"));
-        let message = format!("Register with {}", site_name);
-        self.test_user_presence(&message)
-    }
-
-    fn approve_authentication(
-        &self,
-        application: &AppId,
-    ) -> Box<dyn Future<Item = bool, Error = io::Error>> {
-        let site_name = try_reverse_app_id(application).unwrap_or(String::from("site"));
-        let message = format!("Authenticate with {}", site_name);
+        let site_name = request.facet.as_ref().map_or("site", String::as_str);
+        let message = match request.operation {
+            Operation::Register => format!("Register with {}", site_name),
+            Operation::Authenticate => format!("Authenticate with {}", site_name),
+        };
         self.test_user_presence(&message)
     }
 
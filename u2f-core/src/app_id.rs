@@ -1,10 +1,11 @@
 use std::result::Result;
 
 use hex;
+use ring::digest;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_base64::{from_base64, to_base64};
 use slog;
-use subtle::ConstantTimeEq;
+use subtle::{Choice, ConstantTimeEq};
 
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub struct AppId(pub(crate) [u8; 32]);
@@ -16,13 +17,34 @@ impl AppId {
         AppId(bytes)
     }
 
+    /// Application parameter for a facet or AppID URL, the SHA-256 hash of the URL string
+    pub fn from_url(url: &str) -> AppId {
+        AppId::from_bytes(digest::digest(&digest::SHA256, url.as_bytes()).as_ref())
+    }
+
+    /// The 32-byte application parameter, as it appears in register and authenticate
+    /// requests and in what they sign
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
     pub fn eq_consttime(&self, other: &AppId) -> bool {
-        self.0.ct_eq(&other.0).unwrap_u8() == 1
+        self.ct_eq(other).into()
     }
 
     pub fn to_base64(&self) -> String {
         base64::encode(&self.0)
     }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+}
+
+impl ConstantTimeEq for AppId {
+    fn ct_eq(&self, other: &AppId) -> Choice {
+        self.0.ct_eq(&other.0)
+    }
 }
 
 impl AsRef<[u8]> for AppId {
@@ -1,24 +1,143 @@
 use std::fmt::{self, Debug};
+
+use openssl::ec::EcKey;
+use openssl::error::ErrorStack;
+use openssl::pkey::PKey;
 use openssl::x509::X509;
 
 use private_key::PrivateKey;
 
-#[derive(Clone)]
+quick_error! {
+    #[derive(Debug)]
+    pub enum AttestationError {
+        OpenSSL(err: ErrorStack) {
+            from()
+            cause(err)
+            display("OpenSSL error: {}", err)
+        }
+        EmptyChain {
+            display("Attestation certificate chain is empty")
+        }
+        KeyMismatch {
+            display("Attestation key does not match the attestation certificate")
+        }
+    }
+}
+
+/// Certificate and private key used to sign registrations
+///
+/// Only the leaf certificate is sent in U2F registration responses, any intermediate
+/// certificates are kept so that they can be provided to relying parties out of band.
+#[derive(Clone, Debug)]
 pub struct Attestation {
     pub(crate) certificate: AttestationCertificate,
+    pub(crate) intermediates: Vec<AttestationCertificate>,
     pub(crate) key: PrivateKey,
 }
 
+impl Attestation {
+    /// Build from a DER encoded certificate chain, leaf first, and a DER encoded EC private key
+    pub fn from_der<C: AsRef<[u8]>>(
+        chain: &[C],
+        key: &[u8],
+    ) -> Result<Attestation, AttestationError> {
+        let chain = chain
+            .iter()
+            .map(|der| X509::from_der(der.as_ref()).map(AttestationCertificate::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKey(EcKey::private_key_from_der(key)?);
+        Attestation::new(chain, key)
+    }
+
+    /// Build from PEM encoded certificates, leaf first, and a PEM encoded EC private key
+    pub fn from_pem(chain: &[u8], key: &[u8]) -> Result<Attestation, AttestationError> {
+        let chain = X509::stack_from_pem(chain)?
+            .into_iter()
+            .map(AttestationCertificate::new)
+            .collect();
+        let key = PrivateKey(EcKey::private_key_from_pem(key)?);
+        Attestation::new(chain, key)
+    }
+
+    fn new(
+        mut chain: Vec<AttestationCertificate>,
+        key: PrivateKey,
+    ) -> Result<Attestation, AttestationError> {
+        if chain.is_empty() {
+            return Err(AttestationError::EmptyChain);
+        }
+        let certificate = chain.remove(0);
+        let private_key = PKey::from_ec_key(key.0.to_owned())?;
+        if !certificate.0.public_key()?.public_eq(&private_key) {
+            return Err(AttestationError::KeyMismatch);
+        }
+        Ok(Attestation {
+            certificate,
+            intermediates: chain,
+            key,
+        })
+    }
+
+    pub fn certificate(&self) -> &AttestationCertificate {
+        &self.certificate
+    }
+
+    pub fn intermediates(&self) -> &[AttestationCertificate] {
+        &self.intermediates
+    }
+
+    /// DER encoding of the leaf certificate, as sent in registration responses
+    pub fn certificate_der(&self) -> &[u8] {
+        &self.certificate.1
+    }
+
+    /// AAGUID in the leaf certificate's FIDO extension, all zeros if it has none
+    pub fn attestation_aaguid(&self) -> [u8; 16] {
+        aaguid_from_der(&self.certificate.1).unwrap_or([0u8; 16])
+    }
+}
+
+/// id-fido-gen-ce-aaguid (1.3.6.1.4.1.45724.1.1.4), DER encoded
+const AAGUID_EXTENSION_OID_DER: [u8; 13] = [
+    0x06, 0x0b, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0xe5, 0x1c, 0x01, 0x01, 0x04,
+];
+
+/// The extension's value is an OCTET STRING holding an OCTET STRING of the 16 AAGUID bytes
+const AAGUID_EXTENSION_VALUE_HEADER: [u8; 4] = [0x04, 0x12, 0x04, 0x10];
+
+/// Find the AAGUID extension in a DER encoded certificate
+///
+/// The extension must not be critical, so the OID is directly followed by its value.
+fn aaguid_from_der(der: &[u8]) -> Option<[u8; 16]> {
+    let prefix_len = AAGUID_EXTENSION_OID_DER.len() + AAGUID_EXTENSION_VALUE_HEADER.len();
+    der.windows(prefix_len + 16).find_map(|window| {
+        let (oid, rest) = window.split_at(AAGUID_EXTENSION_OID_DER.len());
+        let (header, value) = rest.split_at(AAGUID_EXTENSION_VALUE_HEADER.len());
+        if oid != AAGUID_EXTENSION_OID_DER || header != AAGUID_EXTENSION_VALUE_HEADER {
+            return None;
+        }
+        let mut aaguid = [0u8; 16];
+        aaguid.copy_from_slice(value);
+        Some(aaguid)
+    })
+}
+
+/// Certificate along with its DER encoding, kept so it can be borrowed
 #[derive(Clone)]
-pub struct AttestationCertificate(pub(crate) X509);
+pub struct AttestationCertificate(pub(crate) X509, Vec<u8>);
 
 impl AttestationCertificate {
+    pub(crate) fn new(certificate: X509) -> AttestationCertificate {
+        let der = certificate.to_der().unwrap();
+        AttestationCertificate(certificate, der)
+    }
+
     pub(crate) fn from_pem(pem: &str) -> AttestationCertificate {
-        AttestationCertificate(X509::from_pem(pem.as_bytes()).unwrap())
+        AttestationCertificate::new(X509::from_pem(pem.as_bytes()).unwrap())
     }
 
-    pub(crate) fn to_der(&self) -> Vec<u8> {
-        self.0.to_der().unwrap()
+    pub fn to_der(&self) -> Vec<u8> {
+        self.1.clone()
     }
 }
 
@@ -27,3 +146,75 @@ impl Debug for AttestationCertificate {
         write!(f, "AttestationCertificate")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use self_signed_attestation::{self_signed_attestation, SelfSigned, SOFT_U2F_AAGUID};
+
+    use super::*;
+
+    #[test]
+    fn from_der_round_trips_generated_attestation() {
+        let generated = SelfSigned::generate();
+        let key = generated.key.0.private_key_to_der().unwrap();
+
+        let attestation = Attestation::from_der(&[generated.certificate.to_der()], &key).unwrap();
+
+        assert_eq!(
+            attestation.certificate.to_der(),
+            generated.certificate.to_der()
+        );
+        assert!(attestation.intermediates().is_empty());
+    }
+
+    #[test]
+    fn from_der_with_other_key_is_key_mismatch() {
+        let certificate = SelfSigned::generate().certificate.to_der();
+        let key = self_signed_attestation()
+            .key
+            .0
+            .private_key_to_der()
+            .unwrap();
+
+        let result = Attestation::from_der(&[certificate], &key);
+
+        assert_matches!(result, Err(AttestationError::KeyMismatch));
+    }
+
+    #[test]
+    fn from_der_with_empty_chain_errors() {
+        let key = self_signed_attestation()
+            .key
+            .0
+            .private_key_to_der()
+            .unwrap();
+
+        let result = Attestation::from_der::<Vec<u8>>(&[], &key);
+
+        assert_matches!(result, Err(AttestationError::EmptyChain));
+    }
+
+    #[test]
+    fn generated_certificate_der_parses_as_x509() {
+        let attestation = SelfSigned::generate();
+
+        let certificate = X509::from_der(attestation.certificate_der()).unwrap();
+
+        assert_eq!(certificate.to_der().unwrap(), attestation.certificate_der());
+    }
+
+    #[test]
+    fn generated_attestation_has_configured_aaguid() {
+        let aaguid = [0x42u8; 16];
+
+        let attestation = SelfSigned::generate_with_aaguid(aaguid);
+
+        assert_eq!(attestation.attestation_aaguid(), aaguid);
+        assert_eq!(SelfSigned::generate().attestation_aaguid(), SOFT_U2F_AAGUID);
+    }
+
+    #[test]
+    fn shared_attestation_has_no_aaguid() {
+        assert_eq!(self_signed_attestation().attestation_aaguid(), [0u8; 16]);
+    }
+}
@@ -0,0 +1,83 @@
+//! Minimal CBOR encoder, see RFC 7049
+//!
+//! Only what the token sends is covered: integers, byte and text strings, arrays,
+//! maps and booleans. Items are appended to a `Vec<u8>` in the order given, callers
+//! write map keys in canonical order themselves.
+
+pub const MAJOR_UNSIGNED: u8 = 0;
+pub const MAJOR_NEGATIVE: u8 = 1;
+pub const MAJOR_BYTES: u8 = 2;
+pub const MAJOR_TEXT: u8 = 3;
+pub const MAJOR_ARRAY: u8 = 4;
+pub const MAJOR_MAP: u8 = 5;
+
+const FALSE: u8 = 0xf4;
+const TRUE: u8 = 0xf5;
+
+/// Append the head of an item, `value` is its argument: the integer itself, a
+/// length or a number of entries, in the shortest encoding
+pub fn push_header(cbor: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    if value < 24 {
+        cbor.push(major | value as u8);
+    } else if value <= u64::from(u8::MAX) {
+        cbor.push(major | 24);
+        cbor.push(value as u8);
+    } else if value <= u64::from(u16::MAX) {
+        cbor.push(major | 25);
+        cbor.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= u64::from(u32::MAX) {
+        cbor.push(major | 26);
+        cbor.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        cbor.push(major | 27);
+        cbor.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+pub fn push_int(cbor: &mut Vec<u8>, value: i64) {
+    if value < 0 {
+        push_header(cbor, MAJOR_NEGATIVE, (-1 - value) as u64);
+    } else {
+        push_header(cbor, MAJOR_UNSIGNED, value as u64);
+    }
+}
+
+pub fn push_bytes(cbor: &mut Vec<u8>, bytes: &[u8]) {
+    push_header(cbor, MAJOR_BYTES, bytes.len() as u64);
+    cbor.extend_from_slice(bytes);
+}
+
+pub fn push_text(cbor: &mut Vec<u8>, text: &str) {
+    push_header(cbor, MAJOR_TEXT, text.len() as u64);
+    cbor.extend_from_slice(text.as_bytes());
+}
+
+pub fn push_bool(cbor: &mut Vec<u8>, value: bool) {
+    cbor.push(if value { TRUE } else { FALSE });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_uses_shortest_encoding() {
+        let mut cbor = Vec::new();
+        push_header(&mut cbor, MAJOR_BYTES, 23);
+        push_header(&mut cbor, MAJOR_BYTES, 24);
+        push_header(&mut cbor, MAJOR_BYTES, 256);
+
+        assert_eq!(cbor, vec![0x57, 0x58, 0x18, 0x59, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn negative_int_is_encoded_as_minus_one_minus_argument() {
+        let mut cbor = Vec::new();
+        push_int(&mut cbor, -1);
+        push_int(&mut cbor, -7);
+        push_int(&mut cbor, 2);
+
+        assert_eq!(cbor, vec![0x20, 0x26, 0x02]);
+    }
+}
@@ -4,12 +4,16 @@ pub(crate) const VERSION_COMMAND_CODE: u8 = 0x03;
 pub(crate) const VENDOR_FIRST_COMMAND_CODE: u8 = 0x40;
 pub(crate) const VENDOR_LAST_COMMAND_CODE: u8 = 0xbf;
 
+/// Version string answered to the VERSION command, as required by the U2F raw message spec
+pub const U2F_VERSION: &str = "U2F_V2";
+
 pub(crate) const SW_NO_ERROR: u16 = 0x9000; // The command completed successfully without error.
 pub(crate) const SW_WRONG_DATA: u16 = 0x6A80; // The request was rejected due to an invalid key handle.
 pub(crate) const SW_CONDITIONS_NOT_SATISFIED: u16 = 0x6985; // The request was rejected due to test-of-user-presence being required.
 pub(crate) const SW_COMMAND_NOT_ALLOWED: u16 = 0x6986;
 pub(crate) const SW_INS_NOT_SUPPORTED: u16 = 0x6D00; // The Instruction of the request is not supported.
 pub(crate) const SW_WRONG_LENGTH: u16 = 0x6700; // The length of the request was invalid.
+pub(crate) const SW_WRONG_P1P2: u16 = 0x6A86; // The parameters P1 or P2 of the request are invalid.
 pub(crate) const SW_CLA_NOT_SUPPORTED: u16 = 0x6E00; // The Class byte of the request is not supported.
 pub(crate) const SW_UNKNOWN: u16 = 0x6F00; // Response status : No precise diagnosis
 
@@ -21,3 +25,4 @@ pub(crate) const DEFAULT_KEY_HANDLE_LEN: usize = 255;
 pub(crate) const MAX_KEY_HANDLE_LEN: usize = 255;
 
 pub(crate) const EC_POINT_FORMAT_UNCOMPRESSED: u8 = 0x04;
+pub(crate) const REGISTRATION_RESERVED_BYTE: u8 = 0x05; // Legacy reserved byte leading a registration response
@@ -0,0 +1,168 @@
+//! Events for an approval or notification UI
+//!
+//! `event_channel` returns an `EventPresence`, to give to `U2F` in place of another
+//! `UserPresence`, and the `EventStream` the UI consumes. Every test of user presence
+//! becomes a `ServiceEvent::PresenceRequested` the UI answers through its responder,
+//! completed requests are reported so the UI can confirm them to the user.
+use std::io;
+
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future, Poll, Stream};
+
+use app_id::AppId;
+use user_presence::{ApprovalRequest, Operation, UserPresence};
+
+pub enum ServiceEvent {
+    /// The user must approve or deny the operation through `responder`
+    PresenceRequested {
+        op: Operation,
+        app_id: AppId,
+        /// Display name of the application, if it is a well-known one
+        facet_name: Option<String>,
+        responder: PresenceResponder,
+    },
+    Registered {
+        app_id: AppId,
+    },
+    Authenticated {
+        app_id: AppId,
+    },
+}
+
+/// Answers one presence request, dropping it unanswered denies the request
+pub struct PresenceResponder(oneshot::Sender<bool>);
+
+impl PresenceResponder {
+    pub fn approve(self) {
+        self.respond(true)
+    }
+
+    pub fn deny(self) {
+        self.respond(false)
+    }
+
+    pub fn respond(self, approved: bool) {
+        // The request was abandoned, e.g. the browser gave up, if nobody is listening
+        let _ = self.0.send(approved);
+    }
+}
+
+/// `UserPresence` that asks the UI listening on the matching `EventStream`
+///
+/// Requests are denied while nothing is listening.
+#[derive(Clone)]
+pub struct EventPresence(mpsc::UnboundedSender<ServiceEvent>);
+
+/// Events of the `U2F` instance the matching `EventPresence` was given to
+pub struct EventStream(mpsc::UnboundedReceiver<ServiceEvent>);
+
+pub fn event_channel() -> (EventPresence, EventStream) {
+    let (sender, receiver) = mpsc::unbounded();
+    (EventPresence(sender), EventStream(receiver))
+}
+
+impl EventPresence {
+    fn send(&self, event: ServiceEvent) -> bool {
+        self.0.unbounded_send(event).is_ok()
+    }
+}
+
+impl UserPresence for EventPresence {
+    fn approve(
+        &self,
+        request: &ApprovalRequest,
+    ) -> Box<dyn Future<Item = bool, Error = io::Error>> {
+        let (sender, receiver) = oneshot::channel();
+        let sent = self.send(ServiceEvent::PresenceRequested {
+            op: request.operation,
+            app_id: request.application,
+            facet_name: request.facet.clone(),
+            responder: PresenceResponder(sender),
+        });
+        if !sent {
+            return Box::new(future::ok(false));
+        }
+        // A dropped responder is a denial
+        Box::new(receiver.or_else(|oneshot::Canceled| Ok(false)))
+    }
+
+    fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        Box::new(future::ok(()))
+    }
+
+    fn registered(&self, application: &AppId) {
+        self.send(ServiceEvent::Registered {
+            app_id: *application,
+        });
+    }
+
+    fn authenticated(&self, application: &AppId) {
+        self.send(ServiceEvent::Authenticated {
+            app_id: *application,
+        });
+    }
+}
+
+impl Stream for EventStream {
+    type Item = ServiceEvent;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<ServiceEvent>, ()> {
+        self.0.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use self_signed_attestation::self_signed_attestation;
+    use {Challenge, InMemoryStore, SecureCryptoOperations, U2F};
+
+    #[test]
+    fn registration_is_approved_through_events() {
+        let (presence, events) = event_channel();
+        let u2f = U2F::new(
+            Box::new(presence),
+            Box::new(SecureCryptoOperations::new(self_signed_attestation())),
+            Box::new(InMemoryStore::new()),
+            None,
+        )
+        .unwrap();
+        let application = AppId::from_bytes(&[0x22; 32]);
+        let mut events = events.wait();
+
+        let registration = u2f.register(application, Challenge([0x11; 32]));
+        match events.next() {
+            Some(Ok(ServiceEvent::PresenceRequested {
+                op: Operation::Register,
+                app_id,
+                responder,
+                ..
+            })) => {
+                assert_eq!(app_id, application);
+                responder.approve();
+            }
+            _ => panic!("Expected a presence request"),
+        }
+        registration.wait().unwrap();
+
+        match events.next() {
+            Some(Ok(ServiceEvent::Registered { app_id })) => assert_eq!(app_id, application),
+            _ => panic!("Expected a registered event"),
+        }
+    }
+
+    #[test]
+    fn dropped_responder_denies() {
+        let (presence, events) = event_channel();
+        let mut events = events.wait();
+
+        let approval = presence.approve(&ApprovalRequest::new(
+            Operation::Authenticate,
+            AppId::from_bytes(&[0x22; 32]),
+        ));
+        drop(events.next());
+
+        assert!(!approval.wait().unwrap());
+    }
+}
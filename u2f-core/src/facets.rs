@@ -0,0 +1,197 @@
+use serde_json;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum FacetListError {
+        Json(err: serde_json::Error) {
+            from()
+            cause(err)
+            display("Invalid trusted facets document: {}", err)
+        }
+        UnsupportedVersion {
+            display("Trusted facets document has no list for version 1.0")
+        }
+    }
+}
+
+/// Trusted facet list of an AppID, parsed from the JSON document the AppID URL points to
+///
+/// Fetching the document is left to the caller, see the FIDO AppID and Facet
+/// specification for how that must be done.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FacetList {
+    ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct TrustedFacetsDocument {
+    #[serde(rename = "trustedFacets")]
+    trusted_facets: Vec<TrustedFacets>,
+}
+
+#[derive(Deserialize)]
+struct TrustedFacets {
+    version: Version,
+    #[serde(default)]
+    ids: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Version {
+    major: u32,
+    minor: u32,
+}
+
+impl FacetList {
+    /// Parse a `trustedFacets` document, keeping the ids listed for version 1.0
+    ///
+    /// Web facets that are not valid `https` origins are ignored, as the specification
+    /// requires, other facets (e.g. `android:apk-key-hash:...`) are kept as is.
+    pub fn from_json(json: &str) -> Result<FacetList, FacetListError> {
+        let document: TrustedFacetsDocument = serde_json::from_str(json)?;
+        let facets = document
+            .trusted_facets
+            .into_iter()
+            .find(|facets| facets.version.major == 1 && facets.version.minor == 0)
+            .ok_or(FacetListError::UnsupportedVersion)?;
+        let ids = facets
+            .ids
+            .into_iter()
+            .filter(|id| !is_web_facet(id) || WebOrigin::parse(id).is_some())
+            .collect();
+        Ok(FacetList { ids })
+    }
+
+    pub fn ids(&self) -> &[String] {
+        &self.ids
+    }
+
+    /// Whether the caller's facet is trusted
+    ///
+    /// Web origins match when scheme, host and port are the same, the host compared
+    /// case-insensitively and a missing port being the scheme's default. Only `https`
+    /// origins can match. Other facets must equal a listed id exactly.
+    pub fn contains_facet(&self, origin: &str) -> bool {
+        if !is_web_facet(origin) {
+            return self.ids.iter().any(|id| id == origin);
+        }
+        match WebOrigin::parse(origin) {
+            Some(origin) => self
+                .ids
+                .iter()
+                .filter_map(|id| WebOrigin::parse(id))
+                .any(|id| id == origin),
+            None => false,
+        }
+    }
+}
+
+fn is_web_facet(facet: &str) -> bool {
+    facet.contains("://")
+}
+
+/// `https` origin, scheme and host lowercased and the port always given
+#[derive(Debug, Eq, PartialEq)]
+struct WebOrigin {
+    host: String,
+    port: u16,
+}
+
+impl WebOrigin {
+    fn parse(origin: &str) -> Option<WebOrigin> {
+        let separator = origin.find("://")?;
+        if !origin[..separator].eq_ignore_ascii_case("https") {
+            return None;
+        }
+        let rest = &origin[separator + 3..];
+        let authority = match rest.find('/') {
+            // Facets are origins, a path other than '/' makes this some other URL
+            Some(index) if &rest[index..] != "/" => return None,
+            Some(index) => &rest[..index],
+            None => rest,
+        };
+        if authority.contains('@') {
+            return None;
+        }
+        let (host, port) = split_port(authority)?;
+        if host.is_empty() {
+            return None;
+        }
+        Some(WebOrigin {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+fn split_port(authority: &str) -> Option<(&str, u16)> {
+    // An IPv6 literal contains colons itself, the port follows the closing bracket
+    let host_end = if authority.starts_with('[') {
+        authority.find(']')? + 1
+    } else {
+        authority.find(':').unwrap_or(authority.len())
+    };
+    let (host, port) = authority.split_at(host_end);
+    if port.is_empty() {
+        return Some((host, 443));
+    }
+    let port = port.strip_prefix(':')?;
+    port.parse().ok().map(|port| (host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FACETS: &str = r#"{
+        "trustedFacets": [{
+            "version": { "major": 1, "minor": 0 },
+            "ids": [
+                "https://accounts.example.com",
+                "https://login.example.com:8443",
+                "http://insecure.example.com",
+                "android:apk-key-hash:585215fd5153209a7e246f53286035838a0be227"
+            ]
+        }]
+    }"#;
+
+    #[test]
+    fn listed_facets_match() {
+        let facets = FacetList::from_json(FACETS).unwrap();
+
+        assert!(facets.contains_facet("https://accounts.example.com"));
+        assert!(facets.contains_facet("https://Accounts.Example.com:443"));
+        assert!(facets.contains_facet("https://login.example.com:8443"));
+        assert!(
+            facets.contains_facet("android:apk-key-hash:585215fd5153209a7e246f53286035838a0be227")
+        );
+    }
+
+    #[test]
+    fn cross_origin_facets_are_rejected() {
+        let facets = FacetList::from_json(FACETS).unwrap();
+
+        assert!(!facets.contains_facet("https://evil.example.com"));
+        assert!(!facets.contains_facet("https://accounts.example.com.evil.com"));
+        assert!(!facets.contains_facet("https://login.example.com"));
+        assert!(!facets.contains_facet("http://accounts.example.com"));
+        assert!(!facets.contains_facet("http://insecure.example.com"));
+    }
+
+    #[test]
+    fn insecure_web_facets_are_ignored() {
+        let facets = FacetList::from_json(FACETS).unwrap();
+
+        assert_eq!(facets.ids().len(), 3);
+    }
+
+    #[test]
+    fn document_without_version_1_0_is_rejected() {
+        let json = r#"{"trustedFacets": [{"version": {"major": 2, "minor": 0}, "ids": []}]}"#;
+
+        assert_matches!(
+            FacetList::from_json(json),
+            Err(FacetListError::UnsupportedVersion)
+        );
+    }
+}
@@ -1,12 +1,54 @@
 use std::fmt::{self, Debug};
 use std::result::Result;
 
+use app_id::AppId;
 use constants::{DEFAULT_KEY_HANDLE_LEN, MAX_KEY_HANDLE_LEN};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::symm::{self, Cipher};
+use private_key::PrivateKey;
 use rand::distributions::{Distribution, Standard};
 use rand::Rng;
+use ring::{digest, hmac};
+use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_base64::{from_base64, to_base64};
-use subtle::ConstantTimeEq;
+use subtle::{Choice, ConstantTimeEq};
+
+/// How a key handle leads to its private key, given by the handle's first byte
+///
+/// Handles issued before the format byte was added are random bytes without one.
+/// Stores look handles up by value, so those keep working whatever their first byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyHandleFormat {
+    /// Random bytes naming a key kept in the `SecretStore`
+    Stored,
+    /// The private key itself, sealed under the `MasterKey` by `KeyHandle::wrap`
+    Wrapped,
+}
+
+impl KeyHandleFormat {
+    fn tag(self) -> u8 {
+        match self {
+            KeyHandleFormat::Stored => FORMAT_STORED,
+            KeyHandleFormat::Wrapped => FORMAT_WRAPPED,
+        }
+    }
+}
+
+quick_error! {
+    #[derive(Debug, PartialEq)]
+    pub enum KeyHandleError {
+        Empty {
+            display("Key handle is empty")
+        }
+        UnknownFormat(tag: u8) {
+            display("Unknown key handle format {:#04x}", tag)
+        }
+    }
+}
 
 #[derive(Clone, Eq, PartialEq)]
 pub struct KeyHandle(Vec<u8>);
@@ -17,13 +59,225 @@ impl KeyHandle {
         KeyHandle(bytes.to_vec())
     }
 
+    /// Format named by the first byte, a wrapped handle must also have the length
+    /// `wrap` gives it
+    pub fn format(&self) -> Result<KeyHandleFormat, KeyHandleError> {
+        match self.0.first() {
+            None => Err(KeyHandleError::Empty),
+            Some(&FORMAT_STORED) => Ok(KeyHandleFormat::Stored),
+            Some(&FORMAT_WRAPPED) if self.0.len() == WRAPPED_KEY_HANDLE_LEN => {
+                Ok(KeyHandleFormat::Wrapped)
+            }
+            Some(&tag) => Err(KeyHandleError::UnknownFormat(tag)),
+        }
+    }
+
+    /// Random handle naming a key kept in the `SecretStore`, ending in tags that bind
+    /// it to this token and to `application`
+    ///
+    /// Both tags are HMACs under a key derived from the master key, so only the token
+    /// itself can tell which application a handle was issued to, see `issued_to`.
+    pub fn stored<R: Rng + ?Sized>(
+        application: &AppId,
+        master_key: &MasterKey,
+        rng: &mut R,
+    ) -> KeyHandle {
+        let mut bytes = Vec::with_capacity(DEFAULT_KEY_HANDLE_LEN);
+        bytes.push(KeyHandleFormat::Stored.tag());
+        bytes.extend((0..STORED_RANDOM_LEN).map(|_| rng.gen::<u8>()));
+        let binding = BindingKey::new(master_key);
+        let issuer_tag = binding.issuer_tag(&bytes[1..]);
+        let application_tag = binding.application_tag(&bytes[1..], application);
+        bytes.extend_from_slice(&issuer_tag.as_ref()[..BINDING_TAG_LEN]);
+        bytes.extend_from_slice(&application_tag.as_ref()[..BINDING_TAG_LEN]);
+        KeyHandle(bytes)
+    }
+
+    /// Whether a handle created by `stored` under `master_key` was bound to
+    /// `application`, `None` for any handle not created that way
+    ///
+    /// Handles stored before they were bound, or issued by another token, are `None`.
+    /// Takes the same time whichever application the handle was bound to.
+    pub fn issued_to(&self, application: &AppId, master_key: &MasterKey) -> Option<bool> {
+        if self.format() != Ok(KeyHandleFormat::Stored) || self.0.len() != DEFAULT_KEY_HANDLE_LEN {
+            return None;
+        }
+        let (random, tags) = self.0[1..].split_at(STORED_RANDOM_LEN);
+        let (issuer_tag, application_tag) = tags.split_at(BINDING_TAG_LEN);
+        let binding = BindingKey::new(master_key);
+        let issued: bool = issuer_tag
+            .ct_eq(&binding.issuer_tag(random).as_ref()[..BINDING_TAG_LEN])
+            .into();
+        if !issued {
+            return None;
+        }
+        let expected = binding.application_tag(random, application);
+        Some(
+            application_tag
+                .ct_eq(&expected.as_ref()[..BINDING_TAG_LEN])
+                .into(),
+        )
+    }
+
     pub fn eq_consttime(&self, other: &KeyHandle) -> bool {
-        self.0.ct_eq(&other.0).unwrap_u8() == 1
+        self.ct_eq(other).into()
     }
 
     pub fn to_base64(&self) -> String {
         base64::encode(&self.0)
     }
+
+    /// Short hex digest telling handles apart in logs, the handle itself may wrap a key
+    pub fn fingerprint(&self) -> String {
+        let digest = digest::digest(&digest::SHA256, &self.0);
+        hex::encode(&digest.as_ref()[..FINGERPRINT_LEN])
+    }
+
+    /// Encrypt a private key into a key handle bound to an application
+    ///
+    /// The key is sealed with AES-256-GCM under the master key with the application id as
+    /// associated data, so the handle itself is the only state needed to authenticate.
+    /// The nonce is drawn from `rng`, which must be cryptographically secure.
+    pub fn wrap<R: Rng + ?Sized>(
+        application: &AppId,
+        key: &PrivateKey,
+        master_key: &MasterKey,
+        rng: &mut R,
+    ) -> Result<KeyHandle, ErrorStack> {
+        let scalar = key
+            .0
+            .private_key()
+            .to_vec_padded(PRIVATE_SCALAR_LEN as i32)?;
+        let nonce: [u8; WRAP_NONCE_LEN] = rng.gen();
+        let mut tag = [0u8; WRAP_TAG_LEN];
+        let ciphertext = symm::encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &master_key.0,
+            Some(&nonce),
+            application.as_ref(),
+            &scalar,
+            &mut tag,
+        )?;
+
+        let mut bytes = Vec::with_capacity(WRAPPED_KEY_HANDLE_LEN);
+        bytes.push(KeyHandleFormat::Wrapped.tag());
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&ciphertext);
+        bytes.extend_from_slice(&tag);
+        Ok(KeyHandle(bytes))
+    }
+
+    /// Recover the private key from a handle created by `wrap`
+    ///
+    /// Returns `None` if the handle is not in the wrapped format, was not wrapped with
+    /// this master key or was issued to a different application.
+    pub fn unwrap(
+        application: &AppId,
+        handle: &KeyHandle,
+        master_key: &MasterKey,
+    ) -> Option<PrivateKey> {
+        if handle.format() != Ok(KeyHandleFormat::Wrapped) {
+            return None;
+        }
+        let (nonce, rest) = handle.0[1..].split_at(WRAP_NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(PRIVATE_SCALAR_LEN);
+        let scalar = symm::decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &master_key.0,
+            Some(nonce),
+            application.as_ref(),
+            ciphertext,
+            tag,
+        )
+        .ok()?;
+        private_key_from_scalar(&scalar).ok()
+    }
+}
+
+const PRIVATE_SCALAR_LEN: usize = 32;
+const WRAP_NONCE_LEN: usize = 12;
+const WRAP_TAG_LEN: usize = 16;
+const WRAPPED_KEY_HANDLE_LEN: usize = 1 + WRAP_NONCE_LEN + PRIVATE_SCALAR_LEN + WRAP_TAG_LEN;
+const FORMAT_STORED: u8 = 0x01;
+const FORMAT_WRAPPED: u8 = 0x02;
+/// Bytes of the SHA-256 digest shown by `KeyHandle::fingerprint`
+const FINGERPRINT_LEN: usize = 8;
+/// Bytes of each HMAC-SHA256 tag ending a handle created by `KeyHandle::stored`
+const BINDING_TAG_LEN: usize = 16;
+const STORED_RANDOM_LEN: usize = DEFAULT_KEY_HANDLE_LEN - 1 - 2 * BINDING_TAG_LEN;
+/// Derives the binding key from the master key, which also seals wrapped handles
+const BINDING_KEY_LABEL: &[u8] = b"u2f key handle binding";
+const ISSUER_TAG_LABEL: u8 = 0x01;
+const APPLICATION_TAG_LABEL: u8 = 0x02;
+
+/// Key of the tags binding stored handles to this token and their application
+struct BindingKey(hmac::Key);
+
+impl BindingKey {
+    fn new(master_key: &MasterKey) -> BindingKey {
+        let master = hmac::Key::new(hmac::HMAC_SHA256, &master_key.0);
+        let derived = hmac::sign(&master, BINDING_KEY_LABEL);
+        BindingKey(hmac::Key::new(hmac::HMAC_SHA256, derived.as_ref()))
+    }
+
+    fn issuer_tag(&self, random: &[u8]) -> hmac::Tag {
+        let mut context = hmac::Context::with_key(&self.0);
+        context.update(&[ISSUER_TAG_LABEL]);
+        context.update(random);
+        context.sign()
+    }
+
+    fn application_tag(&self, random: &[u8], application: &AppId) -> hmac::Tag {
+        let mut context = hmac::Context::with_key(&self.0);
+        context.update(&[APPLICATION_TAG_LABEL]);
+        context.update(random);
+        context.update(application.as_ref());
+        context.sign()
+    }
+}
+
+pub(crate) fn private_key_from_scalar(scalar: &[u8]) -> Result<PrivateKey, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let private_number = BigNum::from_slice(scalar)?;
+    let mut context = BigNumContext::new()?;
+    let mut public_point = EcPoint::new(&group)?;
+    public_point.mul_generator2(&group, &private_number, &mut context)?;
+    let key = EcKey::from_private_components(&group, &private_number, &public_point)?;
+    key.check_key()?;
+    Ok(PrivateKey(key))
+}
+
+/// Device secret used to wrap private keys into key handles
+pub struct MasterKey([u8; 32]);
+
+impl MasterKey {
+    pub fn from_bytes(bytes: [u8; 32]) -> MasterKey {
+        MasterKey(bytes)
+    }
+}
+
+impl Distribution<MasterKey> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> MasterKey {
+        MasterKey(rng.gen())
+    }
+}
+
+impl Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MasterKey(<redacted>)")
+    }
+}
+
+impl ConstantTimeEq for KeyHandle {
+    /// Handles are padded to the maximum length before comparing, so a length mismatch
+    /// takes as long to detect as a mismatch in the last byte.
+    fn ct_eq(&self, other: &KeyHandle) -> Choice {
+        let mut padded = [0u8; MAX_KEY_HANDLE_LEN];
+        let mut other_padded = [0u8; MAX_KEY_HANDLE_LEN];
+        padded[..self.0.len()].copy_from_slice(&self.0);
+        other_padded[..other.0.len()].copy_from_slice(&other.0);
+        (self.0.len() as u64).ct_eq(&(other.0.len() as u64)) & padded[..].ct_eq(&other_padded[..])
+    }
 }
 
 impl AsRef<[u8]> for KeyHandle {
@@ -34,13 +288,16 @@ impl AsRef<[u8]> for KeyHandle {
 
 impl Distribution<KeyHandle> for Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> KeyHandle {
-        KeyHandle(Standard.sample_iter(rng).take(DEFAULT_KEY_HANDLE_LEN).collect())
+        let mut bytes = Vec::with_capacity(DEFAULT_KEY_HANDLE_LEN);
+        bytes.push(KeyHandleFormat::Stored.tag());
+        bytes.extend((1..DEFAULT_KEY_HANDLE_LEN).map(|_| rng.gen::<u8>()));
+        KeyHandle(bytes)
     }
 }
 
 impl Debug for KeyHandle {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "KeyHandle")
+        write!(f, "KeyHandle({})", self.fingerprint())
     }
 }
 
@@ -58,6 +315,151 @@ impl<'de> Deserialize<'de> for KeyHandle {
     where
         D: Deserializer<'de>,
     {
-        Ok(KeyHandle(from_base64(deserializer)?))
+        let bytes: Vec<u8> = from_base64(deserializer)?;
+        if bytes.len() > MAX_KEY_HANDLE_LEN {
+            return Err(D::Error::custom("key handle too long"));
+        }
+        Ok(KeyHandle(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrap(application: &AppId, key: &PrivateKey, master_key: &MasterKey) -> KeyHandle {
+        KeyHandle::wrap(application, key, master_key, &mut rand::thread_rng()).unwrap()
+    }
+
+    fn generate_key() -> PrivateKey {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PrivateKey(EcKey::generate(&group).unwrap())
+    }
+
+    #[test]
+    fn unwrap_recovers_wrapped_key() {
+        let master_key: MasterKey = rand::random();
+        let application = AppId([7u8; 32]);
+        let key = generate_key();
+
+        let handle = wrap(&application, &key, &master_key);
+        let unwrapped = KeyHandle::unwrap(&application, &handle, &master_key).unwrap();
+
+        assert_eq!(
+            unwrapped.0.private_key().to_vec(),
+            key.0.private_key().to_vec()
+        );
+    }
+
+    #[test]
+    fn unwrap_with_other_app_id_is_none() {
+        let master_key: MasterKey = rand::random();
+        let handle = wrap(&AppId([7u8; 32]), &generate_key(), &master_key);
+
+        assert!(KeyHandle::unwrap(&AppId([8u8; 32]), &handle, &master_key).is_none());
+    }
+
+    #[test]
+    fn unwrap_with_other_master_key_is_none() {
+        let application = AppId([7u8; 32]);
+        let handle = wrap(&application, &generate_key(), &rand::random());
+
+        assert!(KeyHandle::unwrap(&application, &handle, &rand::random()).is_none());
+    }
+
+    #[test]
+    fn wrapped_handle_has_wrapped_format() {
+        let handle = wrap(&AppId([7u8; 32]), &generate_key(), &rand::random());
+
+        assert_eq!(handle.format(), Ok(KeyHandleFormat::Wrapped));
+        assert!(handle.as_ref().len() <= MAX_KEY_HANDLE_LEN);
+    }
+
+    #[test]
+    fn random_handle_has_stored_format() {
+        let handle: KeyHandle = rand::random();
+
+        assert_eq!(handle.format(), Ok(KeyHandleFormat::Stored));
+        assert_eq!(handle.as_ref().len(), DEFAULT_KEY_HANDLE_LEN);
+    }
+
+    #[test]
+    fn stored_handle_is_issued_to_its_application_only() {
+        let master_key: MasterKey = rand::random();
+        let application = AppId([7u8; 32]);
+        let handle = KeyHandle::stored(&application, &master_key, &mut rand::thread_rng());
+
+        assert_eq!(handle.format(), Ok(KeyHandleFormat::Stored));
+        assert_eq!(handle.as_ref().len(), DEFAULT_KEY_HANDLE_LEN);
+        assert_eq!(handle.issued_to(&application, &master_key), Some(true));
+        assert_eq!(
+            handle.issued_to(&AppId([8u8; 32]), &master_key),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn issued_to_handles_not_bound_under_master_key_is_none() {
+        let master_key: MasterKey = rand::random();
+        let application = AppId([7u8; 32]);
+        let other_token = KeyHandle::stored(&application, &rand::random(), &mut rand::thread_rng());
+        let unbound: KeyHandle = rand::random();
+        let wrapped = wrap(&application, &generate_key(), &master_key);
+
+        assert_eq!(other_token.issued_to(&application, &master_key), None);
+        assert_eq!(unbound.issued_to(&application, &master_key), None);
+        assert_eq!(wrapped.issued_to(&application, &master_key), None);
+        assert_eq!(
+            KeyHandle::from(&[1, 2, 3]).issued_to(&application, &master_key),
+            None
+        );
+    }
+
+    #[test]
+    fn unknown_format_is_rejected() {
+        let master_key: MasterKey = rand::random();
+        let application = AppId([7u8; 32]);
+        let handle = wrap(&application, &generate_key(), &master_key);
+        let mut retagged = handle.as_ref().to_vec();
+        retagged[0] = 0x7f;
+        let retagged = KeyHandle::from(&retagged);
+
+        assert_eq!(retagged.format(), Err(KeyHandleError::UnknownFormat(0x7f)));
+        assert!(KeyHandle::unwrap(&application, &retagged, &master_key).is_none());
+        assert_eq!(KeyHandle::from(&[]).format(), Err(KeyHandleError::Empty));
+    }
+
+    #[test]
+    fn unwrap_random_handle_is_none() {
+        let handle: KeyHandle = rand::random();
+
+        assert!(KeyHandle::unwrap(&AppId([7u8; 32]), &handle, &rand::random()).is_none());
+    }
+
+    #[test]
+    fn eq_consttime_with_different_length_is_false() {
+        let handle = KeyHandle::from(&[1, 2, 3]);
+
+        assert!(!handle.eq_consttime(&KeyHandle::from(&[1, 2])));
+        assert!(!handle.eq_consttime(&KeyHandle::from(&[1, 2, 3, 0])));
+        assert!(handle.eq_consttime(&KeyHandle::from(&[1, 2, 3])));
+    }
+
+    fn assert_constant_time_eq<T: ConstantTimeEq>() {}
+
+    #[test]
+    fn eq_consttime_goes_through_constant_time_eq() {
+        assert_constant_time_eq::<KeyHandle>();
+        let handle = KeyHandle::from(&[0x55; MAX_KEY_HANDLE_LEN]);
+        let mut last_byte_differs = [0x55; MAX_KEY_HANDLE_LEN];
+        last_byte_differs[MAX_KEY_HANDLE_LEN - 1] = 0xaa;
+
+        for other in &[
+            KeyHandle::from(&last_byte_differs),
+            KeyHandle::from(&[0x55]),
+            handle.clone(),
+        ] {
+            assert_eq!(handle.eq_consttime(other), bool::from(handle.ct_eq(other)));
+        }
     }
 }
@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 
 use app_id::AppId;
-use ring::digest;
 
 // Known bogus hash. Chrome will try to register with this hash after certain failures,
 // such as the common case of authentication failing because there are no matching keys.
@@ -16,8 +15,13 @@ pub const BOGUS_APP_ID_HASH: AppId = AppId([
     65u8, 65u8, 65u8, 65u8, 65u8, 65u8, 65u8, 65u8
 ]);
 
+/// Display name of a well-known application
+pub fn lookup(app_id: &AppId) -> Option<&'static str> {
+    KNOWN_APP_IDS.get(app_id).cloned()
+}
+
 pub fn try_reverse_app_id(app_id: &AppId) -> Option<String> {
-    KNOWN_APP_IDS.get(app_id).map(|s| String::from(*s))
+    lookup(app_id).map(String::from)
 }
 
 lazy_static! {
@@ -25,32 +29,65 @@ lazy_static! {
         let mut map = HashMap::new();
 
         // Should be kept in sync with https://github.com/github/SoftU2F/blob/master/SoftU2FTool/KnownFacets.swift
-        map.insert(from_url("https://github.com/u2f/trusted_facets"), "github.com");
-        map.insert(from_url("https://demo.yubico.com"), "demo.yubico.com");
-        map.insert(from_url("https://www.dropbox.com/u2f-app-id.json"), "dropbox.com");
-        map.insert(from_url("https://www.gstatic.com/securitykey/origins.json"), "google.com");
-        map.insert(from_url("https://vault.bitwarden.com/app-id.json"), "vault.bitwarden.com");
-        map.insert(from_url("https://keepersecurity.com"), "keepersecurity.com");
-        map.insert(from_url("https://api-9dcf9b83.duosecurity.com"), "duosecurity.com");
-        map.insert(from_url("https://dashboard.stripe.com"), "dashboard.stripe.com");
-        map.insert(from_url("https://id.fedoraproject.org/u2f-origins.json"), "id.fedoraproject.org");
-        map.insert(from_url("https://lastpass.com"), "lastpass.com");
+        map.insert(AppId::from_url("https://github.com/u2f/trusted_facets"), "github.com");
+        map.insert(AppId::from_url("https://demo.yubico.com"), "demo.yubico.com");
+        map.insert(AppId::from_url("https://www.dropbox.com/u2f-app-id.json"), "dropbox.com");
+        map.insert(AppId::from_url("https://www.gstatic.com/securitykey/origins.json"), "google.com");
+        map.insert(AppId::from_url("https://vault.bitwarden.com/app-id.json"), "vault.bitwarden.com");
+        map.insert(AppId::from_url("https://keepersecurity.com"), "keepersecurity.com");
+        map.insert(AppId::from_url("https://api-9dcf9b83.duosecurity.com"), "duosecurity.com");
+        map.insert(AppId::from_url("https://dashboard.stripe.com"), "dashboard.stripe.com");
+        map.insert(AppId::from_url("https://id.fedoraproject.org/u2f-origins.json"), "id.fedoraproject.org");
+        map.insert(AppId::from_url("https://lastpass.com"), "lastpass.com");
 
         // Additional known app IDs not yet in KnownFacets.swift
-        map.insert(from_url("bin.coffee"), "bin.coffee");
-        map.insert(from_url("coinbase.com"), "coinbase.com");
-        map.insert(from_url("demo.yubico.com"), "demo.yubico.com");
-        map.insert(from_url("https://gitlab.com"), "gitlab.com");
-        map.insert(from_url("https://mdp.github.io"), "mdp.github.io");
-        map.insert(from_url("https://u2f.bin.coffee"), "u2f.bin.coffee");
-        map.insert(from_url("https://www.fastmail.com"), "www.fastmail.com");
-        map.insert(from_url("webauthn.bin.coffee"), "webauthn.bin.coffee");
-        map.insert(from_url("webauthn.io"), "webauthn.io");
+        map.insert(AppId::from_url("bin.coffee"), "bin.coffee");
+        map.insert(AppId::from_url("coinbase.com"), "coinbase.com");
+        map.insert(AppId::from_url("demo.yubico.com"), "demo.yubico.com");
+        map.insert(AppId::from_url("https://gitlab.com"), "gitlab.com");
+        map.insert(AppId::from_url("https://mdp.github.io"), "mdp.github.io");
+        map.insert(AppId::from_url("https://u2f.bin.coffee"), "u2f.bin.coffee");
+        map.insert(AppId::from_url("https://www.fastmail.com"), "www.fastmail.com");
+        map.insert(AppId::from_url("webauthn.bin.coffee"), "webauthn.bin.coffee");
+        map.insert(AppId::from_url("webauthn.io"), "webauthn.io");
 
         map
     };
 }
 
-fn from_url(url: &str) -> AppId {
-    AppId::from_bytes(digest::digest(&digest::SHA256, url.as_bytes()).as_ref())
+#[cfg(test)]
+mod tests {
+    use hex;
+
+    use super::*;
+
+    fn app_id_from_hex(hex_str: &str) -> AppId {
+        AppId::from_bytes(&hex::decode(hex_str).unwrap())
+    }
+
+    #[test]
+    fn lookup_google() {
+        let app_id =
+            app_id_from_hex("a54672b222c4cf95e151ed8d4d3c767a6cc349435943794e884f3d023a8229fd");
+
+        assert_eq!(lookup(&app_id), Some("google.com"));
+    }
+
+    #[test]
+    fn lookup_github() {
+        let app_id =
+            app_id_from_hex("70617dfed065863af47c15556c91798880828cc407fdf70ae85011569465a075");
+
+        assert_eq!(lookup(&app_id), Some("github.com"));
+    }
+
+    #[test]
+    fn lookup_unknown_is_none() {
+        assert_eq!(lookup(&AppId::from_url("https://example.com")), None);
+    }
+
+    #[test]
+    fn bogus_app_id_is_not_known() {
+        assert_eq!(lookup(&BOGUS_APP_ID_HASH), None);
+    }
 }
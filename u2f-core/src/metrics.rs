@@ -0,0 +1,28 @@
+//! Hooks for monitoring a running token
+//!
+//! `U2F` calls into a `Metrics` implementation as requests complete, so counts and
+//! latencies can be exported to a monitoring system without this crate depending on
+//! one. Every method defaults to doing nothing, implementations only override the
+//! events they care about.
+use std::time::Duration;
+
+use request::ApduError;
+
+pub trait Metrics {
+    /// A new key was registered
+    fn on_register(&self) {}
+
+    /// An authentication was signed, `duration` is the time spent signing it
+    fn on_authenticate(&self, _duration: Duration) {}
+
+    /// The user did not approve a register or authenticate request
+    fn on_denied(&self) {}
+
+    /// A request APDU could not be decoded
+    fn on_decode_error(&self, _err: &ApduError) {}
+}
+
+/// Records nothing, used unless other metrics are given
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
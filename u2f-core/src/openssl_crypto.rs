@@ -1,15 +1,14 @@
+use std::cell::RefCell;
 use std::io;
 
 use app_id::AppId;
 use application_key::ApplicationKey;
 use attestation::{Attestation, AttestationCertificate};
-use key_handle::KeyHandle;
-use openssl::ec::{EcGroup, EcKey};
-use openssl::hash::MessageDigest;
-use openssl::nid::Nid;
-use openssl::pkey::PKey;
-use openssl::sign::Signer;
+use key_handle::{private_key_from_scalar, KeyHandle, KeyHandleFormat, MasterKey};
 use private_key::PrivateKey;
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore};
+use signature::EcdsaSignature;
 
 use super::CryptoOperations;
 use super::Signature;
@@ -17,23 +16,62 @@ use super::SignError;
 
 pub struct OpenSSLCryptoOperations {
     attestation: Attestation,
+    key_handle_format: KeyHandleFormat,
+    master_key: Option<MasterKey>,
+    rng: RefCell<Box<dyn RngCore>>,
 }
 
 impl OpenSSLCryptoOperations {
     pub fn new(attestation: Attestation) -> OpenSSLCryptoOperations {
+        OpenSSLCryptoOperations::with_rng(attestation, Box::new(OsRng))
+    }
+
+    /// Generate keys and key handles from `rng` instead of the OS
+    ///
+    /// Meant for tests that need reproducible registrations. Production use must
+    /// pass a cryptographically secure generator, anyone able to predict its
+    /// output can recover every private key.
+    pub fn with_rng(attestation: Attestation, rng: Box<dyn RngCore>) -> OpenSSLCryptoOperations {
         OpenSSLCryptoOperations {
             attestation: attestation,
+            key_handle_format: KeyHandleFormat::Stored,
+            master_key: None,
+            rng: RefCell::new(rng),
         }
     }
 
-    fn generate_key() -> PrivateKey {
-        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
-        let ec_key = EcKey::generate(&group).unwrap();
-        PrivateKey(ec_key)
+    /// Issue key handles of `format` under the device secret `master_key`
+    ///
+    /// A `KeyHandleFormat::Stored` handle is bound to its application under the master
+    /// key, an authentication with a handle issued to another application then fails
+    /// with `AuthenticateError::AppIdMismatch` instead of `UnknownHandle`.
+    /// A `KeyHandleFormat::Wrapped` handle holds its private key sealed under the master
+    /// key, registering stores nothing and the handle keeps working for as long as the
+    /// token is given the same master key. Authentications with wrapped handles are
+    /// signed with the store's global counter, see `CounterMode::Global`, as there is no
+    /// stored key to keep a counter with.
+    pub fn with_master_key(
+        mut self,
+        master_key: MasterKey,
+        format: KeyHandleFormat,
+    ) -> OpenSSLCryptoOperations {
+        self.key_handle_format = format;
+        self.master_key = Some(master_key);
+        self
+    }
+
+    fn generate_key(rng: &mut dyn RngCore) -> PrivateKey {
+        // Rejection sample scalars until one is a valid P-256 private key
+        loop {
+            let scalar: [u8; 32] = rng.gen();
+            if let Ok(key) = private_key_from_scalar(&scalar) {
+                return key;
+            }
+        }
     }
 
-    fn generate_key_handle() -> io::Result<KeyHandle> {
-        Ok(rand::random())
+    fn generate_key_handle(rng: &mut dyn RngCore) -> io::Result<KeyHandle> {
+        Ok(rng.gen())
     }
 }
 
@@ -43,21 +81,41 @@ impl CryptoOperations for OpenSSLCryptoOperations {
     }
 
     fn generate_application_key(&self, application: &AppId) -> io::Result<ApplicationKey> {
-        let key = Self::generate_key();
-        let handle = Self::generate_key_handle()?;
+        let mut rng = self.rng.borrow_mut();
+        let key = Self::generate_key(&mut **rng);
+        let handle = match (self.key_handle_format, &self.master_key) {
+            (KeyHandleFormat::Wrapped, Some(master_key)) => {
+                KeyHandle::wrap(application, &key, master_key, &mut **rng)
+                    .map_err(io::Error::other)?
+            }
+            (KeyHandleFormat::Stored, Some(master_key)) => {
+                KeyHandle::stored(application, master_key, &mut **rng)
+            }
+            (_, None) => Self::generate_key_handle(&mut **rng)?,
+        };
         Ok(ApplicationKey::new(*application, handle, key))
     }
 
+    fn unwrap_application_key(
+        &self,
+        application: &AppId,
+        handle: &KeyHandle,
+    ) -> Option<ApplicationKey> {
+        let master_key = self.master_key.as_ref()?;
+        let key = KeyHandle::unwrap(application, handle, master_key)?;
+        Some(ApplicationKey::new(*application, handle.clone(), key))
+    }
+
+    fn key_handle_issued_to(&self, application: &AppId, handle: &KeyHandle) -> Option<bool> {
+        handle.issued_to(application, self.master_key.as_ref()?)
+    }
+
     fn get_attestation_certificate(&self) -> AttestationCertificate {
         self.attestation.certificate.clone()
     }
 
     fn sign(&self, key: &PrivateKey, data: &[u8]) -> Result<Box<dyn Signature>, SignError> {
-        let ec_key = key.0.to_owned();
-        let pkey = PKey::from_ec_key(ec_key).unwrap();
-        let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
-        signer.update(data).unwrap();
-        let signature = signer.sign_to_vec().unwrap();
+        let signature = EcdsaSignature::sign(key, data).to_der();
         Ok(Box::new(RawSignature(signature)))
     }
 }
@@ -3,9 +3,12 @@ use std::result::Result;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use openssl::ec::EcKey;
 use openssl::pkey::Private;
+use zeroize::Zeroizing;
 
 use serde_base64::{to_base64, from_base64};
 
+/// EC private key, the secret scalar is owned by OpenSSL which clears it when the key
+/// is freed. Encodings of the key made to serialize it are zeroized once dropped.
 pub struct PrivateKey(pub(crate) EcKey<Private>);
 
 impl PrivateKey {
@@ -22,7 +25,7 @@ impl Clone for PrivateKey {
 
 impl Debug for PrivateKey {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "PrivateKey")
+        write!(f, "PrivateKey(<redacted>)")
     }
 }
 
@@ -44,7 +47,7 @@ impl<'de> Deserialize<'de> for PrivateKey {
     }
 }
 
-struct PrivateKeyAsPEM(Vec<u8>);
+struct PrivateKeyAsPEM(Zeroizing<Vec<u8>>);
 
 impl PrivateKeyAsPEM {
     fn as_key(&self) -> PrivateKey {
@@ -52,7 +55,7 @@ impl PrivateKeyAsPEM {
     }
 
     fn from_key(key: &PrivateKey) -> PrivateKeyAsPEM {
-        PrivateKeyAsPEM(key.0.private_key_to_pem().unwrap())
+        PrivateKeyAsPEM(Zeroizing::new(key.0.private_key_to_pem().unwrap()))
     }
 }
 
@@ -70,6 +73,6 @@ impl<'de> Deserialize<'de> for PrivateKeyAsPEM {
     where
         D: Deserializer<'de>,
     {
-        Ok(PrivateKeyAsPEM(from_base64(deserializer)?))
+        Ok(PrivateKeyAsPEM(Zeroizing::new(from_base64(deserializer)?)))
     }
 }
@@ -1,9 +1,12 @@
 use openssl::bn::BigNumContext;
 use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
+use openssl::ecdsa::EcdsaSig;
 use openssl::nid::Nid;
 use openssl::pkey::Public;
+use openssl::sha::sha256;
 use std::result::Result;
 
+use attestation::AttestationCertificate;
 use constants::EC_POINT_FORMAT_UNCOMPRESSED;
 use private_key::PrivateKey;
 
@@ -34,6 +37,21 @@ impl PublicKey {
         Ok(PublicKey(EcKey::from_public_key(&group, &point).unwrap()))
     }
 
+    /// Key the certificate was issued for, if it is an EC key
+    pub(crate) fn from_certificate(certificate: &AttestationCertificate) -> Option<PublicKey> {
+        let key = certificate.0.public_key().ok()?;
+        key.ec_key().ok().map(PublicKey)
+    }
+
+    /// Whether `signature` is a DER encoded ECDSA signature of the SHA-256 hash of `data`
+    /// made with this key
+    pub(crate) fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        match EcdsaSig::from_der(signature) {
+            Ok(signature) => signature.verify(&sha256(data), &self.0).unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
     pub(crate) fn as_ec_key(&self) -> &EcKey<Public> {
         &self.0
     }
@@ -46,4 +64,11 @@ impl PublicKey {
         let form = PointConversionForm::UNCOMPRESSED;
         self.0.public_key().to_bytes(self.0.group(), form, &mut ctx).unwrap()
     }
+
+    /// Same as `to_raw`, as a fixed size array
+    pub(crate) fn to_sec1(&self) -> [u8; 65] {
+        let mut bytes = [0u8; 65];
+        bytes.copy_from_slice(&self.to_raw());
+        bytes
+    }
 }
@@ -0,0 +1,141 @@
+//! Limits on how often one application may register or authenticate
+//!
+//! A page can send register requests as fast as it likes, each one generating and
+//! storing a new key if approved. A `RateLimiter` is consulted before the user is
+//! asked, requests over the limit are refused with `SW_CONDITIONS_NOT_SATISFIED`
+//! as if the user had not approved them yet, so a well behaved client simply retries.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use app_id::AppId;
+use user_presence::Operation;
+
+pub trait RateLimiter {
+    /// Record an attempt, returns false if it is over the limit and must be refused
+    fn try_acquire(&self, operation: Operation, application: &AppId) -> bool;
+}
+
+/// Allows every request, used unless another limiter is given
+pub struct NoRateLimit;
+
+impl RateLimiter for NoRateLimit {
+    fn try_acquire(&self, _operation: Operation, _application: &AppId) -> bool {
+        true
+    }
+}
+
+/// Requests allowed per application in each window
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub registrations: u32,
+    pub authentications: u32,
+    pub window: Duration,
+}
+
+impl Default for Limits {
+    /// Ten registrations and sixty authentications per application per minute
+    fn default() -> Limits {
+        Limits {
+            registrations: 10,
+            authentications: 60,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Limits {
+    fn limit(&self, operation: Operation) -> u32 {
+        match operation {
+            Operation::Register => self.registrations,
+            Operation::Authenticate => self.authentications,
+        }
+    }
+}
+
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+/// Counts requests per application and operation in fixed windows
+///
+/// A window starts with the first request after the previous one ended, expired
+/// windows are dropped as requests come in so memory stays bounded by the number
+/// of applications active within one window.
+pub struct WindowRateLimiter {
+    limits: Limits,
+    windows: RefCell<HashMap<(Operation, AppId), Window>>,
+}
+
+impl WindowRateLimiter {
+    pub fn new(limits: Limits) -> WindowRateLimiter {
+        WindowRateLimiter {
+            limits,
+            windows: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn try_acquire_at(&self, operation: Operation, application: &AppId, now: Instant) -> bool {
+        let window_len = self.limits.window;
+        let mut windows = self.windows.borrow_mut();
+        windows.retain(|_, window| now.duration_since(window.started) < window_len);
+        let window = windows
+            .entry((operation, *application))
+            .or_insert(Window {
+                started: now,
+                count: 0,
+            });
+        if window.count >= self.limits.limit(operation) {
+            return false;
+        }
+        window.count += 1;
+        true
+    }
+}
+
+impl RateLimiter for WindowRateLimiter {
+    fn try_acquire(&self, operation: Operation, application: &AppId) -> bool {
+        self.try_acquire_at(operation, application, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            registrations: 2,
+            authentications: 3,
+            window: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn limit_is_per_application_and_operation() {
+        let limiter = WindowRateLimiter::new(limits());
+        let now = Instant::now();
+        let first = AppId([1u8; 32]);
+        let second = AppId([2u8; 32]);
+
+        assert!(limiter.try_acquire_at(Operation::Register, &first, now));
+        assert!(limiter.try_acquire_at(Operation::Register, &first, now));
+        assert!(!limiter.try_acquire_at(Operation::Register, &first, now));
+        assert!(limiter.try_acquire_at(Operation::Register, &second, now));
+        assert!(limiter.try_acquire_at(Operation::Authenticate, &first, now));
+    }
+
+    #[test]
+    fn limit_resets_after_window() {
+        let limiter = WindowRateLimiter::new(limits());
+        let now = Instant::now();
+        let application = AppId([1u8; 32]);
+        limiter.try_acquire_at(Operation::Register, &application, now);
+        limiter.try_acquire_at(Operation::Register, &application, now);
+
+        let later = now + Duration::from_secs(60);
+
+        assert!(limiter.try_acquire_at(Operation::Register, &application, later));
+    }
+}
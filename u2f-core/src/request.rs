@@ -1,15 +1,62 @@
-use std::io::Cursor;
-use std::io::Read;
 use std::result::Result;
 
 use app_id::AppId;
-use byteorder::{BigEndian, ReadBytesExt};
+use byteorder::{BigEndian, ByteOrder};
 use constants::*;
 use key_handle::KeyHandle;
 
 use super::Challenge;
+use super::StatusCode;
 
-#[derive(Debug)]
+quick_error! {
+    #[derive(Debug, PartialEq)]
+    pub enum ApduError {
+        /// Fewer than the four header bytes CLA, INS, P1 and P2
+        TooShort {
+            display("APDU is shorter than its header")
+        }
+        /// The Lc/Le fields do not match the number of bytes in the APDU, or the request
+        /// data has the wrong length for the command
+        InvalidLength {
+            display("APDU length fields do not match its contents")
+        }
+        /// The request data is too short for the 32 byte challenge and application
+        /// parameters, or for a register request longer than them
+        BadParameterLength(len: usize) {
+            display("Request data of {} bytes does not hold the challenge and application parameters", len)
+        }
+        /// The Lc field claims more request data than the APDU carries
+        TruncatedBody(expected_len: usize, actual_len: usize) {
+            display("APDU claims {} bytes of request data but carries {}", expected_len, actual_len)
+        }
+        ClassNotSupported(class: u8) {
+            display("APDU class {:#04x} is not supported", class)
+        }
+        UnsupportedInstruction(instruction: u8) {
+            display("APDU instruction {:#04x} is not supported", instruction)
+        }
+        InvalidParameters(parameter1: u8, parameter2: u8) {
+            display("APDU parameters {:#04x} {:#04x} are not valid for the instruction", parameter1, parameter2)
+        }
+    }
+}
+
+impl ApduError {
+    /// Status word that rejects a request failing to decode with this error
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ApduError::TooShort
+            | ApduError::InvalidLength
+            | ApduError::BadParameterLength(_)
+            | ApduError::TruncatedBody(..) => StatusCode::RequestLengthInvalid,
+            ApduError::ClassNotSupported(_) => StatusCode::RequestClassNotSupported,
+            ApduError::UnsupportedInstruction(_) => StatusCode::RequestInstructionNotSuppored,
+            ApduError::InvalidParameters(_, _) => StatusCode::RequestParametersInvalid,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum AuthenticateControlCode {
     CheckOnly,
     EnforceUserPresenceAndSign,
@@ -33,131 +80,456 @@ pub enum Request {
 }
 
 impl Request {
-    /// Only supports Extended Length Encoding
-    pub fn decode(data: &[u8]) -> Result<Request, ()> {
-        let mut reader = Cursor::new(data);
+    /// Decode a raw U2F request message, in either short or extended length encoding
+    pub fn decode(data: &[u8]) -> Result<Request, ApduError> {
+        if data.len() < 4 {
+            return Err(ApduError::TooShort);
+        }
 
         // CLA: Reserved to be used by the underlying transport protocol
-        let _class_byte = reader.read_u8().unwrap();
-        // TODO check or error with RequestClassNotSupported
+        let class_byte = data[0];
+        if class_byte != 0 {
+            return Err(ApduError::ClassNotSupported(class_byte));
+        }
 
         // INS: U2F command code
-        let command_code = reader.read_u8().unwrap();
-        // TODO check or error with RequestInstructionNotSuppored
+        let command_code = data[1];
 
         // P1, P2: Parameter 1 and 2, defined by each command.
-        let parameter1 = reader.read_u8().unwrap();
-        let parameter2 = reader.read_u8().unwrap();
-
-        // Extended Length Encoding
-        // Always begins with a byte of value 0
-        let zero_byte = reader.read_u8().unwrap();
-        assert_eq!(zero_byte, 0);
-
-        // Nc: Length of the request-data, range 0..65 535
-        // Lc: Encoding of Nc as two bytes
-        // If Nc is 0, Lc is omitted (Caveat: Not all implementations respect this)
-        let remaining_len = data.len() - reader.position() as usize;
-        let request_data_len = match remaining_len {
-            2 => {
-                // Lc was omitted, there is no request data
-                0
-            }
-            _ => {
-                // Lc in big-endian order
-                reader.read_u16::<BigEndian>().unwrap() as usize
-            }
-        };
+        let parameter1 = data[2];
+        let parameter2 = data[3];
 
-        // Request-data
-        let mut request_data = vec![0u8; request_data_len];
-        reader.read_exact(&mut request_data[..]).unwrap();
-
-        // Ne: Maximum length of the response data, range 0..65 536
-        // Le: Encoding of Ne as two bytes
-        // If no response data are expected, Le may be omitted.
-        let remaining_len = data.len() - reader.position() as usize;
-        let _max_response_data_len = match remaining_len {
-            0 => {
-                // Lc was omitted, instruction is not expected to yield any response bytes
-                0
-            }
-            2 => {
-                // Encoded as: Le1 Le2
-                let mut value = reader.read_u16::<BigEndian>().unwrap() as usize;
-                // When Ne = 65 536, let Le1 = 0 and Le2 = 0.
-                if value == 0 {
-                    // The MSB is lost when encoding to two bytes, but
-                    // since Le can be omitted when there are no request data
-                    // bytes, we can unambigously assume 0 to mean 65 535
-                    value = 65535;
-                }
-                value
-            }
-            _ => return Err(()),
+        // Checked before the body, which an unknown instruction may lay out differently
+        let instruction = match command_code {
+            REGISTER_COMMAND_CODE => Instruction::Register,
+            AUTHENTICATE_COMMAND_CODE => Instruction::Authenticate,
+            VERSION_COMMAND_CODE => Instruction::Version,
+            _ => return Err(ApduError::UnsupportedInstruction(command_code)),
         };
 
-        // TODO If the instruction is not expected to yield any response bytes, L e may be omitted. O
-        let mut reader = Cursor::new(request_data);
-        let request = match command_code {
-            REGISTER_COMMAND_CODE => {
-                // The challenge parameter [32 bytes].
-                let mut challenge_parameter = [0u8; 32];
-                reader.read_exact(&mut challenge_parameter[..]).unwrap();
-
-                // The application parameter [32 bytes].
-                let mut application_parameter = [0u8; 32];
-                reader.read_exact(&mut application_parameter[..]).unwrap();
-
-                assert_eq!(reader.position() as usize, request_data_len);
-                Request::Register {
-                    application: AppId(application_parameter),
-                    challenge: Challenge(challenge_parameter),
+        let request_data = request_data(&data[4..])?;
+
+        match instruction {
+            Instruction::Register => {
+                let (challenge, application, rest) = parameters(request_data)?;
+                if !rest.is_empty() {
+                    return Err(ApduError::BadParameterLength(request_data.len()));
                 }
-            }
-            AUTHENTICATE_COMMAND_CODE => {
-                assert_eq!(parameter2, 0);
 
+                Ok(Request::Register {
+                    application,
+                    challenge,
+                })
+            }
+            Instruction::Authenticate => {
                 // Control byte (P1).
-                let control_code = match parameter1 {
-                    AUTH_CHECK_ONLY => AuthenticateControlCode::CheckOnly,
-                    AUTH_ENFORCE => AuthenticateControlCode::EnforceUserPresenceAndSign,
-                    AUTH_DONT_ENFORCE => {
+                let control_code = match (parameter1, parameter2) {
+                    (AUTH_CHECK_ONLY, 0) => AuthenticateControlCode::CheckOnly,
+                    (AUTH_ENFORCE, 0) => AuthenticateControlCode::EnforceUserPresenceAndSign,
+                    (AUTH_DONT_ENFORCE, 0) => {
                         AuthenticateControlCode::DontEnforceUserPresenceAndSign
                     }
-                    _ => panic!("Unknown control code"),
+                    _ => return Err(ApduError::InvalidParameters(parameter1, parameter2)),
                 };
 
-                // The challenge parameter [32 bytes].
-                let mut challenge_parameter = [0u8; 32];
-                reader.read_exact(&mut challenge_parameter[..]).unwrap();
-
-                // The application parameter [32 bytes].
-                let mut application_parameter = [0u8; 32];
-                reader.read_exact(&mut application_parameter[..]).unwrap();
+                let (challenge, application, rest) = parameters(request_data)?;
 
                 // key handle length byte [1 byte]
-                let key_handle_len = reader.read_u8().unwrap();
+                let (key_handle_len, key_handle_bytes) = match rest.split_first() {
+                    Some((&len, key_handle_bytes)) => (len as usize, key_handle_bytes),
+                    None => return Err(ApduError::InvalidLength),
+                };
 
                 // key handle [length specified in previous field]
-                let mut key_handle_bytes = vec![0u8; key_handle_len as usize];
-                reader.read_exact(&mut key_handle_bytes[..]).unwrap();
+                if key_handle_bytes.len() != key_handle_len {
+                    return Err(ApduError::InvalidLength);
+                }
 
-                Request::Authenticate {
-                    application: AppId(application_parameter),
-                    challenge: Challenge(challenge_parameter),
+                Ok(Request::Authenticate {
+                    application,
+                    challenge,
                     control_code,
-                    key_handle: KeyHandle::from(&key_handle_bytes),
+                    key_handle: KeyHandle::from(key_handle_bytes),
+                })
+            }
+            Instruction::Version => {
+                if parameter1 != 0 || parameter2 != 0 {
+                    return Err(ApduError::InvalidParameters(parameter1, parameter2));
+                }
+                if !request_data.is_empty() {
+                    return Err(ApduError::InvalidLength);
                 }
+                Ok(Request::GetVersion)
+            }
+        }
+    }
+}
+
+/// Instructions this token implements, decoded from the INS byte
+enum Instruction {
+    Register,
+    Authenticate,
+    Version,
+}
+
+/// Split the challenge and application parameters off the start of the request-data
+///
+/// Both are 32 bytes, in that order, in register and authenticate requests.
+fn parameters(request_data: &[u8]) -> Result<(Challenge, AppId, &[u8]), ApduError> {
+    if request_data.len() < 64 {
+        return Err(ApduError::BadParameterLength(request_data.len()));
+    }
+
+    // The challenge parameter [32 bytes].
+    let mut challenge_parameter = [0u8; 32];
+    challenge_parameter.copy_from_slice(&request_data[..32]);
+
+    // The application parameter [32 bytes].
+    let mut application_parameter = [0u8; 32];
+    application_parameter.copy_from_slice(&request_data[32..64]);
+
+    Ok((
+        Challenge(challenge_parameter),
+        AppId(application_parameter),
+        &request_data[64..],
+    ))
+}
+
+/// Extract the request-data from the APDU body following the header
+///
+/// The body is made up of an optional Lc field, Nc bytes of request-data and an optional
+/// Le field. With short encoding Lc and Le are both one byte. With extended encoding the
+/// body begins with a zero byte, followed by a two byte Lc and a two byte Le, or a two
+/// byte Le alone if there is no request-data. The maximum response length Ne is not
+/// needed since responses are never truncated.
+fn request_data(body: &[u8]) -> Result<&[u8], ApduError> {
+    match body.len() {
+        // No Lc and no Le
+        0 => Ok(&[]),
+        // Short Le only
+        1 => Ok(&[]),
+        _ if body[0] != 0 => {
+            // Short Lc, range 1..255, optionally followed by a short Le
+            let request_data_len = body[0] as usize;
+            let request_data = &body[1..];
+            match request_data.len().checked_sub(request_data_len) {
+                Some(0) | Some(1) => Ok(&request_data[..request_data_len]),
+                Some(_) => Err(ApduError::InvalidLength),
+                None => Err(ApduError::TruncatedBody(request_data_len, request_data.len())),
+            }
+        }
+        // Extended Le only
+        3 => Ok(&[]),
+        len if len > 3 => {
+            // Extended Lc in big-endian order, optionally followed by an extended Le
+            let request_data_len = BigEndian::read_u16(&body[1..3]) as usize;
+            let request_data = &body[3..];
+            match request_data.len().checked_sub(request_data_len) {
+                Some(0) | Some(2) if request_data_len > 0 => Ok(&request_data[..request_data_len]),
+                Some(_) => Err(ApduError::InvalidLength),
+                None => Err(ApduError::TruncatedBody(request_data_len, request_data.len())),
+            }
+        }
+        _ => Err(ApduError::InvalidLength),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Extended length encoding with the maximum Le
+    fn encode(command_code: u8, parameter1: u8, request_data: &[u8]) -> Vec<u8> {
+        let mut apdu = vec![0x00, command_code, parameter1, 0x00, 0x00];
+        if !request_data.is_empty() {
+            apdu.push((request_data.len() >> 8) as u8);
+            apdu.push(request_data.len() as u8);
+            apdu.extend_from_slice(request_data);
+        }
+        apdu.extend_from_slice(&[0x00, 0x00]);
+        apdu
+    }
+
+    /// Short length encoding with the maximum Le, request data must fit in 255 bytes
+    fn short_encode(command_code: u8, parameter1: u8, request_data: &[u8]) -> Vec<u8> {
+        let mut apdu = vec![0x00, command_code, parameter1, 0x00];
+        if !request_data.is_empty() {
+            apdu.push(request_data.len() as u8);
+            apdu.extend_from_slice(request_data);
+        }
+        apdu.push(0x00);
+        apdu
+    }
+
+    fn register_data() -> Vec<u8> {
+        let mut data = vec![1u8; 32];
+        data.extend_from_slice(&[2u8; 32]);
+        data
+    }
+
+    fn authenticate_data(key_handle: &[u8]) -> Vec<u8> {
+        let mut data = register_data();
+        data.push(key_handle.len() as u8);
+        data.extend_from_slice(key_handle);
+        data
+    }
+
+    #[test]
+    fn decode_register() {
+        let request = Request::decode(&encode(REGISTER_COMMAND_CODE, 0, &register_data())).unwrap();
+
+        match request {
+            Request::Register {
+                application,
+                challenge,
+            } => {
+                assert_eq!(challenge.0, [1u8; 32]);
+                assert_eq!(application, AppId([2u8; 32]));
+            }
+            _ => panic!("expected register request"),
+        }
+    }
+
+    #[test]
+    fn decode_authenticate() {
+        let apdu = encode(
+            AUTHENTICATE_COMMAND_CODE,
+            AUTH_ENFORCE,
+            &authenticate_data(&[7u8; 64]),
+        );
+
+        let request = Request::decode(&apdu).unwrap();
+
+        match request {
+            Request::Authenticate {
+                application,
+                challenge,
+                control_code,
+                key_handle,
+            } => {
+                assert_eq!(challenge.0, [1u8; 32]);
+                assert_eq!(application, AppId([2u8; 32]));
+                assert_eq!(
+                    control_code,
+                    AuthenticateControlCode::EnforceUserPresenceAndSign
+                );
+                assert_eq!(key_handle, KeyHandle::from(&[7u8; 64]));
             }
-            VERSION_COMMAND_CODE => {
-                assert_eq!(parameter1, 0);
-                assert_eq!(parameter2, 0);
-                assert_eq!(request_data_len, 0);
-                Request::GetVersion
+            _ => panic!("expected authenticate request"),
+        }
+    }
+
+    #[test]
+    fn decode_authenticate_check_only() {
+        let apdu = encode(
+            AUTHENTICATE_COMMAND_CODE,
+            AUTH_CHECK_ONLY,
+            &authenticate_data(&[7u8; 64]),
+        );
+
+        match Request::decode(&apdu).unwrap() {
+            Request::Authenticate { control_code, .. } => {
+                assert_eq!(control_code, AuthenticateControlCode::CheckOnly)
             }
-            _ => panic!("Not implemented"),
+            _ => panic!("expected authenticate request"),
+        }
+    }
+
+    #[test]
+    fn decode_version() {
+        let request = Request::decode(&encode(VERSION_COMMAND_CODE, 0, &[])).unwrap();
+
+        assert_matches!(request, Request::GetVersion);
+    }
+
+    #[test]
+    fn decode_version_without_le() {
+        let request = Request::decode(&[0x00, VERSION_COMMAND_CODE, 0x00, 0x00]).unwrap();
+
+        assert_matches!(request, Request::GetVersion);
+    }
+
+    #[test]
+    fn decode_short_encoded_register() {
+        let mut apdu = vec![0x00, REGISTER_COMMAND_CODE, 0x00, 0x00, 64];
+        apdu.extend_from_slice(&register_data());
+        apdu.push(0x00);
+
+        let request = Request::decode(&apdu).unwrap();
+
+        assert_matches!(request, Request::Register { .. });
+    }
+
+    #[test]
+    fn decode_short_encoded_authenticate_without_le() {
+        let data = authenticate_data(&[7u8; 32]);
+        let mut apdu = vec![
+            0x00,
+            AUTHENTICATE_COMMAND_CODE,
+            AUTH_ENFORCE,
+            0x00,
+            data.len() as u8,
+        ];
+        apdu.extend_from_slice(&data);
+
+        let request = Request::decode(&apdu).unwrap();
+
+        assert_matches!(request, Request::Authenticate { .. });
+    }
+
+    #[test]
+    fn short_and_extended_authenticate_decode_alike() {
+        let data = authenticate_data(&[7u8; 64]);
+        let decode = |apdu: Vec<u8>| match Request::decode(&apdu).unwrap() {
+            Request::Authenticate {
+                application,
+                challenge,
+                control_code,
+                key_handle,
+            } => (application, challenge.0, control_code, key_handle),
+            _ => panic!("expected authenticate request"),
         };
-        Ok(request)
+
+        let short = decode(short_encode(AUTHENTICATE_COMMAND_CODE, AUTH_ENFORCE, &data));
+        let extended = decode(encode(AUTHENTICATE_COMMAND_CODE, AUTH_ENFORCE, &data));
+
+        assert_eq!(short, extended);
+        assert_eq!(short.3, KeyHandle::from(&[7u8; 64]));
+    }
+
+    #[test]
+    fn decode_truncated_request_data_is_truncated_body() {
+        let mut apdu = encode(REGISTER_COMMAND_CODE, 0, &register_data());
+        apdu.truncate(apdu.len() - 10);
+
+        assert_matches!(Request::decode(&apdu), Err(ApduError::TruncatedBody(64, 56)));
+    }
+
+    #[test]
+    fn decode_maximum_lc_with_short_body_is_truncated_body() {
+        let apdu = [0x00, REGISTER_COMMAND_CODE, 0x00, 0x00, 0x00, 0xff, 0xff, 1, 2, 3];
+
+        assert_matches!(
+            Request::decode(&apdu),
+            Err(ApduError::TruncatedBody(0xffff, 3))
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn decode_never_panics(apdu in proptest::collection::vec(proptest::num::u8::ANY, 0..300)) {
+            let _ = Request::decode(&apdu);
+        }
+
+        #[test]
+        fn decode_never_panics_on_valid_headers(
+            instruction in 0x01u8..0x04,
+            parameter1 in proptest::num::u8::ANY,
+            body in proptest::collection::vec(proptest::num::u8::ANY, 0..300),
+        ) {
+            let mut apdu = vec![0x00, instruction, parameter1, 0x00];
+            apdu.extend_from_slice(&body);
+            let _ = Request::decode(&apdu);
+        }
+    }
+
+    #[test]
+    fn decode_register_with_wrong_data_length_is_bad_parameter_length() {
+        let apdu = encode(REGISTER_COMMAND_CODE, 0, &[0u8; 63]);
+
+        assert_matches!(
+            Request::decode(&apdu),
+            Err(ApduError::BadParameterLength(63))
+        );
+    }
+
+    #[test]
+    fn decode_register_with_trailing_data_is_bad_parameter_length() {
+        let apdu = encode(REGISTER_COMMAND_CODE, 0, &[0u8; 65]);
+
+        assert_matches!(
+            Request::decode(&apdu),
+            Err(ApduError::BadParameterLength(65))
+        );
+    }
+
+    #[test]
+    fn decode_authenticate_with_truncated_parameters_is_bad_parameter_length() {
+        let mut data = authenticate_data(&[7u8; 64]);
+        data.truncate(40);
+        let apdu = encode(AUTHENTICATE_COMMAND_CODE, AUTH_ENFORCE, &data);
+
+        assert_matches!(
+            Request::decode(&apdu),
+            Err(ApduError::BadParameterLength(40))
+        );
+    }
+
+    #[test]
+    fn decode_authenticate_without_key_handle_length_is_invalid_length() {
+        let apdu = encode(AUTHENTICATE_COMMAND_CODE, AUTH_ENFORCE, &register_data());
+
+        assert_matches!(Request::decode(&apdu), Err(ApduError::InvalidLength));
+    }
+
+    #[test]
+    fn decode_authenticate_with_wrong_key_handle_length_is_invalid_length() {
+        let mut data = authenticate_data(&[7u8; 64]);
+        data[64] = 65;
+        let apdu = encode(AUTHENTICATE_COMMAND_CODE, AUTH_ENFORCE, &data);
+
+        assert_matches!(Request::decode(&apdu), Err(ApduError::InvalidLength));
+    }
+
+    #[test]
+    fn decode_header_only_is_too_short() {
+        assert_matches!(
+            Request::decode(&[0x00, 0x01, 0x00]),
+            Err(ApduError::TooShort)
+        );
+    }
+
+    #[test]
+    fn decode_unknown_instruction() {
+        assert_matches!(
+            Request::decode(&encode(0x10, 0, &[])),
+            Err(ApduError::UnsupportedInstruction(0x10))
+        );
+    }
+
+    #[test]
+    fn decode_unknown_instruction_before_its_body() {
+        // Extended length with Lc of zero, which no known instruction accepts
+        let apdu = [0x00, 0x99, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+        assert_matches!(
+            Request::decode(&apdu),
+            Err(ApduError::UnsupportedInstruction(0x99))
+        );
+    }
+
+    #[test]
+    fn decode_unknown_class() {
+        let mut apdu = encode(VERSION_COMMAND_CODE, 0, &[]);
+        apdu[0] = 0x80;
+
+        assert_matches!(
+            Request::decode(&apdu),
+            Err(ApduError::ClassNotSupported(0x80))
+        );
+    }
+
+    #[test]
+    fn decode_unknown_control_code() {
+        let apdu = encode(
+            AUTHENTICATE_COMMAND_CODE,
+            0x42,
+            &authenticate_data(&[7u8; 64]),
+        );
+
+        assert_matches!(
+            Request::decode(&apdu),
+            Err(ApduError::InvalidParameters(0x42, 0x00))
+        );
     }
 }
@@ -1,22 +1,17 @@
 use std::io;
 
-use attestation::AttestationCertificate;
 use byteorder::{BigEndian, WriteBytesExt};
-use key_handle::KeyHandle;
+use request::ApduError;
 
+use super::user_presence_byte;
 use super::Counter;
-use super::Signature;
+use super::Registration;
 use super::SignError;
+use super::Signature;
 use super::StatusCode;
-use super::user_presence_byte;
 
 pub enum Response {
-    Registration {
-        user_public_key: Vec<u8>,
-        key_handle: KeyHandle,
-        attestation_certificate: AttestationCertificate,
-        signature: Box<dyn Signature>,
-    },
+    Registration(Registration),
     Authentication {
         counter: Counter,
         signature: Box<dyn Signature>,
@@ -28,87 +23,68 @@ pub enum Response {
     DidWink,
     TestOfUserPresenceNotSatisfied,
     InvalidKeyHandle,
+    /// The request could not be decoded
+    InvalidRequest(ApduError),
     UnknownError,
 }
 
 impl Response {
     pub fn into_bytes(self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        match self {
-            Response::Registration {
-                user_public_key,
-                key_handle,
-                attestation_certificate,
-                signature,
-            } => {
-                // reserved byte [1 byte], which for legacy reasons has the value 0x05.
-                bytes.push(0x05);
-
-                // user public key [65 bytes]. This is the (uncompressed) x,y-representation of a curve point on the P-256 NIST elliptic curve.
-                bytes.extend_from_slice(&user_public_key);
-
-                // key handle length byte [1 byte], which specifies the length of the key handle (see below). The value is unsigned (range 0-255).
-                let key_handle_bytes = key_handle.as_ref();
-                bytes.push(key_handle_bytes.len() as u8);
-
-                // A key handle [length specified in previous field].
-                bytes.extend_from_slice(key_handle_bytes);
-
-                // An attestation certificate [variable length]. This is a certificate in X.509 DER format
-                bytes.extend_from_slice(&attestation_certificate.to_der());
+        self.encode()
+    }
 
-                // A signature [variable length, 71-73 bytes]
-                let signature_bytes = signature.as_ref().as_ref();
-                bytes.extend_from_slice(signature_bytes);
+    /// Status word the response message ends with
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Response::Registration(_)
+            | Response::Authentication { .. }
+            | Response::Version { .. }
+            | Response::DidWink => StatusCode::NoError,
+            Response::TestOfUserPresenceNotSatisfied => StatusCode::TestOfUserPresenceNotSatisfied,
+            Response::InvalidKeyHandle => StatusCode::InvalidKeyHandle,
+            Response::InvalidRequest(err) => err.status_code(),
+            Response::UnknownError => StatusCode::UnknownError,
+        }
+    }
 
-                // Status word [2 bytes]
-                StatusCode::NoError.write(&mut bytes);
+    /// Raw response message, including the trailing status word
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            Response::Registration(registration) => {
+                bytes.extend_from_slice(&registration.to_u2f_response_bytes());
             }
             Response::Authentication {
                 counter,
                 signature,
                 user_present,
             } => {
-                let user_presence_byte = user_presence_byte(user_present);
+                let user_presence_byte = user_presence_byte(*user_present);
 
                 // A user presence byte [1 byte].
                 bytes.push(user_presence_byte);
 
                 // A counter [4 bytes].
-                bytes.write_u32::<BigEndian>(counter).unwrap();
+                bytes.write_u32::<BigEndian>(*counter).unwrap();
 
                 // A signature [variable length, 71-73 bytes]
                 bytes.extend_from_slice(signature.as_ref().as_ref());
-
-                // Status word [2 bytes]
-                StatusCode::NoError.write(&mut bytes);
             }
             Response::Version { version_string } => {
                 // The response message's raw representation is the
                 // ASCII representation of the string 'U2F_V2'
                 // (without quotes, and without any NUL terminator).
                 bytes.extend_from_slice(version_string.as_bytes());
-
-                // Status word [2 bytes]
-                StatusCode::NoError.write(&mut bytes);
-            }
-            Response::DidWink => {
-                // Status word [2 bytes]
-                StatusCode::NoError.write(&mut bytes);
-            }
-            Response::TestOfUserPresenceNotSatisfied => {
-                // Status word [2 bytes]
-                StatusCode::TestOfUserPresenceNotSatisfied.write(&mut bytes);
-            }
-            Response::InvalidKeyHandle => {
-                // Status word [2 bytes]
-                StatusCode::InvalidKeyHandle.write(&mut bytes);
-            }
-            Response::UnknownError => {
-                // Status word [2 bytes]
-                StatusCode::UnknownError.write(&mut bytes);
             }
+            Response::DidWink
+            | Response::TestOfUserPresenceNotSatisfied
+            | Response::InvalidKeyHandle
+            | Response::InvalidRequest(_)
+            | Response::UnknownError => {}
         }
+
+        // Status word [2 bytes]
+        self.status_code().write(&mut bytes);
         bytes
     }
 }
@@ -125,11 +101,77 @@ quick_error! {
     }
 }
 
-impl Into<io::Error> for ResponseError {
-    fn into(self: Self) -> io::Error {
-        match self {
+impl From<ResponseError> for io::Error {
+    fn from(err: ResponseError) -> io::Error {
+        match err {
             ResponseError::Io(err) => err,
-            ResponseError::Signing(_) => io::Error::new(io::ErrorKind::Other, "Signing error"),
+            ResponseError::Signing(_) => io::Error::other("Signing error"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use constants::U2F_VERSION;
+
+    #[test]
+    fn encode_version() {
+        let response = Response::Version {
+            version_string: String::from(U2F_VERSION),
+        };
+
+        assert_eq!(response.encode(), b"U2F_V2\x90\x00".to_vec());
+    }
+
+    #[test]
+    fn encode_did_wink_is_no_error() {
+        assert_eq!(Response::DidWink.encode(), vec![0x90, 0x00]);
+    }
+
+    #[test]
+    fn encode_invalid_request_uses_error_status() {
+        assert_eq!(
+            Response::InvalidRequest(ApduError::InvalidLength).encode(),
+            vec![0x67, 0x00]
+        );
+        assert_eq!(
+            Response::InvalidRequest(ApduError::UnsupportedInstruction(0x10)).encode(),
+            vec![0x6D, 0x00]
+        );
+    }
+
+    #[test]
+    fn status_codes_round_trip() {
+        let status_codes = vec![
+            (StatusCode::NoError, [0x90, 0x00]),
+            (StatusCode::TestOfUserPresenceNotSatisfied, [0x69, 0x85]),
+            (StatusCode::InvalidKeyHandle, [0x6A, 0x80]),
+            (StatusCode::CommandNotAllowed, [0x69, 0x86]),
+            (StatusCode::RequestLengthInvalid, [0x67, 0x00]),
+            (StatusCode::RequestClassNotSupported, [0x6E, 0x00]),
+            (StatusCode::RequestInstructionNotSuppored, [0x6D, 0x00]),
+            (StatusCode::RequestParametersInvalid, [0x6A, 0x86]),
+            (StatusCode::UnknownError, [0x6F, 0x00]),
+        ];
+
+        for (status_code, bytes) in status_codes {
+            assert_eq!(status_code.to_bytes(), bytes);
+            assert_eq!(
+                StatusCode::from_u16(status_code.to_u16()),
+                Some(status_code)
+            );
         }
+        assert_eq!(StatusCode::from_u16(0x1234), None);
+    }
+
+    #[test]
+    fn encode_ends_with_status_code() {
+        let response = Response::InvalidKeyHandle;
+
+        assert_eq!(
+            response.encode(),
+            response.status_code().to_bytes().to_vec()
+        );
     }
 }
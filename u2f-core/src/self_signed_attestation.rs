@@ -1,13 +1,90 @@
+use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time};
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::x509::{X509Extension, X509NameBuilder, X509};
+
 use attestation::{Attestation, AttestationCertificate};
 use private_key::PrivateKey;
 
+/// Attestation shared by every soft token, see the comment on the key below
 pub fn self_signed_attestation() -> Attestation {
     Attestation {
         certificate: AttestationCertificate::from_pem(SELF_SIGNED_ATTESTATION_CERTIFICATE_PEM),
+        intermediates: Vec::new(),
         key: PrivateKey::from_pem(SELF_SIGNED_ATTESTATION_KEY_PEM),
     }
 }
 
+/// AAGUID embedded in generated certificates unless another one is given
+pub const SOFT_U2F_AAGUID: [u8; 16] = [
+    0x5f, 0x2a, 0x8c, 0x3e, 0x91, 0x47, 0x4d, 0x0b, 0xa6, 0x1e, 0x73, 0xc4, 0x08, 0xd9, 0x5b, 0x22,
+];
+
+/// Per-device attestation with a freshly generated key and certificate
+pub struct SelfSigned;
+
+impl SelfSigned {
+    pub fn generate() -> Attestation {
+        SelfSigned::generate_with_aaguid(SOFT_U2F_AAGUID)
+    }
+
+    /// Generate with `aaguid` in the certificate's FIDO extension, e.g. one enrolled
+    /// with a metadata service
+    pub fn generate_with_aaguid(aaguid: [u8; 16]) -> Attestation {
+        let key =
+            EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap();
+        let certificate = self_signed_certificate(&key, aaguid).unwrap();
+        Attestation {
+            certificate: AttestationCertificate::new(certificate),
+            intermediates: Vec::new(),
+            key: PrivateKey(key),
+        }
+    }
+}
+
+fn self_signed_certificate(
+    key: &EcKey<openssl::pkey::Private>,
+    aaguid: [u8; 16],
+) -> Result<X509, ErrorStack> {
+    let pkey = PKey::from_ec_key(key.to_owned())?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "Soft U2F")?;
+    let name = name.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(64, MsbOption::MAYBE_ZERO, false)?;
+
+    let serial = serial.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(3652)?;
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    builder.set_pubkey(&pkey)?;
+    builder.append_extension(aaguid_extension(aaguid)?)?;
+    builder.sign(&pkey, MessageDigest::sha256())?;
+    Ok(builder.build())
+}
+
+/// Non-critical id-fido-gen-ce-aaguid extension, its value an OCTET STRING of the AAGUID
+fn aaguid_extension(aaguid: [u8; 16]) -> Result<X509Extension, ErrorStack> {
+    let oid = Asn1Object::from_str("1.3.6.1.4.1.45724.1.1.4")?;
+    let mut value = vec![0x04, aaguid.len() as u8];
+    value.extend_from_slice(&aaguid);
+    let value = Asn1OctetString::new_from_bytes(&value)?;
+    X509Extension::new_from_der(&oid, false, &value)
+}
+
 // Generated by the following commands:
 // > openssl ecparam -name prime256v1 -genkey -noout -out key.pem
 // > openssl req -new -sha256 -x509 -days 3652 -key key.pem -subj "/CN=Soft U2F" -out certificate.pem
@@ -1,12 +1,15 @@
 use base64;
 use serde::{Deserialize, Deserializer, Serializer};
+use zeroize::Zeroizing;
 
 pub(crate) fn to_base64<T, S>(buffer: &T, serializer: S) -> Result<S::Ok, S::Error>
 where
     T: AsRef<[u8]>,
     S: Serializer,
 {
-    serializer.serialize_str(&base64::encode(buffer.as_ref()))
+    // Also used for private keys, don't leave their encoding behind in freed memory
+    let encoded = Zeroizing::new(base64::encode(buffer.as_ref()));
+    serializer.serialize_str(&encoded)
 }
 
 pub(crate) fn from_base64<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
@@ -15,5 +18,8 @@ where
 {
     use serde::de::Error;
     String::deserialize(deserializer)
-        .and_then(|string| base64::decode(&string).map_err(|err| Error::custom(err.to_string())))
+        .map(Zeroizing::new)
+        .and_then(|string| {
+            base64::decode(string.as_str()).map_err(|err| Error::custom(err.to_string()))
+        })
 }
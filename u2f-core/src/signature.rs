@@ -0,0 +1,189 @@
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::sha::sha256;
+
+use private_key::PrivateKey;
+
+/// Order n of the P-256 (prime256v1) group
+const P256_ORDER: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xbc, 0xe6, 0xfa, 0xad, 0xa7, 0x17, 0x9e, 0x84, 0xf3, 0xb9, 0xca, 0xc2, 0xfc, 0x63, 0x25, 0x51,
+];
+
+/// ECDSA signature over P-256 as its two unsigned big-endian integers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EcdsaSignature {
+    pub r: Vec<u8>,
+    /// Always the low one of the two valid values, see `normalize_low_s`
+    pub s: Vec<u8>,
+}
+
+impl EcdsaSignature {
+    /// Sign the SHA-256 hash of `data`
+    pub(crate) fn sign(key: &PrivateKey, data: &[u8]) -> EcdsaSignature {
+        let ecdsa_sig = EcdsaSig::sign(&sha256(data), &key.0).unwrap();
+        EcdsaSignature {
+            r: ecdsa_sig.r().to_vec(),
+            s: normalize_low_s(&ecdsa_sig.s().to_vec()),
+        }
+    }
+
+    /// DER encoding, as sent in U2F responses
+    pub fn to_der(&self) -> Vec<u8> {
+        to_der(&self.r, &self.s)
+    }
+}
+
+/// ASN.1 DER encoding of an ECDSA signature, `SEQUENCE { r INTEGER, s INTEGER }`
+///
+/// `r` and `s` are unsigned big-endian integers of any width. Leading zero bytes are
+/// stripped and a 0x00 is prepended when the high bit is set, so the integers are
+/// minimal and positive as DER requires.
+pub fn to_der(r: &[u8], s: &[u8]) -> Vec<u8> {
+    let mut integers = Vec::new();
+    push_integer(&mut integers, r);
+    push_integer(&mut integers, s);
+
+    let mut der = Vec::with_capacity(integers.len() + 4);
+    der.push(0x30);
+    push_length(&mut der, integers.len());
+    der.extend_from_slice(&integers);
+    der
+}
+
+/// Ensure `s` of a P-256 signature is at most n/2, replacing it with `n - s` otherwise
+///
+/// Both values form a valid signature, strict verifiers only accept the low one.
+pub fn normalize_low_s(s: &[u8]) -> Vec<u8> {
+    let order = BigNum::from_slice(&P256_ORDER).unwrap();
+    let s = BigNum::from_slice(s).unwrap();
+    let mut half_order = BigNum::new().unwrap();
+    half_order.rshift1(&order).unwrap();
+    if s <= half_order {
+        return s.to_vec();
+    }
+    let mut low_s = BigNum::new().unwrap();
+    low_s.checked_sub(&order, &s).unwrap();
+    low_s.to_vec()
+}
+
+fn push_integer(der: &mut Vec<u8>, value: &[u8]) {
+    let first_non_zero = value.iter().position(|&byte| byte != 0);
+    let value = match first_non_zero {
+        Some(index) => &value[index..],
+        // Zero is encoded as a single 0x00 content byte
+        None => &[0u8][..],
+    };
+    let needs_padding = value[0] & 0x80 != 0;
+
+    der.push(0x02);
+    push_length(der, value.len() + needs_padding as usize);
+    if needs_padding {
+        der.push(0x00);
+    }
+    der.extend_from_slice(value);
+}
+
+fn push_length(der: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        der.push(len as u8);
+        return;
+    }
+    let len_bytes = (len as u64).to_be_bytes();
+    let first_non_zero = len_bytes.iter().position(|&byte| byte != 0).unwrap();
+    der.push(0x80 | (len_bytes.len() - first_non_zero) as u8);
+    der.extend_from_slice(&len_bytes[first_non_zero..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::ecdsa::EcdsaSig;
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::sha::sha256;
+    use openssl::sign::Verifier;
+
+    use super::*;
+
+    fn is_low_s(s: &[u8]) -> bool {
+        let order = BigNum::from_slice(&P256_ORDER).unwrap();
+        let mut half_order = BigNum::new().unwrap();
+        half_order.rshift1(&order).unwrap();
+        BigNum::from_slice(s).unwrap() <= half_order
+    }
+
+    #[test]
+    fn to_der_pads_integers_with_high_bit_set() {
+        let der = to_der(&[0x80, 0x01], &[0xff]);
+
+        assert_eq!(
+            der,
+            vec![0x30, 0x09, 0x02, 0x03, 0x00, 0x80, 0x01, 0x02, 0x02, 0x00, 0xff]
+        );
+    }
+
+    #[test]
+    fn to_der_strips_leading_zeros() {
+        let der = to_der(&[0x00, 0x00, 0x12, 0x34], &[0x00, 0x80]);
+
+        assert_eq!(
+            der,
+            vec![0x30, 0x08, 0x02, 0x02, 0x12, 0x34, 0x02, 0x02, 0x00, 0x80]
+        );
+    }
+
+    #[test]
+    fn to_der_encodes_zero_as_single_byte() {
+        let der = to_der(&[0x00, 0x00], &[]);
+
+        assert_eq!(der, vec![0x30, 0x06, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn to_der_uses_long_form_length_for_large_sequences() {
+        let der = to_der(&[0x01; 70], &[0x01; 70]);
+
+        assert_eq!(&der[..3], &[0x30, 0x81, 0x90]);
+        assert_eq!(der.len(), 3 + 2 * 72);
+    }
+
+    #[test]
+    fn normalize_low_s_flips_high_s_and_still_verifies() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let digest = sha256(b"message");
+        let sig = EcdsaSig::sign(&digest, &key).unwrap();
+        let r = sig.r().to_vec();
+        // n - s is the other valid signature for r, pick whichever of the two is high
+        let mut high_s = BigNum::new().unwrap();
+        high_s
+            .checked_sub(&BigNum::from_slice(&P256_ORDER).unwrap(), sig.s())
+            .unwrap();
+        let high_s = if is_low_s(&high_s.to_vec()) {
+            sig.s().to_vec()
+        } else {
+            high_s.to_vec()
+        };
+        assert!(!is_low_s(&high_s));
+
+        let low_s = normalize_low_s(&high_s);
+
+        assert!(is_low_s(&low_s));
+        let pkey = PKey::from_ec_key(key).unwrap();
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &pkey).unwrap();
+        verifier.update(b"message").unwrap();
+        assert!(verifier.verify(&to_der(&r, &low_s)).unwrap());
+    }
+
+    #[test]
+    fn normalize_low_s_keeps_low_s() {
+        let order = BigNum::from_slice(&P256_ORDER).unwrap();
+        let mut half_order = BigNum::new().unwrap();
+        half_order.rshift1(&order).unwrap();
+
+        assert_eq!(normalize_low_s(&half_order.to_vec()), half_order.to_vec());
+        assert_eq!(normalize_low_s(&[0x01]), vec![0x01]);
+    }
+}
@@ -0,0 +1,32 @@
+//! Signing with keys this process never holds
+//!
+//! An `ApplicationKey` normally carries its private key, so it can be stored and
+//! signs in process. A key kept in a TPM or a PKCS#11 token instead is reached
+//! through a `Signer`, see `ApplicationKey::with_signer`. Such keys cannot be
+//! serialized, they must be kept by a store that does not write keys out.
+use futures::future;
+use futures::Future;
+
+use super::SignError;
+use private_key::PrivateKey;
+use public_key::PublicKey;
+use signature::EcdsaSignature;
+
+/// Holder of a P-256 private key that signs on request
+pub trait Signer {
+    /// Uncompressed SEC1 public point, [0x04, X (32 bytes), Y (32 bytes)]
+    fn public_key_sec1(&self) -> [u8; 65];
+    /// Sign the SHA-256 hash of `message`, failing with `SignError::Signer` if the
+    /// device holding the key cannot
+    fn sign(&self, message: &[u8]) -> Box<dyn Future<Item = EcdsaSignature, Error = SignError>>;
+}
+
+impl Signer for PrivateKey {
+    fn public_key_sec1(&self) -> [u8; 65] {
+        PublicKey::from_key(self).to_sec1()
+    }
+
+    fn sign(&self, message: &[u8]) -> Box<dyn Future<Item = EcdsaSignature, Error = SignError>> {
+        Box::new(future::ok(EcdsaSignature::sign(self, message)))
+    }
+}
@@ -0,0 +1,159 @@
+//! Test of user presence, the soft token equivalent of touching a hardware key
+//!
+//! Every register and authenticate request that requires presence is passed to
+//! `UserPresence::approve` before any key is generated or used. A denied request is
+//! answered with `SW_CONDITIONS_NOT_SATISFIED`, which makes the browser retry until the
+//! user approves or the request times out.
+//!
+//! A desktop prompt can be wired up by implementing the trait on a type that shows a
+//! notification with approve and deny actions and resolves the returned future with the
+//! action taken. Blocking notification APIs should be run on a thread pool, as the
+//! softu2f user daemon does, so they do not stall the event loop.
+use std::cell::Cell;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use futures::Future;
+
+use app_id::AppId;
+use known_app_ids::try_reverse_app_id;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Operation {
+    Register,
+    Authenticate,
+}
+
+/// What the user is being asked to approve
+#[derive(Clone, Debug)]
+pub struct ApprovalRequest {
+    pub operation: Operation,
+    pub application: AppId,
+    /// Display name of the application, if it is a well-known one
+    pub facet: Option<String>,
+}
+
+impl ApprovalRequest {
+    pub fn new(operation: Operation, application: AppId) -> ApprovalRequest {
+        ApprovalRequest {
+            operation,
+            application,
+            facet: try_reverse_app_id(&application),
+        }
+    }
+}
+
+pub trait UserPresence {
+    fn approve(&self, request: &ApprovalRequest)
+        -> Box<dyn Future<Item = bool, Error = io::Error>>;
+    fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>>;
+
+    /// Called once a key has been registered for the application
+    fn registered(&self, _application: &AppId) {}
+
+    /// Called once an authentication has been signed for the application
+    fn authenticated(&self, _application: &AppId) {}
+}
+
+/// Approves every request without asking, only suitable for tests and headless setups
+pub struct AlwaysApprove;
+
+impl UserPresence for AlwaysApprove {
+    fn approve(&self, _: &ApprovalRequest) -> Box<dyn Future<Item = bool, Error = io::Error>> {
+        Box::new(future::ok(true))
+    }
+
+    fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        Box::new(future::ok(()))
+    }
+}
+
+/// Approves requests made within `window` of the last call to `arm`, a stand-in for a
+/// touch in headless tests
+///
+/// Clones share when they were last armed, so a test keeps a clone to arm while the
+/// token owns another. Never arming it exercises the denied path.
+#[derive(Clone)]
+pub struct TimedApprove {
+    window: Duration,
+    armed_at: Rc<Cell<Option<Instant>>>,
+}
+
+impl TimedApprove {
+    pub fn new(window: Duration) -> TimedApprove {
+        TimedApprove {
+            window,
+            armed_at: Rc::new(Cell::new(None)),
+        }
+    }
+
+    /// Simulate a touch, approving requests for the next `window`
+    pub fn arm(&self) {
+        self.armed_at.set(Some(Instant::now()));
+    }
+
+    fn is_armed_at(&self, now: Instant) -> bool {
+        self.armed_at
+            .get()
+            .is_some_and(|armed_at| now.duration_since(armed_at) <= self.window)
+    }
+}
+
+impl UserPresence for TimedApprove {
+    fn approve(&self, _: &ApprovalRequest) -> Box<dyn Future<Item = bool, Error = io::Error>> {
+        Box::new(future::ok(self.is_armed_at(Instant::now())))
+    }
+
+    fn wink(&self) -> Box<dyn Future<Item = (), Error = io::Error>> {
+        Box::new(future::ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timed_approve() -> TimedApprove {
+        TimedApprove::new(Duration::from_secs(5))
+    }
+
+    #[test]
+    fn armed_within_window_approves() {
+        let presence = timed_approve();
+        presence.arm();
+
+        let approved = presence
+            .approve(&ApprovalRequest::new(Operation::Register, AppId([1u8; 32])))
+            .wait()
+            .unwrap();
+
+        assert!(approved);
+    }
+
+    #[test]
+    fn armed_before_window_is_expired() {
+        let presence = timed_approve();
+        presence.arm();
+
+        let later = Instant::now() + Duration::from_secs(6);
+
+        assert!(!presence.is_armed_at(later));
+    }
+
+    #[test]
+    fn never_armed_denies() {
+        let presence = timed_approve();
+
+        let approved = presence
+            .approve(&ApprovalRequest::new(
+                Operation::Authenticate,
+                AppId([1u8; 32]),
+            ))
+            .wait()
+            .unwrap();
+
+        assert!(!approved);
+    }
+}
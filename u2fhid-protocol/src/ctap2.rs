@@ -0,0 +1,88 @@
+//! Minimal CTAP2 support, enough to answer `authenticatorGetInfo`
+//!
+//! The token only speaks U2F, getInfo says so by listing `U2F_V2` as its only
+//! version, which tells CTAP2 clients to fall back to U2F messages. Every other
+//! CTAP2 command is rejected with `CTAP1_ERR_INVALID_COMMAND`.
+
+use u2f_core::cbor::{push_bool, push_bytes, push_header, push_text, MAJOR_ARRAY, MAJOR_MAP,
+                     MAJOR_UNSIGNED};
+
+pub const AUTHENTICATOR_GET_INFO: u8 = 0x04;
+
+const CTAP2_OK: u8 = 0x00;
+const CTAP1_ERR_INVALID_COMMAND: u8 = 0x01;
+const CTAP1_ERR_INVALID_LENGTH: u8 = 0x03;
+
+const GET_INFO_VERSIONS: u64 = 0x01;
+const GET_INFO_AAGUID: u64 = 0x03;
+const GET_INFO_OPTIONS: u64 = 0x04;
+
+/// A U2F-only authenticator has no AAGUID, it is reported as all zeros
+const AAGUID: [u8; 16] = [0u8; 16];
+
+/// Handle the payload of a `CTAPHID_CBOR` message, returning the response payload
+///
+/// The payload is the CTAP2 command byte followed by its CBOR parameters, the
+/// response is a status byte followed by the CBOR encoded result, if any.
+pub fn handle_request(data: &[u8]) -> Vec<u8> {
+    match data.first() {
+        Some(&AUTHENTICATOR_GET_INFO) => {
+            let mut response = vec![CTAP2_OK];
+            response.extend_from_slice(&get_info());
+            response
+        }
+        Some(_) => vec![CTAP1_ERR_INVALID_COMMAND],
+        None => vec![CTAP1_ERR_INVALID_LENGTH],
+    }
+}
+
+/// CBOR encoded `authenticatorGetInfo` response map
+///
+/// Keys are in canonical CBOR order as CTAP2 requires.
+pub fn get_info() -> Vec<u8> {
+    let mut cbor = Vec::new();
+    push_header(&mut cbor, MAJOR_MAP, 3);
+
+    push_header(&mut cbor, MAJOR_UNSIGNED, GET_INFO_VERSIONS);
+    push_header(&mut cbor, MAJOR_ARRAY, 1);
+    push_text(&mut cbor, "U2F_V2");
+
+    push_header(&mut cbor, MAJOR_UNSIGNED, GET_INFO_AAGUID);
+    push_bytes(&mut cbor, &AAGUID);
+
+    push_header(&mut cbor, MAJOR_UNSIGNED, GET_INFO_OPTIONS);
+    push_header(&mut cbor, MAJOR_MAP, 3);
+    // No resident keys, user presence is tested, not a platform authenticator
+    push_text(&mut cbor, "rk");
+    push_bool(&mut cbor, false);
+    push_text(&mut cbor, "up");
+    push_bool(&mut cbor, true);
+    push_text(&mut cbor, "plat");
+    push_bool(&mut cbor, false);
+    cbor
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_info_lists_u2f_v2_as_only_version() {
+        let response = handle_request(&[AUTHENTICATOR_GET_INFO]);
+
+        assert_eq!(response[0], CTAP2_OK);
+        // Map of three entries, key 1 => ["U2F_V2"]
+        assert_eq!(
+            &response[1..10],
+            &[0xa3, 0x01, 0x81, 0x66, b'U', b'2', b'F', b'_', b'V']
+        );
+        assert_eq!(response[10], b'2');
+        assert!(!response.windows(8).any(|window| window == b"FIDO_2_0"));
+    }
+
+    #[test]
+    fn other_commands_are_invalid() {
+        assert_eq!(handle_request(&[0x01]), vec![CTAP1_ERR_INVALID_COMMAND]);
+        assert_eq!(handle_request(&[]), vec![CTAP1_ERR_INVALID_LENGTH]);
+    }
+}
@@ -0,0 +1,803 @@
+//! CTAPHID framing between 64-byte HID reports and complete messages
+//!
+//! A message is sent as one initialization packet followed by up to 128
+//! continuation packets with sequence numbers `0x00..=0x7f`. The sequence
+//! number never wraps around: bit 7 marks an initialization packet, so a
+//! message that would need a 129th continuation packet is too long to be
+//! framed at all and is rejected with `ERR_INVALID_LEN` instead.
+//!
+//! Only one transaction can be in flight at a time. It starts with the
+//! initialization packet of a request and lasts until the caller has sent
+//! the response and calls
+//! [`finish`](struct.Reassembler.html#method.finish), until then other
+//! channels get `ERR_CHANNEL_BUSY`.
+//!
+//! Nothing here performs IO or schedules timers. A message whose
+//! continuation packets stall is only dropped once the caller polls
+//! [`expire`](struct.Reassembler.html#method.expire), which reads the time
+//! from a `Clock` so tests can control it.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::vec_deque::VecDeque;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use definitions::*;
+
+/// Highest sequence number a continuation packet can carry
+pub const MAX_SEQUENCE_NUMBER: u8 = 0x7f;
+
+/// Largest payload that fits in one initialization and 128 continuation packets
+pub const MAX_MESSAGE_LEN: usize =
+    INITIAL_PACKET_DATA_LEN + (MAX_SEQUENCE_NUMBER as usize + 1) * CONTINUATION_PACKET_DATA_LEN;
+
+const MAX_CHANNEL_ID: ChannelId = ChannelId(BROADCAST_CHANNEL_ID.0 - 1);
+const MIN_CHANNEL_ID: ChannelId = ChannelId(1);
+
+/// A complete message reassembled from its packets
+#[derive(Debug, PartialEq)]
+pub struct Message {
+    pub channel_id: ChannelId,
+    pub command: Command,
+    pub data: Vec<u8>,
+}
+
+quick_error! {
+    #[derive(Debug, PartialEq)]
+    pub enum FramingError {
+        InvalidSequence(channel_id: ChannelId, expected: u8, actual: u8) {
+            display("Expected sequence number {} but got {}", expected, actual)
+        }
+        InterruptedMessage(channel_id: ChannelId) {
+            display("Initialization packet received while a message was in progress")
+        }
+        MessageTooLong(channel_id: ChannelId, len: usize) {
+            display("Message length {} exceeds maximum of {}", len, MAX_MESSAGE_LEN)
+        }
+        ChannelBusy(channel_id: ChannelId) {
+            display("A transaction is in progress on another channel")
+        }
+        MessageTimedOut(channel_id: ChannelId) {
+            display("Timed out waiting for the rest of the message")
+        }
+    }
+}
+
+impl FramingError {
+    pub fn channel_id(&self) -> ChannelId {
+        match *self {
+            FramingError::InvalidSequence(channel_id, ..) => channel_id,
+            FramingError::InterruptedMessage(channel_id) => channel_id,
+            FramingError::MessageTooLong(channel_id, _) => channel_id,
+            FramingError::ChannelBusy(channel_id) => channel_id,
+            FramingError::MessageTimedOut(channel_id) => channel_id,
+        }
+    }
+
+    pub fn error_code(&self) -> ErrorCode {
+        match *self {
+            FramingError::InvalidSequence(..) => ErrorCode::InvalidMessageSequencing,
+            FramingError::InterruptedMessage(_) => ErrorCode::InvalidMessageSequencing,
+            FramingError::MessageTooLong(..) => ErrorCode::InvalidMessageLength,
+            FramingError::ChannelBusy(_) => ErrorCode::ChannelBusy,
+            FramingError::MessageTimedOut(_) => ErrorCode::MessageTimedOut,
+        }
+    }
+
+    /// Error response to send back on the offending channel
+    pub fn into_response(self) -> Response {
+        Response {
+            channel_id: self.channel_id(),
+            message: ResponseMessage::Error {
+                code: self.error_code(),
+            },
+        }
+    }
+}
+
+struct PartialMessage {
+    command: Command,
+    payload_len: usize,
+    data: Vec<u8>,
+    next_sequence_number: u8,
+    last_packet: Instant,
+}
+
+impl PartialMessage {
+    fn is_complete(&self) -> bool {
+        self.data.len() >= self.payload_len
+    }
+
+    fn into_message(mut self, channel_id: ChannelId) -> Message {
+        // Packets are zero padded to the full report length
+        self.data.truncate(self.payload_len);
+        Message {
+            channel_id,
+            command: self.command,
+            data: self.data,
+        }
+    }
+}
+
+/// Source of the current time for message timeouts
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Reassembles packets into messages, one transaction at a time
+pub struct Reassembler {
+    pending: HashMap<ChannelId, PartialMessage>,
+    active: Option<ChannelId>,
+    timeout: Duration,
+    clock: Box<dyn Clock>,
+}
+
+impl Reassembler {
+    /// Reassembler with the spec's packet timeout, see `packet_timeout_duration`
+    pub fn new() -> Reassembler {
+        Reassembler::with_timeout(packet_timeout_duration(), Box::new(SystemClock))
+    }
+
+    /// Reassembler dropping messages that go `timeout` without a packet, as told by `clock`
+    pub fn with_timeout(timeout: Duration, clock: Box<dyn Clock>) -> Reassembler {
+        Reassembler {
+            pending: HashMap::new(),
+            active: None,
+            timeout,
+            clock,
+        }
+    }
+
+    /// Accept the next packet, returning a message once its last packet arrives
+    ///
+    /// An error discards anything received so far on that channel and ends
+    /// its transaction. A `CTAPHID_INIT` in the middle of a message is a
+    /// resynchronization and replaces the partial message, other commands are
+    /// an `ERR_INVALID_SEQ`. Initialization packets on other channels while a
+    /// transaction is in flight are an `ERR_CHANNEL_BUSY` and leave that
+    /// transaction untouched. Continuation packets on a channel with no
+    /// message in progress are ignored.
+    pub fn accept(&mut self, packet: Packet) -> Result<Option<Message>, FramingError> {
+        match packet {
+            Packet::Initialization {
+                channel_id,
+                command,
+                data,
+                payload_len,
+            } => {
+                match self.active {
+                    Some(active) if active != channel_id => {
+                        return Err(FramingError::ChannelBusy(channel_id));
+                    }
+                    _ => {}
+                }
+                let resync = matches!(command, Command::Init);
+                if self.pending.contains_key(&channel_id) && !resync {
+                    self.discard(channel_id);
+                    return Err(FramingError::InterruptedMessage(channel_id));
+                }
+                self.discard(channel_id);
+                if payload_len > MAX_MESSAGE_LEN {
+                    return Err(FramingError::MessageTooLong(channel_id, payload_len));
+                }
+                self.active = Some(channel_id);
+                let partial = PartialMessage {
+                    command,
+                    payload_len,
+                    data,
+                    next_sequence_number: 0,
+                    last_packet: self.clock.now(),
+                };
+                Ok(self.complete_or_store(channel_id, partial))
+            }
+            Packet::Continuation {
+                channel_id,
+                sequence_number,
+                data,
+            } => {
+                let mut partial = match self.pending.remove(&channel_id) {
+                    Some(partial) => partial,
+                    None => return Ok(None),
+                };
+                if sequence_number != partial.next_sequence_number {
+                    self.discard(channel_id);
+                    return Err(FramingError::InvalidSequence(
+                        channel_id,
+                        partial.next_sequence_number,
+                        sequence_number,
+                    ));
+                }
+                let remaining = partial.payload_len - partial.data.len();
+                partial
+                    .data
+                    .extend_from_slice(&data[..cmp::min(remaining, data.len())]);
+                // Cannot pass MAX_SEQUENCE_NUMBER, the payload length is
+                // bounded so the message is complete by the last sequence number
+                partial.next_sequence_number = sequence_number + 1;
+                partial.last_packet = self.clock.now();
+                Ok(self.complete_or_store(channel_id, partial))
+            }
+        }
+    }
+
+    /// Forget any partial message on the channel and end its transaction,
+    /// e.g. after a timeout
+    pub fn abort(&mut self, channel_id: ChannelId) {
+        self.discard(channel_id);
+    }
+
+    /// Drop a partial message whose next packet is overdue, ending its transaction
+    ///
+    /// Returns the `ERR_MSG_TIMEOUT` error to send on that channel. Should be
+    /// called periodically while `active_channel` has a message pending.
+    pub fn expire(&mut self) -> Option<FramingError> {
+        let now = self.clock.now();
+        let timeout = self.timeout;
+        let channel_id = self
+            .pending
+            .iter()
+            .find(|&(_, partial)| now.duration_since(partial.last_packet) >= timeout)
+            .map(|(&channel_id, _)| channel_id)?;
+        self.discard(channel_id);
+        Some(FramingError::MessageTimedOut(channel_id))
+    }
+
+    /// End the transaction on the channel once its response has been sent
+    pub fn finish(&mut self, channel_id: ChannelId) {
+        if self.active == Some(channel_id) {
+            self.active = None;
+        }
+    }
+
+    pub fn is_pending(&self, channel_id: ChannelId) -> bool {
+        self.pending.contains_key(&channel_id)
+    }
+
+    /// Channel with a transaction in flight, if any
+    pub fn active_channel(&self) -> Option<ChannelId> {
+        self.active
+    }
+
+    fn discard(&mut self, channel_id: ChannelId) {
+        self.pending.remove(&channel_id);
+        self.finish(channel_id);
+    }
+
+    fn complete_or_store(
+        &mut self,
+        channel_id: ChannelId,
+        partial: PartialMessage,
+    ) -> Option<Message> {
+        if partial.is_complete() {
+            Some(partial.into_message(channel_id))
+        } else {
+            self.pending.insert(channel_id, partial);
+            None
+        }
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Reassembler {
+        Reassembler::new()
+    }
+}
+
+/// Split a message into an initialization packet and continuation packets
+pub fn fragment(
+    channel_id: ChannelId,
+    command: Command,
+    data: &[u8],
+) -> Result<VecDeque<Packet>, FramingError> {
+    if data.len() > MAX_MESSAGE_LEN {
+        return Err(FramingError::MessageTooLong(channel_id, data.len()));
+    }
+    let mut packets = VecDeque::new();
+    let split_index = cmp::min(data.len(), INITIAL_PACKET_DATA_LEN);
+    let (initial, remaining) = data.split_at(split_index);
+    packets.push_back(Packet::Initialization {
+        channel_id,
+        command,
+        payload_len: data.len(),
+        data: initial.to_vec(),
+    });
+    for (i, chunk) in remaining.chunks(CONTINUATION_PACKET_DATA_LEN).enumerate() {
+        packets.push_back(Packet::Continuation {
+            channel_id,
+            sequence_number: i as u8,
+            data: chunk.to_vec(),
+        });
+    }
+    Ok(packets)
+}
+
+/// Allocates channel IDs in response to `CTAPHID_INIT`
+#[derive(Debug)]
+pub struct Channels {
+    next_allocation: ChannelId,
+}
+
+impl Channels {
+    pub fn new() -> Channels {
+        Channels {
+            next_allocation: MIN_CHANNEL_ID,
+        }
+    }
+
+    pub fn allocate(&mut self) -> Option<ChannelId> {
+        if self.next_allocation > MAX_CHANNEL_ID {
+            None
+        } else {
+            let allocation = self.next_allocation;
+            self.next_allocation = self.next_allocation.checked_add(1).unwrap();
+            Some(allocation)
+        }
+    }
+
+    pub fn is_valid(&self, channel_id: ChannelId) -> bool {
+        let is_broadcast = channel_id == BROADCAST_CHANNEL_ID;
+        let is_in_allocated_range =
+            channel_id >= MIN_CHANNEL_ID && channel_id < self.next_allocation;
+        is_broadcast || is_in_allocated_range
+    }
+
+    /// Respond to `CTAPHID_INIT`, echoing the nonce back and advertising `capabilities`
+    ///
+    /// On the broadcast channel a new channel is allocated, on an already
+    /// allocated channel the same channel ID is returned.
+    pub fn init(
+        &mut self,
+        channel_id: ChannelId,
+        nonce: [u8; 8],
+        capabilities: CapabilityFlags,
+    ) -> Response {
+        let new_channel_id = if channel_id == BROADCAST_CHANNEL_ID {
+            match self.allocate() {
+                Some(new_channel_id) => new_channel_id,
+                None => {
+                    return Response {
+                        channel_id,
+                        message: ResponseMessage::Error {
+                            code: ErrorCode::Other,
+                        },
+                    }
+                }
+            }
+        } else {
+            channel_id
+        };
+        Response {
+            channel_id,
+            message: ResponseMessage::Init {
+                nonce,
+                new_channel_id,
+                u2fhid_protocol_version: U2FHID_PROTOCOL_VERSION,
+                major_device_version_number: MAJOR_DEVICE_VERSION_NUMBER,
+                minor_device_version_number: MINOR_DEVICE_VERSION_NUMBER,
+                build_device_version_number: BUILD_DEVICE_VERSION_NUMBER,
+                capabilities,
+            },
+        }
+    }
+}
+
+impl Default for Channels {
+    fn default() -> Channels {
+        Channels::new()
+    }
+}
+
+/// Channel allocations that can outlive the device they were made on
+///
+/// When a device is recreated, e.g. after suspend and resume, browsers keep using
+/// the channel IDs the old device gave them. Binding each new device to the same
+/// table lets those channels stay valid. With `reset_on_recreate` every device
+/// bound to the table starts with no channels allocated instead, so browsers have
+/// to send `CTAPHID_INIT` again.
+#[derive(Clone, Debug)]
+pub struct SharedChannels {
+    channels: Rc<RefCell<Channels>>,
+    reset_on_recreate: bool,
+}
+
+impl SharedChannels {
+    pub fn new(reset_on_recreate: bool) -> SharedChannels {
+        SharedChannels {
+            channels: Rc::new(RefCell::new(Channels::new())),
+            reset_on_recreate,
+        }
+    }
+
+    /// Table for a newly created device, reset first if `reset_on_recreate`
+    pub fn attach(&self) -> SharedChannels {
+        if self.reset_on_recreate {
+            *self.channels.borrow_mut() = Channels::new();
+        }
+        self.clone()
+    }
+
+    pub fn is_valid(&self, channel_id: ChannelId) -> bool {
+        self.channels.borrow().is_valid(channel_id)
+    }
+
+    /// See `Channels::init`
+    pub fn init(
+        &self,
+        channel_id: ChannelId,
+        nonce: [u8; 8],
+        capabilities: CapabilityFlags,
+    ) -> Response {
+        self.channels
+            .borrow_mut()
+            .init(channel_id, nonce, capabilities)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    const CHANNEL_ID: ChannelId = ChannelId(7);
+
+    fn message_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    /// Round trip packets through their wire encoding, as the transport would
+    fn over_the_wire(packets: VecDeque<Packet>) -> Vec<Packet> {
+        packets
+            .into_iter()
+            .map(|packet| {
+                let mut report = vec![0u8];
+                report.extend_from_slice(&packet.into_bytes());
+                Packet::from_bytes(&report).unwrap()
+            })
+            .collect()
+    }
+
+    fn reassemble(packets: Vec<Packet>) -> Result<Option<Message>, FramingError> {
+        let mut reassembler = Reassembler::new();
+        let mut result = Ok(None);
+        for packet in packets {
+            result = reassembler.accept(packet);
+        }
+        result
+    }
+
+    #[test]
+    fn single_packet_message() {
+        let data = message_data(INITIAL_PACKET_DATA_LEN);
+        let packets = over_the_wire(fragment(CHANNEL_ID, Command::Ping, &data).unwrap());
+
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            reassemble(packets),
+            Ok(Some(Message {
+                channel_id: CHANNEL_ID,
+                command: Command::Ping,
+                data,
+            }))
+        );
+    }
+
+    #[test]
+    fn multi_packet_message() {
+        let data = message_data(300);
+        let packets = over_the_wire(fragment(CHANNEL_ID, Command::Msg, &data).unwrap());
+
+        assert_eq!(packets.len(), 6);
+        assert_eq!(reassemble(packets).unwrap().unwrap().data, data);
+    }
+
+    #[test]
+    fn maximum_length_message_uses_every_sequence_number() {
+        let data = message_data(MAX_MESSAGE_LEN);
+        let packets = over_the_wire(fragment(CHANNEL_ID, Command::Msg, &data).unwrap());
+
+        assert_eq!(packets.len(), MAX_SEQUENCE_NUMBER as usize + 2);
+        match packets.last() {
+            Some(&Packet::Continuation {
+                sequence_number, ..
+            }) => assert_eq!(sequence_number, MAX_SEQUENCE_NUMBER),
+            _ => panic!("expected continuation packet"),
+        }
+        assert_eq!(reassemble(packets).unwrap().unwrap().data, data);
+    }
+
+    #[test]
+    fn fragment_rejects_message_needing_sequence_wraparound() {
+        assert_eq!(
+            fragment(CHANNEL_ID, Command::Msg, &message_data(MAX_MESSAGE_LEN + 1)),
+            Err(FramingError::MessageTooLong(
+                CHANNEL_ID,
+                MAX_MESSAGE_LEN + 1
+            ))
+        );
+    }
+
+    #[test]
+    fn reassemble_rejects_message_needing_sequence_wraparound() {
+        let packet = Packet::Initialization {
+            channel_id: CHANNEL_ID,
+            command: Command::Msg,
+            data: message_data(INITIAL_PACKET_DATA_LEN),
+            payload_len: MAX_MESSAGE_LEN + 1,
+        };
+
+        let error = Reassembler::new().accept(packet).unwrap_err();
+
+        assert_eq!(error.error_code(), ErrorCode::InvalidMessageLength);
+    }
+
+    #[test]
+    fn out_of_order_continuation_is_invalid_sequence() {
+        let mut packets =
+            over_the_wire(fragment(CHANNEL_ID, Command::Msg, &message_data(300)).unwrap());
+        packets.swap(2, 3);
+        let mut reassembler = Reassembler::new();
+
+        let mut results = packets.into_iter().map(|packet| reassembler.accept(packet));
+
+        assert_eq!(results.next(), Some(Ok(None)));
+        assert_eq!(results.next(), Some(Ok(None)));
+        let error = results.next().unwrap().unwrap_err();
+        assert_eq!(error, FramingError::InvalidSequence(CHANNEL_ID, 1, 2));
+        assert_eq!(error.error_code(), ErrorCode::InvalidMessageSequencing);
+        assert!(!reassembler.is_pending(CHANNEL_ID));
+    }
+
+    #[test]
+    fn repeated_continuation_is_invalid_sequence() {
+        let packets =
+            over_the_wire(fragment(CHANNEL_ID, Command::Msg, &message_data(300)).unwrap());
+        let mut reassembler = Reassembler::new();
+        reassembler.accept(packets[0].clone()).unwrap();
+        reassembler.accept(packets[1].clone()).unwrap();
+
+        assert_eq!(
+            reassembler.accept(packets[1].clone()),
+            Err(FramingError::InvalidSequence(CHANNEL_ID, 1, 0))
+        );
+    }
+
+    #[test]
+    fn continuation_without_initialization_is_ignored() {
+        let packet = Packet::Continuation {
+            channel_id: CHANNEL_ID,
+            sequence_number: 0,
+            data: message_data(CONTINUATION_PACKET_DATA_LEN),
+        };
+
+        assert_eq!(Reassembler::new().accept(packet), Ok(None));
+    }
+
+    #[test]
+    fn initialization_during_message_is_invalid_sequence() {
+        let packets =
+            over_the_wire(fragment(CHANNEL_ID, Command::Msg, &message_data(300)).unwrap());
+        let mut reassembler = Reassembler::new();
+        reassembler.accept(packets[0].clone()).unwrap();
+
+        let error = reassembler.accept(packets[0].clone()).unwrap_err();
+
+        assert_eq!(error, FramingError::InterruptedMessage(CHANNEL_ID));
+        assert_eq!(error.error_code(), ErrorCode::InvalidMessageSequencing);
+    }
+
+    #[test]
+    fn init_during_message_resynchronizes() {
+        let packets =
+            over_the_wire(fragment(CHANNEL_ID, Command::Msg, &message_data(300)).unwrap());
+        let mut reassembler = Reassembler::new();
+        reassembler.accept(packets[0].clone()).unwrap();
+
+        let message = reassembler
+            .accept(Packet::Initialization {
+                channel_id: CHANNEL_ID,
+                command: Command::Init,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                payload_len: 8,
+            })
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(message.data, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(!reassembler.is_pending(CHANNEL_ID));
+    }
+
+    #[test]
+    fn other_channel_is_busy_during_transaction() {
+        let other_channel_id = ChannelId(8);
+        let first = over_the_wire(fragment(CHANNEL_ID, Command::Msg, &message_data(100)).unwrap());
+        let second =
+            over_the_wire(fragment(other_channel_id, Command::Ping, &message_data(70)).unwrap());
+        let mut reassembler = Reassembler::new();
+
+        assert_eq!(reassembler.accept(first[0].clone()), Ok(None));
+        let error = reassembler.accept(second[0].clone()).unwrap_err();
+        assert_eq!(error, FramingError::ChannelBusy(other_channel_id));
+        assert_eq!(error.error_code(), ErrorCode::ChannelBusy);
+        assert_eq!(reassembler.accept(second[1].clone()), Ok(None));
+        let first_message = reassembler.accept(first[1].clone()).unwrap().unwrap();
+        assert_eq!(first_message.channel_id, CHANNEL_ID);
+        assert_eq!(first_message.data, message_data(100));
+
+        // Still busy until the response has been sent
+        assert_eq!(
+            reassembler.accept(second[0].clone()),
+            Err(FramingError::ChannelBusy(other_channel_id))
+        );
+        reassembler.finish(CHANNEL_ID);
+
+        assert_eq!(reassembler.accept(second[0].clone()), Ok(None));
+        let second_message = reassembler.accept(second[1].clone()).unwrap().unwrap();
+        assert_eq!(second_message.channel_id, other_channel_id);
+        assert_eq!(second_message.data, message_data(70));
+        assert_eq!(reassembler.active_channel(), Some(other_channel_id));
+    }
+
+    #[test]
+    fn error_ends_transaction() {
+        let packets =
+            over_the_wire(fragment(CHANNEL_ID, Command::Msg, &message_data(100)).unwrap());
+        let mut reassembler = Reassembler::new();
+        reassembler.accept(packets[0].clone()).unwrap();
+
+        reassembler.accept(packets[0].clone()).unwrap_err();
+
+        assert_eq!(reassembler.active_channel(), None);
+    }
+
+    #[test]
+    fn abort_discards_partial_message() {
+        let packets =
+            over_the_wire(fragment(CHANNEL_ID, Command::Msg, &message_data(100)).unwrap());
+        let mut reassembler = Reassembler::new();
+        reassembler.accept(packets[0].clone()).unwrap();
+
+        reassembler.abort(CHANNEL_ID);
+
+        assert!(!reassembler.is_pending(CHANNEL_ID));
+        assert_eq!(reassembler.accept(packets[1].clone()), Ok(None));
+    }
+
+    /// Clock that only moves when told to
+    #[derive(Clone)]
+    struct MockClock(Rc<Cell<Instant>>);
+
+    impl MockClock {
+        fn new() -> MockClock {
+            MockClock(Rc::new(Cell::new(Instant::now())))
+        }
+
+        fn advance(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn stalled_message_times_out() {
+        let clock = MockClock::new();
+        let timeout = Duration::from_millis(500);
+        let packets =
+            over_the_wire(fragment(CHANNEL_ID, Command::Msg, &message_data(200)).unwrap());
+        let mut reassembler = Reassembler::with_timeout(timeout, Box::new(clock.clone()));
+        reassembler.accept(packets[0].clone()).unwrap();
+        clock.advance(Duration::from_millis(400));
+        reassembler.accept(packets[1].clone()).unwrap();
+
+        // Each packet restarts the timeout
+        clock.advance(Duration::from_millis(400));
+        assert_eq!(reassembler.expire(), None);
+        clock.advance(Duration::from_millis(100));
+        let error = reassembler.expire().unwrap();
+
+        assert_eq!(error, FramingError::MessageTimedOut(CHANNEL_ID));
+        assert_eq!(error.error_code(), ErrorCode::MessageTimedOut);
+        assert!(!reassembler.is_pending(CHANNEL_ID));
+        assert_eq!(reassembler.active_channel(), None);
+        assert_eq!(reassembler.accept(packets[2].clone()), Ok(None));
+    }
+
+    #[test]
+    fn report_of_wrong_length_is_not_a_packet() {
+        assert_eq!(Packet::from_bytes(&[0u8; HID_REPORT_LEN]), Err(()));
+        assert_eq!(Packet::from_bytes(&[0u8; HID_REPORT_LEN + 2]), Err(()));
+    }
+
+    proptest! {
+        #[test]
+        fn random_reports_never_panic_or_overgrow(
+            reports in proptest::collection::vec(
+                proptest::collection::vec(proptest::num::u8::ANY, HID_REPORT_LEN + 1),
+                0..200,
+            ),
+        ) {
+            let mut reassembler = Reassembler::new();
+            for report in reports {
+                let packet = Packet::from_bytes(&report).unwrap();
+                let channel_id = packet.channel_id();
+                if let Ok(Some(message)) = reassembler.accept(packet) {
+                    prop_assert!(message.data.len() <= MAX_MESSAGE_LEN);
+                    reassembler.finish(channel_id);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn init_on_broadcast_allocates_channel_and_echoes_nonce() {
+        let mut channels = Channels::new();
+        let nonce = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        match channels.init(BROADCAST_CHANNEL_ID, nonce, CapabilityFlags::empty()) {
+            Response {
+                channel_id,
+                message:
+                    ResponseMessage::Init {
+                        nonce: response_nonce,
+                        new_channel_id,
+                        ..
+                    },
+            } => {
+                assert_eq!(channel_id, BROADCAST_CHANNEL_ID);
+                assert_eq!(response_nonce, nonce);
+                assert_ne!(new_channel_id, BROADCAST_CHANNEL_ID);
+                assert!(channels.is_valid(new_channel_id));
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn init_allocates_distinct_channels() {
+        let mut channels = Channels::new();
+        let first = channels.allocate().unwrap();
+        let second = channels.allocate().unwrap();
+
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn init_on_allocated_channel_keeps_channel() {
+        let mut channels = Channels::new();
+        let channel_id = channels.allocate().unwrap();
+
+        match channels
+            .init(channel_id, [0u8; 8], CapabilityFlags::empty())
+            .message
+        {
+            ResponseMessage::Init { new_channel_id, .. } => {
+                assert_eq!(new_channel_id, channel_id)
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn unallocated_channel_is_invalid() {
+        let channels = Channels::new();
+
+        assert!(!channels.is_valid(ChannelId(1)));
+        assert!(!channels.is_valid(ChannelId(0)));
+    }
+}
@@ -1,9 +1,9 @@
-use std::cmp;
 use std::collections::vec_deque::VecDeque;
 use std::io::{Cursor, Read};
 use std::mem::size_of;
 use std::time::Duration;
 
+use ctaphid;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use slog;
 use u2f_core;
@@ -14,9 +14,9 @@ pub const BUILD_DEVICE_VERSION_NUMBER: u8 = 0;
 
 pub const U2FHID_PROTOCOL_VERSION: u8 = 2;
 
-const HID_REPORT_LEN: usize = 64;
-const INITIAL_PACKET_DATA_LEN: usize = HID_REPORT_LEN - 7;
-const CONTINUATION_PACKET_DATA_LEN: usize = HID_REPORT_LEN - 5;
+pub(crate) const HID_REPORT_LEN: usize = 64;
+pub(crate) const INITIAL_PACKET_DATA_LEN: usize = HID_REPORT_LEN - 7;
+pub(crate) const CONTINUATION_PACKET_DATA_LEN: usize = HID_REPORT_LEN - 5;
 
 const FRAME_TYPE_INIT: u8 = 0b1000_0000;
 const FRAME_TYPE_CONT: u8 = 0b0000_0000;
@@ -27,6 +27,9 @@ const U2FHID_MSG: u8 = FRAME_TYPE_INIT | 0x03; // Send U2F message frame
 const U2FHID_LOCK: u8 = FRAME_TYPE_INIT | 0x04; // Send lock channel command
 const U2FHID_INIT: u8 = FRAME_TYPE_INIT | 0x06; // Channel initialization
 const U2FHID_WINK: u8 = FRAME_TYPE_INIT | 0x08; // Send device identification wink
+const U2FHID_CBOR: u8 = FRAME_TYPE_INIT | 0x10; // Send CTAP2 CBOR encoded message
+const U2FHID_CANCEL: u8 = FRAME_TYPE_INIT | 0x11; // Cancel any outstanding request on the channel
+const U2FHID_KEEPALIVE: u8 = FRAME_TYPE_INIT | 0x3b; // Processing a request, sent until the response
 const U2FHID_SYNC: u8 = FRAME_TYPE_INIT | 0x3c; // Protocol resync command
 const U2FHID_ERROR: u8 = FRAME_TYPE_INIT | 0x3f; // Error response
 
@@ -44,8 +47,11 @@ pub fn packet_timeout_duration() -> Duration {
 pub fn transaction_timeout_duration() -> Duration {
     Duration::from_millis(3000)
 }
+pub fn keepalive_interval_duration() -> Duration {
+    Duration::from_millis(100)
+}
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct ChannelId(pub u32);
 
 impl ChannelId {
@@ -72,10 +78,14 @@ impl slog::Value for ChannelId {
 bitflags! {
     pub struct CapabilityFlags: u8 {
         const CAPFLAG_WINK = 0b0000_0001;
+        /// `CTAPHID_CBOR` is answered, see `ctap2`
+        const CAPFLAG_CBOR = 0b0000_0100;
+        /// `CTAPHID_MSG` is not supported, never set by this token
+        const CAPFLAG_NMSG = 0b0000_1000;
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ErrorCode {
     None,
     InvalidChannel,
@@ -87,6 +97,8 @@ pub enum ErrorCode {
     ChannelBusy,
     CommandRequiresChannelLock,
     SyncCommandFailed,
+    /// CTAP2_ERR_KEEPALIVE_CANCEL, the request was aborted by a cancel command
+    KeepaliveCancel,
     Other,
 }
 
@@ -103,12 +115,28 @@ impl ErrorCode {
             ErrorCode::CommandRequiresChannelLock => 0x0a,
             ErrorCode::SyncCommandFailed => 0x0b,
             ErrorCode::InvalidChannel => 0x0b,
+            ErrorCode::KeepaliveCancel => 0x2d,
             ErrorCode::Other => 0x7f,
         }
     }
 }
 
-#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeepaliveStatus {
+    Processing,
+    UserPresenceNeeded,
+}
+
+impl KeepaliveStatus {
+    fn into_byte(self) -> u8 {
+        match self {
+            KeepaliveStatus::Processing => 0x01,
+            KeepaliveStatus::UserPresenceNeeded => 0x02,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
 pub enum Command {
     Msg,
     Ping,
@@ -116,6 +144,9 @@ pub enum Command {
     Error,
     Wink,
     Lock,
+    Cbor,
+    Cancel,
+    Keepalive,
     Sync,
     Vendor { identifier: u8 },
     Unknown { identifier: u8 },
@@ -135,6 +166,9 @@ impl slog::Value for Command {
             &Command::Error => "Error",
             &Command::Wink => "Wink",
             &Command::Lock => "Lock",
+            &Command::Cbor => "Cbor",
+            &Command::Cancel => "Cancel",
+            &Command::Keepalive => "Keepalive",
             &Command::Sync => "Sync",
             &Command::Unknown { .. } => "Unknown",
             &Command::Vendor { .. } => "Vendor",
@@ -142,7 +176,7 @@ impl slog::Value for Command {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Packet {
     Initialization {
         channel_id: ChannelId,
@@ -180,7 +214,9 @@ impl Packet {
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Result<Packet, ()> {
-        assert_eq!(bytes.len(), HID_REPORT_LEN + 1);
+        if bytes.len() != HID_REPORT_LEN + 1 {
+            return Err(());
+        }
         let mut reader = Cursor::new(bytes);
         reader.read_u8().unwrap(); // TODO why do we have this extra byte to skip here
         let channel_id = ChannelId(reader.read_u32::<BigEndian>().unwrap());
@@ -193,6 +229,9 @@ impl Packet {
                 U2FHID_ERROR => Command::Error,
                 U2FHID_WINK => Command::Wink,
                 U2FHID_LOCK => Command::Lock,
+                U2FHID_CBOR => Command::Cbor,
+                U2FHID_CANCEL => Command::Cancel,
+                U2FHID_KEEPALIVE => Command::Keepalive,
                 U2FHID_SYNC => Command::Sync,
                 id if id >= U2FHID_VENDOR_FIRST && id <= U2FHID_VENDOR_LAST => {
                     Command::Vendor { identifier: id }
@@ -241,6 +280,9 @@ impl Packet {
                     Command::Error => U2FHID_ERROR,
                     Command::Wink => U2FHID_WINK,
                     Command::Lock => U2FHID_LOCK,
+                    Command::Cbor => U2FHID_CBOR,
+                    Command::Cancel => U2FHID_CANCEL,
+                    Command::Keepalive => U2FHID_KEEPALIVE,
                     Command::Sync => U2FHID_SYNC,
                     Command::Vendor { identifier } => identifier,
                     Command::Unknown { identifier } => identifier,
@@ -291,6 +333,8 @@ pub struct Request {
 #[derive(Debug)]
 pub enum RequestMessage {
     EncapsulatedRequest { data: Vec<u8> },
+    // CTAP2 command byte followed by its CBOR encoded parameters
+    Cbor { data: Vec<u8> },
     Init { nonce: [u8; 8] },
     // Lock time in seconds 0..10. A value of 0 immediately releases the lock
     Lock { lock_time: Duration },
@@ -307,6 +351,9 @@ impl RequestMessage {
             &Command::Ping => Ok(RequestMessage::Ping {
                 data: data.to_vec(),
             }),
+            &Command::Cbor => Ok(RequestMessage::Cbor {
+                data: data.to_vec(),
+            }),
             &Command::Init => {
                 if data.len() != COMMAND_INIT_DATA_LEN {
                     Err(RequestMessageDecodeError::PayloadLength(COMMAND_INIT_DATA_LEN, data.len()))
@@ -329,7 +376,10 @@ impl RequestMessage {
             &Command::Sync => {
                 Err(RequestMessageDecodeError::UnsupportedCommand(*command))
             },
+            // Only meaningful while a request is outstanding, handled by the state machine
+            &Command::Cancel => Err(RequestMessageDecodeError::UnsupportedCommand(*command)),
             &Command::Error => Err(RequestMessageDecodeError::UnsupportedCommand(*command)),
+            &Command::Keepalive => Err(RequestMessageDecodeError::UnsupportedCommand(*command)),
             &Command::Vendor { .. } => Err(RequestMessageDecodeError::UnsupportedCommand(*command)),
 
             &Command::Unknown { .. } => {
@@ -371,38 +421,10 @@ pub struct Response {
 impl Response {
     pub fn into_packets(self) -> VecDeque<Packet> {
         let channel_id = self.channel_id;
-        match self.message {
-            ResponseMessage::EncapsulatedResponse { data } => {
-                encode_response(channel_id, Command::Msg, &data)
-            }
-            ResponseMessage::Init {
-                nonce,
-                new_channel_id,
-                u2fhid_protocol_version,
-                major_device_version_number,
-                minor_device_version_number,
-                build_device_version_number,
-                capabilities,
-            } => {
-                let mut data = Vec::with_capacity(17);
-                data.extend_from_slice(&nonce);
-                new_channel_id.write(&mut data);
-                data.push(u2fhid_protocol_version);
-                data.push(major_device_version_number);
-                data.push(minor_device_version_number);
-                data.push(build_device_version_number);
-                data.push(capabilities.bits);
-                assert_eq!(data.len(), 17);
-                encode_response(channel_id, Command::Init, &data)
-            }
-            ResponseMessage::Pong { data } => encode_response(channel_id, Command::Ping, &data),
-            ResponseMessage::Error { code } => {
-                let data = vec![code.into_byte()];
-                encode_response(channel_id, Command::Error, &data)
-            }
-            ResponseMessage::Wink => encode_response(channel_id, Command::Wink, &[]),
-            ResponseMessage::Lock => encode_response(channel_id, Command::Lock, &[]),
-        }
+        let (command, data) = self.message.into_payload();
+        ctaphid::fragment(channel_id, command, &data).unwrap_or_else(|err| {
+            err.into_response().into_packets()
+        })
     }
 }
 
@@ -411,6 +433,9 @@ pub enum ResponseMessage {
     EncapsulatedResponse {
         data: Vec<u8>,
     },
+    Cbor {
+        data: Vec<u8>,
+    },
     Init {
         nonce: [u8; 8],
         new_channel_id: ChannelId,
@@ -426,10 +451,49 @@ pub enum ResponseMessage {
     Error {
         code: ErrorCode,
     },
+    Keepalive {
+        status: KeepaliveStatus,
+    },
     Wink,
     Lock,
 }
 
+impl ResponseMessage {
+    fn into_payload(self) -> (Command, Vec<u8>) {
+        match self {
+            ResponseMessage::EncapsulatedResponse { data } => (Command::Msg, data),
+            ResponseMessage::Cbor { data } => (Command::Cbor, data),
+            ResponseMessage::Init {
+                nonce,
+                new_channel_id,
+                u2fhid_protocol_version,
+                major_device_version_number,
+                minor_device_version_number,
+                build_device_version_number,
+                capabilities,
+            } => {
+                let mut data = Vec::with_capacity(17);
+                data.extend_from_slice(&nonce);
+                new_channel_id.write(&mut data);
+                data.push(u2fhid_protocol_version);
+                data.push(major_device_version_number);
+                data.push(minor_device_version_number);
+                data.push(build_device_version_number);
+                data.push(capabilities.bits);
+                assert_eq!(data.len(), 17);
+                (Command::Init, data)
+            }
+            ResponseMessage::Pong { data } => (Command::Ping, data),
+            ResponseMessage::Error { code } => (Command::Error, vec![code.into_byte()]),
+            ResponseMessage::Keepalive { status } => {
+                (Command::Keepalive, vec![status.into_byte()])
+            }
+            ResponseMessage::Wink => (Command::Wink, Vec::new()),
+            ResponseMessage::Lock => (Command::Lock, Vec::new()),
+        }
+    }
+}
+
 impl slog::Value for ResponseMessage {
     fn serialize(
         &self,
@@ -439,9 +503,11 @@ impl slog::Value for ResponseMessage {
     ) -> slog::Result {
         match self {
             ResponseMessage::EncapsulatedResponse { .. } => "EncapsulatedResponse",
+            ResponseMessage::Cbor { .. } => "Cbor",
             ResponseMessage::Init { .. } => "Init",
             ResponseMessage::Pong { .. } => "Pong",
             ResponseMessage::Error { .. } => "Error",
+            ResponseMessage::Keepalive { .. } => "Keepalive",
             ResponseMessage::Wink => "Wink",
             ResponseMessage::Lock => "Lock",
         }.serialize(record, key, serializer)
@@ -455,24 +521,3 @@ impl From<u2f_core::Response> for ResponseMessage {
         }
     }
 }
-
-fn encode_response(channel_id: ChannelId, command: Command, data: &[u8]) -> VecDeque<Packet> {
-    let mut packets = VecDeque::new();
-    let payload_len = data.len();
-    let split_index = cmp::min(data.len(), INITIAL_PACKET_DATA_LEN);
-    let (initial, remaining) = data.split_at(split_index);
-    packets.push_back(Packet::Initialization {
-        channel_id,
-        command,
-        payload_len,
-        data: initial.to_vec(),
-    });
-    for (i, chunk) in remaining.chunks(CONTINUATION_PACKET_DATA_LEN).enumerate() {
-        packets.push_back(Packet::Continuation {
-            channel_id,
-            sequence_number: i as u8,
-            data: chunk.to_vec(),
-        });
-    }
-    packets
-}
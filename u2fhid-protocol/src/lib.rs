@@ -4,6 +4,9 @@ extern crate byteorder;
 #[macro_use]
 extern crate futures;
 extern crate itertools;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
 #[macro_use]
 extern crate quick_error;
 #[macro_use]
@@ -16,19 +19,26 @@ extern crate u2f_core;
 
 use std::collections::vec_deque::VecDeque;
 use std::io;
+use std::time::Duration;
 
+use ctaphid::SharedChannels;
 use definitions::*;
-pub use definitions::Packet;
+pub use definitions::{CapabilityFlags, ChannelId, Command, ErrorCode, KeepaliveStatus, Packet,
+                      Response, ResponseMessage, BROADCAST_CHANNEL_ID};
 use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
 use protocol_state_machine::StateMachine;
 use segmenting_sink::{Segmenter, SegmentingSink};
+pub use service::{ServiceError, U2FService};
 use slog::Drain;
 use tokio_core::reactor::Handle;
 use u2f_core::{Service, U2F};
 
+mod ctap2;
+pub mod ctaphid;
 mod definitions;
 mod protocol_state_machine;
 mod segmenting_sink;
+mod service;
 
 struct PacketSegmenter;
 
@@ -62,12 +72,49 @@ where
             .into()
             .unwrap_or_else(|| slog::Logger::root(slog_stdlog::StdLog.fuse(), o!()));
         let state_machine_logger = logger.new(o!());
+        let metrics = service.metrics();
         U2FHID {
             logger,
-            state_machine: StateMachine::new(service, handle, state_machine_logger),
+            state_machine: StateMachine::new(service, handle, state_machine_logger)
+                .with_metrics(metrics),
             transport: SegmentingSink::new(transport, PacketSegmenter),
         }
     }
+
+    /// Like `bind_service`, allocating channels in `channels` so that a device
+    /// recreated after the kernel removed it keeps the channels browsers were given
+    pub fn bind_service_with_channels<L: Into<Option<slog::Logger>>>(
+        handle: Handle,
+        transport: T,
+        service: U2F,
+        channels: &SharedChannels,
+        logger: L,
+    ) -> U2FHID<T, U2F> {
+        let mut u2fhid = Self::bind_service(handle, transport, service, logger);
+        u2fhid.state_machine = u2fhid.state_machine.with_channels(channels);
+        u2fhid
+    }
+
+    /// Call `on_wink` when a browser sends `CTAPHID_WINK`, see `StateMachine::with_on_wink`
+    pub fn with_on_wink<F: Fn() + 'static>(mut self, on_wink: F) -> U2FHID<T, U2F> {
+        self.state_machine = self.state_machine.with_on_wink(on_wink);
+        self
+    }
+
+    /// Time out messages after `timeout` without a packet, see
+    /// `StateMachine::with_packet_timeout`
+    pub fn with_packet_timeout(mut self, timeout: Duration) -> U2FHID<T, U2F> {
+        self.state_machine = self.state_machine.with_packet_timeout(timeout);
+        self
+    }
+
+    /// Stop handling packets and flush the secret store, see `U2FService::shutdown`
+    ///
+    /// Responses not yet written to the transport are dropped along with it.
+    pub fn shutdown(self) -> impl Future<Item = (), Error = io::Error> {
+        info!(self.logger, "Shutting down");
+        self.state_machine.shutdown()
+    }
 }
 
 impl<T, S, E> Future for U2FHID<T, S>
@@ -1,14 +1,17 @@
 use std::io;
 use std::mem;
-use std::time::Duration;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+use ctap2;
+use ctaphid::{FramingError, Message, Reassembler, SharedChannels, SystemClock};
 use definitions::*;
 use futures::{Async, Future};
 use futures::future;
 use slog::Logger;
 use tokio_core::reactor::Handle;
 use tokio_core::reactor::Timeout;
-use u2f_core::{self, Service};
+use u2f_core::{self, Metrics, NoMetrics, Service, U2F};
 
 macro_rules! try_some {
     ($e:expr) => (match $e {
@@ -18,25 +21,15 @@ macro_rules! try_some {
     })
 }
 
-struct ReceiveState {
-    buffer: Vec<u8>,
-    command: Command,
-    next_sequence_number: u8,
-    payload_len: usize,
-    channel_id: ChannelId,
-    packet_timeout: Timeout,
-    transaction_timeout: Timeout,
-}
-
 struct DispatchState {
     channel_id: ChannelId,
     future: Box<dyn Future<Item = ResponseMessage, Error = io::Error>>,
+    keepalive: Timeout,
     timeout: Timeout,
 }
 
 enum State {
     Idle,
-    Receive(ReceiveState),
     Dispatch(DispatchState),
     Unknown,
 }
@@ -47,39 +40,6 @@ impl State {
     }
 }
 
-const MAX_CHANNEL_ID: ChannelId = ChannelId(BROADCAST_CHANNEL_ID.0 - 1);
-const MIN_CHANNEL_ID: ChannelId = ChannelId(1);
-
-#[derive(Debug)]
-struct Channels {
-    next_allocation: ChannelId,
-}
-
-impl Channels {
-    fn new() -> Channels {
-        Channels {
-            next_allocation: MIN_CHANNEL_ID,
-        }
-    }
-
-    fn allocate(&mut self) -> Result<ChannelId, ()> {
-        if self.next_allocation > MAX_CHANNEL_ID {
-            Err(())
-        } else {
-            let allocation = self.next_allocation;
-            self.next_allocation = self.next_allocation.checked_add(1).unwrap();
-            Ok(allocation)
-        }
-    }
-
-    fn is_valid(&self, channel_id: ChannelId) -> bool {
-        let is_broadcast = channel_id == BROADCAST_CHANNEL_ID;
-        let is_in_allocated_range =
-            channel_id >= MIN_CHANNEL_ID && channel_id < self.next_allocation;
-        is_broadcast || is_in_allocated_range
-    }
-}
-
 enum LockState {
     None,
     Locked {
@@ -122,8 +82,6 @@ impl LockState {
             *self = LockState::None;
         }
 
-        // TODO Check frame and transation timeouts
-
         Ok(())
     }
 }
@@ -134,10 +92,15 @@ struct StateTransition<O> {
 }
 
 pub struct StateMachine<S> {
-    channels: Channels,
+    channels: SharedChannels,
     handle: Handle,
     lock: LockState,
     logger: Logger,
+    metrics: Rc<dyn Metrics>,
+    on_wink: Option<Box<dyn Fn()>>,
+    packet_timeout: Duration,
+    packet_timer: Option<Timeout>,
+    reassembler: Reassembler,
     service: S,
     state: State,
 }
@@ -154,33 +117,76 @@ where
 {
     pub fn new(service: S, handle: Handle, logger: Logger) -> StateMachine<S> {
         StateMachine {
-            channels: Channels::new(),
+            channels: SharedChannels::new(true),
             handle: handle,
             lock: LockState::None,
             logger: logger,
+            metrics: Rc::new(NoMetrics),
+            on_wink: None,
+            packet_timeout: packet_timeout_duration(),
+            packet_timer: None,
+            reassembler: Reassembler::new(),
             service: service,
             state: State::Idle,
         }
     }
 
+    /// Report requests that cannot be decoded, and so never reach the service, to `metrics`
+    pub fn with_metrics(mut self, metrics: Rc<dyn Metrics>) -> StateMachine<S> {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Allocate channels in `channels`, shared with the devices this one replaces
+    pub fn with_channels(mut self, channels: &SharedChannels) -> StateMachine<S> {
+        self.channels = channels.attach();
+        self
+    }
+
+    /// Drop messages that go `timeout` without a packet, answering `ERR_MSG_TIMEOUT`
+    /// instead of the spec's 500ms
+    pub fn with_packet_timeout(mut self, timeout: Duration) -> StateMachine<S> {
+        self.packet_timeout = timeout;
+        self.reassembler = Reassembler::with_timeout(timeout, Box::new(SystemClock));
+        self
+    }
+
+    /// Answer `CTAPHID_WINK` by calling `on_wink`, e.g. to flash an indicator, and
+    /// advertise the wink capability in `CTAPHID_INIT` responses
+    ///
+    /// Without it winks are passed to the service as before, but not advertised.
+    pub fn with_on_wink<F: Fn() + 'static>(mut self, on_wink: F) -> StateMachine<S> {
+        self.on_wink = Some(Box::new(on_wink));
+        self
+    }
+
+    /// `CTAPHID_CBOR` is always answered, if only to tell clients to use U2F instead
+    fn capabilities(&self) -> CapabilityFlags {
+        if self.on_wink.is_some() {
+            CapabilityFlags::CAPFLAG_CBOR | CapabilityFlags::CAPFLAG_WINK
+        } else {
+            CapabilityFlags::CAPFLAG_CBOR
+        }
+    }
+
+    /// Whether a complete request is being handled and its response is not yet ready
+    pub fn is_dispatching(&self) -> bool {
+        matches!(self.state, State::Dispatch(_))
+    }
+
     pub fn step(&mut self) -> Result<Option<Response>, io::Error> {
         // Tick the lock for possible timeout
         self.lock.tick()?;
 
+        try_some!(self.expire_message());
+
         let transition = match self.state.take() {
-            State::Receive(receive) => {
-                // TODO check timeouts
-                // receive.packet_timeout
-                StateTransition {
-                    new_state: State::Receive(receive),
-                    output: None,
-                }
-            }
             State::Dispatch(mut dispatch) => {
                 // check if ready
                 match dispatch.future.poll()? {
                     Async::Ready(result) => {
                         let channel_id = dispatch.channel_id;
+                        self.reassembler.finish(channel_id);
                         StateTransition {
                             new_state: State::Idle,
                             output: Some(Response {
@@ -189,10 +195,28 @@ where
                             }),
                         }
                     }
-                    Async::NotReady => StateTransition {
-                        new_state: State::Dispatch(dispatch),
-                        output: None,
-                    },
+                    Async::NotReady => {
+                        // Most likely waiting on user presence, keep the
+                        // client from timing out the transaction meanwhile
+                        let output = match dispatch.keepalive.poll()? {
+                            Async::Ready(()) => {
+                                dispatch
+                                    .keepalive
+                                    .reset(Instant::now() + keepalive_interval_duration());
+                                Some(Response {
+                                    channel_id: dispatch.channel_id,
+                                    message: ResponseMessage::Keepalive {
+                                        status: KeepaliveStatus::UserPresenceNeeded,
+                                    },
+                                })
+                            }
+                            Async::NotReady => None,
+                        };
+                        StateTransition {
+                            new_state: State::Dispatch(dispatch),
+                            output,
+                        }
+                    }
                 }
             }
             state => StateTransition {
@@ -214,9 +238,6 @@ where
         debug!(self.logger, "step_with_packet");
         try_some!(self.step_with_packet(packet));
 
-        debug!(self.logger, "try_complete_receive");
-        try_some!(self.try_complete_receive());
-
         debug!(self.logger, "try_complete_dispatch");
         try_some!(self.try_complete_dispatch());
 
@@ -256,108 +277,88 @@ where
     fn step_with_packet(&mut self, packet: Packet) -> Result<Option<Response>, io::Error> {
         let transition = match (self.state.take(), packet) {
             (
-                State::Idle,
+                State::Dispatch(dispatch),
                 Packet::Initialization {
                     channel_id,
-                    data,
-                    payload_len,
-                    command,
+                    command: Command::Cancel,
+                    ..
                 },
             ) => {
-                debug!(self.logger, "Begin transaction"; "channel_id" => &channel_id, "command" => &command, "payload_len" => payload_len);
-                StateTransition {
-                    new_state: State::Receive(ReceiveState {
-                        buffer: data.to_vec(),
-                        channel_id: channel_id,
-                        command: command,
-                        next_sequence_number: 0,
-                        payload_len: payload_len,
-                        packet_timeout: Timeout::new(packet_timeout_duration(), &self.handle)?,
-                        transaction_timeout: Timeout::new(
-                            transaction_timeout_duration(),
-                            &self.handle,
-                        )?,
-                    }),
-                    output: None,
-                }
-            }
-            (state @ State::Idle, Packet::Continuation { .. }) => {
-                debug!(self.logger, "Out of order continuation packet, ignoring");
-                StateTransition {
-                    new_state: state,
-                    output: None,
-                }
-            }
-            (State::Receive(receive), Packet::Initialization { channel_id, .. }) => {
-                if channel_id == receive.channel_id {
-                    debug!(self.logger, "Invalid message sequencing");
+                if channel_id == dispatch.channel_id {
+                    // Dropping the future drops any pending user presence check with it
+                    debug!(self.logger, "Request cancelled"; "channel_id" => &channel_id);
+                    self.reassembler.finish(channel_id);
                     StateTransition {
                         new_state: State::Idle,
-                        output: Some(Response {
-                            channel_id: channel_id,
-                            message: ResponseMessage::Error {
-                                code: ErrorCode::InvalidMessageSequencing,
-                            },
-                        }),
+                        output: Some(Self::error_output(
+                            ErrorCode::KeepaliveCancel,
+                            channel_id,
+                        )),
                     }
                 } else {
-                    debug!(self.logger, "Other channel busy with transaction");
+                    debug!(self.logger, "Cancel for other channel, ignoring"; "channel_id" => &channel_id);
                     StateTransition {
-                        new_state: State::Receive(receive),
-                        output: Some(Response {
-                            channel_id: channel_id,
-                            message: ResponseMessage::Error {
-                                code: ErrorCode::ChannelBusy,
-                            },
-                        }),
+                        new_state: State::Dispatch(dispatch),
+                        output: None,
                     }
                 }
             }
             (
-                State::Receive(mut receive),
-                Packet::Continuation {
-                    channel_id,
-                    sequence_number,
-                    data,
+                state,
+                Packet::Initialization {
+                    command: Command::Cancel,
+                    ..
                 },
             ) => {
-                if channel_id != receive.channel_id {
-                    StateTransition {
-                        new_state: State::Receive(receive),
-                        output: Some(Response {
-                            channel_id: channel_id,
-                            message: ResponseMessage::Error {
-                                code: ErrorCode::ChannelBusy,
-                            },
-                        }),
-                    }
-                } else if sequence_number != receive.next_sequence_number {
-                    StateTransition {
-                        new_state: State::Idle,
-                        output: Some(Response {
-                            channel_id: channel_id,
-                            message: ResponseMessage::Error {
-                                code: ErrorCode::InvalidMessageSequencing,
-                            },
-                        }),
-                    }
+                // Cancel has no response of its own, there is nothing to cancel
+                debug!(self.logger, "No outstanding request to cancel, ignoring");
+                StateTransition {
+                    new_state: state,
+                    output: None,
+                }
+            }
+            (State::Dispatch(dispatch), packet) => {
+                let output = if packet.channel_id() == dispatch.channel_id {
+                    Some(Self::error_output(
+                        ErrorCode::ChannelBusy,
+                        dispatch.channel_id,
+                    ))
                 } else {
-                    receive.next_sequence_number += 1;
-                    receive.buffer.extend_from_slice(&data);
-                    receive.packet_timeout = Timeout::new(packet_timeout_duration(), &self.handle)?;
-                    StateTransition {
-                        new_state: State::Receive(receive),
-                        output: None,
+                    // The transaction lasts until `finish`, until then the
+                    // reassembler turns other channels away as busy
+                    self.reassembler
+                        .accept(packet)
+                        .err()
+                        .map(FramingError::into_response)
+                };
+                StateTransition {
+                    new_state: State::Dispatch(dispatch),
+                    output,
+                }
+            }
+            (State::Idle, packet) => {
+                let channel_id = packet.channel_id();
+                match self.reassembler.accept(packet) {
+                    Ok(Some(message)) => self.receive(message)?,
+                    Ok(None) => {
+                        if self.reassembler.is_pending(channel_id) {
+                            self.packet_timer =
+                                Some(Timeout::new(self.packet_timeout, &self.handle)?);
+                        }
+                        StateTransition {
+                            new_state: State::Idle,
+                            output: None,
+                        }
+                    }
+                    Err(error) => {
+                        debug!(self.logger, "Framing error"; "error" => %error);
+                        StateTransition {
+                            new_state: State::Idle,
+                            output: Some(error.into_response()),
+                        }
                     }
                 }
             }
-            (state @ State::Dispatch(_), packet) => StateTransition {
-                new_state: state,
-                output: Some(Self::error_output(
-                    ErrorCode::ChannelBusy,
-                    packet.channel_id(),
-                )),
-            },
             (State::Unknown, _) => panic!(),
         };
 
@@ -365,81 +366,88 @@ where
         Ok(transition.output)
     }
 
-    fn try_complete_receive(&mut self) -> Result<Option<Response>, io::Error> {
-        let transition = match self.state.take() {
-            State::Receive(receive) => {
-                if receive.buffer.len() >= receive.payload_len {
-                    let bytes = &receive.buffer[0..receive.payload_len];
-                    debug!(self.logger, "Received payload"; "len" => receive.payload_len);
-                    match RequestMessage::decode(&receive.command, bytes) {
-                        Err(RequestMessageDecodeError::UnsupportedCommand(Command::Unknown { .. })) => {
-                            info!(self.logger, "Unknown command. Responding with InvalidCommand error to encourage fallback to U2F protocol");
-                            StateTransition {
-                                new_state: State::Idle,
-                                output: Some(Response {
-                                    channel_id: receive.channel_id,
-                                    message: ResponseMessage::Error {
-                                        code: ErrorCode::InvalidCommand,
-                                    },
-                                }),
-                            }
-                        },
-                        Err(error) => {
-                            debug!(self.logger, "Unable to decode request message"; "error" => error);
-                            StateTransition {
-                                new_state: State::Idle,
-                                output: Some(Response {
-                                    channel_id: receive.channel_id,
-                                    message: ResponseMessage::Error {
-                                        code: ErrorCode::Other,
-                                    },
-                                }),
-                            }
-                        },
-                        Ok(message) => {
-                            let response_future = self.handle_request(Request {
-                                channel_id: receive.channel_id,
-                                message: message,
-                            })?;
-                            let dispatch_state = DispatchState {
-                                channel_id: receive.channel_id,
-                                future: response_future,
-                                timeout: receive.transaction_timeout,
-                            };
-                            StateTransition {
-                                new_state: State::Dispatch(dispatch_state),
-                                output: None,
-                            }
-                        }
-                    }
-                } else {
-                    debug!(self.logger, "Payload incomplete"; "payload_len" => receive.payload_len, "receive_len" => receive.buffer.len());
-                    StateTransition {
-                        new_state: State::Receive(receive),
-                        output: None,
-                    }
-                }
-            }
-            state => StateTransition {
-                new_state: state,
-                output: None,
-            },
+    /// Drop a message whose next packet is overdue, answering `ERR_MSG_TIMEOUT`
+    ///
+    /// The timer only wakes the task, whether the message has expired is up to
+    /// the reassembler.
+    fn expire_message(&mut self) -> Result<Option<Response>, io::Error> {
+        let receiving = self
+            .reassembler
+            .active_channel()
+            .is_some_and(|channel_id| self.reassembler.is_pending(channel_id));
+        if !receiving {
+            self.packet_timer = None;
+            return Ok(None);
+        }
+        let fired = match self.packet_timer {
+            Some(ref mut timer) => timer.poll()?.is_ready(),
+            None => false,
         };
+        if fired {
+            self.packet_timer = None;
+        }
+        Ok(self.reassembler.expire().map(|error| {
+            debug!(self.logger, "Message timed out"; "channel_id" => &error.channel_id());
+            error.into_response()
+        }))
+    }
 
-        self.state = transition.new_state;
-        Ok(transition.output)
+    /// Decode a reassembled message and start handling it
+    fn receive(
+        &mut self,
+        message: Message,
+    ) -> Result<StateTransition<Option<Response>>, io::Error> {
+        let channel_id = message.channel_id;
+        debug!(self.logger, "Received payload"; "len" => message.data.len());
+        match RequestMessage::decode(&message.command, &message.data) {
+            Err(RequestMessageDecodeError::UnsupportedCommand(Command::Unknown { .. })) => {
+                info!(self.logger, "Unknown command. Responding with InvalidCommand error to encourage fallback to U2F protocol");
+                self.reassembler.finish(channel_id);
+                Ok(StateTransition {
+                    new_state: State::Idle,
+                    output: Some(Self::error_output(ErrorCode::InvalidCommand, channel_id)),
+                })
+            }
+            Err(error) => {
+                debug!(self.logger, "Unable to decode request message"; "error" => error);
+                self.reassembler.finish(channel_id);
+                Ok(StateTransition {
+                    new_state: State::Idle,
+                    output: Some(Self::error_output(ErrorCode::Other, channel_id)),
+                })
+            }
+            Ok(message) => {
+                let response_future = self.handle_request(Request {
+                    channel_id,
+                    message,
+                })?;
+                let dispatch_state = DispatchState {
+                    channel_id,
+                    future: response_future,
+                    keepalive: Timeout::new(keepalive_interval_duration(), &self.handle)?,
+                    timeout: Timeout::new(transaction_timeout_duration(), &self.handle)?,
+                };
+                Ok(StateTransition {
+                    new_state: State::Dispatch(dispatch_state),
+                    output: None,
+                })
+            }
+        }
     }
 
     fn try_complete_dispatch(&mut self) -> Result<Option<Response>, io::Error> {
         let transition = match self.state.take() {
             State::Dispatch(mut dispatch) => match dispatch.future.poll()? {
-                Async::Ready(response) => StateTransition {
-                    new_state: State::Idle,
-                    output: Some(Response {
-                        channel_id: dispatch.channel_id,
-                        message: response,
-                    }),
-                },
+                Async::Ready(response) => {
+                    self.reassembler.finish(dispatch.channel_id);
+                    StateTransition {
+                        new_state: State::Idle,
+                        output: Some(Response {
+                            channel_id: dispatch.channel_id,
+                            message: response,
+                        }),
+                    }
+                }
                 Async::NotReady => StateTransition {
                     new_state: State::Dispatch(dispatch),
                     output: None,
@@ -469,33 +477,41 @@ where
         let channel_id = request.channel_id;
         match request.message {
             RequestMessage::EncapsulatedRequest { data } => {
-                // TODO no unwrap
                 debug!(self.logger, "RequestMessage::EncapsulatedRequest"; "data.len" => data.len());
-                let request = u2f_core::Request::decode(&data).unwrap();
-                Ok(self.dispatch(request))
+                match u2f_core::Request::decode(&data) {
+                    Ok(request) => Ok(self.dispatch(request)),
+                    Err(err) => {
+                        info!(self.logger, "Invalid request"; "error" => %err);
+                        self.metrics.on_decode_error(&err);
+                        Ok(Box::new(future::ok(
+                            u2f_core::Response::InvalidRequest(err).into(),
+                        )))
+                    }
+                }
             }
-            RequestMessage::Init { nonce } => {
-                // TODO Check what channnel message came in on
-                // TODO check unwrap
-                let new_channel_id = self.channels
-                    .allocate()
-                    .expect("Failed to allocate new channel");
-                debug!(self.logger, "RequestMessage::Init"; "new_channel_id" => new_channel_id);
-                Ok(Box::new(future::ok(ResponseMessage::Init {
-                    nonce,
-                    new_channel_id: new_channel_id,
-                    u2fhid_protocol_version: U2FHID_PROTOCOL_VERSION,
-                    major_device_version_number: MAJOR_DEVICE_VERSION_NUMBER,
-                    minor_device_version_number: MINOR_DEVICE_VERSION_NUMBER,
-                    build_device_version_number: BUILD_DEVICE_VERSION_NUMBER,
-                    capabilities: CapabilityFlags::CAPFLAG_WINK,
+            RequestMessage::Cbor { data } => {
+                debug!(self.logger, "RequestMessage::Cbor"; "data.len" => data.len());
+                Ok(Box::new(future::ok(ResponseMessage::Cbor {
+                    data: ctap2::handle_request(&data),
                 })))
             }
+            RequestMessage::Init { nonce } => {
+                let response = self.channels.init(channel_id, nonce, self.capabilities());
+                debug!(self.logger, "RequestMessage::Init"; "message" => &response.message);
+                Ok(Box::new(future::ok(response.message)))
+            }
             RequestMessage::Ping { data } => {
                 debug!(self.logger, "RequestMessage::Ping"; "data.len" => data.len());
                 Ok(Box::new(future::ok(ResponseMessage::Pong { data: data })))
             }
-            RequestMessage::Wink => Ok(self.dispatch(u2f_core::Request::Wink)),
+            RequestMessage::Wink => match self.on_wink {
+                Some(ref on_wink) => {
+                    debug!(self.logger, "RequestMessage::Wink");
+                    on_wink();
+                    Ok(Box::new(future::ok(ResponseMessage::Wink)))
+                }
+                None => Ok(self.dispatch(u2f_core::Request::Wink)),
+            },
             RequestMessage::Lock { lock_time } => {
                 debug!(self.logger, "RequestMessage::Lock"; "lock_time" => lock_time.as_secs());
                 if lock_time == Duration::from_secs(0) {
@@ -519,12 +535,42 @@ where
     }
 }
 
+impl StateMachine<U2F> {
+    /// Run the request being handled to completion, dropping its response, then flush
+    /// the secret store
+    ///
+    /// The store is never flushed half way through a request, e.g. one holding a
+    /// freshly incremented counter.
+    pub fn shutdown(self) -> impl Future<Item = (), Error = io::Error> {
+        let mut state_machine = Some(self);
+        future::poll_fn(move || {
+            {
+                let state_machine = state_machine.as_mut().expect("polled after completion");
+                while let Some(response) = state_machine.step()? {
+                    debug!(state_machine.logger, "Dropping response sent during shutdown";
+                           "channel_id" => &response.channel_id);
+                }
+                if state_machine.is_dispatching() {
+                    return Ok(Async::NotReady);
+                }
+            }
+            Ok(Async::Ready(state_machine.take().unwrap()))
+        })
+        .and_then(|state_machine| state_machine.service.flush())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate rand;
 
+    use std::cell::Cell;
+
+    use ctaphid;
+    use ctaphid::Channels;
     use slog::{self, Drain};
     use slog_stdlog;
+    use futures::Poll;
     use tokio_core::reactor::Core;
 
     use super::*;
@@ -557,6 +603,36 @@ mod tests {
         assert!(channels.is_valid(channel_id));
     }
 
+    #[test]
+    fn shared_channels_survive_recreate() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let channels = SharedChannels::new(false);
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger.clone())
+            .with_channels(&channels);
+        let channel_id = init_channel(&mut state_machine);
+
+        let recreated =
+            StateMachine::new(FakeU2FService, core.handle(), logger).with_channels(&channels);
+
+        assert!(recreated.channels.is_valid(channel_id));
+    }
+
+    #[test]
+    fn shared_channels_reset_on_recreate() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let channels = SharedChannels::new(true);
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger.clone())
+            .with_channels(&channels);
+        let channel_id = init_channel(&mut state_machine);
+
+        let recreated =
+            StateMachine::new(FakeU2FService, core.handle(), logger).with_channels(&channels);
+
+        assert!(!recreated.channels.is_valid(channel_id));
+    }
+
     #[test]
     fn init() {
         let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
@@ -642,4 +718,435 @@ mod tests {
             _ => panic!(),
         };
     }
+
+    fn init_capabilities<S>(state_machine: &mut StateMachine<S>) -> CapabilityFlags
+    where
+        S: Service<
+            Request = u2f_core::Request,
+            Response = u2f_core::Response,
+            Error = io::Error,
+            Future = Box<dyn Future<Item = u2f_core::Response, Error = io::Error>>,
+        >,
+    {
+        let response = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: BROADCAST_CHANNEL_ID,
+                command: Command::Init,
+                data: vec![0u8; 8],
+                payload_len: 8,
+            })
+            .unwrap();
+        match response {
+            Some(Response {
+                message: ResponseMessage::Init { capabilities, .. },
+                ..
+            }) => capabilities,
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn init_on_broadcast_echoes_nonce_and_allocates_channel() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+        let nonce: [u8; 8] = OsRng::new().unwrap().gen();
+
+        let response = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id: BROADCAST_CHANNEL_ID,
+                command: Command::Init,
+                data: nonce.to_vec(),
+                payload_len: nonce.len(),
+            })
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(response.channel_id, BROADCAST_CHANNEL_ID);
+        match response.message {
+            ResponseMessage::Init {
+                nonce: response_nonce,
+                new_channel_id,
+                u2fhid_protocol_version,
+                capabilities,
+                ..
+            } => {
+                assert_eq!(response_nonce, nonce);
+                assert_ne!(new_channel_id, ChannelId(0));
+                assert_ne!(new_channel_id, BROADCAST_CHANNEL_ID);
+                assert!(state_machine.channels.is_valid(new_channel_id));
+                assert_eq!(u2fhid_protocol_version, 2);
+                assert!(capabilities.contains(CapabilityFlags::CAPFLAG_CBOR));
+                assert!(!capabilities.contains(CapabilityFlags::CAPFLAG_NMSG));
+            }
+            ref message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn wink_is_advertised_only_with_on_wink() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut without_on_wink = StateMachine::new(FakeU2FService, core.handle(), logger.clone());
+        let mut with_on_wink =
+            StateMachine::new(FakeU2FService, core.handle(), logger).with_on_wink(|| {});
+
+        assert!(!init_capabilities(&mut without_on_wink).contains(CapabilityFlags::CAPFLAG_WINK));
+        assert!(init_capabilities(&mut with_on_wink).contains(CapabilityFlags::CAPFLAG_WINK));
+    }
+
+    #[test]
+    fn wink_calls_on_wink() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let winks = Rc::new(Cell::new(0));
+        let winks_seen = winks.clone();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger)
+            .with_on_wink(move || winks_seen.set(winks_seen.get() + 1));
+        let channel_id = init_channel(&mut state_machine);
+
+        let response = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id,
+                command: Command::Wink,
+                data: Vec::new(),
+                payload_len: 0,
+            })
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(winks.get(), 1);
+        assert_eq!(response.channel_id, channel_id);
+        match response.message {
+            ResponseMessage::Wink => {}
+            ref message => panic!("unexpected message {:?}", message),
+        }
+        let packets = response.into_packets();
+        assert_eq!(packets.len(), 1);
+        match packets[0] {
+            Packet::Initialization {
+                command: Command::Wink,
+                payload_len: 0,
+                ..
+            } => {}
+            ref packet => panic!("unexpected packet {:?}", packet),
+        }
+    }
+
+    /// Stands in for a service blocked on `UserPresence::approve`
+    struct SlowApprovalService {
+        delay: Duration,
+        handle: Handle,
+    }
+
+    impl Service for SlowApprovalService {
+        type Request = u2f_core::Request;
+        type Response = u2f_core::Response;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, _req: Self::Request) -> Self::Future {
+            let approval = Timeout::new(self.delay, &self.handle).unwrap();
+            Box::new(approval.map(|()| u2f_core::Response::DidWink))
+        }
+    }
+
+    #[test]
+    fn keepalive_while_approval_pending() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let mut core = Core::new().unwrap();
+        let service = SlowApprovalService {
+            delay: Duration::from_millis(350),
+            handle: core.handle(),
+        };
+        let mut state_machine = StateMachine::new(service, core.handle(), logger);
+        let channel_id = init_channel(&mut state_machine);
+        let mut request = Some(Packet::Initialization {
+            channel_id,
+            command: Command::Wink,
+            data: Vec::new(),
+            payload_len: 0,
+        });
+        let mut responses = Vec::new();
+
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            if let Some(packet) = request.take() {
+                if let Some(response) = state_machine.accept_packet(packet)? {
+                    responses.push(response);
+                }
+            }
+            while let Some(response) = state_machine.step()? {
+                let is_keepalive = matches!(response.message, ResponseMessage::Keepalive { .. });
+                responses.push(response);
+                if !is_keepalive {
+                    return Ok(Async::Ready(()));
+                }
+            }
+            Ok(Async::NotReady)
+        })).unwrap();
+
+        let (last, keepalives) = responses.split_last().unwrap();
+        assert!(!keepalives.is_empty());
+        for keepalive in keepalives {
+            assert_eq!(keepalive.channel_id, channel_id);
+            match keepalive.message {
+                ResponseMessage::Keepalive {
+                    status: KeepaliveStatus::UserPresenceNeeded,
+                } => {}
+                ref message => panic!("unexpected message {:?}", message),
+            }
+        }
+        assert_eq!(last.channel_id, channel_id);
+        match last.message {
+            ResponseMessage::EncapsulatedResponse { .. } => {}
+            ref message => panic!("unexpected message {:?}", message),
+        }
+        assert!(state_machine.step().unwrap().is_none());
+    }
+
+    /// Records the user presence check being dropped, the check never completes
+    struct PendingApprovalService {
+        approval_dropped: Rc<Cell<bool>>,
+        signed: Rc<Cell<bool>>,
+    }
+
+    struct ApprovalGuard(Rc<Cell<bool>>);
+
+    impl Drop for ApprovalGuard {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    impl Service for PendingApprovalService {
+        type Request = u2f_core::Request;
+        type Response = u2f_core::Response;
+        type Error = io::Error;
+        type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+        fn call(&self, _req: Self::Request) -> Self::Future {
+            let guard = ApprovalGuard(self.approval_dropped.clone());
+            let signed = self.signed.clone();
+            Box::new(future::empty().map(move |()| {
+                let _guard = &guard;
+                signed.set(true);
+                u2f_core::Response::DidWink
+            }))
+        }
+    }
+
+    #[test]
+    fn cancel_aborts_pending_register() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let approval_dropped = Rc::new(Cell::new(false));
+        let signed = Rc::new(Cell::new(false));
+        let service = PendingApprovalService {
+            approval_dropped: approval_dropped.clone(),
+            signed: signed.clone(),
+        };
+        let mut state_machine = StateMachine::new(service, core.handle(), logger);
+        let channel_id = init_channel(&mut state_machine);
+        let mut register = vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x40];
+        register.extend_from_slice(&[0x11; 64]);
+        register.extend_from_slice(&[0x00, 0x00]);
+        for packet in ctaphid::fragment(channel_id, Command::Msg, &register).unwrap() {
+            assert!(state_machine.accept_packet(packet).unwrap().is_none());
+        }
+        assert!(state_machine.is_dispatching());
+        assert!(!approval_dropped.get());
+
+        let response = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id,
+                command: Command::Cancel,
+                data: Vec::new(),
+                payload_len: 0,
+            })
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(response.channel_id, channel_id);
+        match response.message {
+            ResponseMessage::Error {
+                code: ErrorCode::KeepaliveCancel,
+            } => {}
+            ref message => panic!("unexpected message {:?}", message),
+        }
+        assert!(approval_dropped.get());
+        assert!(!signed.get());
+        assert!(!state_machine.is_dispatching());
+        assert!(state_machine.step().unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_message_is_invalid_length() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+        let channel_id = init_channel(&mut state_machine);
+
+        let response = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id,
+                command: Command::Msg,
+                data: vec![0x22; INITIAL_PACKET_DATA_LEN],
+                payload_len: ctaphid::MAX_MESSAGE_LEN + 1,
+            })
+            .unwrap();
+
+        assert_error(response, channel_id, ErrorCode::InvalidMessageLength);
+        assert!(!state_machine.is_dispatching());
+        // Nothing was buffered, the continuation has no message to join
+        let response = state_machine
+            .accept_packet(Packet::Continuation {
+                channel_id,
+                sequence_number: 0,
+                data: vec![0x22; CONTINUATION_PACKET_DATA_LEN],
+            })
+            .unwrap();
+        assert!(response.is_none());
+    }
+
+    #[test]
+    fn stalled_message_times_out() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let mut core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger)
+            .with_packet_timeout(Duration::from_millis(50));
+        let channel_id = init_channel(&mut state_machine);
+        let mut packets = ctaphid::fragment(channel_id, Command::Ping, &[0x22; 100]).unwrap();
+        let first = packets.pop_front().unwrap();
+        assert!(state_machine.accept_packet(first).unwrap().is_none());
+
+        let response = core
+            .run(future::poll_fn(|| -> Poll<Response, io::Error> {
+                match state_machine.step()? {
+                    Some(response) => Ok(Async::Ready(response)),
+                    None => Ok(Async::NotReady),
+                }
+            }))
+            .unwrap();
+
+        assert_error(Some(response), channel_id, ErrorCode::MessageTimedOut);
+        // The rest of the message is ignored and the channel free again
+        for packet in packets {
+            assert!(state_machine.accept_packet(packet).unwrap().is_none());
+        }
+        let response = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id,
+                command: Command::Ping,
+                data: vec![0x33; 8],
+                payload_len: 8,
+            })
+            .unwrap()
+            .unwrap();
+        match response.message {
+            ResponseMessage::Pong { ref data } => assert_eq!(data, &vec![0x33; 8]),
+            ref message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    fn assert_error(response: Option<Response>, channel_id: ChannelId, code: ErrorCode) {
+        match response {
+            Some(Response {
+                channel_id: response_channel_id,
+                message:
+                    ResponseMessage::Error {
+                        code: response_code,
+                    },
+            }) => {
+                assert_eq!(response_channel_id, channel_id);
+                assert_eq!(response_code, code);
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn other_channel_is_busy_until_response() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let service = PendingApprovalService {
+            approval_dropped: Rc::new(Cell::new(false)),
+            signed: Rc::new(Cell::new(false)),
+        };
+        let mut state_machine = StateMachine::new(service, core.handle(), logger);
+        let channel_id = init_channel(&mut state_machine);
+        let other_channel_id = init_channel(&mut state_machine);
+        let mut register = vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x40];
+        register.extend_from_slice(&[0x11; 64]);
+        register.extend_from_slice(&[0x00, 0x00]);
+        let mut packets = ctaphid::fragment(channel_id, Command::Msg, &register).unwrap();
+        let ping = || Packet::Initialization {
+            channel_id: other_channel_id,
+            command: Command::Ping,
+            data: vec![0x22; 8],
+            payload_len: 8,
+        };
+
+        // While the request is being received
+        let first = packets.pop_front().unwrap();
+        assert!(state_machine.accept_packet(first).unwrap().is_none());
+        let response = state_machine.accept_packet(ping()).unwrap();
+        assert_error(response, other_channel_id, ErrorCode::ChannelBusy);
+
+        // While it is being handled
+        for packet in packets {
+            assert!(state_machine.accept_packet(packet).unwrap().is_none());
+        }
+        assert!(state_machine.is_dispatching());
+        let response = state_machine.accept_packet(ping()).unwrap();
+        assert_error(response, other_channel_id, ErrorCode::ChannelBusy);
+
+        let cancel = Packet::Initialization {
+            channel_id,
+            command: Command::Cancel,
+            data: Vec::new(),
+            payload_len: 0,
+        };
+        let response = state_machine.accept_packet(cancel).unwrap();
+        assert_error(response, channel_id, ErrorCode::KeepaliveCancel);
+        let response = state_machine.accept_packet(ping()).unwrap().unwrap();
+        assert_eq!(response.channel_id, other_channel_id);
+        match response.message {
+            ResponseMessage::Pong { .. } => {}
+            ref message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn cancel_without_outstanding_request_is_ignored() {
+        let logger = slog::Logger::root(slog_stdlog::StdLog.fuse(), o!());
+        let core = Core::new().unwrap();
+        let mut state_machine = StateMachine::new(FakeU2FService, core.handle(), logger);
+        let channel_id = init_channel(&mut state_machine);
+
+        let response = state_machine
+            .accept_packet(Packet::Initialization {
+                channel_id,
+                command: Command::Cancel,
+                data: Vec::new(),
+                payload_len: 0,
+            })
+            .unwrap();
+
+        assert!(response.is_none());
+        assert!(!state_machine.is_dispatching());
+    }
+
+    #[test]
+    fn keepalive_encoding() {
+        let response = Response {
+            channel_id: ChannelId(7),
+            message: ResponseMessage::Keepalive {
+                status: KeepaliveStatus::UserPresenceNeeded,
+            },
+        };
+
+        let bytes = response.into_packets().pop_front().unwrap().into_bytes();
+
+        assert_eq!(&bytes[..8], &[0x00, 0x00, 0x00, 0x07, 0xbb, 0x00, 0x01, 0x02]);
+    }
 }
@@ -0,0 +1,433 @@
+use std::io;
+use std::mem;
+
+use definitions::*;
+use futures::future;
+use futures::{Async, Future};
+use protocol_state_machine::StateMachine;
+use slog::{self, Drain};
+use slog_stdlog;
+use tokio_core::reactor::Handle;
+use u2f_core::{self, Service, U2F};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ServiceError {
+        /// Reports are 64 bytes, optionally preceded by a zero report number
+        InvalidReportLength(len: usize) {
+            display("HID report has length {}, expected {}", len, HID_REPORT_LEN)
+        }
+        Io(err: io::Error) {
+            from()
+            display("I/O error: {}", err)
+            cause(err)
+        }
+    }
+}
+
+/// U2FHID device driven one HID report at a time, for callers that own the transport
+///
+/// Accepts the output reports the host writes to the device and produces the input
+/// reports to send back. Channel allocation, message reassembly and the U2F requests
+/// themselves are handled as by `U2FHID`.
+pub struct U2FService<S> {
+    logger: slog::Logger,
+    state_machine: StateMachine<S>,
+}
+
+impl U2FService<U2F> {
+    pub fn new<L: Into<Option<slog::Logger>>>(
+        handle: Handle,
+        service: U2F,
+        logger: L,
+    ) -> U2FService<U2F> {
+        let logger = logger
+            .into()
+            .unwrap_or_else(|| slog::Logger::root(slog_stdlog::StdLog.fuse(), o!()));
+        let state_machine_logger = logger.new(o!());
+        let metrics = service.metrics();
+        U2FService {
+            logger,
+            state_machine: StateMachine::new(service, handle, state_machine_logger)
+                .with_metrics(metrics),
+        }
+    }
+
+    /// Stop handling packets and flush the secret store
+    ///
+    /// Taking `self` means no further packets can be accepted, a request already being
+    /// handled is completed first, see `StateMachine::shutdown`. The devices themselves
+    /// are owned by the caller and should be destroyed once this resolves.
+    pub fn shutdown(self) -> impl Future<Item = (), Error = io::Error> {
+        info!(self.logger, "Shutting down");
+        self.state_machine.shutdown()
+    }
+}
+
+impl<S> U2FService<S>
+where
+    S: Service<
+        Request = u2f_core::Request,
+        Response = u2f_core::Response,
+        Error = io::Error,
+        Future = Box<dyn Future<Item = u2f_core::Response, Error = io::Error>>,
+    >,
+{
+    /// Handle one report written by the host, resolving to the reports to send back
+    ///
+    /// Continuation packets are buffered internally, until the last packet of a request
+    /// has been handled this resolves to no reports (unless the packet itself is
+    /// rejected, e.g. with a channel busy error). Once a request is complete the future
+    /// resolves when its response is ready, which may mean waiting on user presence.
+    /// Keepalives are not produced, the response is only returned once it is final.
+    pub fn handle_packet<'a>(
+        &'a mut self,
+        channel_report: &[u8],
+    ) -> Box<dyn Future<Item = Vec<[u8; HID_REPORT_LEN]>, Error = ServiceError> + 'a> {
+        let mut packet = match parse_report(channel_report) {
+            Ok(packet) => Some(packet),
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let mut reports = Vec::new();
+        Box::new(future::poll_fn(move || {
+            if let Some(packet) = packet.take() {
+                trace!(self.logger, "Handle packet"; "packet" => &packet);
+                if let Some(response) = self.state_machine.accept_packet(packet)? {
+                    push_reports(&mut reports, response);
+                }
+            }
+            while let Some(response) = self.state_machine.step()? {
+                if let ResponseMessage::Keepalive { .. } = response.message {
+                    continue;
+                }
+                push_reports(&mut reports, response);
+            }
+            if self.state_machine.is_dispatching() {
+                Ok(Async::NotReady)
+            } else {
+                Ok(Async::Ready(mem::take(&mut reports)))
+            }
+        }))
+    }
+}
+
+fn parse_report(report: &[u8]) -> Result<Packet, ServiceError> {
+    let packet = match report.len() {
+        HID_REPORT_LEN => {
+            let mut bytes = Vec::with_capacity(HID_REPORT_LEN + 1);
+            bytes.push(0u8);
+            bytes.extend_from_slice(report);
+            Packet::from_bytes(&bytes)
+        }
+        len if len == HID_REPORT_LEN + 1 => Packet::from_bytes(report),
+        len => return Err(ServiceError::InvalidReportLength(len)),
+    };
+    Ok(packet.expect("report of valid length is always a packet"))
+}
+
+fn push_reports(reports: &mut Vec<[u8; HID_REPORT_LEN]>, response: Response) {
+    for packet in response.into_packets() {
+        let mut report = [0u8; HID_REPORT_LEN];
+        report.copy_from_slice(&packet.into_bytes());
+        reports.push(report);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use ctaphid::{self, Message, Reassembler};
+    use tokio_core::reactor::Core;
+    use u2f_core::{
+        self_signed_attestation, AlwaysApprove, ApduError, AppId, ApplicationKey, Counter,
+        InMemoryStore, KeyHandle, Metrics, SecretStore, SecureCryptoOperations, StoreFuture,
+    };
+
+    use super::*;
+
+    const INIT_NONCE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    #[derive(Default)]
+    struct CountingMetrics {
+        decode_errors: Cell<usize>,
+    }
+
+    impl Metrics for CountingMetrics {
+        fn on_decode_error(&self, _err: &ApduError) {
+            self.decode_errors.set(self.decode_errors.get() + 1);
+        }
+    }
+
+    fn service(core: &Core) -> U2FService<U2F> {
+        service_with_metrics(core, Rc::new(CountingMetrics::default()))
+    }
+
+    /// In memory store that counts how often it was flushed
+    #[derive(Default)]
+    struct FlushCountingStore {
+        inner: InMemoryStore,
+        flushes: Rc<Cell<usize>>,
+    }
+
+    impl SecretStore for FlushCountingStore {
+        fn add_application_key(&self, key: &ApplicationKey) -> StoreFuture<()> {
+            self.inner.add_application_key(key)
+        }
+        fn get_and_increment_counter(
+            &self,
+            application: &AppId,
+            handle: &KeyHandle,
+        ) -> StoreFuture<Counter> {
+            self.inner.get_and_increment_counter(application, handle)
+        }
+        fn retrieve_application_key(
+            &self,
+            application: &AppId,
+            handle: &KeyHandle,
+        ) -> StoreFuture<Option<ApplicationKey>> {
+            self.inner.retrieve_application_key(application, handle)
+        }
+        fn list_application_keys(&self) -> StoreFuture<Vec<(AppId, KeyHandle)>> {
+            self.inner.list_application_keys()
+        }
+        fn remove_application_key(
+            &self,
+            application: &AppId,
+            handle: &KeyHandle,
+        ) -> StoreFuture<bool> {
+            self.inner.remove_application_key(application, handle)
+        }
+        fn clear_all(&self) -> StoreFuture<()> {
+            self.inner.clear_all()
+        }
+        fn flush(&self) -> StoreFuture<()> {
+            self.flushes.set(self.flushes.get() + 1);
+            self.inner.flush()
+        }
+    }
+
+    fn service_with_metrics(core: &Core, metrics: Rc<dyn Metrics>) -> U2FService<U2F> {
+        let u2f = U2F::with_metrics(
+            Box::new(AlwaysApprove),
+            Box::new(SecureCryptoOperations::new(self_signed_attestation())),
+            Box::new(InMemoryStore::new()),
+            metrics,
+            None,
+        )
+        .unwrap();
+        U2FService::new(core.handle(), u2f, None)
+    }
+
+    /// Send a message one report at a time and reassemble the response
+    fn exchange(
+        core: &mut Core,
+        service: &mut U2FService<U2F>,
+        channel_id: ChannelId,
+        command: Command,
+        data: &[u8],
+    ) -> Message {
+        let packets = ctaphid::fragment(channel_id, command, data).unwrap();
+        let last = packets.len() - 1;
+        let mut reports = Vec::new();
+        for (index, packet) in packets.into_iter().enumerate() {
+            reports = core
+                .run(service.handle_packet(&packet.into_bytes()))
+                .unwrap();
+            if index != last {
+                assert!(
+                    reports.is_empty(),
+                    "response before the request was complete"
+                );
+            }
+        }
+
+        let mut reassembler = Reassembler::new();
+        let mut message = None;
+        for report in reports {
+            let mut bytes = vec![0u8];
+            bytes.extend_from_slice(&report);
+            message = reassembler
+                .accept(Packet::from_bytes(&bytes).unwrap())
+                .unwrap();
+        }
+        message.expect("response was incomplete")
+    }
+
+    fn init(core: &mut Core, service: &mut U2FService<U2F>) -> ChannelId {
+        let response = exchange(
+            core,
+            service,
+            BROADCAST_CHANNEL_ID,
+            Command::Init,
+            &INIT_NONCE,
+        );
+
+        assert_eq!(response.channel_id, BROADCAST_CHANNEL_ID);
+        assert_eq!(response.command, Command::Init);
+        assert_eq!(&response.data[..8], &INIT_NONCE);
+        let mut channel_id = [0u8; 4];
+        channel_id.copy_from_slice(&response.data[8..12]);
+        ChannelId(u32::from_be_bytes(channel_id))
+    }
+
+    fn extended_apdu(instruction: u8, parameter1: u8, data: &[u8]) -> Vec<u8> {
+        let mut apdu = vec![0x00, instruction, parameter1, 0x00];
+        apdu.push(0x00);
+        apdu.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        apdu.extend_from_slice(data);
+        apdu.extend_from_slice(&[0x00, 0x00]);
+        apdu
+    }
+
+    #[test]
+    fn init_register_authenticate() {
+        let mut core = Core::new().unwrap();
+        let mut service = service(&core);
+        let challenge = [0x11u8; 32];
+        let application = [0x22u8; 32];
+
+        let channel_id = init(&mut core, &mut service);
+
+        let mut register_data = challenge.to_vec();
+        register_data.extend_from_slice(&application);
+        let register = exchange(
+            &mut core,
+            &mut service,
+            channel_id,
+            Command::Msg,
+            &extended_apdu(0x01, 0x00, &register_data),
+        );
+        assert_eq!(register.channel_id, channel_id);
+        assert_eq!(register.command, Command::Msg);
+        assert_eq!(register.data[0], 0x05);
+        assert_eq!(&register.data[register.data.len() - 2..], &[0x90, 0x00]);
+        let key_handle_len = register.data[66] as usize;
+        let key_handle = register.data[67..67 + key_handle_len].to_vec();
+
+        let mut authenticate_data = challenge.to_vec();
+        authenticate_data.extend_from_slice(&application);
+        authenticate_data.push(key_handle_len as u8);
+        authenticate_data.extend_from_slice(&key_handle);
+        let authenticate = exchange(
+            &mut core,
+            &mut service,
+            channel_id,
+            Command::Msg,
+            &extended_apdu(0x02, 0x03, &authenticate_data),
+        );
+        assert_eq!(authenticate.channel_id, channel_id);
+        // User present, counter of one
+        assert_eq!(&authenticate.data[..5], &[0x01, 0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(
+            &authenticate.data[authenticate.data.len() - 2..],
+            &[0x90, 0x00]
+        );
+    }
+
+    #[test]
+    fn undecodable_request_is_counted() {
+        let mut core = Core::new().unwrap();
+        let metrics = Rc::new(CountingMetrics::default());
+        let mut service = service_with_metrics(&core, metrics.clone());
+        let channel_id = init(&mut core, &mut service);
+
+        let response = exchange(&mut core, &mut service, channel_id, Command::Msg, &[0x00]);
+
+        assert_eq!(response.command, Command::Msg);
+        assert_eq!(metrics.decode_errors.get(), 1);
+    }
+
+    #[test]
+    fn unsupported_instruction_is_ins_not_supported() {
+        let mut core = Core::new().unwrap();
+        let mut service = service(&core);
+        let channel_id = init(&mut core, &mut service);
+
+        let response = exchange(
+            &mut core,
+            &mut service,
+            channel_id,
+            Command::Msg,
+            &extended_apdu(0x99, 0x00, &[]),
+        );
+
+        assert_eq!(response.command, Command::Msg);
+        assert_eq!(response.data, vec![0x6D, 0x00]);
+    }
+
+    #[test]
+    fn get_info_is_answered() {
+        let mut core = Core::new().unwrap();
+        let mut service = service(&core);
+        let channel_id = init(&mut core, &mut service);
+
+        let response = exchange(&mut core, &mut service, channel_id, Command::Cbor, &[0x04]);
+
+        assert_eq!(response.command, Command::Cbor);
+        assert_eq!(response.data[0], 0x00);
+        assert!(response.data.windows(6).any(|window| window == b"U2F_V2"));
+    }
+
+    #[test]
+    fn multi_packet_ping_is_echoed() {
+        let mut core = Core::new().unwrap();
+        let mut service = service(&core);
+        let channel_id = init(&mut core, &mut service);
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+
+        let response = exchange(&mut core, &mut service, channel_id, Command::Ping, &data);
+
+        assert_eq!(response.channel_id, channel_id);
+        assert_eq!(response.command, Command::Ping);
+        assert_eq!(response.data, data);
+    }
+
+    #[test]
+    fn report_with_report_number_is_accepted() {
+        let mut core = Core::new().unwrap();
+        let mut service = service(&core);
+        let packet = ctaphid::fragment(BROADCAST_CHANNEL_ID, Command::Init, &INIT_NONCE)
+            .unwrap()
+            .pop_front()
+            .unwrap();
+        let mut report = vec![0u8];
+        report.extend_from_slice(&packet.into_bytes());
+
+        let reports = core.run(service.handle_packet(&report)).unwrap();
+
+        assert_eq!(reports.len(), 1);
+    }
+
+    #[test]
+    fn report_of_wrong_length_is_rejected() {
+        let core = Core::new().unwrap();
+        let mut service = service(&core);
+
+        match service.handle_packet(&[0u8; 10]).wait() {
+            Err(ServiceError::InvalidReportLength(10)) => {}
+            other => panic!("unexpected result {:?}", other.map(|reports| reports.len())),
+        }
+    }
+
+    #[test]
+    fn shutdown_flushes_store() {
+        let mut core = Core::new().unwrap();
+        let store = FlushCountingStore::default();
+        let flushes = store.flushes.clone();
+        let u2f = U2F::new(
+            Box::new(AlwaysApprove),
+            Box::new(SecureCryptoOperations::new(self_signed_attestation())),
+            Box::new(store),
+            None,
+        )
+        .unwrap();
+        let mut service = U2FService::new(core.handle(), u2f, None);
+        init(&mut core, &mut service);
+
+        core.run(service.shutdown()).unwrap();
+
+        assert_eq!(flushes.get(), 1);
+    }
+}